@@ -29,6 +29,7 @@ CLUSTER_TYPE_ATAC = ATAC_PREFIX + "_" + CLUSTER_TYPE_KMEANS
 CLUSTER_TYPE_GRAPHCLUST = GEX_PREFIX + "_" + "graphclust"
 CLUSTER_TYPE_ATAC_GRAPHCLUST = ATAC_PREFIX + "_" + "graphclust"
 CLUSTER_TYPE_ANTIBODY_GRAPHCLUST = f"{AB_PREFIX}_graphclust"
+CLUSTER_TYPE_WNN_GRAPHCLUST = "wnn_graphclust"
 CLUSTER_TYPE_KMEDOIDS = "kmedoids"
 CLUSTER_TYPE_CELLTYPES = "celltype"
 
@@ -91,6 +92,7 @@ def parse_clustering_key(clustering_key: str):
         CLUSTER_TYPE_GRAPHCLUST,
         CLUSTER_TYPE_ATAC_GRAPHCLUST,
         CLUSTER_TYPE_ANTIBODY_GRAPHCLUST,
+        CLUSTER_TYPE_WNN_GRAPHCLUST,
     ):
         return (clustering_key, 0)
     elif clustering_key.startswith(GEX_PREFIX):
@@ -118,6 +120,8 @@ def humanify_clustering_key(clustering_key: str):
         return "Gene Expression Graph-based"
     elif cluster_type == CLUSTER_TYPE_ATAC_GRAPHCLUST:
         return "Peaks Graph-based"
+    elif cluster_type == CLUSTER_TYPE_WNN_GRAPHCLUST:
+        return "Weighted Nearest Neighbor Graph-based"
     elif cluster_type == CLUSTER_TYPE_KMEANS:
         return "Gene Expression K-means (K=%d)" % cluster_param
     elif cluster_type == CLUSTER_TYPE_ANTIBODY_KMEANS:
@@ -153,11 +153,16 @@ NUM_MOLECULE_INFO_ENTRIES_PER_CHUNK = 40000000
 # Ensure this value matches cr_types::rna_read::HIGH_CONF_MAPQ.
 STAR_DEFAULT_HIGH_CONF_MAPQ = 255
 
+NORM_MODE_MAPPED = "mapped"
+NORM_MODE_MEDIAN_UMIS = "median_umis"
+NORM_MODE_SATURATION = "saturation"
+NORM_MODE_RATE = "rate"
 NORM_MODE_NONE = "none"
 
 AGG_ID_FIELD = "library_id"
 AGG_H5_FIELD = "molecule_h5"
 AGG_BATCH_FIELD = "batch"
+AGG_NORMALIZATION_RATE_FIELD = "normalization_rate"
 AGG_DESCRIPTION_FIELD = "description"
 
 # Cloupe field
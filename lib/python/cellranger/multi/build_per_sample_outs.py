@@ -15,7 +15,17 @@ def build_sample_outs(args, outs, is_pd: bool):
 
     count[analysis_key] = hard_link(args.rna_analysis)
     count["sample_cloupe"] = hard_link(args.cloupe)
+    count["sample_filtered_feature_bc_matrix_h5ad"] = hard_link(getattr(args, "h5ad", None))
     count["crispr_analysis"] = hard_link(args.crispr_analysis)
+    count["sample_ambient_corrected_matrix"] = hard_link(
+        getattr(args, "ambient_corrected_matrix_h5", None)
+    )
+    count["sample_ambient_rna_csv"] = hard_link(getattr(args, "ambient_rna_csv", None))
+    count["sample_doublets_csv"] = hard_link(getattr(args, "doublets_csv", None))
+    count["sample_molecule_info_parquet"] = hard_link(getattr(args, "molecule_info_parquet", None))
+    count["sample_barcode_summary_parquet"] = hard_link(
+        getattr(args, "barcode_summary_parquet", None)
+    )
     if hasattr(args, "analysis"):
         count["analysis"] = hard_link(args.analysis)
 
@@ -80,6 +90,7 @@ def build_sample_outs(args, outs, is_pd: bool):
     sample_outs["vdj_t"] = args.vdj_t_outs
     sample_outs["vdj_t_gd"] = args.vdj_t_gd_outs
     sample_outs["web_summary"] = hard_link(args.web_summary)
+    sample_outs["web_summary_alerts"] = hard_link(getattr(args, "web_summary_alerts", None))
 
     if args.beam_analyzer is not None:
         sample_outs["antigen_analysis"] = {}
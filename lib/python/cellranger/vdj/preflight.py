@@ -151,8 +151,6 @@ def check_inner_enrichment_primers(primers_file, reference_path):
 
 
 def check_chain(chain):
-    if ensure_binary(chain) == b"TR_GD" or ensure_binary(chain) == b"TR-GD":
-        raise PreflightException("Please use Cellranger multi to run Gamma/Delta TCR datasets.")
     if ensure_binary(chain) not in chain_types.CHAIN_TYPE_SPECS:
         raise PreflightException(
             "Must specify --chain as one of: {}.".format(
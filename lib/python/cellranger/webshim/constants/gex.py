@@ -1104,6 +1104,16 @@ CHEMISTRY_BATCH_CORRECTION_METRICS = [
     },
 ]
 
+# Note: these metrics only apply when ambient RNA estimation ran
+AMBIENT_RNA_METRICS = [
+    {
+        "name": "estimated_fraction_ambient_rna",
+        "display_name": "Estimated Fraction Ambient RNA",
+        "description": "The estimated fraction of UMIs in cell-associated barcodes that originate from ambient RNA, based on the expression profile of empty-droplet barcodes.",
+        "format": "percent",
+    },
+]
+
 METRICS = [
     {"name": "Summary", "metrics": SUMMARY_METRICS},
     {"name": "Sequencing", "metrics": SEQUENCING_METRICS},
@@ -1129,6 +1139,7 @@ METRICS = [
         "metrics": CHEMISTRY_BATCH_CORRECTION_METRICS,
     },
     {"name": "Targeted Enrichment", "metrics": TARGETED_APPLICATION_METRICS},
+    {"name": "Ambient RNA", "metrics": AMBIENT_RNA_METRICS},
 ]
 
 # pylint: disable=line-too-long
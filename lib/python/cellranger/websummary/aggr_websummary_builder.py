@@ -89,6 +89,7 @@ def build_web_summary_html_aggr(
     gg_id_to_name_map,
     sample_data_paths,
     sample_defs=None,
+    alerts_config=None,
 ):
     """Build a web summary file for an AGGR Run.
 
@@ -98,12 +99,13 @@ def build_web_summary_html_aggr(
         gg_id_to_name_map: dictionary to map gem group ids in barcode to library names
         sample_data_paths: Instance of SampleDataPaths
         sample_defs: Map of sample defs passed in from PARSE_CSV
+        alerts_config: Optional instance of AlertsConfig
 
     Returns:
         None
     """
     web_sum_data = build_web_summary_data_aggr(
-        sample_properties, gg_id_to_name_map, sample_data_paths, sample_defs
+        sample_properties, gg_id_to_name_map, sample_data_paths, sample_defs, alerts_config
     )
 
     write_html_file(filename, web_sum_data)
@@ -114,6 +116,7 @@ def build_web_summary_data_aggr(
     gg_id_to_name_map,
     sample_data_paths,
     sample_defs=None,
+    alerts_config=None,
 ):
     """Build a web summary file for an AGGR Run.
 
@@ -122,6 +125,7 @@ def build_web_summary_data_aggr(
         gg_id_to_name_map: dictionary to map gem group ids in barcode to library names
         sample_data_paths: Instance of SampleDataPaths
         sample_defs: Map of sample defs from PARSE_CSV
+        alerts_config: Optional instance of AlertsConfig
 
     Returns:
         web_sum_data: Web summary data
@@ -147,6 +151,8 @@ def build_web_summary_data_aggr(
                 metadata = TargetedAggrMetricAnnotations()
         else:
             metadata = MetricAnnotations(intron_mode_alerts=sample_properties.include_introns)
+    if alerts_config is not None:
+        alerts_config.apply_threshold_overrides(metadata)
 
     web_sum_data = build_web_summary_html_sc_and_aggr(
         sample_properties,
@@ -156,6 +162,8 @@ def build_web_summary_data_aggr(
         command,
         sample_defs,
     )
+    if alerts_config is not None:
+        alerts_config.apply_alarms(web_sum_data.alarms, sample_data.summary)
 
     tsne_plot = library_on_tsne_plot(sample_data, gg_id_to_name_map)
     if tsne_plot:
@@ -0,0 +1,186 @@
+#!/usr/bin/env python
+#
+# Copyright (c) 2024 10X Genomics, Inc. All rights reserved.
+#
+"""User-provided web summary alert configuration.
+
+The same TOML or JSON file is accepted by the multi and the aggr web summaries.
+[[override]] entries change the thresholds or the level of existing alerts, or disable them.
+An override selects an alert either by its metric key or by its title.
+[[alert]] entries define new alerts raised when a metric crosses a threshold.
+
+This mirrors UserAlertConfig of the multi web summary in lib/rust/cr_websummary/src/alert.rs.
+"""
+
+from __future__ import annotations
+
+import json
+import os
+
+import tomli
+
+from cellranger.websummary.metrics import (
+    ERROR_THRESHOLD,
+    WARNING_THRESHOLD,
+    MetricAnnotations,
+    get_maybe_nested_key,
+)
+
+_OVERRIDE_KEYS = {"metric", "title", "error_threshold", "warn_threshold", "level", "disabled"}
+_ALERT_KEYS = {"metric", "if_metric_is", "error_threshold", "warn_threshold", "title", "detail"}
+_LEVELS = {ERROR_THRESHOLD, WARNING_THRESHOLD, "INFO"}
+_IF_METRIC_IS = {"greater_than_or_equal", "less_than_or_equal"}
+
+
+def _check_keys(entry, allowed, kind):
+    unknown = set(entry) - allowed
+    if unknown:
+        raise ValueError(f"unknown field(s) in alert {kind}: {', '.join(sorted(unknown))}")
+
+
+def _is_met(alert, threshold_key, value):
+    threshold = alert.get(threshold_key)
+    if threshold is None:
+        return False
+    if alert["if_metric_is"] == "greater_than_or_equal":
+        return value >= threshold
+    return value <= threshold
+
+
+class AlertsConfig:
+    """Overrides of the existing alerts and user-defined metric alerts."""
+
+    def __init__(self, overrides=None, alerts=None):
+        self.overrides = overrides or []
+        self.alerts = alerts or []
+        self._validate()
+
+    @classmethod
+    def load(cls, path):
+        """Load the configuration from a .toml or .json file."""
+        ext = os.path.splitext(path)[1]
+        if ext == ".toml":
+            with open(path, "rb") as f:
+                data = tomli.load(f)
+        elif ext == ".json":
+            with open(path) as f:
+                data = json.load(f)
+        else:
+            raise ValueError(f"the alerts config {path} must have a .toml or .json extension")
+        _check_keys(data, {"override", "alert"}, "config")
+        return cls(overrides=data.get("override"), alerts=data.get("alert"))
+
+    def _validate(self):
+        for o in self.overrides:
+            _check_keys(o, _OVERRIDE_KEYS, "override")
+            if ("metric" in o) == ("title" in o):
+                raise ValueError("an alert override must specify exactly one of metric or title")
+            if "title" in o and ("error_threshold" in o or "warn_threshold" in o):
+                raise ValueError(
+                    f'the override of the alert "{o["title"]}" selects the alert by title '
+                    "and cannot change its thresholds, select it by metric instead"
+                )
+            if o.get("level") is not None and o["level"] not in _LEVELS:
+                raise ValueError(f"unknown alert level: {o['level']}")
+        for a in self.alerts:
+            _check_keys(a, _ALERT_KEYS, "definition")
+            if a.get("if_metric_is") not in _IF_METRIC_IS:
+                raise ValueError(
+                    f'the alert "{a.get("title")}" must set if_metric_is to one of '
+                    f"{', '.join(sorted(_IF_METRIC_IS))}"
+                )
+            if a.get("error_threshold") is None and a.get("warn_threshold") is None:
+                raise ValueError(
+                    f'the alert "{a.get("title")}" must specify an error_threshold '
+                    "or a warn_threshold"
+                )
+
+    def apply_threshold_overrides(self, metadata: MetricAnnotations):
+        """Replace the thresholds of the metrics in metrics.csv.
+
+        The error threshold is the acceptable value and the warn threshold is the targeted value.
+        """
+        for o in self.overrides:
+            key = o.get("metric")
+            if key not in metadata.metric_data:
+                continue
+            info = metadata.metric_data[key].copy()
+            if o.get("error_threshold") is not None:
+                info["acceptable"] = info["acceptable_cs"] = o["error_threshold"]
+            if o.get("warn_threshold") is not None:
+                info["targeted"] = info["targeted_cs"] = o["warn_threshold"]
+            metadata.metric_data[key] = info
+
+    def _matches(self, o, alarm):
+        if "metric" in o:
+            return o["metric"] == alarm.get("id")
+        return o["title"] == alarm.get("title")
+
+    def apply_alarm_overrides(self, alarms: list[dict], by_title: bool):
+        """Disable or change the level of the raised alarms, in place.
+
+        Apply either the overrides that select an alert by title or those that select it by metric.
+        """
+        for o in self.overrides:
+            if ("title" in o) != by_title:
+                continue
+            if o.get("disabled", False):
+                alarms[:] = [alarm for alarm in alarms if not self._matches(o, alarm)]
+            elif o.get("level") is not None:
+                for alarm in alarms:
+                    if self._matches(o, alarm):
+                        alarm["level"] = o["level"]
+
+    def metric_alarms(self, summary: dict) -> list[dict]:
+        """Evaluate the user-defined alerts on the metrics of the summary."""
+        alarms = []
+        for a in self.alerts:
+            value = get_maybe_nested_key(summary, a["metric"])
+            if not isinstance(value, (int, float)):
+                continue
+            if _is_met(a, "error_threshold", value):
+                level = ERROR_THRESHOLD
+            elif _is_met(a, "warn_threshold", value):
+                level = WARNING_THRESHOLD
+            else:
+                continue
+            alarms.append(
+                {
+                    "raw_value": value,
+                    "formatted_value": f"{value:g}",
+                    "raised": True,
+                    "parent": a["metric"],
+                    "title": a["title"],
+                    "message": a.get("detail", ""),
+                    "level": level,
+                    "test": "",
+                    "id": a["metric"],
+                }
+            )
+        return alarms
+
+    def apply_alarms(self, alarms: list[dict], summary: dict):
+        """Apply the overrides to the raised alarms and add the user-defined alarms, in place.
+
+        The overrides selecting an alert by metric apply to the existing alarms only,
+        and those selecting an alert by title apply to the user-defined alarms as well.
+        """
+        self.apply_alarm_overrides(alarms, by_title=False)
+        alarms.extend(self.metric_alarms(summary))
+        self.apply_alarm_overrides(alarms, by_title=True)
+
+
+def write_alerts_json(filename, alarms: list[dict]):
+    """Write the raised alarms to a machine-readable JSON file."""
+    alerts = [
+        {
+            "metric": alarm.get("id"),
+            "level": alarm["level"],
+            "title": alarm["title"],
+            "formatted_value": alarm.get("formatted_value"),
+            "message": alarm.get("message"),
+        }
+        for alarm in alarms
+    ]
+    with open(filename, "w") as f:
+        json.dump(alerts, f, indent=4)
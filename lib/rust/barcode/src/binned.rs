@@ -42,6 +42,16 @@ impl SquareBinIndex {
             size_um: self.size_um * bin_scale as u32,
         }
     }
+
+    /// The center of this bin in micrometers as (x, y),
+    /// where x increases with the column and y with the row.
+    pub fn center_um(self) -> (f64, f64) {
+        let size_um = f64::from(self.size_um);
+        (
+            (self.col as f64 + 0.5) * size_um,
+            (self.row as f64 + 0.5) * size_um,
+        )
+    }
 }
 
 #[pymethods]
@@ -121,6 +131,241 @@ impl FromStr for SquareBinIndex {
     }
 }
 
+pub const HEXAGONAL_BIN_PREFIX: &str = "h";
+
+/// A hexagonal bin of a pointy-top hexagonal grid in odd-row offset coordinates.
+/// `size_um` is the distance between the centers of adjacent bins of a row,
+/// and odd rows are shifted right by half a bin.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Debug)]
+pub struct HexagonalBinIndex {
+    pub row: usize,
+    pub col: usize,
+    pub size_um: u32,
+}
+
+impl HexagonalBinIndex {
+    /// Return the hexagonal bin whose center is nearest to the point (x, y) in micrometers.
+    /// Both coordinates must be non-negative.
+    pub fn containing(x: f64, y: f64, size_um: u32) -> Self {
+        let side = f64::from(size_um) / 3f64.sqrt();
+        // Fractional axial coordinates.
+        let q = (3f64.sqrt() / 3.0 * x - y / 3.0) / side;
+        let r = 2.0 / 3.0 * y / side;
+        // Round the cube coordinates (q, r, -q - r) to the nearest hexagon.
+        let s = -q - r;
+        let (mut rq, mut rr, rs) = (q.round(), r.round(), s.round());
+        let (dq, dr, ds) = ((rq - q).abs(), (rr - r).abs(), (rs - s).abs());
+        if dq > dr && dq > ds {
+            rq = -rr - rs;
+        } else if dr > ds {
+            rr = -rq - rs;
+        }
+        let (q, r) = (rq as i64, rr as i64);
+        // The nearest center of a point in the positive quadrant is never at a negative offset.
+        HexagonalBinIndex {
+            row: r.max(0) as usize,
+            col: (q + (r - (r & 1)) / 2).max(0) as usize,
+            size_um,
+        }
+    }
+
+    /// The center of this bin in micrometers as (x, y).
+    pub fn center_um(self) -> (f64, f64) {
+        let size_um = f64::from(self.size_um);
+        let shift = if self.row % 2 == 1 { 0.5 } else { 0.0 };
+        (
+            (self.col as f64 + shift) * size_um,
+            self.row as f64 * 1.5 * size_um / 3f64.sqrt(),
+        )
+    }
+}
+
+impl Display for HexagonalBinIndex {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{HEXAGONAL_BIN_PREFIX}_{:03}{MICROMETER}_{:05}_{:05}",
+            self.size_um, self.row, self.col
+        )
+    }
+}
+
+pub const POLYGON_BIN_PREFIX: &str = "p";
+
+/// A bin defined by membership in a polygon, such as a segmented cell.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Debug)]
+pub struct PolygonBinIndex {
+    pub id: u32,
+}
+
+impl Display for PolygonBinIndex {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{POLYGON_BIN_PREFIX}_{:09}", self.id)
+    }
+}
+
+/// A simple polygon with vertices in micrometers, in the same coordinates as
+/// [`SquareBinIndex::center_um`].
+#[derive(Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct Polygon {
+    pub id: u32,
+    pub vertices: Vec<(f64, f64)>,
+}
+
+impl Polygon {
+    /// The bounding box of this polygon as (min_x, min_y, max_x, max_y).
+    fn bounding_box(&self) -> (f64, f64, f64, f64) {
+        self.vertices.iter().fold(
+            (
+                f64::INFINITY,
+                f64::INFINITY,
+                f64::NEG_INFINITY,
+                f64::NEG_INFINITY,
+            ),
+            |(x0, y0, x1, y1), &(x, y)| (x0.min(x), y0.min(y), x1.max(x), y1.max(y)),
+        )
+    }
+
+    /// Return true if the point (x, y) is inside this polygon, using the even-odd rule.
+    pub fn contains(&self, x: f64, y: f64) -> bool {
+        let mut inside = false;
+        for (&(xi, yi), &(xj, yj)) in self
+            .vertices
+            .iter()
+            .zip(self.vertices.iter().cycle().skip(1))
+        {
+            if (yi > y) != (yj > y) && x < (xj - xi) * (y - yi) / (yj - yi) + xi {
+                inside = !inside;
+            }
+        }
+        inside
+    }
+}
+
+/// The bin of an aggregated barcode of any binning scheme.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Debug)]
+pub enum BinIndex {
+    Square(SquareBinIndex),
+    Hexagonal(HexagonalBinIndex),
+    Polygon(PolygonBinIndex),
+}
+
+impl Display for BinIndex {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BinIndex::Square(index) => write!(f, "{index}"),
+            BinIndex::Hexagonal(index) => write!(f, "{index}"),
+            BinIndex::Polygon(index) => write!(f, "{index}"),
+        }
+    }
+}
+
+/// A scheme aggregating the unbinned HD barcodes into larger bins.
+/// Each barcode is assigned to a bin by the position of its center.
+#[derive(Clone, Debug)]
+pub enum BinningScheme {
+    /// Square bins of any size in micrometers, not necessarily a multiple of the pitch.
+    Square { size_um: u32 },
+    /// Hexagonal bins with centers `size_um` micrometers apart.
+    Hexagonal { size_um: u32 },
+    /// Bins defined by the polygon containing each barcode.
+    /// Barcodes outside of every polygon are not assigned to a bin.
+    Polygons(PolygonIndex),
+}
+
+impl BinningScheme {
+    /// The name of this scheme, such as square_008um or hexagonal_016um.
+    pub fn name(&self) -> String {
+        match self {
+            BinningScheme::Square { size_um } => format!("square_{size_um:03}{MICROMETER}"),
+            BinningScheme::Hexagonal { size_um } => {
+                format!("hexagonal_{size_um:03}{MICROMETER}")
+            }
+            BinningScheme::Polygons(_) => "polygons".to_string(),
+        }
+    }
+
+    /// Return the bin of an unbinned barcode, if any.
+    pub fn bin(&self, barcode: SquareBinIndex) -> Option<BinIndex> {
+        let (x, y) = barcode.center_um();
+        match self {
+            &BinningScheme::Square { size_um } => {
+                let size = f64::from(size_um);
+                Some(BinIndex::Square(SquareBinIndex {
+                    row: (y / size) as usize,
+                    col: (x / size) as usize,
+                    size_um,
+                }))
+            }
+            &BinningScheme::Hexagonal { size_um } => Some(BinIndex::Hexagonal(
+                HexagonalBinIndex::containing(x, y, size_um),
+            )),
+            BinningScheme::Polygons(index) => index
+                .containing(x, y)
+                .map(|id| BinIndex::Polygon(PolygonBinIndex { id })),
+        }
+    }
+}
+
+/// Polygons indexed by the rows of a coarse grid spanned by their bounding boxes.
+#[derive(Clone, Debug)]
+pub struct PolygonIndex {
+    polygons: Vec<Polygon>,
+    bounding_boxes: Vec<(f64, f64, f64, f64)>,
+    row_height_um: f64,
+    /// The indices of the polygons overlapping each row of the grid.
+    rows: Vec<Vec<usize>>,
+}
+
+impl PolygonIndex {
+    const ROW_HEIGHT_UM: f64 = 50.0;
+
+    pub fn new(polygons: Vec<Polygon>) -> anyhow::Result<Self> {
+        let bounding_boxes: Vec<_> = polygons.iter().map(Polygon::bounding_box).collect();
+        let mut rows: Vec<Vec<usize>> = Vec::new();
+        for (i, (polygon, &(_, y0, _, y1))) in polygons.iter().zip(&bounding_boxes).enumerate() {
+            ensure!(
+                polygon.vertices.len() >= 3,
+                "polygon {} has fewer than three vertices",
+                polygon.id
+            );
+            ensure!(
+                y0 >= 0.0 && y1.is_finite(),
+                "polygon {} has a negative or non-finite coordinate",
+                polygon.id
+            );
+            let (first, last) = (
+                (y0 / Self::ROW_HEIGHT_UM) as usize,
+                (y1 / Self::ROW_HEIGHT_UM) as usize,
+            );
+            if rows.len() <= last {
+                rows.resize(last + 1, Vec::new());
+            }
+            for row in &mut rows[first..=last] {
+                row.push(i);
+            }
+        }
+        Ok(PolygonIndex {
+            polygons,
+            bounding_boxes,
+            row_height_um: Self::ROW_HEIGHT_UM,
+            rows,
+        })
+    }
+
+    /// Return the ID of the first polygon containing the point (x, y), if any.
+    pub fn containing(&self, x: f64, y: f64) -> Option<u32> {
+        self.rows
+            .get((y / self.row_height_um) as usize)?
+            .iter()
+            .find(|&&i| {
+                let (x0, y0, x1, y1) = self.bounding_boxes[i];
+                (x0..=x1).contains(&x) && (y0..=y1).contains(&y) && self.polygons[i].contains(x, y)
+            })
+            .map(|&i| self.polygons[i].id)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -137,6 +382,127 @@ mod tests {
         assert_eq!(b, "s_016um_00001_00002-1".parse().unwrap());
     }
 
+    #[test]
+    fn test_square_scheme_matches_integer_scaling() {
+        let scheme = BinningScheme::Square { size_um: 16 };
+        for (row, col) in [(0, 0), (7, 8), (15, 16), (1234, 3001)] {
+            let spot = SquareBinIndex {
+                row,
+                col,
+                size_um: 2,
+            };
+            assert_eq!(
+                scheme.bin(spot),
+                Some(BinIndex::Square(spot.extract_binned_barcode(8)))
+            );
+        }
+        assert_eq!(scheme.name(), "square_016um");
+    }
+
+    #[test]
+    fn test_square_scheme_arbitrary_size() {
+        let scheme = BinningScheme::Square { size_um: 5 };
+        let bin = |row, col| {
+            scheme
+                .bin(SquareBinIndex {
+                    row,
+                    col,
+                    size_um: 2,
+                })
+                .unwrap()
+                .to_string()
+        };
+        // Spot centers at 1, 3 and 5 um fall in bins 0, 0 and 1.
+        assert_eq!(bin(0, 0), "s_005um_00000_00000");
+        assert_eq!(bin(1, 1), "s_005um_00000_00000");
+        assert_eq!(bin(2, 1), "s_005um_00001_00000");
+    }
+
+    #[test]
+    fn test_hexagonal_bin() {
+        let size_um = 10;
+        for row in 0..20 {
+            for col in 0..20 {
+                let hex = HexagonalBinIndex { row, col, size_um };
+                let (x, y) = hex.center_um();
+                assert_eq!(HexagonalBinIndex::containing(x, y, size_um), hex);
+                // A point just inside the hexagon of its nearest center.
+                assert_eq!(
+                    HexagonalBinIndex::containing(x + 4.0, y + 1.0, size_um),
+                    hex
+                );
+            }
+        }
+        let hex = HexagonalBinIndex {
+            row: 1,
+            col: 2,
+            size_um: 16,
+        };
+        assert_eq!(hex.to_string(), "h_016um_00001_00002");
+    }
+
+    #[test]
+    fn test_hexagonal_bin_is_nearest_center() {
+        let size_um = 7;
+        for i in 0..200 {
+            for j in 0..200 {
+                let (x, y) = (0.37 * i as f64 + 0.1, 0.41 * j as f64 + 0.1);
+                let hex = HexagonalBinIndex::containing(x, y, size_um);
+                let dist = |h: HexagonalBinIndex| {
+                    let (cx, cy) = h.center_um();
+                    (cx - x).powi(2) + (cy - y).powi(2)
+                };
+                for dr in 0..=2 {
+                    for dc in 0..=2 {
+                        let (Some(row), Some(col)) =
+                            ((hex.row + dr).checked_sub(1), (hex.col + dc).checked_sub(1))
+                        else {
+                            continue;
+                        };
+                        let other = HexagonalBinIndex { row, col, size_um };
+                        assert!(dist(hex) <= dist(other) + 1e-9);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_polygon_scheme() {
+        let polygons = vec![
+            Polygon {
+                id: 1,
+                vertices: vec![(0.0, 0.0), (10.0, 0.0), (10.0, 10.0), (0.0, 10.0)],
+            },
+            // A triangle spanning two rows of the index.
+            Polygon {
+                id: 2,
+                vertices: vec![(20.0, 40.0), (60.0, 40.0), (20.0, 80.0)],
+            },
+        ];
+        let scheme = BinningScheme::Polygons(PolygonIndex::new(polygons).unwrap());
+        let bin = |row, col| {
+            scheme.bin(SquareBinIndex {
+                row,
+                col,
+                size_um: 2,
+            })
+        };
+        assert_eq!(
+            bin(2, 3),
+            Some(BinIndex::Polygon(PolygonBinIndex { id: 1 }))
+        );
+        assert_eq!(bin(2, 7), None);
+        assert_eq!(
+            bin(30, 12),
+            Some(BinIndex::Polygon(PolygonBinIndex { id: 2 }))
+        );
+        // Inside the bounding box but outside the triangle.
+        assert_eq!(bin(35, 25), None);
+        assert_eq!(bin(500, 500), None);
+        assert_eq!(PolygonBinIndex { id: 2 }.to_string(), "p_000000002");
+    }
+
     proptest::proptest! {
         #[test]
         fn test_roundtrip(row in 0..5000usize, col in 0..5000usize, size_um in 0..200u32) {
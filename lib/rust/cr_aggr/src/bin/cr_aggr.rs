@@ -88,6 +88,7 @@ fn main() -> Result<()> {
         cr_aggr::write_aggr_ann::WriteAggrAnn,
         cr_aggr::write_ws_json::WriteWsJson,
         cr_aggr::create_antigen_clonotype_clustermap::CreateClonotypeClustermap,
+        cr_aggr::qc_report::BuildQcReport,
     ];
 
     if args.cmd_martian {
@@ -17,6 +17,8 @@ martian_filetype!(VdjLoupeFile, "vloupe");
 pub struct VdjAggrResults {
     airr_rearrangement: TsvFile<()>,
     clonotypes: CsvFile<()>,
+    clonotype_sharing: CsvFile<()>,
+    clonotype_overlap: CsvFile<()>,
     donor_regions: FaFile,
     consensus_fasta: FastaFile,
     filtered_contig_annotations_csv: CsvFile<()>,
@@ -48,6 +50,8 @@ pub struct MatchVdjOutsStageInputs {
     antigen_analysis: Vec<Option<AntigenAggrResults>>,
     antigen_aggr_web_summary_data_in: Vec<Option<JsonFile<()>>>,
     airr_rearrangements: Vec<TsvFile<()>>,
+    clonotype_sharing_csvs: Vec<CsvFile<()>>,
+    clonotype_overlap_csvs: Vec<CsvFile<()>>,
 }
 
 impl MatchVdjOutsStageInputs {
@@ -55,6 +59,8 @@ impl MatchVdjOutsStageInputs {
         idx.map(|i| VdjAggrResults {
             airr_rearrangement: self.airr_rearrangements[i].clone(),
             clonotypes: self.clonotypes[i].clone(),
+            clonotype_sharing: self.clonotype_sharing_csvs[i].clone(),
+            clonotype_overlap: self.clonotype_overlap_csvs[i].clone(),
             donor_regions: self.donor_ref_fas[i].clone(),
             consensus_fasta: self.consensus_fastas[i].clone(),
             filtered_contig_annotations_csv: self.filtered_contig_annotations_csvs[i].clone(),
@@ -0,0 +1,566 @@
+//! Martian stage BUILD_QC_REPORT
+//! Compare the metrics summaries of many runs and flag the runs that deviate from the cohort.
+
+use anyhow::{bail, Context, Result};
+use cr_types::MetricsFile;
+use itertools::Itertools;
+use martian::prelude::*;
+use martian_derive::{make_mro, martian_filetype, MartianStruct};
+use martian_filetypes::tabular_file::CsvFile;
+use martian_filetypes::{FileTypeRead, FileTypeWrite};
+use metric::TxHashMap;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use tenx_websummary::components::{HeroMetric, PlotlyChart, TitleWithHelp, WithTitle, WsNavBar};
+use tenx_websummary::{HtmlTemplate, SinglePageHtml};
+
+martian_filetype! {HtmlFile, "html"}
+
+/// Flag a metric of a run when the absolute value of its z-score is at least this large.
+const DEFAULT_Z_SCORE_THRESHOLD: f64 = 2.0;
+
+/// A z-score requires the metric to be present in at least this many runs.
+const MIN_RUNS_PER_METRIC: usize = 3;
+
+/// The columns of the multi metrics_summary.csv, which has one metric per row.
+const MULTI_CSV_KEY_COLUMNS: [&str; 4] = ["Category", "Library Type", "Group Name", "Metric Name"];
+const MULTI_CSV_VALUE_COLUMN: &str = "Metric Value";
+
+/// One row of the input CSV, which lists the runs to compare in order.
+#[derive(Debug, Deserialize)]
+struct QcRunRow {
+    run_id: String,
+    metrics_summary: PathBuf,
+}
+
+/// The numeric metrics of one run.
+struct RunMetrics {
+    run_id: String,
+    metrics: BTreeMap<String, f64>,
+}
+
+/// A metric of a run that deviates from the cohort.
+/// The mean and the standard deviation of the cohort exclude the run itself.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct QcOutlier {
+    pub run_id: String,
+    pub metric: String,
+    pub value: f64,
+    pub cohort_mean: f64,
+    pub cohort_sd: f64,
+    pub z_score: f64,
+}
+
+/// The distribution of a metric across the runs.
+#[derive(Debug, PartialEq)]
+struct MetricStats {
+    mean: f64,
+    sd: f64,
+}
+
+impl MetricStats {
+    /// Return the mean and the sample standard deviation, or None if there are too few values.
+    fn new(values: &[f64]) -> Option<Self> {
+        if values.len() < 2 {
+            return None;
+        }
+        let n = values.len() as f64;
+        let mean = values.iter().sum::<f64>() / n;
+        let sd = (values.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.0)).sqrt();
+        Some(MetricStats { mean, sd })
+    }
+
+    fn z_score(&self, value: f64) -> f64 {
+        if self.sd > 0.0 {
+            (value - self.mean) / self.sd
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Parse a metric value such as "1,234", "93.5%" or "0.25".
+/// Percentages are kept in percent units.
+fn parse_metric_value(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let value = value.strip_suffix('%').unwrap_or(value).replace(',', "");
+    value.parse::<f64>().ok().filter(|x| x.is_finite())
+}
+
+/// Read the metrics of a metrics_summary.csv, either the count format with one column per
+/// metric, or the multi format with one row per metric.
+fn read_metrics_csv(reader: impl Read) -> Result<BTreeMap<String, f64>> {
+    let mut reader = csv::Reader::from_reader(reader);
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| headers.iter().position(|h| h == name);
+
+    let mut metrics = BTreeMap::new();
+    if let Some(value_idx) = column(MULTI_CSV_VALUE_COLUMN) {
+        let key_idxs: Vec<_> = MULTI_CSV_KEY_COLUMNS
+            .iter()
+            .filter_map(|name| column(name))
+            .collect();
+        for record in reader.records() {
+            let record = record?;
+            let key = key_idxs
+                .iter()
+                .filter_map(|&i| record.get(i))
+                .filter(|field| !field.is_empty())
+                .join(" - ");
+            if let Some(value) = record.get(value_idx).and_then(parse_metric_value) {
+                metrics.insert(key, value);
+            }
+        }
+    } else if let Some(record) = reader.records().next() {
+        for (name, value) in headers.iter().zip(record?.iter()) {
+            if let Some(value) = parse_metric_value(value) {
+                metrics.insert(name.to_string(), value);
+            }
+        }
+    }
+    Ok(metrics)
+}
+
+/// Read the numeric metrics of a metrics summary JSON or CSV file.
+fn read_metrics(path: &Path) -> Result<BTreeMap<String, f64>> {
+    let metrics = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => {
+            let metrics: TxHashMap<String, Value> = MetricsFile::from_path(path).read()?;
+            metrics
+                .into_iter()
+                .filter_map(|(key, value)| Some((key, value.as_f64()?)))
+                .filter(|(_, value)| value.is_finite())
+                .collect()
+        }
+        Some("csv") => read_metrics_csv(std::fs::File::open(path)?)?,
+        _ => bail!(
+            "the metrics summary {} must be a .json or .csv file",
+            path.display()
+        ),
+    };
+    Ok(metrics)
+}
+
+/// Resolve a path relative to the pipestance root or to the folder of the runs CSV.
+fn resolve_path(path: &Path, pipestance_root: &Path, runs_csv: &Path) -> Result<PathBuf> {
+    [
+        path.to_path_buf(),
+        pipestance_root.join(path),
+        runs_csv.parent().unwrap().join(path),
+    ]
+    .into_iter()
+    .find(|p| p.is_file())
+    .with_context(|| format!("the metrics summary {} does not exist", path.display()))
+}
+
+/// Compute the z-scores of every metric of every run, each relative to the other runs,
+/// so that an outlying run does not inflate the standard deviation that it is scored against.
+/// Return the z-scores indexed by metric then run, and the outliers.
+fn compute_z_scores(
+    runs: &[RunMetrics],
+    threshold: f64,
+) -> (BTreeMap<String, Vec<Option<f64>>>, Vec<QcOutlier>) {
+    let metric_names: Vec<&String> = runs
+        .iter()
+        .flat_map(|run| run.metrics.keys())
+        .sorted()
+        .dedup()
+        .collect();
+
+    let mut z_scores = BTreeMap::new();
+    let mut outliers = Vec::new();
+    for metric in metric_names {
+        let values: Vec<Option<f64>> = runs
+            .iter()
+            .map(|run| run.metrics.get(metric).copied())
+            .collect();
+        if values.iter().flatten().count() < MIN_RUNS_PER_METRIC {
+            continue;
+        }
+        let metric_z_scores = runs
+            .iter()
+            .zip(&values)
+            .enumerate()
+            .map(|(i, (run, &value))| {
+                let value = value?;
+                let others: Vec<f64> = values
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(j, &other)| if j == i { None } else { other })
+                    .collect();
+                let stats = MetricStats::new(&others)?;
+                let z_score = stats.z_score(value);
+                if z_score.abs() >= threshold {
+                    outliers.push(QcOutlier {
+                        run_id: run.run_id.clone(),
+                        metric: metric.clone(),
+                        value,
+                        cohort_mean: stats.mean,
+                        cohort_sd: stats.sd,
+                        z_score,
+                    });
+                }
+                Some(z_score)
+            })
+            .collect();
+        z_scores.insert(metric.clone(), metric_z_scores);
+    }
+    (z_scores, outliers)
+}
+
+fn title(title: &str, help: &str) -> TitleWithHelp {
+    TitleWithHelp {
+        help: help.to_string(),
+        title: title.to_string(),
+    }
+}
+
+/// Plot the z-score of each metric across the runs, marking the outliers.
+/// Only the metrics with an outlier are shown initially.
+fn metric_trends_plot(
+    runs: &[RunMetrics],
+    z_scores: &BTreeMap<String, Vec<Option<f64>>>,
+    outliers: &[QcOutlier],
+    threshold: f64,
+) -> PlotlyChart {
+    let run_ids: Vec<_> = runs.iter().map(|run| run.run_id.as_str()).collect();
+    let mut data: Vec<Value> = z_scores
+        .iter()
+        .map(|(metric, metric_z_scores)| {
+            let values: Vec<_> = runs.iter().map(|run| run.metrics.get(metric)).collect();
+            let has_outlier = outliers.iter().any(|o| &o.metric == metric);
+            json!({
+                "type": "scatter",
+                "mode": "lines+markers",
+                "name": metric,
+                "x": run_ids,
+                "y": metric_z_scores,
+                "customdata": values,
+                "visible": if has_outlier { json!(true) } else { json!("legendonly") },
+                "hovertemplate": format!("%{{x}}<br>{metric}: %{{customdata:,.4~g}}<br>z = %{{y:.2f}}<extra></extra>"),
+            })
+        })
+        .collect();
+    data.push(json!({
+        "type": "scatter",
+        "mode": "markers",
+        "name": "Outliers",
+        "x": outliers.iter().map(|o| &o.run_id).collect::<Vec<_>>(),
+        "y": outliers.iter().map(|o| o.z_score).collect::<Vec<_>>(),
+        "text": outliers.iter().map(|o| &o.metric).collect::<Vec<_>>(),
+        "marker": {"color": "red", "size": 12, "symbol": "x"},
+        "hovertemplate": "%{x}<br>%{text}<br>z = %{y:.2f}<extra></extra>",
+    }));
+    let threshold_line = |y: f64| {
+        json!({
+            "type": "line",
+            "xref": "paper",
+            "x0": 0,
+            "x1": 1,
+            "y0": y,
+            "y1": y,
+            "line": {"color": "gray", "dash": "dash"},
+        })
+    };
+    PlotlyChart::with_layout_and_data(
+        json!({
+            "xaxis": {"title": "Run", "type": "category"},
+            "yaxis": {"title": "z-score"},
+            "shapes": [threshold_line(threshold), threshold_line(-threshold)],
+            "hovermode": "closest",
+        }),
+        data,
+    )
+}
+
+/// Plot the number of outlier metrics of each run.
+fn outlier_counts_plot(runs: &[RunMetrics], outliers: &[QcOutlier]) -> PlotlyChart {
+    let counts = outliers.iter().counts_by(|o| o.run_id.as_str());
+    PlotlyChart::with_layout_and_data(
+        json!({
+            "xaxis": {"title": "Run", "type": "category"},
+            "yaxis": {"title": "Outlier metrics"},
+        }),
+        vec![json!({
+            "type": "bar",
+            "x": runs.iter().map(|run| &run.run_id).collect::<Vec<_>>(),
+            "y": runs.iter().map(|run| counts.get(run.run_id.as_str()).copied().unwrap_or(0)).collect::<Vec<_>>(),
+        })],
+    )
+}
+
+/// A table of the runs that deviate from the cohort, colored by z-score.
+/// The cells show the metric values.
+fn deviating_runs_table(
+    runs: &[RunMetrics],
+    z_scores: &BTreeMap<String, Vec<Option<f64>>>,
+    outliers: &[QcOutlier],
+) -> Option<PlotlyChart> {
+    if outliers.is_empty() {
+        return None;
+    }
+    let metrics: Vec<&String> = outliers
+        .iter()
+        .map(|o| &o.metric)
+        .sorted()
+        .dedup()
+        .collect();
+    let deviating: Vec<(usize, &RunMetrics)> = runs
+        .iter()
+        .enumerate()
+        .filter(|(_, run)| outliers.iter().any(|o| o.run_id == run.run_id))
+        .collect();
+    let z: Vec<Vec<Option<f64>>> = deviating
+        .iter()
+        .map(|&(i, _)| metrics.iter().map(|m| z_scores[*m][i]).collect())
+        .collect();
+    let text: Vec<Vec<String>> = deviating
+        .iter()
+        .map(|(_, run)| {
+            metrics
+                .iter()
+                .map(|m| {
+                    run.metrics
+                        .get(*m)
+                        .map_or_else(String::new, |v| format!("{v}"))
+                })
+                .collect()
+        })
+        .collect();
+    Some(PlotlyChart::with_layout_and_data(
+        json!({
+            "xaxis": {"type": "category", "automargin": true},
+            "yaxis": {"type": "category", "automargin": true, "autorange": "reversed"},
+        }),
+        vec![json!({
+            "type": "heatmap",
+            "x": metrics,
+            "y": deviating.iter().map(|(_, run)| &run.run_id).collect::<Vec<_>>(),
+            "z": z,
+            "text": text,
+            "texttemplate": "%{text}",
+            "colorscale": "RdBu",
+            "reversescale": true,
+            "zmid": 0,
+            "colorbar": {"title": "z-score"},
+            "hovertemplate": "%{y}<br>%{x}: %{text}<br>z = %{z:.2f}<extra></extra>",
+        })],
+    ))
+}
+
+#[derive(Serialize, Clone, HtmlTemplate)]
+struct QcReportContent {
+    #[html(row = "hero")]
+    num_runs: HeroMetric,
+    #[html(row = "hero")]
+    num_metrics: HeroMetric,
+    #[html(row = "hero")]
+    num_deviating_runs: HeroMetric,
+    metric_trends: WithTitle<PlotlyChart>,
+    outlier_counts: WithTitle<PlotlyChart>,
+    deviating_runs: Option<WithTitle<PlotlyChart>>,
+}
+
+#[derive(Clone, Deserialize, MartianStruct)]
+pub struct BuildQcReportStageInputs {
+    pub sample_id: String,
+    pub sample_desc: String,
+    pub pipestance_root: PathBuf,
+    /// CSV with the columns run_id and metrics_summary
+    pub runs_csv: CsvFile<()>,
+    pub z_score_threshold: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, MartianStruct)]
+pub struct BuildQcReportStageOutputs {
+    pub qc_report: HtmlFile,
+    /// The metrics of all the runs, one row per run
+    pub qc_metrics: CsvFile<()>,
+    pub qc_outliers: CsvFile<QcOutlier>,
+}
+
+/// Martian stage BUILD_QC_REPORT
+pub struct BuildQcReport;
+
+#[make_mro(mem_gb = 2, volatile = strict)]
+impl MartianMain for BuildQcReport {
+    type StageInputs = BuildQcReportStageInputs;
+    type StageOutputs = BuildQcReportStageOutputs;
+
+    fn main(&self, args: Self::StageInputs, rover: MartianRover) -> Result<Self::StageOutputs> {
+        let threshold = args.z_score_threshold.unwrap_or(DEFAULT_Z_SCORE_THRESHOLD);
+
+        let rows: Vec<QcRunRow> = csv::Reader::from_path(&args.runs_csv)?
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .with_context(|| args.runs_csv.display().to_string())?;
+        if let Some(run_id) = rows.iter().map(|row| &row.run_id).duplicates().next() {
+            bail!("the run_id {run_id} appears more than once in the input CSV");
+        }
+        if rows.len() < MIN_RUNS_PER_METRIC {
+            bail!("at least {MIN_RUNS_PER_METRIC} runs are required to build a QC report");
+        }
+        let runs: Vec<RunMetrics> = rows
+            .into_iter()
+            .map(|row| {
+                let path =
+                    resolve_path(&row.metrics_summary, &args.pipestance_root, &args.runs_csv)?;
+                let metrics = read_metrics(&path).with_context(|| path.display().to_string())?;
+                Ok(RunMetrics {
+                    run_id: row.run_id,
+                    metrics,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        let (z_scores, outliers) = compute_z_scores(&runs, threshold);
+
+        let qc_metrics: CsvFile<()> = rover.make_path("qc_metrics");
+        let mut writer = csv::Writer::from_path(&qc_metrics)?;
+        writer
+            .write_record(std::iter::once("run_id").chain(z_scores.keys().map(String::as_str)))?;
+        for run in &runs {
+            writer.write_record(
+                std::iter::once(run.run_id.clone()).chain(
+                    z_scores
+                        .keys()
+                        .map(|m| run.metrics.get(m).map_or_else(String::new, f64::to_string)),
+                ),
+            )?;
+        }
+        writer.flush()?;
+
+        let qc_outliers: CsvFile<QcOutlier> = rover.make_path("qc_outliers");
+        qc_outliers.write(&outliers)?;
+
+        let num_deviating_runs = outliers.iter().map(|o| &o.run_id).unique().count();
+        let content = QcReportContent {
+            num_runs: HeroMetric::new("Runs", runs.len().to_string()),
+            num_metrics: HeroMetric::new("Metrics compared", z_scores.len().to_string()),
+            num_deviating_runs: HeroMetric::new("Deviating runs", num_deviating_runs.to_string()),
+            metric_trends: WithTitle {
+                title: title(
+                    "Metric trends",
+                    &format!(
+                        "The z-score of each metric relative to the cohort of runs. \
+                         Metrics with |z| >= {threshold} are flagged as outliers. \
+                         Click a metric in the legend to show or hide it."
+                    ),
+                )
+                .into(),
+                inner: metric_trends_plot(&runs, &z_scores, &outliers, threshold),
+            },
+            outlier_counts: WithTitle {
+                title: title("Outlier metrics per run", "").into(),
+                inner: outlier_counts_plot(&runs, &outliers),
+            },
+            deviating_runs: deviating_runs_table(&runs, &z_scores, &outliers).map(|inner| {
+                WithTitle {
+                    title: title(
+                        "Runs deviating from the cohort",
+                        "The metric values of the runs with at least one outlier metric, \
+                         colored by z-score.",
+                    )
+                    .into(),
+                    inner,
+                }
+            }),
+        };
+
+        let qc_report: HtmlFile = rover.make_path("qc_report");
+        SinglePageHtml::new(
+            WsNavBar {
+                pipeline: "QC Report".to_string(),
+                id: args.sample_id,
+                description: args.sample_desc,
+            },
+            content,
+            None,
+        )
+        .generate_html_file_with_build_files(&qc_report, websummary_build::build_files()?)?;
+
+        Ok(BuildQcReportStageOutputs {
+            qc_report,
+            qc_metrics,
+            qc_outliers,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_metric_value() {
+        assert_eq!(parse_metric_value("1,234"), Some(1234.0));
+        assert_eq!(parse_metric_value("93.5%"), Some(93.5));
+        assert_eq!(parse_metric_value(" 0.25 "), Some(0.25));
+        assert_eq!(parse_metric_value("SC3Pv3"), None);
+        assert_eq!(parse_metric_value("NaN"), None);
+    }
+
+    #[test]
+    fn test_read_metrics_csv() -> Result<()> {
+        let count = "Estimated Number of Cells,Mean Reads per Cell,Chemistry\n\"5,000\",\"42,000\",SC3Pv3\n";
+        let metrics = read_metrics_csv(count.as_bytes())?;
+        assert_eq!(metrics.len(), 2);
+        assert_eq!(metrics["Estimated Number of Cells"], 5000.0);
+
+        let multi = "Category,Library Type,Grouped By,Group Name,Metric Name,Metric Value\n\
+                     Cells,Gene Expression,,,Cells,\"5,000\"\n\
+                     Library,Gene Expression,Fastq ID,run1,Number of reads,\"1,000\"\n\
+                     Library,Gene Expression,,,Valid barcodes,97.5%\n";
+        let metrics = read_metrics_csv(multi.as_bytes())?;
+        assert_eq!(metrics["Cells - Gene Expression - Cells"], 5000.0);
+        assert_eq!(
+            metrics["Library - Gene Expression - run1 - Number of reads"],
+            1000.0
+        );
+        assert_eq!(metrics["Library - Gene Expression - Valid barcodes"], 97.5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compute_z_scores() {
+        let runs: Vec<_> = [10.0, 11.0, 9.0, 10.0, 30.0]
+            .into_iter()
+            .enumerate()
+            .map(|(i, cells)| RunMetrics {
+                run_id: format!("run{i}"),
+                metrics: [("cells".to_string(), cells), ("reads".to_string(), 1.0)]
+                    .into_iter()
+                    .collect(),
+            })
+            .collect();
+        let (z_scores, outliers) = compute_z_scores(&runs, DEFAULT_Z_SCORE_THRESHOLD);
+        assert_eq!(z_scores.len(), 2);
+        assert!(z_scores["reads"].iter().all(|z| *z == Some(0.0)));
+        assert_eq!(outliers.len(), 1);
+        assert_eq!(outliers[0].run_id, "run4");
+        assert_eq!(outliers[0].metric, "cells");
+        assert_eq!(outliers[0].cohort_mean, 10.0);
+        assert!(outliers[0].z_score > 20.0);
+        // The outlier does not mask itself, and the other runs are not flagged.
+        assert!(z_scores["cells"][..4]
+            .iter()
+            .all(|z| z.unwrap().abs() < 1.0));
+
+        // Too few runs have the metric.
+        let (z_scores, outliers) = compute_z_scores(&runs[..2], DEFAULT_Z_SCORE_THRESHOLD);
+        assert!(z_scores.is_empty());
+        assert!(outliers.is_empty());
+
+        assert_eq!(MetricStats::new(&[1.0]), None);
+        assert_eq!(
+            MetricStats::new(&[1.0, 3.0]),
+            Some(MetricStats {
+                mean: 2.0,
+                sd: 2.0f64.sqrt()
+            })
+        );
+    }
+}
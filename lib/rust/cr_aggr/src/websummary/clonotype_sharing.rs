@@ -0,0 +1,283 @@
+//! Clonotype sharing across the samples of a VDJ aggr run. A sample here is a unique
+//! (donor, origin) pair from the aggr csv.
+
+use crate::websummary::{ChartWithHelp, PlotlyChart, TitleWithHelp};
+use metric::SimpleHistogram;
+use plotly::common::{ColorBar, Title};
+use plotly::layout::{Axis, AxisType};
+use plotly::{HeatMap, Layout};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Number of cells in each clonotype (keyed by the 1-based clonotype id)
+pub type ClonotypeCounts = SimpleHistogram<usize>;
+
+/// Fraction of clonotypes shared between two samples, ignoring clonotype sizes.
+pub fn jaccard_index(a: &ClonotypeCounts, b: &ClonotypeCounts) -> f64 {
+    let shared = a.distribution().keys().filter(|&id| b.get(id) > 0).count();
+    let union = a.distribution().len() + b.distribution().len() - shared;
+    if union == 0 {
+        0.0
+    } else {
+        shared as f64 / union as f64
+    }
+}
+
+/// Morisita-Horn overlap between two samples, which weighs each shared clonotype by
+/// its frequency in both samples. 0 means no overlap and 1 means identical frequencies.
+pub fn morisita_horn_index(a: &ClonotypeCounts, b: &ClonotypeCounts) -> f64 {
+    let total = |h: &ClonotypeCounts| h.raw_counts().sum::<i64>() as f64;
+    let (x, y) = (total(a), total(b));
+    if x == 0.0 || y == 0.0 {
+        return 0.0;
+    }
+    let simpson = |h: &ClonotypeCounts, n: f64| {
+        h.raw_counts().map(|&c| (c as f64).powi(2)).sum::<f64>() / n.powi(2)
+    };
+    let cross: f64 = a
+        .distribution()
+        .iter()
+        .map(|(id, c)| (c.count() as f64) * (b.get(id) as f64))
+        .sum();
+    2.0 * cross / ((simpson(a, x) + simpson(b, y)) * x * y)
+}
+
+/// One row per clonotype per sample in which it is observed
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ClonotypeSharingRow {
+    pub clonotype_id: String,
+    pub donor: String,
+    pub origin: String,
+    pub num_cells: usize,
+    /// Fraction of the cells in the sample belonging to this clonotype
+    pub frequency: f64,
+    /// Number of samples in which this clonotype is observed
+    pub num_samples: usize,
+}
+
+/// One row per pair of distinct samples
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ClonotypeOverlapRow {
+    pub donor_1: String,
+    pub origin_1: String,
+    pub donor_2: String,
+    pub origin_2: String,
+    pub num_shared_clonotypes: usize,
+    pub jaccard: f64,
+    pub morisita_horn: f64,
+}
+
+pub struct ClonotypeSharing {
+    /// (donor, origin) of each sample
+    samples: Vec<(String, String)>,
+    counts: Vec<ClonotypeCounts>,
+}
+
+impl ClonotypeSharing {
+    pub fn new(per_sample_counts: BTreeMap<(String, String), ClonotypeCounts>) -> Self {
+        let (samples, counts) = per_sample_counts.into_iter().unzip();
+        ClonotypeSharing { samples, counts }
+    }
+
+    pub fn sharing_rows(&self) -> Vec<ClonotypeSharingRow> {
+        let mut num_samples = ClonotypeCounts::default();
+        for counts in &self.counts {
+            for &id in counts.distribution().keys() {
+                num_samples.observe_owned(id);
+            }
+        }
+
+        let num_samples = &num_samples;
+        let mut rows: Vec<_> = self
+            .samples
+            .iter()
+            .zip(&self.counts)
+            .flat_map(move |((donor, origin), counts)| {
+                let total: i64 = counts.raw_counts().sum();
+                counts.distribution().iter().map(move |(&id, c)| {
+                    (
+                        id,
+                        ClonotypeSharingRow {
+                            clonotype_id: format!("clonotype{id}"),
+                            donor: donor.clone(),
+                            origin: origin.clone(),
+                            num_cells: c.count() as usize,
+                            frequency: c.count() as f64 / total as f64,
+                            num_samples: num_samples.get(&id) as usize,
+                        },
+                    )
+                })
+            })
+            .collect();
+        rows.sort_by(|(id1, r1), (id2, r2)| {
+            (id1, &r1.donor, &r1.origin).cmp(&(id2, &r2.donor, &r2.origin))
+        });
+        rows.into_iter().map(|(_, row)| row).collect()
+    }
+
+    pub fn overlap_rows(&self) -> Vec<ClonotypeOverlapRow> {
+        let mut rows = Vec::new();
+        for (i, ((donor_1, origin_1), a)) in self.samples.iter().zip(&self.counts).enumerate() {
+            for ((donor_2, origin_2), b) in self.samples.iter().zip(&self.counts).skip(i + 1) {
+                rows.push(ClonotypeOverlapRow {
+                    donor_1: donor_1.clone(),
+                    origin_1: origin_1.clone(),
+                    donor_2: donor_2.clone(),
+                    origin_2: origin_2.clone(),
+                    num_shared_clonotypes: a
+                        .distribution()
+                        .keys()
+                        .filter(|&id| b.get(id) > 0)
+                        .count(),
+                    jaccard: jaccard_index(a, b),
+                    morisita_horn: morisita_horn_index(a, b),
+                });
+            }
+        }
+        rows
+    }
+
+    pub fn heatmap(&self) -> ClonotypeSharingHeatmap {
+        ClonotypeSharingHeatmap {
+            samples: self
+                .samples
+                .iter()
+                .map(|(donor, origin)| format!("{donor} - {origin}"))
+                .collect(),
+            morisita_horn: self
+                .counts
+                .iter()
+                .map(|a| {
+                    self.counts
+                        .iter()
+                        .map(|b| morisita_horn_index(a, b))
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq, Default, Clone)]
+#[serde(into = "ChartWithHelp")]
+pub struct ClonotypeSharingHeatmap {
+    pub samples: Vec<String>,
+    pub morisita_horn: Vec<Vec<f64>>, // One row per sample
+}
+
+const TITLE: &str = "Clonotype Sharing Across Samples";
+const HELP: &str = "This heatmap displays the Morisita-Horn overlap of the clonotypes between each pair of samples, where a sample is a unique combination of donor and origin in the aggregation CSV. The overlap ranges from 0 (no shared clonotypes) to 1 (identical clonotype frequencies). The per-sample clonotype frequencies and the Jaccard overlap are available in the \"clonotype_sharing.csv\" and \"clonotype_overlap.csv\" files produced by the pipeline.";
+const AXIS_LABEL: &str = "Donor - Origin";
+
+impl From<ClonotypeSharingHeatmap> for ChartWithHelp {
+    fn from(heatmap: ClonotypeSharingHeatmap) -> ChartWithHelp {
+        let layout = Layout::new()
+            .x_axis(
+                Axis::new()
+                    .type_(AxisType::Category)
+                    .title(Title::new(AXIS_LABEL)),
+            )
+            .y_axis(
+                Axis::new()
+                    .type_(AxisType::Category)
+                    .auto_margin(true)
+                    .title(Title::new(AXIS_LABEL)),
+            );
+
+        let mut data = serde_json::to_value(
+            HeatMap::new(
+                heatmap.samples.clone(),
+                heatmap.samples,
+                heatmap.morisita_horn,
+            )
+            .hover_template("%{x}<br>%{y}<br>Morisita-Horn: %{z:.3f}")
+            .color_bar(ColorBar::new().title(Title::new("Morisita-Horn")))
+            .name(""),
+        )
+        .unwrap();
+
+        // The plotly API does not expose the color range
+        let map = data.as_object_mut().unwrap();
+        map.insert("zmin".into(), 0.0.into());
+        map.insert("zmax".into(), 1.0.into());
+
+        ChartWithHelp {
+            plot: PlotlyChart::with_layout_and_data(layout, vec![data]),
+            help: TitleWithHelp {
+                title: TITLE.into(),
+                help: HELP.into(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(cells: &[(usize, i64)]) -> ClonotypeCounts {
+        let mut hist = ClonotypeCounts::default();
+        for &(id, n) in cells {
+            hist.observe_by_owned(id, n);
+        }
+        hist
+    }
+
+    #[test]
+    fn test_overlap_indices() {
+        let a = counts(&[(1, 10), (2, 5), (3, 1)]);
+        let b = counts(&[(1, 2), (4, 7)]);
+        let empty = counts(&[]);
+
+        assert!((jaccard_index(&a, &a) - 1.0).abs() < 1e-9);
+        assert!((jaccard_index(&a, &b) - 0.25).abs() < 1e-9);
+        assert_eq!(jaccard_index(&a, &empty), 0.0);
+
+        assert!((morisita_horn_index(&a, &a) - 1.0).abs() < 1e-9);
+        assert!((morisita_horn_index(&a, &counts(&[(1, 20), (2, 10), (3, 2)])) - 1.0).abs() < 1e-9);
+        assert_eq!(morisita_horn_index(&a, &counts(&[(5, 3)])), 0.0);
+        assert_eq!(morisita_horn_index(&a, &empty), 0.0);
+        // 2 * (10 * 2) / ((126 / 256 + 53 / 81) * 16 * 9)
+        let expected = 40.0 / ((126.0 / 256.0 + 53.0 / 81.0) * 144.0);
+        assert!((morisita_horn_index(&a, &b) - expected).abs() < 1e-9);
+        assert!((morisita_horn_index(&b, &a) - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_sharing_rows() {
+        let sharing = ClonotypeSharing::new(BTreeMap::from([
+            (("D1".into(), "Post".into()), counts(&[(1, 3), (2, 1)])),
+            (("D1".into(), "Pre".into()), counts(&[(1, 1)])),
+        ]));
+
+        let row =
+            |id: usize, origin: &str, num_cells, frequency, num_samples| ClonotypeSharingRow {
+                clonotype_id: format!("clonotype{id}"),
+                donor: "D1".into(),
+                origin: origin.into(),
+                num_cells,
+                frequency,
+                num_samples,
+            };
+        assert_eq!(
+            sharing.sharing_rows(),
+            vec![
+                row(1, "Post", 3, 0.75, 2),
+                row(1, "Pre", 1, 1.0, 2),
+                row(2, "Post", 1, 0.25, 1),
+            ]
+        );
+
+        let overlap = sharing.overlap_rows();
+        assert_eq!(overlap.len(), 1);
+        assert_eq!(overlap[0].origin_1, "Post");
+        assert_eq!(overlap[0].origin_2, "Pre");
+        assert_eq!(overlap[0].num_shared_clonotypes, 1);
+        assert!((overlap[0].jaccard - 0.5).abs() < 1e-9);
+
+        let heatmap = sharing.heatmap();
+        assert_eq!(heatmap.samples, vec!["D1 - Post", "D1 - Pre"]);
+        assert!((heatmap.morisita_horn[0][0] - 1.0).abs() < 1e-9);
+        assert_eq!(heatmap.morisita_horn[0][1], heatmap.morisita_horn[1][0]);
+    }
+}
@@ -6,6 +6,7 @@ pub mod annotation_card;
 pub mod cdr3_table;
 pub mod cells_card;
 pub mod clonotype_hist;
+pub mod clonotype_sharing;
 pub mod clonotype_table;
 pub mod hero_metrics;
 
@@ -53,6 +54,7 @@ pub struct VdjAggrWsSummaryTab {
     pub vdj_aggr_cells: cells_card::VdjAggrCellsTable,
     pub vdj_shared_cdr3: cdr3_table::VdjAggrSharedCdr3,
     pub vdj_clonotype_hist: clonotype_hist::ClonotypeHist,
+    pub vdj_clonotype_sharing: clonotype_sharing::ClonotypeSharingHeatmap,
 }
 
 #[cfg(test)]
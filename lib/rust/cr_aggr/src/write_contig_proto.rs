@@ -62,7 +62,7 @@ impl MartianMain for WriteContigProto {
 
         let metrics = MetricsSummary::from_metrics_json(&args.metrics_summary_json)?;
 
-        let mut writer = VdjProtoWriter::new(&proto_file, metadata, reference, metrics)?;
+        let mut writer = VdjProtoWriter::new_with_index(&proto_file, metadata, reference, metrics)?;
         for ann in args.contig_annotations_json.lazy_reader()? {
             writer.write_annotation(ann?)?;
         }
@@ -6,6 +6,9 @@ use crate::websummary::annotation_card::VdjAggrAnnotationTable;
 use crate::websummary::cdr3_table::{VdjAggrSharedCdr3, VdjAggrSharedCdr3Row};
 use crate::websummary::cells_card::{VdjAggrCellsRow, VdjAggrCellsTable};
 use crate::websummary::clonotype_hist::ClonotypeHist;
+use crate::websummary::clonotype_sharing::{
+    ClonotypeCounts, ClonotypeOverlapRow, ClonotypeSharing, ClonotypeSharingRow,
+};
 use crate::websummary::clonotype_table::{VdjAggrClonotypeRow, VdjAggrClonotypeTable};
 use crate::websummary::hero_metrics::VdjAggrHeroMetrics;
 use crate::websummary::{VdjAggrPipelineInfo, VdjAggrWsContent, VdjAggrWsSummaryTab};
@@ -56,6 +59,8 @@ pub struct WriteWsJsonStageInputs {
 pub struct WriteWsJsonStageOutputs {
     web_summary_content: JsonFile<VdjAggrWsContent>,
     per_origin_hist: JsonFile<Vec<(String, SimpleHistogram<usize>)>>,
+    clonotype_sharing: CsvFile<ClonotypeSharingRow>,
+    clonotype_overlap: CsvFile<ClonotypeOverlapRow>,
 }
 
 struct CellBarcodeSummary {
@@ -256,6 +261,22 @@ impl MartianMain for WriteWsJson {
             proportions,
         };
 
+        // Clonotype sharing across (donor, origin), merging the libraries of each
+        let mut per_sample_counts = BTreeMap::new();
+        for ((_library_id, donor, origin), hist) in &per_origin_hist {
+            let counts: &mut ClonotypeCounts = per_sample_counts
+                .entry((donor.clone(), origin.clone()))
+                .or_default();
+            for (&clonotype_id, n) in hist.distribution() {
+                counts.observe_by_owned(clonotype_id, n.count());
+            }
+        }
+        let clonotype_sharing = ClonotypeSharing::new(per_sample_counts);
+        let clonotype_sharing_file: CsvFile<_> = rover.make_path("clonotype_sharing");
+        clonotype_sharing_file.write(&clonotype_sharing.sharing_rows())?;
+        let clonotype_overlap_file: CsvFile<_> = rover.make_path("clonotype_overlap");
+        clonotype_overlap_file.write(&clonotype_sharing.overlap_rows())?;
+
         let vdj_aggr_cells = VdjAggrCellsTable(
             per_origin_hist
                 .into_iter()
@@ -301,6 +322,7 @@ impl MartianMain for WriteWsJson {
                 vdj_aggr_cells,
                 vdj_shared_cdr3,
                 vdj_clonotype_hist,
+                vdj_clonotype_sharing: clonotype_sharing.heatmap(),
             },
         };
 
@@ -311,6 +333,8 @@ impl MartianMain for WriteWsJson {
         Ok(WriteWsJsonStageOutputs {
             web_summary_content,
             per_origin_hist: per_origin_hist_file,
+            clonotype_sharing: clonotype_sharing_file,
+            clonotype_overlap: clonotype_overlap_file,
         })
     }
 }
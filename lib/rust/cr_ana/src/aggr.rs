@@ -0,0 +1,108 @@
+//! Metadata of the libraries of an aggregated matrix
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+/// The gem group of each barcode.
+pub(crate) const GEM_GROUP_KEY: &str = "gem_group";
+/// The aggr library_id of each barcode.
+pub(crate) const LIBRARY_ID_KEY: &str = "library_id";
+/// The batch column of the aggr CSV, as used by chemistry batch correction.
+pub(crate) const BATCH_KEY: &str = "batch";
+
+/// A library of the aggregated matrix, as output by SETUP_SAMPLES.
+#[derive(Clone, Debug, Deserialize)]
+pub struct AggrLibrary {
+    pub gem_group: u32,
+    pub aggr_id: String,
+    pub batch_name: Option<String>,
+}
+
+/// A row of the aggr CSV, keyed by column name.
+pub(crate) type AggrSampleDef = HashMap<String, Value>;
+
+/// Return the value of a column of the aggr CSV for one of its rows.
+fn aggr_column(sample_defs: &[AggrSampleDef], aggr_id: &str, key: &str) -> Result<String> {
+    let Some(row) = sample_defs
+        .iter()
+        .find(|row| row.get(LIBRARY_ID_KEY).and_then(Value::as_str) == Some(aggr_id))
+    else {
+        bail!("library_id {aggr_id} is missing from the aggr CSV");
+    };
+    match row.get(key) {
+        Some(Value::String(value)) => Ok(value.clone()),
+        Some(value) if !value.is_null() => Ok(value.to_string()),
+        _ => bail!("column {key} of the aggr CSV is empty for library_id {aggr_id}"),
+    }
+}
+
+/// Return the value of the key for each gem group, where the key is gem_group, library_id or
+/// another column of the aggr CSV.
+pub(crate) fn library_values(
+    libraries: &[AggrLibrary],
+    sample_defs: &[AggrSampleDef],
+    key: &str,
+) -> Result<BTreeMap<u32, String>> {
+    libraries
+        .iter()
+        .map(|lib| {
+            let value = match (key, &lib.batch_name) {
+                (GEM_GROUP_KEY, _) => lib.gem_group.to_string(),
+                (LIBRARY_ID_KEY, _) => lib.aggr_id.clone(),
+                (BATCH_KEY, Some(batch_name)) => batch_name.clone(),
+                _ => aggr_column(sample_defs, &lib.aggr_id, key)?,
+            };
+            Ok((lib.gem_group, value))
+        })
+        .collect()
+}
+
+/// Return the gem group of a barcode from its suffix.
+pub(crate) fn barcode_gem_group(barcode: &str) -> Result<u32> {
+    match barcode.rsplit_once('-') {
+        Some((_, suffix)) => suffix.parse::<u32>().ok(),
+        None => Some(1),
+    }
+    .with_context(|| format!("invalid barcode {barcode}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_library_values() -> Result<()> {
+        let library = |gem_group: u32, aggr_id: &str| AggrLibrary {
+            gem_group,
+            aggr_id: aggr_id.to_string(),
+            batch_name: None,
+        };
+        let row = |library_id: &str, donor: &str| {
+            HashMap::from([
+                (LIBRARY_ID_KEY.to_string(), Value::from(library_id)),
+                ("donor".to_string(), Value::from(donor)),
+            ])
+        };
+        let libraries = [library(1, "a"), library(2, "b"), library(3, "c")];
+        let sample_defs = [row("a", "x"), row("b", "y"), row("c", "x")];
+
+        let values = library_values(&libraries, &sample_defs, GEM_GROUP_KEY)?;
+        assert_eq!(values[&3], "3");
+        let values = library_values(&libraries, &sample_defs, LIBRARY_ID_KEY)?;
+        assert_eq!(values[&2], "b");
+        let values = library_values(&libraries, &sample_defs, "donor")?;
+        assert_eq!(values[&1], values[&3]);
+        assert_ne!(values[&1], values[&2]);
+        assert!(library_values(&libraries, &sample_defs, "age").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_barcode_gem_group() {
+        assert_eq!(barcode_gem_group("AAACCCAAGAAACACT-2").unwrap(), 2);
+        assert_eq!(barcode_gem_group("AAACCCAAGAAACACT").unwrap(), 1);
+        assert!(barcode_gem_group("AAACCCAAGAAACACT-x").is_err());
+    }
+}
@@ -68,13 +68,21 @@ fn main() -> Result<()> {
     let args: Args = Args::parse();
 
     let (stage_registry, mro_registry) = martian_stages![
+        cr_ana::stages::antibody_normalization::AntibodyNormalizationStage,
         cr_ana::stages::diff_exp_stage::DiffExpStage,
+        cr_ana::stages::diffusion_pseudotime::DiffusionPseudotimeStage,
+        cr_ana::stages::doublet_detection::DoubletDetectionStage,
         cr_ana::stages::graph_clustering::GraphClusteringStage,
+        cr_ana::stages::harmony::HarmonyStage,
         cr_ana::stages::hierarchical_clustering::HierarchicalClusteringStage,
+        cr_ana::stages::marker_annotation::MarkerAnnotationStage,
         cr_ana::stages::pca::PcaStage,
         cr_ana::stages::pca2::Pca2Stage,
+        cr_ana::stages::pseudobulk_diff_exp::PseudobulkDiffExpStage,
+        cr_ana::stages::reference_projection::ReferenceProjectionStage,
         cr_ana::stages::tsne::TsneStage,
         cr_ana::stages::umap::UmapStage,
+        cr_ana::stages::wnn_clustering::WnnClusteringStage,
     ];
 
     match args {
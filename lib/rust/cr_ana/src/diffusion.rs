@@ -0,0 +1,423 @@
+//! Diffusion maps, diffusion pseudotime and partition-based graph abstraction
+//! Haghverdi et al. (2016) Diffusion pseudotime robustly reconstructs lineage branching.
+//! Wolf et al. (2019) PAGA: graph abstraction reconciles clustering with trajectory inference
+//! through a topology preserving map of single cells.
+//! The diffusion components are the leading eigenvectors of the transitions of a random walk on
+//! the k-nearest neighbor graph, and the pseudotime of a barcode is its diffusion distance from a
+//! root barcode.
+
+use crate::neighbors::{distance, MIN_KERNEL_WIDTH};
+use crate::pca::symmetric_eigen;
+use itertools::Itertools;
+use ndarray::{s, Array1, Array2, ArrayView2, Axis};
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg64;
+use std::collections::VecDeque;
+use std::iter::zip;
+
+/// Additional vectors of the subspace iteration, which speed the convergence of the last
+/// diffusion components.
+const OVERSAMPLING: usize = 10;
+const MAX_SUBSPACE_ITERATIONS: usize = 1000;
+const EIGENVALUE_TOLERANCE: f64 = 1e-10;
+/// Diffusion components with an eigenvalue at least this are stationary within a connected
+/// component of the graph, and are excluded from the pseudotime distance.
+const MAX_EIGENVALUE: f64 = 0.9994;
+
+/// A graph of barcodes, with the neighbors and edge weights of each barcode.
+type WeightedGraph = Vec<Vec<(usize, f64)>>;
+
+pub(crate) struct DiffusionMap {
+    /// The eigenvalues of the transition matrix, in decreasing order.
+    pub eigenvalues: Array1<f64>,
+    /// The diffusion components, the eigenvectors of the symmetric transition matrix, with a row
+    /// per barcode and a column per component.
+    pub components: Array2<f64>,
+}
+
+/// Return the undirected graph of the k nearest neighbors, in which two barcodes are adjacent
+/// when either is a neighbor of the other.
+pub(crate) fn symmetrize(neighbors: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    let mut adjacency = neighbors.to_vec();
+    for (i, row) in neighbors.iter().enumerate() {
+        for &j in row {
+            adjacency[j].push(i);
+        }
+    }
+    for row in &mut adjacency {
+        row.sort_unstable();
+        row.dedup();
+    }
+    adjacency
+}
+
+/// Return the Gaussian kernel of the undirected neighbor graph, whose width at each barcode is
+/// the median distance to its neighbors.
+fn gaussian_kernel(
+    proj: ArrayView2<'_, f64>,
+    neighbors: &[Vec<usize>],
+    adjacency: &[Vec<usize>],
+) -> WeightedGraph {
+    let widths: Vec<f64> = neighbors
+        .iter()
+        .enumerate()
+        .map(|(i, row)| {
+            let distances = row
+                .iter()
+                .map(|&j| distance(proj.row(i), proj.row(j)))
+                .sorted_by(f64::total_cmp)
+                .collect::<Vec<_>>();
+            distances
+                .get(distances.len() / 2)
+                .copied()
+                .unwrap_or(0.0)
+                .max(MIN_KERNEL_WIDTH)
+        })
+        .collect();
+    adjacency
+        .iter()
+        .enumerate()
+        .map(|(i, row)| {
+            row.iter()
+                .map(|&j| {
+                    let (wi, wj) = (widths[i], widths[j]);
+                    let d = distance(proj.row(i), proj.row(j));
+                    let s = wi * wi + wj * wj;
+                    (j, (2.0 * wi * wj / s).sqrt() * (-d * d / s).exp())
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Return the weighted degree of each barcode.
+fn degrees(graph: &WeightedGraph) -> Vec<f64> {
+    graph
+        .iter()
+        .map(|row| {
+            row.iter()
+                .map(|&(_, w)| w)
+                .sum::<f64>()
+                .max(f64::MIN_POSITIVE)
+        })
+        .collect()
+}
+
+/// Return the symmetric transition matrix of the kernel, after normalizing the kernel by the
+/// density of barcodes so that the diffusion is independent of the sampling density.
+fn symmetric_transitions(kernel: &WeightedGraph) -> WeightedGraph {
+    let q = degrees(kernel);
+    let normalized: WeightedGraph = kernel
+        .iter()
+        .enumerate()
+        .map(|(i, row)| row.iter().map(|&(j, w)| (j, w / (q[i] * q[j]))).collect())
+        .collect();
+    let z = degrees(&normalized);
+    normalized
+        .iter()
+        .enumerate()
+        .map(|(i, row)| {
+            row.iter()
+                .map(|&(j, w)| (j, w / (z[i] * z[j]).sqrt()))
+                .collect()
+        })
+        .collect()
+}
+
+/// Return the product of the lazy transitions (I + M) / 2 and a matrix. The eigenvalues of the
+/// lazy transitions are not negative, so that subspace iteration converges to the largest
+/// eigenvalues of M.
+fn lazy_multiply(transitions: &WeightedGraph, x: &Array2<f64>) -> Array2<f64> {
+    let mut product = x / 2.0;
+    for (i, row) in transitions.iter().enumerate() {
+        let mut out = product.row_mut(i);
+        for &(j, w) in row {
+            out.scaled_add(w / 2.0, &x.row(j));
+        }
+    }
+    product
+}
+
+/// Orthonormalize the columns of a matrix by the modified Gram-Schmidt process.
+fn orthonormalize(x: &mut Array2<f64>) {
+    for j in 0..x.ncols() {
+        for i in 0..j {
+            let projection = x.column(i).dot(&x.column(j));
+            let basis = x.column(i).to_owned();
+            x.column_mut(j).scaled_add(-projection, &basis);
+        }
+        let norm = x.column(j).dot(&x.column(j)).sqrt();
+        if norm > 0.0 {
+            x.column_mut(j).mapv_inplace(|v| v / norm);
+        }
+    }
+}
+
+/// Return the Ritz values, in decreasing order, and the Ritz vectors of the subspace spanned by
+/// the orthonormal columns of q, given the product of the matrix and q.
+fn rayleigh_ritz(q: &Array2<f64>, product: &Array2<f64>) -> (Array1<f64>, Array2<f64>) {
+    let h = q.t().dot(product);
+    let (values, vectors) = symmetric_eigen(&((&h + &h.t()) / 2.0));
+    let order = (0..values.len())
+        .sorted_by(|&a, &b| values[b].total_cmp(&values[a]))
+        .collect::<Vec<_>>();
+    (
+        values.select(Axis(0), &order),
+        q.dot(&vectors.select(Axis(1), &order)),
+    )
+}
+
+/// Return the leading diffusion components of the k-nearest neighbor graph of the reduced space,
+/// computed by subspace iteration from a random start.
+pub(crate) fn diffusion_map(
+    proj: ArrayView2<'_, f64>,
+    neighbors: &[Vec<usize>],
+    num_components: usize,
+    seed: u64,
+) -> DiffusionMap {
+    let transitions =
+        symmetric_transitions(&gaussian_kernel(proj, neighbors, &symmetrize(neighbors)));
+    let num_barcodes = proj.nrows();
+    let num_components = num_components.min(num_barcodes);
+    let block_size = (num_components + OVERSAMPLING).min(num_barcodes);
+
+    let mut rng = Pcg64::seed_from_u64(seed);
+    let mut q = Array2::from_shape_simple_fn((num_barcodes, block_size), || rng.gen::<f64>() - 0.5);
+    orthonormalize(&mut q);
+    let mut previous: Option<Array1<f64>> = None;
+    let mut iterations = 0;
+    let (values, vectors) = loop {
+        let product = lazy_multiply(&transitions, &q);
+        let (values, vectors) = rayleigh_ritz(&q, &product);
+        iterations += 1;
+        let converged = previous.as_ref().is_some_and(|previous| {
+            zip(&values, previous)
+                .take(num_components)
+                .all(|(a, b)| (a - b).abs() < EIGENVALUE_TOLERANCE)
+        });
+        if converged || iterations == MAX_SUBSPACE_ITERATIONS {
+            break (values, vectors);
+        }
+        previous = Some(values);
+        q = product;
+        orthonormalize(&mut q);
+    };
+    if iterations == MAX_SUBSPACE_ITERATIONS {
+        log::warn!(
+            "diffusion map did not converge within {MAX_SUBSPACE_ITERATIONS} iterations, \
+             using the current eigenvalues {values}"
+        );
+    } else {
+        log::info!("diffusion map converged after {iterations} iterations");
+    }
+
+    DiffusionMap {
+        eigenvalues: values
+            .slice(s![..num_components])
+            .mapv(|lazy| 2.0 * lazy - 1.0),
+        components: vectors.slice(s![.., ..num_components]).to_owned(),
+    }
+}
+
+/// Return the diffusion components that are not stationary, each scaled by λ / (1 - λ), so that
+/// the Euclidean distance between two barcodes is their diffusion pseudotime distance.
+fn scaled_components(map: &DiffusionMap) -> Array2<f64> {
+    let (columns, scales): (Vec<usize>, Vec<f64>) = map
+        .eigenvalues
+        .iter()
+        .enumerate()
+        .filter(|&(_, &l)| l < MAX_EIGENVALUE)
+        .map(|(c, &l)| (c, l / (1.0 - l)))
+        .unzip();
+    map.components.select(Axis(1), &columns) * &Array1::from(scales)
+}
+
+/// Return the connected component of each barcode of an undirected graph.
+fn connected_components(adjacency: &[Vec<usize>]) -> Vec<usize> {
+    let mut components = vec![usize::MAX; adjacency.len()];
+    let mut num_components = 0;
+    for start in 0..adjacency.len() {
+        if components[start] != usize::MAX {
+            continue;
+        }
+        components[start] = num_components;
+        let mut queue = VecDeque::from([start]);
+        while let Some(i) = queue.pop_front() {
+            for &j in &adjacency[i] {
+                if components[j] == usize::MAX {
+                    components[j] = num_components;
+                    queue.push_back(j);
+                }
+            }
+        }
+        num_components += 1;
+    }
+    components
+}
+
+/// Return the diffusion pseudotime of each barcode, its diffusion distance from the root scaled
+/// to at most one. The pseudotime of barcodes that are not connected to the root is NaN.
+pub(crate) fn diffusion_pseudotime(
+    map: &DiffusionMap,
+    adjacency: &[Vec<usize>],
+    root: usize,
+) -> Vec<f64> {
+    let scaled = scaled_components(map);
+    let components = connected_components(adjacency);
+    let root_row = scaled.row(root);
+    let distances: Vec<f64> = scaled
+        .rows()
+        .into_iter()
+        .zip(&components)
+        .map(|(row, &component)| {
+            if component == components[root] {
+                distance(row, root_row)
+            } else {
+                f64::NAN
+            }
+        })
+        .collect();
+    let max = distances
+        .iter()
+        .copied()
+        .filter(|d| !d.is_nan())
+        .fold(0.0, f64::max);
+    if max > 0.0 {
+        distances.into_iter().map(|d| d / max).collect()
+    } else {
+        distances
+    }
+}
+
+/// Return the barcode of the root cluster at its extremity, the one farthest in diffusion
+/// distance from the centroid of the other barcodes, or None when the cluster has no barcodes.
+pub(crate) fn extremal_barcode(
+    map: &DiffusionMap,
+    labels: &[i64],
+    root_cluster: i64,
+) -> Option<usize> {
+    let scaled = scaled_components(map);
+    let others: Vec<usize> = (0..labels.len())
+        .filter(|&i| labels[i] != root_cluster)
+        .collect();
+    let centroid = if others.is_empty() {
+        scaled.mean_axis(Axis(0))?
+    } else {
+        scaled.select(Axis(0), &others).mean_axis(Axis(0))?
+    };
+    (0..labels.len())
+        .filter(|&i| labels[i] == root_cluster)
+        .max_by(|&a, &b| {
+            distance(scaled.row(a), centroid.view())
+                .total_cmp(&distance(scaled.row(b), centroid.view()))
+        })
+}
+
+/// Return the PAGA connectivity of each pair of clusters, the number of edges of the undirected
+/// neighbor graph between them relative to the number expected if edges were placed at random,
+/// at most one. The clusters are numbered from zero.
+pub(crate) fn paga_connectivity(
+    adjacency: &[Vec<usize>],
+    clusters: &[usize],
+    num_clusters: usize,
+) -> Array2<f64> {
+    let mut edges = Array2::<f64>::zeros((num_clusters, num_clusters));
+    for (i, row) in adjacency.iter().enumerate() {
+        for &j in row {
+            if clusters[i] != clusters[j] {
+                edges[[clusters[i], clusters[j]]] += 1.0;
+            }
+        }
+    }
+    let mut sizes = vec![0.0; num_clusters];
+    for &cluster in clusters {
+        sizes[cluster] += 1.0;
+    }
+    let cluster_edges: Vec<f64> = edges.rows().into_iter().map(|row| row.sum()).collect();
+    let num_barcodes = (clusters.len() as f64 - 1.0).max(1.0);
+    Array2::from_shape_fn((num_clusters, num_clusters), |(a, b)| {
+        let expected = (cluster_edges[a] * sizes[b] + cluster_edges[b] * sizes[a]) / num_barcodes;
+        if expected > 0.0 {
+            (edges[[a, b]] / expected).min(1.0)
+        } else {
+            0.0
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn test_diffusion_pseudotime() {
+        // Barcodes along a curve, each adjacent to the two nearest on either side, and a pair of
+        // barcodes disconnected from the curve.
+        let mut proj = Array2::from_shape_fn((62, 2), |(i, j)| {
+            let t = i as f64 / 10.0;
+            if j == 0 {
+                t
+            } else {
+                t * t / 4.0
+            }
+        });
+        proj.slice_mut(s![60.., ..])
+            .assign(&array![[100.0, 100.0], [100.5, 100.0]]);
+        let neighbors: Vec<Vec<usize>> = (0..62_usize)
+            .map(|i| {
+                if i < 60 {
+                    (i.saturating_sub(2)..(i + 3).min(60))
+                        .filter(|&j| j != i)
+                        .collect()
+                } else {
+                    vec![121 - i]
+                }
+            })
+            .collect();
+        let map = diffusion_map(proj.view(), &neighbors, 10, 0);
+        assert_eq!(map.eigenvalues.len(), 10);
+        assert!((map.eigenvalues[0] - 1.0).abs() < 1e-6);
+        assert!((map.eigenvalues[1] - 1.0).abs() < 1e-6);
+        assert!(map.eigenvalues[2] < MAX_EIGENVALUE);
+        assert!(map.eigenvalues.windows(2).into_iter().all(|w| w[0] >= w[1]));
+
+        let pseudotime = diffusion_pseudotime(&map, &symmetrize(&neighbors), 0);
+        assert_eq!(pseudotime[0], 0.0);
+        assert_eq!(pseudotime[..60].iter().copied().fold(0.0, f64::max), 1.0);
+        assert!(pseudotime[..60].windows(11).all(|w| w[0] < w[10]));
+        assert!(pseudotime[59] > 0.9);
+        assert!(pseudotime[60..].iter().all(|t| t.is_nan()));
+
+        let labels: Vec<i64> = (0..62).map(|i| 1 + i / 20).collect();
+        assert!(extremal_barcode(&map, &labels, 3).unwrap() >= 55);
+        assert_eq!(extremal_barcode(&map, &labels, 5), None);
+    }
+
+    #[test]
+    fn test_connected_components() {
+        let adjacency = symmetrize(&[vec![1], vec![], vec![3], vec![], vec![]]);
+        assert_eq!(connected_components(&adjacency), vec![0, 0, 1, 1, 2]);
+    }
+
+    #[test]
+    fn test_paga_connectivity() {
+        // Two cliques of three barcodes joined by a single edge, and an isolated cluster.
+        let adjacency = vec![
+            vec![1, 2],
+            vec![0, 2],
+            vec![0, 1, 3],
+            vec![2, 4, 5],
+            vec![3, 5],
+            vec![3, 4],
+            vec![],
+        ];
+        let connectivity = paga_connectivity(&adjacency, &[0, 0, 0, 1, 1, 1, 2], 3);
+        // One edge between clusters of three barcodes, each with one edge to other clusters,
+        // is the number expected at random.
+        assert_eq!(connectivity[[0, 1]], 1.0);
+        assert_eq!(connectivity[[0, 1]], connectivity[[1, 0]]);
+        assert_eq!(connectivity[[0, 2]], 0.0);
+        assert_eq!(connectivity[[0, 0]], 0.0);
+    }
+}
@@ -0,0 +1,252 @@
+//! Doublet detection by simulating artificial doublets
+//! Wolock et al. (2019) Scrublet: computational identification of cell doublets in single-cell
+//! transcriptomic data.
+
+use crate::neighbors::nearest_neighbors;
+use crate::pca::get_normalized_matrix;
+use cr_types::reference::feature_reference::FeatureType;
+use ndarray::linalg::Dot;
+use ndarray::Array2;
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg64;
+use sprs::{CsMatI, SpIndex};
+use sqz::AdaptiveMatOwned;
+
+/// The number of bins of the histogram used to threshold the doublet scores.
+const THRESHOLD_BINS: usize = 100;
+
+#[derive(Clone, Debug)]
+pub(crate) struct DoubletParams {
+    /// The number of simulated doublets relative to the number of barcodes.
+    pub sim_doublet_ratio: f64,
+    /// The prior fraction of barcodes that are doublets.
+    pub expected_doublet_rate: f64,
+    /// The number of neighbors, by default half the square root of the number of barcodes.
+    pub num_neighbors: Option<usize>,
+    pub random_seed: u64,
+}
+
+impl Default for DoubletParams {
+    fn default() -> Self {
+        DoubletParams {
+            sim_doublet_ratio: 2.0,
+            expected_doublet_rate: 0.06,
+            num_neighbors: None,
+            random_seed: 0,
+        }
+    }
+}
+
+pub(crate) struct DoubletResult {
+    /// The doublet score of each barcode.
+    pub scores: Vec<f64>,
+    /// The doublet score of each simulated doublet.
+    pub simulated_scores: Vec<f64>,
+    /// The score above which a barcode is called a doublet.
+    pub threshold: f64,
+}
+
+impl DoubletResult {
+    pub(crate) fn is_doublet(&self) -> impl Iterator<Item = bool> + '_ {
+        self.scores.iter().map(|&score| score > self.threshold)
+    }
+}
+
+/// A sparse expression profile of (feature, UMI count).
+type Profile = Vec<(usize, u32)>;
+
+/// Return the profile of a doublet of two barcodes.
+fn sum_profiles(a: &Profile, b: &Profile) -> Profile {
+    let mut sum = Vec::with_capacity(a.len() + b.len());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if j == b.len() || (i < a.len() && a[i].0 < b[j].0) {
+            sum.push(a[i]);
+            i += 1;
+        } else if i == a.len() || b[j].0 < a[i].0 {
+            sum.push(b[j]);
+            j += 1;
+        } else {
+            sum.push((a[i].0, a[i].1 + b[j].1));
+            i += 1;
+            j += 1;
+        }
+    }
+    sum
+}
+
+/// Return the matrix of the profiles of the barcodes and of the simulated doublets,
+/// normalized as run_pca does, projected onto the principal components.
+/// The normalization is fit to all profiles, including the simulated doublets.
+fn project(profiles: &[Profile], num_features: usize, components: &Array2<f64>) -> Array2<f64> {
+    let mut indptr = Vec::with_capacity(profiles.len() + 1);
+    indptr.push(0);
+    let (mut indices, mut data) = (Vec::new(), Vec::new());
+    for profile in profiles {
+        for &(feature, count) in profile {
+            indices.push(feature as u32);
+            data.push(count);
+        }
+        indptr.push(indices.len() as u32);
+    }
+    let matrix = AdaptiveMatOwned::from_csmat(&CsMatI::<u32, u32>::new_csc(
+        (num_features, profiles.len()),
+        indptr,
+        indices,
+        data,
+    ));
+    get_normalized_matrix(FeatureType::Gene, &false, matrix.view())
+        .t()
+        .dot(&components.t())
+}
+
+/// Return the threshold that best separates the two modes of the scores, using Otsu's method.
+fn otsu_threshold(scores: &[f64]) -> f64 {
+    let (min, max) = scores
+        .iter()
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), &s| {
+            (lo.min(s), hi.max(s))
+        });
+    if max <= min {
+        return max;
+    }
+    let width = (max - min) / THRESHOLD_BINS as f64;
+    let mut counts = [0.0; THRESHOLD_BINS];
+    for &s in scores {
+        counts[(((s - min) / width) as usize).min(THRESHOLD_BINS - 1)] += 1.0;
+    }
+    let center = |i: usize| min + (i as f64 + 0.5) * width;
+    let total: f64 = counts.iter().sum();
+    let total_sum: f64 = (0..THRESHOLD_BINS).map(|i| counts[i] * center(i)).sum();
+
+    let (mut weight_low, mut sum_low) = (0.0, 0.0);
+    let (mut best, mut best_variance) = (max, f64::NEG_INFINITY);
+    for i in 0..THRESHOLD_BINS - 1 {
+        weight_low += counts[i];
+        sum_low += counts[i] * center(i);
+        let weight_high = total - weight_low;
+        if weight_low == 0.0 || weight_high == 0.0 {
+            continue;
+        }
+        let mean_diff = sum_low / weight_low - (total_sum - sum_low) / weight_high;
+        let variance = weight_low * weight_high * mean_diff * mean_diff;
+        if variance > best_variance {
+            best_variance = variance;
+            best = min + (i + 1) as f64 * width;
+        }
+    }
+    best
+}
+
+/// Score each barcode of the feature-barcode matrix by the fraction of simulated doublets
+/// among its nearest neighbors in the space of the principal components.
+pub(crate) fn detect_doublets<I: SpIndex>(
+    matrix: &CsMatI<u32, I>,
+    components: &Array2<f64>,
+    params: &DoubletParams,
+) -> DoubletResult {
+    assert!(matrix.is_csc());
+    let num_barcodes = matrix.cols();
+    let mut profiles: Vec<Profile> = matrix
+        .outer_iterator()
+        .map(|col| col.iter().map(|(f, &c)| (f, c)).collect())
+        .collect();
+    if num_barcodes < 2 {
+        return DoubletResult {
+            scores: vec![0.0; num_barcodes],
+            simulated_scores: Vec::new(),
+            threshold: 1.0,
+        };
+    }
+
+    let num_simulated = ((params.sim_doublet_ratio * num_barcodes as f64).round() as usize).max(1);
+    let mut rng = Pcg64::seed_from_u64(params.random_seed);
+    for _ in 0..num_simulated {
+        let i = rng.gen_range(0..num_barcodes);
+        let j = (i + rng.gen_range(1..num_barcodes)) % num_barcodes;
+        profiles.push(sum_profiles(&profiles[i], &profiles[j]));
+    }
+    let proj = project(&profiles, matrix.rows(), components);
+
+    // Scale the number of neighbors by the number of simulated doublets.
+    let ratio = num_simulated as f64 / num_barcodes as f64;
+    let k = params
+        .num_neighbors
+        .unwrap_or_else(|| (0.5 * (num_barcodes as f64).sqrt()).round() as usize);
+    let k = ((k as f64 * (1.0 + ratio)).round() as usize).clamp(1, profiles.len() - 1);
+    let neighbors = nearest_neighbors(proj.view(), k);
+
+    let rho = params.expected_doublet_rate;
+    let scores: Vec<f64> = neighbors
+        .iter()
+        .map(|row| {
+            let simulated = row.iter().filter(|&&j| j >= num_barcodes).count();
+            let q = (simulated as f64 + 1.0) / (k as f64 + 2.0);
+            q * rho / ratio / (1.0 - rho - q * (1.0 - rho - rho / ratio))
+        })
+        .collect();
+    let simulated_scores = scores[num_barcodes..].to_vec();
+    let threshold = otsu_threshold(&simulated_scores);
+    DoubletResult {
+        scores: scores[..num_barcodes].to_vec(),
+        simulated_scores,
+        threshold,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sprs::TriMat;
+
+    #[test]
+    fn test_sum_profiles() {
+        let a = vec![(0, 1), (2, 2)];
+        let b = vec![(1, 3), (2, 1), (4, 1)];
+        assert_eq!(sum_profiles(&a, &b), vec![(0, 1), (1, 3), (2, 3), (4, 1)]);
+    }
+
+    #[test]
+    fn test_otsu_threshold() {
+        let scores: Vec<f64> = (0..100)
+            .map(|i| 0.05 + (i % 10) as f64 * 0.01)
+            .chain((0..30).map(|i| 0.8 + (i % 10) as f64 * 0.01))
+            .collect();
+        let threshold = otsu_threshold(&scores);
+        assert!(threshold > 0.14 && threshold < 0.8);
+    }
+
+    #[test]
+    fn test_detect_doublets() {
+        // Two cell types expressing disjoint genes, and ten doublets expressing both.
+        let (num_genes, num_singlets, num_doublets) = (20, 200, 10);
+        let mut tri = TriMat::new((num_genes, num_singlets + num_doublets));
+        for bc in 0..num_singlets + num_doublets {
+            let cell_types: &[usize] = if bc < num_singlets {
+                &[bc % 2]
+            } else {
+                &[0, 1]
+            };
+            for &cell_type in cell_types {
+                for g in 0..num_genes / 2 {
+                    let count = 5 + (bc * 7 + g * 3) % 11;
+                    tri.add_triplet(cell_type * num_genes / 2 + g, bc, count as u32);
+                }
+            }
+        }
+        let matrix: CsMatI<u32, usize> = tri.to_csc();
+        // The first principal component separates the cell types.
+        let components = Array2::from_shape_fn((2, num_genes), |(pc, g)| match pc {
+            0 if g < num_genes / 2 => 0.3,
+            0 => -0.3,
+            _ => ((g % 3) as f64 - 1.0) * 0.3,
+        });
+
+        let result = detect_doublets(&matrix, &components, &DoubletParams::default());
+        assert_eq!(result.scores.len(), num_singlets + num_doublets);
+        let (singlets, doublets) = result.scores.split_at(num_singlets);
+        let max_singlet = singlets.iter().copied().fold(0.0, f64::max);
+        assert!(doublets.iter().all(|&score| score > max_singlet));
+        assert!(result.is_doublet().take(num_singlets).all(|d| !d));
+    }
+}
@@ -0,0 +1,285 @@
+//! Denoised and scaled by background (DSB) normalization of antibody capture
+//! Mulè, Martins and Tsang (2022) Normalizing and denoising protein expression data from
+//! droplet-based single cell profiling.
+//! The log counts of each antibody are standardized by its ambient background in empty droplets,
+//! and a technical component of each barcode, learned from its background level and its isotype
+//! controls, is regressed out.
+
+use crate::pca::symmetric_eigen;
+use ndarray::{Array1, Array2, Axis};
+use num_traits::Num;
+use rayon::prelude::*;
+use sprs::{CsMatI, SpIndex};
+use std::f64::consts::PI;
+
+/// Added to counts before the log transform.
+pub(crate) const PSEUDOCOUNT: f64 = 10.0;
+/// The smallest background standard deviation, to avoid dividing by zero.
+const MIN_STD_DEV: f64 = 1e-3;
+/// The smallest variance of a component of the Gaussian mixture.
+const MIN_VARIANCE: f64 = 1e-6;
+const MAX_EM_ITERATIONS: usize = 100;
+const EM_TOLERANCE: f64 = 1e-6;
+
+/// The mean and standard deviation of the log counts of each feature in empty droplets.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Background {
+    pub mean: Vec<f64>,
+    pub std_dev: Vec<f64>,
+}
+
+impl Background {
+    /// Estimate the background of each feature from the selected barcodes of a matrix with a
+    /// column per barcode.
+    pub(crate) fn new<N, I>(matrix: &CsMatI<N, I>, barcodes: &[usize]) -> Self
+    where
+        N: Num + Copy,
+        f64: From<N>,
+        I: SpIndex,
+    {
+        assert!(matrix.is_csc());
+        let num_features = matrix.rows();
+        let mut sums = vec![0.0; num_features];
+        let mut sum_squares = vec![0.0; num_features];
+        let mut num_stored = vec![0usize; num_features];
+        for column in barcodes.iter().filter_map(|&bc| matrix.outer_view(bc)) {
+            for (feature, &count) in column.iter() {
+                let y = (f64::from(count) + PSEUDOCOUNT).ln();
+                sums[feature] += y;
+                sum_squares[feature] += y * y;
+                num_stored[feature] += 1;
+            }
+        }
+
+        let zero = PSEUDOCOUNT.ln();
+        let n = barcodes.len().max(1) as f64;
+        let (mean, std_dev) = (0..num_features)
+            .map(|f| {
+                let num_zeros = (barcodes.len() - num_stored[f]) as f64;
+                let mean = (sums[f] + num_zeros * zero) / n;
+                let variance = (sum_squares[f] + num_zeros * zero * zero) / n - mean * mean;
+                (mean, variance.max(0.0).sqrt().max(MIN_STD_DEV))
+            })
+            .unzip();
+        Background { mean, std_dev }
+    }
+
+    /// Return the background of the given features, in their order.
+    pub(crate) fn select(&self, features: &[usize]) -> Self {
+        Background {
+            mean: features.iter().map(|&f| self.mean[f]).collect(),
+            std_dev: features.iter().map(|&f| self.std_dev[f]).collect(),
+        }
+    }
+}
+
+/// The denoised values, with a row per feature and a column per barcode, and the technical
+/// component of each barcode.
+pub(crate) struct DsbResult {
+    pub denoised: Array2<f64>,
+    pub technical_component: Array1<f64>,
+}
+
+/// Return the log counts of a matrix with a column per barcode standardized by the background,
+/// with a row per feature and a column per barcode.
+fn standardize<N, I>(matrix: &CsMatI<N, I>, background: &Background) -> Array2<f64>
+where
+    N: Num + Copy,
+    f64: From<N>,
+    I: SpIndex,
+{
+    assert!(matrix.is_csc());
+    assert_eq!(matrix.rows(), background.mean.len());
+    let Background { mean, std_dev } = background;
+    let scale = |feature: usize, count: f64| {
+        ((count + PSEUDOCOUNT).ln() - mean[feature]) / std_dev[feature]
+    };
+    let mut values = Array2::from_shape_fn((matrix.rows(), matrix.cols()), |(f, _)| scale(f, 0.0));
+    for (barcode, column) in matrix.outer_iterator().enumerate() {
+        for (feature, &count) in column.iter() {
+            values[[feature, barcode]] = scale(feature, f64::from(count));
+        }
+    }
+    values
+}
+
+fn gaussian_density(x: f64, mean: f64, variance: f64) -> f64 {
+    (-(x - mean) * (x - mean) / (2.0 * variance)).exp() / (2.0 * PI * variance).sqrt()
+}
+
+/// Return the background level of a barcode, the mean of the lower component of a two component
+/// Gaussian mixture fit to its standardized values by expectation maximization.
+fn background_level(values: &[f64]) -> f64 {
+    let n = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    let (min, max) = values
+        .iter()
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), &x| {
+            (lo.min(x), hi.max(x))
+        });
+    if max <= min {
+        return mean;
+    }
+
+    let variance = values.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / n;
+    let mut means = [min, max];
+    let mut variances = [variance; 2];
+    let mut weights = [0.5; 2];
+    // The responsibility of the lower component for each value.
+    let mut lower = vec![0.0; values.len()];
+    for _ in 0..MAX_EM_ITERATIONS {
+        for (r, &x) in lower.iter_mut().zip(values) {
+            let [p0, p1] = [0, 1].map(|k| weights[k] * gaussian_density(x, means[k], variances[k]));
+            *r = if p0 + p1 > 0.0 {
+                p0 / (p0 + p1)
+            } else if (x - means[0]).abs() <= (x - means[1]).abs() {
+                1.0
+            } else {
+                0.0
+            };
+        }
+
+        let previous = means;
+        for k in 0..2 {
+            let responsibility = |r: f64| if k == 0 { r } else { 1.0 - r };
+            let total: f64 = lower.iter().map(|&r| responsibility(r)).sum();
+            if total <= f64::EPSILON {
+                // One component is empty, which leaves a single Gaussian.
+                return mean;
+            }
+            means[k] = lower
+                .iter()
+                .zip(values)
+                .map(|(&r, &x)| responsibility(r) * x)
+                .sum::<f64>()
+                / total;
+            variances[k] = (lower
+                .iter()
+                .zip(values)
+                .map(|(&r, &x)| responsibility(r) * (x - means[k]) * (x - means[k]))
+                .sum::<f64>()
+                / total)
+                .max(MIN_VARIANCE);
+            weights[k] = total / n;
+        }
+        if (means[0] - previous[0]).abs() < EM_TOLERANCE
+            && (means[1] - previous[1]).abs() < EM_TOLERANCE
+        {
+            break;
+        }
+    }
+    means[0].min(means[1])
+}
+
+/// Return the technical component of each barcode of the standardized values. That is its
+/// background level when there are no isotype controls, and otherwise the first principal
+/// component of its background level and isotype controls, oriented to increase with the
+/// background level.
+fn technical_component(values: &Array2<f64>, isotype_controls: &[usize]) -> Array1<f64> {
+    let levels: Vec<f64> = (0..values.ncols())
+        .into_par_iter()
+        .map(|bc| background_level(&values.column(bc).to_vec()))
+        .collect();
+    let levels = Array1::from(levels);
+    if isotype_controls.is_empty() {
+        return levels;
+    }
+
+    let mut noise = Array2::zeros((values.ncols(), 1 + isotype_controls.len()));
+    noise.column_mut(0).assign(&levels);
+    for (j, &feature) in isotype_controls.iter().enumerate() {
+        noise.column_mut(j + 1).assign(&values.row(feature));
+    }
+    let centered = &noise - &noise.mean_axis(Axis(0)).unwrap();
+    let (eigenvalues, eigenvectors) = symmetric_eigen(&centered.t().dot(&centered));
+    let first = (0..eigenvalues.len())
+        .max_by(|&i, &j| eigenvalues[i].total_cmp(&eigenvalues[j]))
+        .unwrap();
+    let mut direction = eigenvectors.column(first).to_owned();
+    if direction[0] < 0.0 {
+        direction.mapv_inplace(|x| -x);
+    }
+    centered.dot(&direction)
+}
+
+/// Regress the technical component out of the values of each feature, keeping its mean.
+fn remove_technical_component(values: &mut Array2<f64>, technical_component: &Array1<f64>) {
+    let centered = technical_component - technical_component.mean().unwrap_or(0.0);
+    let sum_squares = centered.dot(&centered);
+    if sum_squares <= 0.0 {
+        return;
+    }
+    for mut row in values.rows_mut() {
+        let slope = row.dot(&centered) / sum_squares;
+        row.scaled_add(-slope, &centered);
+    }
+}
+
+/// Normalize the antibody counts of a matrix with a column per barcode by their background in
+/// empty droplets, and remove the technical component of each barcode. The isotype controls are
+/// indices of rows of the matrix.
+pub(crate) fn dsb<N, I>(
+    matrix: &CsMatI<N, I>,
+    background: &Background,
+    isotype_controls: &[usize],
+) -> DsbResult
+where
+    N: Num + Copy,
+    f64: From<N>,
+    I: SpIndex,
+{
+    let mut denoised = standardize(matrix, background);
+    let technical_component = technical_component(&denoised, isotype_controls);
+    remove_technical_component(&mut denoised, &technical_component);
+    DsbResult {
+        denoised,
+        technical_component,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sprs::TriMat;
+
+    #[test]
+    fn test_background() {
+        // Feature 0 has counts 0 and 10 in the two background barcodes, and feature 1 none.
+        let mut tri = TriMat::new((2, 3));
+        tri.add_triplet(0, 1, 10u32);
+        tri.add_triplet(0, 2, 1000u32);
+        tri.add_triplet(1, 2, 1000u32);
+        let matrix: CsMatI<u32, usize> = tri.to_csc();
+        let background = Background::new(&matrix, &[0, 1]);
+
+        let (y0, y1) = (PSEUDOCOUNT.ln(), (10.0 + PSEUDOCOUNT).ln());
+        assert!((background.mean[0] - (y0 + y1) / 2.0).abs() < 1e-12);
+        assert!((background.std_dev[0] - (y1 - y0) / 2.0).abs() < 1e-12);
+        assert!((background.mean[1] - y0).abs() < 1e-12);
+        assert_eq!(background.std_dev[1], MIN_STD_DEV);
+        assert_eq!(background.select(&[1]).mean, vec![background.mean[1]]);
+    }
+
+    #[test]
+    fn test_background_level() {
+        // Most antibodies are at background, and a few are expressed.
+        let values = [-0.2, 0.1, 0.0, 0.2, -0.1, 0.05, 8.0, 9.0, 10.0];
+        let level = background_level(&values);
+        assert!(level.abs() < 0.1, "{level}");
+        assert_eq!(background_level(&[1.0, 1.0]), 1.0);
+    }
+
+    #[test]
+    fn test_remove_technical_component() {
+        // Each barcode has a technical offset shared by every feature.
+        let technical = Array1::from(vec![-1.0, 0.0, 2.0, 3.0, -4.0]);
+        let signal = ndarray::array![[1.0, 0.0, 1.0, 0.0, 1.0], [5.0, 6.0, 5.0, 6.0, 5.0]];
+        let mut values = &signal + &technical;
+        remove_technical_component(&mut values, &technical);
+        let centered = &technical - technical.mean().unwrap();
+        for row in values.rows() {
+            assert!(row.dot(&centered).abs() < 1e-9);
+        }
+        assert!((values.row(1).mean().unwrap() - signal.row(1).mean().unwrap()).abs() < 1e-9);
+    }
+}
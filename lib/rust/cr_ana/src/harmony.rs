@@ -0,0 +1,376 @@
+//! Harmony integration of a PCA embedding across batches
+//! Korsunsky et al. (2019) Fast, sensitive and accurate integration of single-cell data with Harmony.
+
+use ndarray::{Array1, Array2, ArrayView1, Axis};
+
+/// The parameters of Harmony, with the defaults of the reference implementation.
+#[derive(Clone, Debug)]
+pub(crate) struct HarmonyParams {
+    /// The diversity penalty, which encourages each cluster to contain every batch.
+    pub theta: f64,
+    /// The width of the soft k-means clusters.
+    pub sigma: f64,
+    /// The ridge penalty of the batch effects.
+    pub lambda: f64,
+    /// The number of clusters, by default the number of cells divided by 30, at most 100.
+    pub num_clusters: Option<usize>,
+    pub max_iter_harmony: usize,
+    pub max_iter_cluster: usize,
+    pub epsilon_harmony: f64,
+    pub epsilon_cluster: f64,
+    /// The fraction of cells whose cluster assignments are updated at once.
+    pub block_size: f64,
+}
+
+impl Default for HarmonyParams {
+    fn default() -> Self {
+        HarmonyParams {
+            theta: 2.0,
+            sigma: 0.1,
+            lambda: 1.0,
+            num_clusters: None,
+            max_iter_harmony: 10,
+            max_iter_cluster: 20,
+            epsilon_harmony: 1e-4,
+            epsilon_cluster: 1e-5,
+            block_size: 0.05,
+        }
+    }
+}
+
+/// Number of k-means iterations used to initialize the cluster centroids.
+const KMEANS_INIT_ITER: usize = 10;
+
+/// Scale each row to unit length, leaving rows of zeros unchanged.
+fn cosine_normalize(x: &Array2<f64>) -> Array2<f64> {
+    let mut x = x.clone();
+    for mut row in x.rows_mut() {
+        let norm = row.dot(&row).sqrt();
+        if norm > 0.0 {
+            row /= norm;
+        }
+    }
+    x
+}
+
+/// Solve the linear system a x = b with Gaussian elimination and partial pivoting.
+/// Unknowns whose pivot vanishes are set to zero.
+fn solve(mut a: Array2<f64>, mut b: Array2<f64>) -> Array2<f64> {
+    let n = a.nrows();
+    for col in 0..n {
+        let pivot = (col..n)
+            .max_by(|&i, &j| a[[i, col]].abs().total_cmp(&a[[j, col]].abs()))
+            .unwrap();
+        if pivot != col {
+            for j in 0..n {
+                a.swap([col, j], [pivot, j]);
+            }
+            for j in 0..b.ncols() {
+                b.swap([col, j], [pivot, j]);
+            }
+        }
+        let p = a[[col, col]];
+        if p.abs() < 1e-12 {
+            continue;
+        }
+        for row in 0..n {
+            if row == col || a[[row, col]] == 0.0 {
+                continue;
+            }
+            let factor = a[[row, col]] / p;
+            let a_col = a.row(col).to_owned();
+            let b_col = b.row(col).to_owned();
+            a.row_mut(row).scaled_add(-factor, &a_col);
+            b.row_mut(row).scaled_add(-factor, &b_col);
+        }
+    }
+    for (i, mut row) in b.rows_mut().into_iter().enumerate() {
+        let p = a[[i, i]];
+        if p.abs() < 1e-12 {
+            row.fill(0.0);
+        } else {
+            row /= p;
+        }
+    }
+    b
+}
+
+/// The state of the soft k-means clustering of the cells.
+struct Clustering<'a> {
+    params: &'a HarmonyParams,
+    batches: &'a [usize],
+    /// The fraction of cells in each batch.
+    batch_fraction: Array1<f64>,
+    /// The cosine distance of each cell to each centroid.
+    dist: Array2<f64>,
+    /// The probability of each cell to belong to each cluster.
+    r: Array2<f64>,
+}
+
+impl Clustering<'_> {
+    /// Return the total probability of each cluster and the total probability of each
+    /// cluster in each batch.
+    fn cluster_sizes(&self) -> (Array1<f64>, Array2<f64>) {
+        let num_batches = self.batch_fraction.len();
+        let mut observed = Array2::zeros((self.r.ncols(), num_batches));
+        for (r_i, &batch) in self.r.rows().into_iter().zip(self.batches) {
+            observed.column_mut(batch).scaled_add(1.0, &r_i);
+        }
+        (self.r.sum_axis(Axis(0)), observed)
+    }
+
+    /// Return the cluster probabilities of a cell given the expected and observed
+    /// cluster sizes of its batch.
+    fn assign(
+        &self,
+        dist: ArrayView1<'_, f64>,
+        expected: &Array1<f64>,
+        observed: ArrayView1<'_, f64>,
+    ) -> Array1<f64> {
+        let mut r_i = Array1::from_shape_fn(dist.len(), |k| {
+            (-dist[k] / self.params.sigma).exp()
+                * ((expected[k] + 1.0) / (observed[k] + 1.0)).powf(self.params.theta)
+        });
+        let total = r_i.sum();
+        if total > 0.0 {
+            r_i /= total;
+        }
+        r_i
+    }
+
+    /// Update the centroids and the distances of the cells to them.
+    fn update_centroids(&mut self, z_cos: &Array2<f64>) {
+        let centroids = cosine_normalize(&self.r.t().dot(z_cos));
+        self.dist = z_cos.dot(&centroids.t()).mapv(|x| 2.0 * (1.0 - x));
+    }
+
+    /// Update the cluster probabilities one block of cells at a time.
+    fn update_assignments(&mut self) {
+        let n = self.r.nrows();
+        let block_size = ((n as f64 * self.params.block_size) as usize).max(1);
+        let (mut sizes, mut observed) = self.cluster_sizes();
+        for start in (0..n).step_by(block_size) {
+            let block = start..(start + block_size).min(n);
+            for i in block.clone() {
+                sizes.scaled_add(-1.0, &self.r.row(i));
+                observed
+                    .column_mut(self.batches[i])
+                    .scaled_add(-1.0, &self.r.row(i));
+            }
+            for i in block.clone() {
+                let batch = self.batches[i];
+                let expected = &sizes * self.batch_fraction[batch];
+                let r_i = self.assign(self.dist.row(i), &expected, observed.column(batch));
+                self.r.row_mut(i).assign(&r_i);
+            }
+            for i in block {
+                sizes.scaled_add(1.0, &self.r.row(i));
+                observed
+                    .column_mut(self.batches[i])
+                    .scaled_add(1.0, &self.r.row(i));
+            }
+        }
+    }
+
+    /// Return the objective function, which is the sum of the k-means error,
+    /// the entropy penalty and the diversity penalty.
+    fn objective(&self) -> f64 {
+        let sigma = self.params.sigma;
+        let kmeans_error = (&self.r * &self.dist).sum();
+        let entropy = sigma
+            * self
+                .r
+                .iter()
+                .filter(|&&x| x > 0.0)
+                .map(|&x| x * x.ln())
+                .sum::<f64>();
+        let (sizes, observed) = self.cluster_sizes();
+        let diversity = sigma
+            * self.params.theta
+            * observed
+                .indexed_iter()
+                .map(|((k, b), &o)| {
+                    o * ((o + 1.0) / (sizes[k] * self.batch_fraction[b] + 1.0)).ln()
+                })
+                .sum::<f64>();
+        kmeans_error + entropy + diversity
+    }
+
+    /// Iterate the soft k-means clustering until the objective converges.
+    fn cluster(&mut self, z_cos: &Array2<f64>) -> f64 {
+        let mut previous: Option<f64> = None;
+        let mut objective = f64::NAN;
+        for _ in 0..self.params.max_iter_cluster {
+            self.update_centroids(z_cos);
+            self.update_assignments();
+            objective = self.objective();
+            if previous.is_some_and(|prev| {
+                (prev - objective).abs() < self.params.epsilon_cluster * prev.abs()
+            }) {
+                break;
+            }
+            previous = Some(objective);
+        }
+        objective
+    }
+
+    /// Return the embedding with the batch effects of each cluster removed,
+    /// estimated with a ridge regression weighted by the cluster probabilities.
+    fn correct(&self, z: &Array2<f64>) -> Array2<f64> {
+        let num_batches = self.batch_fraction.len();
+        let (sizes, observed) = self.cluster_sizes();
+        let mut z_corr = z.clone();
+        for k in 0..self.r.ncols() {
+            // The design matrix is an intercept followed by the one-hot encoded batches.
+            let mut a = Array2::zeros((num_batches + 1, num_batches + 1));
+            a[[0, 0]] = sizes[k];
+            for b in 0..num_batches {
+                a[[0, b + 1]] = observed[[k, b]];
+                a[[b + 1, 0]] = observed[[k, b]];
+                a[[b + 1, b + 1]] = observed[[k, b]] + self.params.lambda;
+            }
+            let mut rhs = Array2::zeros((num_batches + 1, z.ncols()));
+            for (i, z_i) in z.rows().into_iter().enumerate() {
+                let w = self.r[[i, k]];
+                rhs.row_mut(0).scaled_add(w, &z_i);
+                rhs.row_mut(self.batches[i] + 1).scaled_add(w, &z_i);
+            }
+            let beta = solve(a, rhs);
+            for (i, mut z_i) in z_corr.rows_mut().into_iter().enumerate() {
+                z_i.scaled_add(-self.r[[i, k]], &beta.row(self.batches[i] + 1));
+            }
+        }
+        z_corr
+    }
+}
+
+/// Return the initial cluster probabilities of the cells, using centroids found by
+/// k-means in cosine space initialized with evenly spaced cells.
+fn initial_assignments(z_cos: &Array2<f64>, num_clusters: usize, sigma: f64) -> Array2<f64> {
+    let n = z_cos.nrows();
+    let mut centroids = Array2::from_shape_fn((num_clusters, z_cos.ncols()), |(k, j)| {
+        z_cos[[k * n / num_clusters, j]]
+    });
+    for _ in 0..KMEANS_INIT_ITER {
+        let similarity = z_cos.dot(&centroids.t());
+        let mut sums = Array2::<f64>::zeros(centroids.dim());
+        for (z_i, sim_i) in z_cos.rows().into_iter().zip(similarity.rows()) {
+            let nearest = (0..num_clusters)
+                .max_by(|&a, &b| sim_i[a].total_cmp(&sim_i[b]))
+                .unwrap();
+            sums.row_mut(nearest).scaled_add(1.0, &z_i);
+        }
+        for (mut centroid, sum) in centroids.rows_mut().into_iter().zip(sums.rows()) {
+            if sum.iter().any(|&x| x != 0.0) {
+                centroid.assign(&sum);
+            }
+        }
+        centroids = cosine_normalize(&centroids);
+    }
+    let mut r = z_cos
+        .dot(&centroids.t())
+        .mapv(|x| (-2.0 * (1.0 - x) / sigma).exp());
+    for mut r_i in r.rows_mut() {
+        let total = r_i.sum();
+        if total > 0.0 {
+            r_i /= total;
+        }
+    }
+    r
+}
+
+/// Return the embedding z of the cells with the effect of their batches removed.
+/// Each row of z is a cell, and batches are numbered from zero.
+pub(crate) fn run_harmony(
+    z: &Array2<f64>,
+    batches: &[usize],
+    params: &HarmonyParams,
+) -> Array2<f64> {
+    let n = z.nrows();
+    assert_eq!(n, batches.len());
+    let num_batches = batches.iter().max().map_or(0, |&b| b + 1);
+    if num_batches < 2 {
+        return z.clone();
+    }
+    let num_clusters = params.num_clusters.unwrap_or((n / 30).clamp(1, 100)).min(n);
+    let mut batch_fraction = Array1::zeros(num_batches);
+    for &b in batches {
+        batch_fraction[b] += 1.0 / n as f64;
+    }
+
+    let mut z_corr = z.clone();
+    let mut z_cos = cosine_normalize(z);
+    let mut clustering = Clustering {
+        params,
+        batches,
+        batch_fraction,
+        dist: Array2::zeros((n, num_clusters)),
+        r: initial_assignments(&z_cos, num_clusters, params.sigma),
+    };
+    let mut previous: Option<f64> = None;
+    for iter in 0..params.max_iter_harmony {
+        let objective = clustering.cluster(&z_cos);
+        z_corr = clustering.correct(z);
+        z_cos = cosine_normalize(&z_corr);
+        log::info!("Harmony iteration {}: objective {objective}", iter + 1);
+        if previous
+            .is_some_and(|prev| (prev - objective).abs() < params.epsilon_harmony * prev.abs())
+        {
+            break;
+        }
+        previous = Some(objective);
+    }
+    z_corr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn test_solve() {
+        let a = array![[4.0, 1.0], [1.0, 3.0]];
+        let b = array![[1.0, 0.0], [2.0, 1.0]];
+        let x = solve(a.clone(), b.clone());
+        assert!((a.dot(&x) - b).iter().all(|x| x.abs() < 1e-9));
+    }
+
+    #[test]
+    fn test_run_harmony() {
+        // Two cell types sequenced in two batches, where the second batch is offset.
+        let batches: Vec<usize> = (0..200).map(|i| (i / 2) % 2).collect();
+        let z = Array2::from_shape_fn((200, 3), |(i, j)| {
+            let (cell_type, batch) = (i % 2, (i / 2) % 2);
+            let jitter = [(i % 7) as f64 * 0.05, (i % 5) as f64 * 0.05];
+            match (cell_type, j) {
+                (0, 0) | (1, 1) => 5.0 + jitter[0],
+                (_, 2) => 0.1 + 3.0 * batch as f64,
+                _ => jitter[1],
+            }
+        });
+        let batch_gap = |z: &Array2<f64>| {
+            let mean = |b| {
+                let rows: Vec<_> = (0..200).filter(|&i| batches[i] == b).collect();
+                rows.iter().map(|&i| z[[i, 2]]).sum::<f64>() / rows.len() as f64
+            };
+            (mean(0) - mean(1)).abs()
+        };
+        assert!((batch_gap(&z) - 3.0).abs() < 1e-9);
+
+        let corrected = run_harmony(&z, &batches, &HarmonyParams::default());
+        assert!(batch_gap(&corrected) < 0.5);
+        // The cell types remain separated.
+        for cell_type in 0..2 {
+            let mean = (0..200)
+                .filter(|i| i % 2 == cell_type)
+                .map(|i| corrected[[i, cell_type]])
+                .sum::<f64>()
+                / 100.0;
+            assert!(mean > 4.0);
+        }
+
+        // A single batch is left unchanged.
+        assert_eq!(run_harmony(&z, &[0; 200], &HarmonyParams::default()), z);
+    }
+}
@@ -1,12 +1,13 @@
 //! I/O CSV helper functions
 
 use crate::types::{clustering_key, ClusteringResult, ClusteringType, EmbeddingResult, PcaResult};
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use cr_types::reference::feature_reference::FeatureType;
-use ndarray::Array2;
+use itertools::Itertools;
+use ndarray::{Array1, Array2};
 use std::fs::{copy, create_dir_all, File};
-use std::io::{BufWriter, Write};
-use std::path::Path;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
 
 pub(crate) fn save_pca(
     pca_csv: &Path,
@@ -20,6 +21,9 @@ pub(crate) fn save_pca(
         features_selected,
         transformed_pca_matrix,
         variance_explained,
+        feature_mean,
+        feature_scale,
+        size_factor_target,
         ..
     } = result;
     let (num_bcs, num_pcs) = transformed_pca_matrix.dim();
@@ -76,6 +80,26 @@ pub(crate) fn save_pca(
             writeln!(file)?;
         }
     }
+    {
+        let path = component_dir.join("feature_scaling.csv");
+        let mut file = BufWriter::new(File::create(path)?);
+        // The UMI count target is the same for every feature, and is repeated on each row.
+        match size_factor_target {
+            Some(_) => writeln!(file, "Feature,Mean,Scale,Size.Factor.Target")?,
+            None => writeln!(file, "Feature,Mean,Scale")?,
+        }
+        for (i, feature_id) in feature_ids.iter().enumerate() {
+            write!(
+                file,
+                "{feature_id},{},{}",
+                feature_mean[i], feature_scale[i]
+            )?;
+            if let Some(target) = size_factor_target {
+                write!(file, ",{target}")?;
+            }
+            writeln!(file)?;
+        }
+    }
     {
         let path = component_dir.join("variance.csv");
         let mut file = BufWriter::new(File::create(path)?);
@@ -251,3 +275,176 @@ pub(crate) fn combine_embeddings(
     }
     Ok(())
 }
+
+/// Return the subdirectory of an analysis CSV directory of the feature type, such as
+/// gene_expression_10_components.
+fn find_components_dir(path: &Path, feature_type: FeatureType) -> Result<PathBuf> {
+    let prefix = format!("{}_", feature_type.as_snake_case());
+    path.read_dir()?
+        .filter_map(|d| d.ok().and_then(|d| d.file_name().into_string().ok()))
+        .filter(|d| d.starts_with(&prefix) && d.ends_with("_components"))
+        .sorted()
+        .next()
+        .map(|d| path.join(d))
+        .ok_or_else(|| anyhow!("unable to find {prefix}components in {}", path.display()))
+}
+
+/// Load a CSV with a header whose rows are a label followed by numbers, such as
+/// components.csv and projection.csv. Return the header, the labels, and the numbers.
+fn load_labeled_matrix(path: &Path) -> Result<(Vec<String>, Vec<String>, Array2<f64>)> {
+    let file = BufReader::new(File::open(path).with_context(|| path.display().to_string())?);
+    let mut lines = file.lines();
+    let header: Vec<String> = match lines.next() {
+        Some(line) => line?.split(',').skip(1).map(String::from).collect(),
+        None => bail!("{} is empty", path.display()),
+    };
+    let mut labels = Vec::new();
+    let mut values = Vec::new();
+    for line in lines {
+        let line = line?;
+        let mut fields = line.split(',');
+        labels.push(fields.next().unwrap_or_default().to_string());
+        for field in fields {
+            values.push(
+                field
+                    .parse::<f64>()
+                    .with_context(|| format!("invalid value {field} in {}", path.display()))?,
+            );
+        }
+    }
+    let values = Array2::from_shape_vec((labels.len(), header.len()), values)
+        .with_context(|| format!("rows of {} differ in length", path.display()))?;
+    Ok((header, labels, values))
+}
+
+/// Load the principal components of a PCA CSV directory, returning the feature IDs
+/// and the components-by-features matrix.
+pub(crate) fn load_pca_components(
+    pca_csv: &Path,
+    feature_type: FeatureType,
+) -> Result<(Vec<String>, Array2<f64>)> {
+    let path = find_components_dir(pca_csv, feature_type)?.join("components.csv");
+    let (feature_ids, _, components) = load_labeled_matrix(&path)?;
+    Ok((feature_ids, components))
+}
+
+/// Load the mean and scale of each feature and the UMI count target of a PCA CSV directory,
+/// returning the feature IDs, the means, the scales and the target.
+/// Return None when the analysis has no feature_scaling.csv, or one without the UMI count
+/// target written by earlier versions.
+pub(crate) fn load_pca_feature_scaling(
+    pca_csv: &Path,
+    feature_type: FeatureType,
+) -> Result<Option<(Vec<String>, Array1<f64>, Array1<f64>, f64)>> {
+    let path = find_components_dir(pca_csv, feature_type)?.join("feature_scaling.csv");
+    if !path.exists() {
+        return Ok(None);
+    }
+    let (header, feature_ids, values) = load_labeled_matrix(&path)?;
+    if header == ["Mean", "Scale"] {
+        return Ok(None);
+    }
+    ensure!(
+        header == ["Mean", "Scale", "Size.Factor.Target"],
+        "{} has columns {} rather than Mean,Scale,Size.Factor.Target",
+        path.display(),
+        header.join(",")
+    );
+    let target = values
+        .column(2)
+        .first()
+        .copied()
+        .with_context(|| format!("{} has no features", path.display()))?;
+    Ok(Some((
+        feature_ids,
+        values.column(0).to_owned(),
+        values.column(1).to_owned(),
+        target,
+    )))
+}
+
+/// Load the features selected for a PCA from a PCA CSV directory.
+pub(crate) fn load_pca_features_selected(
+    pca_csv: &Path,
+    feature_type: FeatureType,
+) -> Result<Vec<String>> {
+    let path = find_components_dir(pca_csv, feature_type)?.join("features_selected.csv");
+    let file = BufReader::new(File::open(&path).with_context(|| path.display().to_string())?);
+    file.lines()
+        .skip(1)
+        .map(|line| {
+            let line = line?;
+            match line.split_once(',') {
+                Some((_, feature_id)) => Ok(feature_id.to_string()),
+                None => bail!("invalid line {line} in {}", path.display()),
+            }
+        })
+        .collect()
+}
+
+/// Load the projection of a PCA, t-SNE or UMAP CSV directory,
+/// returning the barcodes and the barcodes-by-dimensions matrix.
+pub(crate) fn load_projection(
+    path: &Path,
+    feature_type: FeatureType,
+) -> Result<(Vec<String>, Array2<f64>)> {
+    let path = find_components_dir(path, feature_type)?.join("projection.csv");
+    let (_, barcodes, projection) = load_labeled_matrix(&path)?;
+    Ok((barcodes, projection))
+}
+
+/// Load a clustering of a clustering CSV directory, returning the barcodes and their labels.
+pub(crate) fn load_clustering(
+    path: &Path,
+    clustering_type: ClusteringType,
+    feature_type: FeatureType,
+) -> Result<(Vec<String>, Vec<i64>)> {
+    let path = path
+        .join(clustering_key(clustering_type, feature_type))
+        .join("clusters.csv");
+    let (_, barcodes, labels) = load_labeled_matrix(&path)?;
+    Ok((barcodes, labels.iter().map(|&x| x as i64).collect()))
+}
+
+/// Save the clusters transferred from a reference analysis and their mapping confidence.
+pub(crate) fn save_reference_mapping(
+    path: &Path,
+    barcodes: &[String],
+    mapping: &[(i64, f64)],
+) -> Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file, "Barcode,Cluster,Mapping Confidence")?;
+    for (barcode, (cluster, confidence)) in barcodes.iter().zip(mapping) {
+        writeln!(file, "{barcode},{cluster},{confidence}")?;
+    }
+    Ok(())
+}
+
+/// Save the diffusion pseudotime and diffusion components of each barcode, and the PAGA
+/// connectivity of each pair of clusters, to a diffusion pseudotime CSV directory.
+pub(crate) fn save_diffusion_pseudotime(
+    path: &Path,
+    barcodes: &[String],
+    pseudotime: &[f64],
+    components: &Array2<f64>,
+    clusters: &[i64],
+    connectivity: &Array2<f64>,
+) -> Result<()> {
+    create_dir_all(path)?;
+    {
+        let mut file = BufWriter::new(File::create(path.join("pseudotime.csv"))?);
+        let header = (1..=components.ncols()).map(|i| format!("DC_{i}"));
+        writeln!(file, "Barcode,Pseudotime,{}", header.format(","))?;
+        for ((barcode, t), row) in barcodes.iter().zip(pseudotime).zip(components.rows()) {
+            writeln!(file, "{barcode},{t},{}", row.iter().format(","))?;
+        }
+    }
+    {
+        let mut file = BufWriter::new(File::create(path.join("paga_connectivity.csv"))?);
+        writeln!(file, "Cluster,{}", clusters.iter().format(","))?;
+        for (cluster, row) in clusters.iter().zip(connectivity.rows()) {
+            writeln!(file, "{cluster},{}", row.iter().format(","))?;
+        }
+    }
+    Ok(())
+}
@@ -1,5 +1,7 @@
 //! I/O HDF5 helper functions
 
+use crate::diffusion::DiffusionMap;
+use crate::dsb::{Background, DsbResult};
 use crate::types::{
     clustering_key, ClusteringKey, ClusteringResult, ClusteringType, EmbeddingResult,
     EmbeddingType, H5File, PcaResult,
@@ -9,7 +11,7 @@ use cr_types::reference::feature_reference::FeatureType;
 use itertools::FoldWhile::{Continue, Done};
 use itertools::Itertools;
 use ndarray::{s, Array2};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashSet};
 use std::path::Path;
 
 const VERSION_DS: &str = "version";
@@ -39,6 +41,8 @@ pub(crate) mod matrix {
     pub(crate) const SHAPE: &str = "shape";
     pub(crate) const FEATURE_GROUP: &str = "features";
     pub(crate) const FEATURE_TYPE: &str = "feature_type";
+    pub(crate) const FEATURE_ID: &str = "id";
+    pub(crate) const ISOTYPE_CONTROL: &str = "isotype_control";
 }
 
 pub(crate) fn matrix_shape(matrix: impl AsRef<Path>) -> Result<(isize, isize)> {
@@ -71,6 +75,28 @@ pub(crate) fn matrix_feature_types(
     Ok(feature_types)
 }
 
+/// Return the IDs of the features of a matrix tagged as isotype controls by the feature reference.
+pub(crate) fn isotype_control_ids(matrix: impl AsRef<Path>) -> Result<HashSet<String>> {
+    let features = hdf5::File::open(&matrix)?
+        .group(matrix::GROUP)?
+        .group(matrix::FEATURE_GROUP)?;
+    if !features
+        .member_names()?
+        .iter()
+        .any(|name| name == matrix::ISOTYPE_CONTROL)
+    {
+        return Ok(HashSet::new());
+    }
+    let ids = features.dataset(matrix::FEATURE_ID)?.read_1d::<FA>()?;
+    let tags = features.dataset(matrix::ISOTYPE_CONTROL)?.read_1d::<FA>()?;
+    Ok(ids
+        .iter()
+        .zip(&tags)
+        .filter(|(_, tag)| tag.as_str().eq_ignore_ascii_case("true"))
+        .map(|(id, _)| id.to_string())
+        .collect())
+}
+
 /// Estimate the memory (GiB) required to load a matrix from the number of non-zero entries (NNZ).
 pub(crate) fn estimate_mem_gib_from_nnz(matrix: &Path) -> Result<f64> {
     Ok(20e-9 * matrix_nnz(matrix)? as f64)
@@ -143,6 +169,34 @@ pub(crate) fn load_transformed_pca_matrix(
     Ok(proj)
 }
 
+pub(crate) fn load_pca_components(
+    pca_h5: impl AsRef<Path>,
+    feature_type: FeatureType,
+    input_pcs: Option<usize>,
+) -> Result<Array2<f64>> {
+    let (n_components, shape) = load_transformed_pca(pca_h5, feature_type, input_pcs)?;
+    Ok(n_components
+        .dataset(pca::COMPONENTS)?
+        .read_slice_2d::<f64, _>(s![0..shape.num_components, ..])?)
+}
+
+/// Copy a PCA file, replacing the transformed PCA matrix of each feature type
+/// with the result of `correct`.
+pub(crate) fn copy_corrected_pca(
+    pca_h5: &Path,
+    corrected_h5: &Path,
+    mut correct: impl FnMut(Array2<f64>) -> Result<Array2<f64>>,
+) -> Result<()> {
+    std::fs::copy(pca_h5, corrected_h5)
+        .with_context(|| format!("{} -> {}", pca_h5.display(), corrected_h5.display()))?;
+    let group = hdf5::File::open_rw(corrected_h5)?.group(pca::GROUP)?;
+    for name in group.member_names()? {
+        let dataset = group.group(&name)?.dataset(pca::MATRIX)?;
+        dataset.write(&correct(dataset.read_2d::<f64>()?)?)?;
+    }
+    Ok(())
+}
+
 pub(crate) fn save_pca(pca_h5: &H5File, result: &PcaResult<'_>) -> Result<()> {
     let PcaResult {
         components,
@@ -429,6 +483,64 @@ pub(crate) fn combine_clusterings<'a>(
     Ok(())
 }
 
+pub(crate) mod neighbors {
+    pub(crate) const GROUP: &str = "neighbors";
+}
+
+/// Save the k-nearest neighbor graph of the reduced space of a feature type, with a row per
+/// barcode of its neighbors ordered by distance.
+pub(crate) fn save_neighbors(
+    path: &H5File,
+    feature_type: FeatureType,
+    neighbors: &Array2<u32>,
+) -> Result<()> {
+    hdf5::File::create(path)?
+        .create_group(neighbors::GROUP)?
+        .new_dataset::<u32>()
+        .shape(neighbors.dim())
+        .create(feature_type.as_snake_case())?
+        .write(neighbors)?;
+    Ok(())
+}
+
+pub(crate) fn combine_neighbors<'a>(
+    path: &H5File,
+    parts: impl IntoIterator<Item = &'a H5File>,
+) -> Result<()> {
+    let out_group = hdf5::File::create(path)?.create_group(neighbors::GROUP)?;
+    for part in parts {
+        let in_group = hdf5::File::open(part)?.group(neighbors::GROUP)?;
+        for member in in_group.member_names()? {
+            let neighbors = in_group.dataset(&member)?.read_2d::<u32>()?;
+            out_group
+                .new_dataset::<u32>()
+                .shape(neighbors.dim())
+                .create(member.as_str())?
+                .write(&neighbors)?;
+        }
+    }
+    Ok(())
+}
+
+/// Load the first k neighbors of each barcode of the k-nearest neighbor graph of a feature type,
+/// or None when the graph is absent or has fewer than k neighbors.
+pub(crate) fn load_neighbors(
+    path: &H5File,
+    feature_type: FeatureType,
+    k: usize,
+) -> Result<Option<Array2<u32>>> {
+    let file = hdf5::File::open(path)?;
+    let name = format!("{}/{}", neighbors::GROUP, feature_type.as_snake_case());
+    if !file.link_exists(&name) {
+        return Ok(None);
+    }
+    let dataset = file.dataset(&name)?;
+    if dataset.shape()[1] < k {
+        return Ok(None);
+    }
+    Ok(Some(dataset.read_slice_2d::<u32, _>(s![.., 0..k])?))
+}
+
 pub(crate) mod embedding {
     pub(crate) const KEY: &str = "key";
     pub(crate) const NAME: &str = "name";
@@ -438,11 +550,9 @@ pub(crate) fn save_embedding(path: &H5File, result: &EmbeddingResult<'_>) -> Res
     let EmbeddingResult {
         embedding,
         embedding_type,
-        feature_type,
         key,
         ..
     } = result;
-    let dims = embedding.dim().1;
     let file = hdf5::File::create(path)?;
     let group = file
         .create_group(embedding_type.lc())?
@@ -459,11 +569,7 @@ pub(crate) fn save_embedding(path: &H5File, result: &EmbeddingResult<'_>) -> Res
     group
         .new_dataset::<FA>()
         .create(embedding::NAME)?
-        .write_scalar(&make_fixed_ascii(&format!(
-            "{}_{}-d",
-            feature_type.as_snake_case(),
-            dims,
-        ))?)?;
+        .write_scalar(&make_fixed_ascii(&format!("{key}-d"))?)?;
     Ok(())
 }
 
@@ -500,3 +606,153 @@ pub(crate) fn combine_embeddings<'a>(
     }
     Ok(())
 }
+
+pub(crate) mod wnn {
+    pub(crate) const GROUP: &str = "wnn";
+    pub(crate) const EMBEDDING: &str = "embedding";
+    pub(crate) const WEIGHTS: &str = "gene_expression_weights";
+}
+
+/// Save the joint embedding and the gene expression weight of each barcode of the weighted
+/// nearest neighbor integration.
+pub(crate) fn save_wnn(path: &H5File, embedding: &Array2<f64>, weights: &[f64]) -> Result<()> {
+    let group = hdf5::File::create(path)?.create_group(wnn::GROUP)?;
+    group
+        .new_dataset::<f64>()
+        .shape(embedding.dim())
+        .create(wnn::EMBEDDING)?
+        .write(embedding)?;
+    group
+        .new_dataset::<f64>()
+        .shape((weights.len(),))
+        .create(wnn::WEIGHTS)?
+        .write(weights)?;
+    Ok(())
+}
+
+pub(crate) fn load_wnn_embedding(path: &H5File) -> Result<Array2<f64>> {
+    Ok(hdf5::File::open(path)?
+        .group(wnn::GROUP)?
+        .dataset(wnn::EMBEDDING)?
+        .read_2d::<f64>()?)
+}
+
+pub(crate) mod antibody_normalization {
+    pub(crate) const GROUP: &str = "antibody_normalization";
+    pub(crate) const BARCODES: &str = "barcodes";
+    pub(crate) const FEATURES: &str = "feature_ids";
+    pub(crate) const DENOISED: &str = "denoised";
+    pub(crate) const TECHNICAL: &str = "technical_component";
+    pub(crate) const BACKGROUND_MEAN: &str = "background_mean";
+    pub(crate) const BACKGROUND_STD_DEV: &str = "background_std_dev";
+}
+
+/// Save the denoised antibody values, with a row per feature and a column per barcode, the
+/// technical component of each barcode and the background of each feature.
+pub(crate) fn save_antibody_normalization(
+    path: &H5File,
+    barcodes: &[String],
+    feature_ids: &[String],
+    result: &DsbResult,
+    background: &Background,
+) -> Result<()> {
+    use antibody_normalization as an;
+    let group = hdf5::File::create(path)?.create_group(an::GROUP)?;
+    for (name, strings) in [(an::BARCODES, barcodes), (an::FEATURES, feature_ids)] {
+        let strings: Vec<_> = strings.iter().map(|x| make_fixed_ascii(x)).try_collect()?;
+        group
+            .new_dataset::<FA>()
+            .shape((strings.len(),))
+            .create(name)?
+            .write(&strings)?;
+    }
+    group
+        .new_dataset::<f64>()
+        .shape(result.denoised.dim())
+        .create(an::DENOISED)?
+        .write(&result.denoised)?;
+    group
+        .new_dataset::<f64>()
+        .shape(result.technical_component.dim())
+        .create(an::TECHNICAL)?
+        .write(&result.technical_component)?;
+    for (name, values) in [
+        (an::BACKGROUND_MEAN, &background.mean),
+        (an::BACKGROUND_STD_DEV, &background.std_dev),
+    ] {
+        group
+            .new_dataset::<f64>()
+            .shape((values.len(),))
+            .create(name)?
+            .write(values.as_slice())?;
+    }
+    Ok(())
+}
+
+/// Return the feature IDs and the denoised antibody values, with a row per feature and a column
+/// per barcode.
+pub(crate) fn load_denoised_antibodies(path: &H5File) -> Result<(Vec<String>, Array2<f64>)> {
+    use antibody_normalization as an;
+    let group = hdf5::File::open(path)?.group(an::GROUP)?;
+    let feature_ids = group
+        .dataset(an::FEATURES)?
+        .read_1d::<FA>()?
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    Ok((feature_ids, group.dataset(an::DENOISED)?.read_2d::<f64>()?))
+}
+
+pub(crate) mod diffusion_pseudotime {
+    pub(crate) const GROUP: &str = "diffusion_pseudotime";
+    pub(crate) const COMPONENTS: &str = "components";
+    pub(crate) const EIGENVALUES: &str = "eigenvalues";
+    pub(crate) const PSEUDOTIME: &str = "pseudotime";
+    pub(crate) const ROOT: &str = "root_barcode";
+    pub(crate) const PAGA_CLUSTERS: &str = "paga_clusters";
+    pub(crate) const PAGA_CONNECTIVITY: &str = "paga_connectivity";
+}
+
+/// Save the diffusion map, the diffusion pseudotime of each barcode from the root barcode, and
+/// the PAGA connectivity of each pair of clusters.
+pub(crate) fn save_diffusion_pseudotime(
+    path: &H5File,
+    map: &DiffusionMap,
+    pseudotime: &[f64],
+    root: &str,
+    clusters: &[i64],
+    connectivity: &Array2<f64>,
+) -> Result<()> {
+    use diffusion_pseudotime as dpt;
+    let group = hdf5::File::create(path)?.create_group(dpt::GROUP)?;
+    group
+        .new_dataset::<f64>()
+        .shape(map.components.dim())
+        .create(dpt::COMPONENTS)?
+        .write(&map.components)?;
+    group
+        .new_dataset::<f64>()
+        .shape(map.eigenvalues.dim())
+        .create(dpt::EIGENVALUES)?
+        .write(&map.eigenvalues)?;
+    group
+        .new_dataset::<f64>()
+        .shape((pseudotime.len(),))
+        .create(dpt::PSEUDOTIME)?
+        .write(pseudotime)?;
+    group
+        .new_dataset::<FA>()
+        .create(dpt::ROOT)?
+        .write_scalar(&make_fixed_ascii(root)?)?;
+    group
+        .new_dataset::<i64>()
+        .shape((clusters.len(),))
+        .create(dpt::PAGA_CLUSTERS)?
+        .write(clusters)?;
+    group
+        .new_dataset::<f64>()
+        .shape(connectivity.dim())
+        .create(dpt::PAGA_CONNECTIVITY)?
+        .write(connectivity)?;
+    Ok(())
+}
@@ -48,16 +48,26 @@
 use cr_types::reference::feature_reference::FeatureType;
 use cr_types::FeatureBarcodeType;
 
+mod aggr;
+mod diffusion;
+mod doublets;
+mod dsb;
+mod harmony;
 mod hclust_utils;
 mod io;
 mod louvain;
+mod marker_annotation;
+mod neighbors;
 mod pca;
+mod projection;
+mod pseudobulk;
 #[cfg(test)]
 mod stage_testing;
 pub mod stages;
 #[cfg(test)]
 mod test_pipeline;
 mod types;
+mod wnn;
 
 pub(crate) const EXCLUDED_FEATURE_TYPES: &[FeatureType] =
     &[FeatureType::Barcode(FeatureBarcodeType::Antigen)];
@@ -0,0 +1,219 @@
+//! Cell type annotation from sets of marker genes
+//! Each cell is scored for each cell type by the mean scaled expression of its positive markers
+//! minus that of its negative markers, and assigned the cell type with the highest score.
+
+use crate::pca::get_normalized_matrix;
+use cr_types::reference::feature_reference::FeatureType;
+use ndarray::linalg::Dot;
+use ndarray::Array2;
+use sqz::AdaptiveMat;
+
+/// The label of cells and clusters whose best score is not positive.
+pub(crate) const UNASSIGNED: &str = "Unassigned";
+
+/// The positive and negative marker genes of a cell type, as feature indices.
+#[derive(Clone, Debug)]
+pub(crate) struct MarkerSet {
+    pub cell_type: String,
+    pub positive: Vec<usize>,
+    pub negative: Vec<usize>,
+}
+
+/// The cell type assigned to a cell or a cluster.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Assignment {
+    /// The index of the marker set, or None when unassigned.
+    pub cell_type: Option<usize>,
+    pub score: f64,
+    pub confidence: f64,
+}
+
+/// Return the scaled expression of the features of each barcode of a feature-barcode matrix,
+/// normalized by get_normalized_matrix as for the PCA.
+fn scaled_expression(matrix: &AdaptiveMat, features: &[usize]) -> Array2<f64> {
+    // Select the features from the normalized matrix without making it dense.
+    let mut selection = Array2::zeros((matrix.rows(), features.len()));
+    for (j, &feature) in features.iter().enumerate() {
+        selection[[feature, j]] = 1.0;
+    }
+    let mut expr = get_normalized_matrix(FeatureType::Gene, &false, matrix.view())
+        .t()
+        .dot(&selection);
+    // A feature without variance is not scaled.
+    expr.mapv_inplace(|x| if x.is_finite() { x } else { 0.0 });
+    expr
+}
+
+/// Return the barcode-by-cell-type matrix of marker scores.
+pub(crate) fn score_cells(matrix: &AdaptiveMat, marker_sets: &[MarkerSet]) -> Array2<f64> {
+    let mut features: Vec<usize> = marker_sets
+        .iter()
+        .flat_map(|set| set.positive.iter().chain(&set.negative))
+        .copied()
+        .collect();
+    features.sort_unstable();
+    features.dedup();
+    let expr = scaled_expression(matrix, &features);
+    let column = |feature: &usize| features.binary_search(feature).unwrap();
+    let mean_expr = |row: ndarray::ArrayView1<'_, f64>, markers: &[usize]| {
+        if markers.is_empty() {
+            0.0
+        } else {
+            markers.iter().map(|f| row[column(f)]).sum::<f64>() / markers.len() as f64
+        }
+    };
+
+    let mut scores = Array2::zeros((matrix.cols(), marker_sets.len()));
+    for (mut barcode_scores, row) in scores.rows_mut().into_iter().zip(expr.rows()) {
+        for (score, set) in barcode_scores.iter_mut().zip(marker_sets) {
+            *score = mean_expr(row, &set.positive) - mean_expr(row, &set.negative);
+        }
+    }
+    scores
+}
+
+/// Assign each cell the cell type of its highest score, with the softmax of its scores as
+/// confidence.
+pub(crate) fn assign_cells(scores: &Array2<f64>) -> Vec<Assignment> {
+    scores
+        .rows()
+        .into_iter()
+        .map(|row| {
+            let Some((best, &score)) = row.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1))
+            else {
+                return Assignment {
+                    cell_type: None,
+                    score: 0.0,
+                    confidence: 0.0,
+                };
+            };
+            let sum_exp: f64 = row.iter().map(|&s| (s - score).exp()).sum();
+            Assignment {
+                cell_type: (score > 0.0).then_some(best),
+                score,
+                confidence: 1.0 / sum_exp,
+            }
+        })
+        .collect()
+}
+
+/// Assign each cluster the cell type of its highest mean score, with the fraction of its cells
+/// assigned the same cell type as confidence.
+pub(crate) fn assign_clusters(
+    scores: &Array2<f64>,
+    cells: &[Assignment],
+    labels: &[usize],
+    num_clusters: usize,
+) -> Vec<Assignment> {
+    let mut sums = Array2::<f64>::zeros((num_clusters, scores.ncols()));
+    let mut sizes = vec![0usize; num_clusters];
+    for (row, &label) in scores.rows().into_iter().zip(labels) {
+        let mut sum = sums.row_mut(label);
+        sum += &row;
+        sizes[label] += 1;
+    }
+    sums.rows()
+        .into_iter()
+        .zip(&sizes)
+        .enumerate()
+        .map(|(cluster, (sum, &size))| {
+            let Some((best, &total)) = sum.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1))
+            else {
+                return Assignment {
+                    cell_type: None,
+                    score: 0.0,
+                    confidence: 0.0,
+                };
+            };
+            let score = if size > 0 { total / size as f64 } else { 0.0 };
+            let cell_type = (score > 0.0).then_some(best);
+            let agreeing = cells
+                .iter()
+                .zip(labels)
+                .filter(|&(cell, &label)| label == cluster && cell.cell_type == cell_type)
+                .count();
+            Assignment {
+                cell_type,
+                score,
+                confidence: if size > 0 {
+                    agreeing as f64 / size as f64
+                } else {
+                    0.0
+                },
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+    use sqz::AdaptiveMatOwned;
+
+    /// Two cell types marked by genes 0 and 1, and a housekeeping gene 2.
+    fn test_matrix() -> AdaptiveMatOwned<u32> {
+        let dense: Array2<u32> = array![
+            [10, 10, 10, 0, 0, 0],
+            [0, 0, 0, 10, 10, 10],
+            [20, 20, 20, 20, 20, 20],
+        ];
+        AdaptiveMatOwned::<u32>::from_dense(dense.view())
+    }
+
+    fn test_marker_sets() -> Vec<MarkerSet> {
+        vec![
+            MarkerSet {
+                cell_type: "A".to_string(),
+                positive: vec![0],
+                negative: vec![1],
+            },
+            MarkerSet {
+                cell_type: "B".to_string(),
+                positive: vec![1],
+                negative: vec![],
+            },
+        ]
+    }
+
+    #[test]
+    fn test_score_cells() {
+        let scores = score_cells(&test_matrix(), &test_marker_sets());
+        assert_eq!(scores.dim(), (6, 2));
+        for barcode in 0..3 {
+            assert!((scores[[barcode, 0]] - 2.0).abs() < 1e-12);
+            assert!((scores[[barcode, 1]] + 1.0).abs() < 1e-12);
+        }
+        for barcode in 3..6 {
+            assert!((scores[[barcode, 0]] + 2.0).abs() < 1e-12);
+            assert!((scores[[barcode, 1]] - 1.0).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_assign() {
+        let scores = score_cells(&test_matrix(), &test_marker_sets());
+        let cells = assign_cells(&scores);
+        let cell_types: Vec<_> = cells.iter().map(|a| a.cell_type).collect();
+        assert_eq!(
+            cell_types,
+            [Some(0), Some(0), Some(0), Some(1), Some(1), Some(1)]
+        );
+        assert!(cells.iter().all(|a| a.confidence > 0.9));
+
+        // The second cluster mixes both cell types, with more cells of type B.
+        let clusters = assign_clusters(&scores, &cells, &[0, 0, 1, 1, 1, 1], 2);
+        assert_eq!(clusters[0].cell_type, Some(0));
+        assert_eq!(clusters[0].confidence, 1.0);
+        assert_eq!(clusters[1].cell_type, Some(1));
+        assert_eq!(clusters[1].confidence, 0.75);
+    }
+
+    #[test]
+    fn test_unassigned() {
+        let scores = ndarray::array![[-0.5, -1.0], [0.0, 0.0]];
+        let cells = assign_cells(&scores);
+        assert!(cells.iter().all(|a| a.cell_type.is_none()));
+        assert!((cells[1].confidence - 0.5).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,54 @@
+//! k-nearest neighbors of the barcodes of a reduced space, shared by the analyses that build a
+//! kernel on the neighbor graph.
+
+use ndarray::{ArrayView1, ArrayView2};
+use scan_rs::nn::knn;
+
+/// The smallest kernel width, to avoid dividing by zero when neighbors coincide.
+pub(crate) const MIN_KERNEL_WIDTH: f64 = 1e-8;
+
+/// Return the Euclidean distance between two points.
+pub(crate) fn distance(a: ArrayView1<'_, f64>, b: ArrayView1<'_, f64>) -> f64 {
+    a.iter()
+        .zip(b)
+        .map(|(x, y)| (x - y) * (x - y))
+        .sum::<f64>()
+        .sqrt()
+}
+
+/// Return the first k neighbors of each barcode of a k-nearest neighbor search, ordered by
+/// distance, excluding the barcode itself and the missing neighbors.
+pub(crate) fn exclude_self(neighbors: ArrayView2<'_, u32>, k: usize) -> Vec<Vec<usize>> {
+    neighbors
+        .rows()
+        .into_iter()
+        .enumerate()
+        .map(|(i, row)| {
+            row.iter()
+                .map(|&j| j as usize)
+                .filter(|&j| j != i && j != u32::MAX as usize)
+                .take(k)
+                .collect()
+        })
+        .collect()
+}
+
+/// Return the k nearest neighbors of each barcode, excluding itself.
+pub(crate) fn nearest_neighbors(proj: ArrayView2<'_, f64>, k: usize) -> Vec<Vec<usize>> {
+    exclude_self(knn::<u32>(&proj, k + 1).view(), k)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn test_exclude_self() {
+        let neighbors = array![[0, 1, 2], [0, 1, u32::MAX], [2, 1, 0]];
+        assert_eq!(
+            exclude_self(neighbors.view(), 1),
+            vec![vec![1], vec![0], vec![1]]
+        );
+    }
+}
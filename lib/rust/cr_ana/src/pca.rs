@@ -138,7 +138,7 @@ fn select_features(
 /// If the feature type is not Antibody
 /// runs in spatial, use the standard cellranger normalization
 /// For feature type antibody in a spatial run, normalizes just taking logarithms
-fn get_normalized_matrix<D, M>(
+pub(crate) fn get_normalized_matrix<D, M>(
     feature_type: FeatureType,
     is_spatial: &bool,
     filtered_matrix: AdaptiveMat<u32, D, M>,
@@ -188,6 +188,8 @@ pub(crate) fn run_pca<'a>(
         num_pcs
     };
     let (u, s, _) = BkSvd::new().run_pca(&norm_matrix, num_pcs)?;
+    let target = size_factor_target(matrix);
+    let (feature_mean, feature_scale) = feature_mean_scale(matrix, target);
     let full_norm_matrix = get_normalized_matrix(feature_type, &is_spatial, matrix.view());
     let mut components = Array2::from_elem((num_pcs, matrix.rows()), 0.0);
     for (j, &i) in selected_features.iter().enumerate() {
@@ -206,9 +208,192 @@ pub(crate) fn run_pca<'a>(
         selected_feature_ids,
         transformed_pca_matrix,
         variance_explained,
+        feature_mean,
+        feature_scale,
+        Some(target),
     ))
 }
 
+/// Return the median UMI count of the barcodes, to which get_normalized_matrix scales each
+/// barcode.
+pub(crate) fn size_factor_target(matrix: &AdaptiveMat) -> f64 {
+    median_mut(&mut matrix.sum_axis::<f64>(Axis(0)).mapv(n64)).map_or(f64::NAN, N64::raw)
+}
+
+/// Call f with the barcode, the feature and the value of each nonzero entry of the matrix,
+/// scaled to the UMI count target and log transformed as by get_normalized_matrix before its
+/// centering and scaling.
+fn for_each_log_normalized(
+    matrix: &AdaptiveMat,
+    target: f64,
+    mut f: impl FnMut(usize, usize, f64),
+) {
+    let counts_per_bc = matrix.sum_axis::<f64>(Axis(0));
+    let csc = matrix.to_csmat().to_csc();
+    for (barcode, (column, &count)) in csc.outer_iterator().zip(&counts_per_bc).enumerate() {
+        for (feature, &x) in column.iter() {
+            f(
+                barcode,
+                feature,
+                (1.0 + f64::from(x) * target / count).log2(),
+            );
+        }
+    }
+}
+
+/// Return the mean and the standard deviation of each feature of the normalized matrix,
+/// by which get_normalized_matrix centers and scales the features.
+pub(crate) fn feature_mean_scale(matrix: &AdaptiveMat, target: f64) -> (Array1<f64>, Array1<f64>) {
+    let mut sum = Array1::<f64>::zeros(matrix.rows());
+    let mut sum_sq = Array1::<f64>::zeros(matrix.rows());
+    for_each_log_normalized(matrix, target, |_, feature, x| {
+        sum[feature] += x;
+        sum_sq[feature] += x * x;
+    });
+    let num_bcs = matrix.cols().max(1) as f64;
+    let mean = sum / num_bcs;
+    let scale = (sum_sq / num_bcs - &mean * &mean).mapv(|v| v.max(0.0).sqrt());
+    (mean, scale)
+}
+
+/// Project the matrix onto the principal components of another analysis. The matrix is
+/// normalized as by run_pca, except that its barcodes are scaled to the UMI count target and its
+/// features are centered and scaled by the mean and scale of the features of the other analysis
+/// rather than of this matrix.
+/// The components, mean and scale have an entry for each row of the matrix.
+pub(crate) fn project_pca(
+    matrix: &AdaptiveMat,
+    components: &Array2<f64>,
+    feature_mean: &Array1<f64>,
+    feature_scale: &Array1<f64>,
+    size_factor_target: f64,
+) -> Array2<f64> {
+    assert_eq!(components.ncols(), matrix.rows());
+    assert_eq!(feature_mean.len(), matrix.rows());
+    assert_eq!(feature_scale.len(), matrix.rows());
+    let weight = feature_scale.mapv(|sd| if sd > 0.0 { 1.0 / sd } else { 0.0 });
+    let scaled = components * &weight;
+    let offset = scaled.dot(feature_mean);
+    let mut projected = Array2::zeros((matrix.cols(), components.nrows()));
+    for mut row in projected.rows_mut() {
+        row -= &offset;
+    }
+    for_each_log_normalized(matrix, size_factor_target, |barcode, feature, x| {
+        projected
+            .row_mut(barcode)
+            .scaled_add(x, &scaled.column(feature));
+    });
+    projected
+}
+
+/// The largest number of sweeps of the Jacobi eigenvalue algorithm.
+const MAX_JACOBI_SWEEPS: usize = 100;
+
+/// Return the eigenvalues and the eigenvectors, as columns, of a symmetric matrix by the cyclic
+/// Jacobi eigenvalue algorithm. Used for matrices with few rows, such as the covariance of the
+/// features of an antibody panel.
+pub(crate) fn symmetric_eigen(matrix: &Array2<f64>) -> (Array1<f64>, Array2<f64>) {
+    assert!(matrix.is_square());
+    let n = matrix.nrows();
+    let mut a = matrix.clone();
+    let mut v = Array2::eye(n);
+    let tolerance = 1e-24 * a.iter().map(|x| x * x).sum::<f64>();
+    for _ in 0..MAX_JACOBI_SWEEPS {
+        let off_diagonal: f64 = a
+            .indexed_iter()
+            .filter(|((i, j), _)| i != j)
+            .map(|(_, x)| x * x)
+            .sum();
+        if off_diagonal <= tolerance {
+            break;
+        }
+        for p in 0..n {
+            for q in p + 1..n {
+                let apq = a[[p, q]];
+                if apq == 0.0 {
+                    continue;
+                }
+                // Rotate rows and columns p and q to zero a[p, q].
+                let theta = (a[[q, q]] - a[[p, p]]) / (2.0 * apq);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let c = 1.0 / (t * t + 1.0).sqrt();
+                let s = t * c;
+                for k in 0..n {
+                    let (akp, akq) = (a[[k, p]], a[[k, q]]);
+                    a[[k, p]] = c * akp - s * akq;
+                    a[[k, q]] = s * akp + c * akq;
+                }
+                for k in 0..n {
+                    let (apk, aqk) = (a[[p, k]], a[[q, k]]);
+                    a[[p, k]] = c * apk - s * aqk;
+                    a[[q, k]] = s * apk + c * aqk;
+                }
+                for k in 0..n {
+                    let (vkp, vkq) = (v[[k, p]], v[[k, q]]);
+                    v[[k, p]] = c * vkp - s * vkq;
+                    v[[k, q]] = s * vkp + c * vkq;
+                }
+            }
+        }
+    }
+    (a.diag().to_owned(), v)
+}
+
+/// Run PCA on a dense matrix of already normalized values, such as denoised antibody
+/// expression, with a row per feature and a column per barcode. Every feature is selected, and
+/// the dispersion of a feature is its variance.
+pub(crate) fn run_dense_pca<'a>(
+    values: &Array2<f64>,
+    feature_ids: &'a [String],
+    feature_type: FeatureType,
+    num_pcs: usize,
+) -> PcaResult<'a> {
+    let (num_features, num_bcs) = values.dim();
+    assert_eq!(feature_ids.len(), num_features);
+    let num_pcs = if num_features.min(num_bcs) < num_pcs {
+        warn!(
+            "matrix shape {:?} < requested PCs {}, reducing to {}",
+            values.dim(),
+            num_pcs,
+            num_features.min(num_bcs)
+        );
+        num_features.min(num_bcs)
+    } else {
+        num_pcs
+    };
+    let mean = values
+        .mean_axis(Axis(1))
+        .unwrap_or_else(|| Array1::zeros(num_features));
+    let centered = values - &mean.view().insert_axis(Axis(1));
+    let covariance = centered.dot(&centered.t()) / (num_bcs.max(2) - 1) as f64;
+    let dispersion = covariance.diag().to_owned();
+
+    let (eigenvalues, eigenvectors) = symmetric_eigen(&covariance);
+    let mut order: Vec<usize> = (0..num_features).collect();
+    order.sort_by(|&i, &j| eigenvalues[j].total_cmp(&eigenvalues[i]));
+    order.truncate(num_pcs);
+    let components = eigenvectors.select(Axis(1), &order).reversed_axes();
+    let transformed_pca_matrix = centered.t().dot(&components.t());
+    let feature_scale = Array1::ones(num_features);
+    let total_variance = dispersion.sum().max(f64::EPSILON);
+    let variance_explained = order
+        .iter()
+        .map(|&i| eigenvalues[i].max(0.0) / total_variance)
+        .collect();
+
+    PcaResult::new(
+        components,
+        dispersion,
+        feature_type,
+        feature_ids.iter().map(String::as_str).collect(),
+        transformed_pca_matrix,
+        variance_explained,
+        mean,
+        feature_scale,
+        None,
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -311,4 +496,74 @@ mod tests {
 
         assert!(expected_out_dense.abs_diff_eq(&norm_mat.to_dense(), 1e-6));
     }
+
+    #[test]
+    fn test_project_pca() {
+        // The same matrix and normalized matrix as test_get_normalized_matrix_gex_spatial.
+        let dense: Array2<u32> = array![
+            [136, 936, 0, 0, 264],
+            [134, 682, 417, 8, 391],
+            [0, 133, 780, 0, 0],
+            [396, 76, 96, 198, 0],
+        ];
+        let mtx = AdaptiveMatOwned::<u32>::from_dense(dense.view());
+        let norm_mat = get_normalized_matrix(FeatureType::Gene, &false, mtx.view()).to_dense();
+
+        // Projecting a matrix onto the identity with its own mean and scale normalizes it.
+        let target = size_factor_target(&mtx);
+        let (mean, scale) = feature_mean_scale(&mtx, target);
+        let projected = project_pca(&mtx, &Array2::eye(4), &mean, &scale, target);
+        assert!(projected.abs_diff_eq(&norm_mat.t(), 1e-6));
+
+        // The mean and scale of the reference rather than of the matrix are used.
+        let projected = project_pca(
+            &mtx,
+            &Array2::eye(4),
+            &(&mean + 1.0),
+            &(&scale * 2.0),
+            target,
+        );
+        let expected = (&norm_mat.t() - &(1.0 / &scale)) / 2.0;
+        assert!(projected.abs_diff_eq(&expected, 1e-6));
+
+        // So is the UMI count target of the reference.
+        let projected = project_pca(&mtx, &Array2::eye(4), &mean, &scale, 2.0 * target);
+        let mut expected = Array2::zeros(projected.dim());
+        for_each_log_normalized(&mtx, 2.0 * target, |barcode, feature, x| {
+            expected[[barcode, feature]] = x;
+        });
+        let expected = (&expected - &mean) / &scale;
+        assert!(projected.abs_diff_eq(&expected, 1e-6));
+    }
+
+    #[test]
+    fn test_symmetric_eigen() {
+        let matrix = array![[4.0, 1.0, 2.0], [1.0, 3.0, 0.5], [2.0, 0.5, 5.0]];
+        let (eigenvalues, eigenvectors) = symmetric_eigen(&matrix);
+        let reconstructed = eigenvectors
+            .dot(&Array2::from_diag(&eigenvalues))
+            .dot(&eigenvectors.t());
+        assert!(matrix.abs_diff_eq(&reconstructed, 1e-9));
+        assert!(Array2::<f64>::eye(3).abs_diff_eq(&eigenvectors.t().dot(&eigenvectors), 1e-9));
+    }
+
+    #[test]
+    fn test_run_dense_pca() {
+        // The two features vary together, with a little independent noise in the second.
+        let values = array![[-2.0, -1.0, 0.0, 1.0, 2.0], [-2.0, -1.1, 0.0, 1.1, 2.0]];
+        let feature_ids = vec!["a".to_string(), "b".to_string()];
+        let result = run_dense_pca(
+            &values,
+            &feature_ids,
+            FeatureType::Barcode(FeatureBarcodeType::Antibody),
+            5,
+        );
+        assert_eq!(result.components.dim(), (2, 2));
+        assert_eq!(result.transformed_pca_matrix.dim(), (5, 2));
+        let first = result.components.row(0);
+        assert!((first[0].abs() - first[1].abs()).abs() < 0.05);
+        assert!(first[0] * first[1] > 0.0);
+        assert!(result.variance_explained[0] > 0.99);
+        assert!((result.variance_explained.sum() - 1.0).abs() < 1e-9);
+    }
 }
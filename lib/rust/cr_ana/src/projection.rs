@@ -0,0 +1,157 @@
+//! Projection of a new sample onto the principal components of a reference analysis
+//! Cluster labels and embedding coordinates are transferred from the nearest reference barcodes.
+
+use ndarray::{concatenate, Array2, ArrayView1, Axis};
+use rayon::prelude::*;
+use scan_rs::nn::knn;
+use std::collections::HashMap;
+
+/// A reference barcode near a query barcode, and its weight.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Neighbor {
+    pub index: usize,
+    pub weight: f64,
+}
+
+fn squared_distance(a: ArrayView1<'_, f64>, b: ArrayView1<'_, f64>) -> f64 {
+    a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum()
+}
+
+/// Return the k nearest reference barcodes of each query barcode, weighted by a Gaussian
+/// kernel whose bandwidth is the distance to the kth neighbor.
+/// The reference barcodes are searched among the nearest neighbors of the query barcode in the
+/// reference and query barcodes together. A query barcode with fewer than k reference barcodes
+/// among these neighbors, because other query barcodes are nearer, is compared with every
+/// reference barcode.
+pub(crate) fn nearest_neighbors(
+    reference: &Array2<f64>,
+    query: &Array2<f64>,
+    k: usize,
+) -> Vec<Vec<Neighbor>> {
+    assert_eq!(reference.ncols(), query.ncols());
+    let num_reference = reference.nrows();
+    let k = k.clamp(1, num_reference);
+    let combined = concatenate![Axis(0), reference.view(), query.view()];
+    // The neighbors include the query barcode itself.
+    let num_search = (2 * k + 1).min(combined.nrows());
+    let candidates: Vec<Vec<usize>> = knn::<u32>(&combined.view(), num_search)
+        .rows()
+        .into_iter()
+        .skip(num_reference)
+        .map(|row| {
+            row.iter()
+                .map(|&j| j as usize)
+                .filter(|&j| j < num_reference)
+                .collect()
+        })
+        .collect();
+    candidates
+        .into_par_iter()
+        .enumerate()
+        .map(|(i, candidates)| {
+            let q = query.row(i);
+            let mut distances: Vec<(usize, f64)> = if candidates.len() >= k {
+                candidates
+                    .into_iter()
+                    .map(|j| (j, squared_distance(q, reference.row(j))))
+                    .collect()
+            } else {
+                reference
+                    .outer_iter()
+                    .enumerate()
+                    .map(|(j, r)| (j, squared_distance(q, r)))
+                    .collect()
+            };
+            distances.select_nth_unstable_by(k - 1, |a, b| a.1.total_cmp(&b.1));
+            distances.truncate(k);
+            distances.sort_by(|a, b| a.1.total_cmp(&b.1));
+            let bandwidth = distances[k - 1].1;
+            distances
+                .into_iter()
+                .map(|(index, d)| Neighbor {
+                    index,
+                    weight: if bandwidth > 0.0 {
+                        (-d / bandwidth).exp()
+                    } else {
+                        1.0
+                    },
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Return the label with the largest total weight among the neighbors of each query barcode,
+/// and the fraction of the weight that supports it as the mapping confidence.
+pub(crate) fn transfer_labels(neighbors: &[Vec<Neighbor>], labels: &[i64]) -> Vec<(i64, f64)> {
+    neighbors
+        .iter()
+        .map(|neighbors| {
+            let mut votes: HashMap<i64, f64> = HashMap::new();
+            for n in neighbors {
+                *votes.entry(labels[n.index]).or_default() += n.weight;
+            }
+            let total: f64 = votes.values().sum();
+            let (label, weight) = votes
+                .into_iter()
+                .max_by(|a, b| a.1.total_cmp(&b.1).then(b.0.cmp(&a.0)))
+                .unwrap();
+            (label, weight / total)
+        })
+        .collect()
+}
+
+/// Place each query barcode at the weighted mean of the embedding of its neighbors.
+pub(crate) fn transfer_embedding(
+    neighbors: &[Vec<Neighbor>],
+    embedding: &Array2<f64>,
+) -> Array2<f64> {
+    let mut result = Array2::zeros((neighbors.len(), embedding.ncols()));
+    for (mut row, neighbors) in result.outer_iter_mut().zip(neighbors) {
+        let total: f64 = neighbors.iter().map(|n| n.weight).sum();
+        for n in neighbors {
+            row.scaled_add(n.weight / total, &embedding.row(n.index));
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn test_transfer() {
+        let reference = array![[0.0, 0.0], [0.1, 0.0], [0.0, 0.1], [5.0, 5.0], [5.1, 5.0]];
+        let labels = [1, 1, 1, 2, 2];
+        let embedding = array![[-1.0], [-1.0], [-1.0], [1.0], [1.0]];
+        let query = array![[0.05, 0.05], [5.0, 4.9], [2.7, 2.6]];
+
+        let neighbors = nearest_neighbors(&reference, &query, 3);
+        assert!(neighbors.iter().all(|n| n.len() == 3));
+        assert_eq!(neighbors[1][0].index, 3);
+
+        let transferred = transfer_labels(&neighbors, &labels);
+        assert_eq!(transferred[0], (1, 1.0));
+        assert_eq!(transferred[1].0, 2);
+        assert!(transferred[1].1 > 0.5 && transferred[1].1 < 1.0);
+        assert!(transferred[2].1 < transferred[1].1);
+
+        let placed = transfer_embedding(&neighbors, &embedding);
+        assert!((placed[[0, 0]] + 1.0).abs() < 1e-12);
+        assert!(placed[[1, 0]] > 0.0);
+    }
+
+    #[test]
+    fn test_nearest_neighbors_beyond_query() {
+        // Each query barcode is nearer to the other query barcodes than to any reference barcode.
+        let reference = array![[0.0], [1.0], [2.0], [3.0]];
+        let query = array![[10.0], [10.1], [10.2], [10.3], [10.4]];
+        let neighbors = nearest_neighbors(&reference, &query, 2);
+        for neighbors in neighbors {
+            let indices: Vec<usize> = neighbors.iter().map(|n| n.index).collect();
+            assert_eq!(indices, [3, 2]);
+        }
+    }
+}
@@ -0,0 +1,310 @@
+//! Pseudobulk differential expression between two conditions
+//! Counts are summed per sample, and each gene is tested with a negative binomial GLM whose
+//! dispersion is estimated from the replicate samples of each condition.
+
+use ndarray::{Array1, Array2};
+use num_traits::Num;
+use sprs::{CsMatI, SpIndex};
+use statrs::function::erf::erfc;
+use std::f64::consts::{LN_2, SQRT_2};
+
+/// The weight of the dispersion trend, in degrees of freedom of the per-gene estimates.
+const PRIOR_DISPERSION_DF: f64 = 10.0;
+const MIN_DISPERSION: f64 = 1e-8;
+const MAX_DISPERSION: f64 = 10.0;
+/// The mean assumed for a condition without any counts, in reads per unit size factor.
+const ZERO_COUNT_MEAN: f64 = 0.5;
+const MAX_NEWTON_ITER: usize = 50;
+
+pub(crate) struct PseudobulkDeResult {
+    /// The mean normalized count of each gene across samples.
+    pub base_mean: Vec<f64>,
+    pub log2_fold_change: Vec<f64>,
+    pub p_value: Vec<f64>,
+    pub adjusted_p_value: Vec<f64>,
+}
+
+/// Sum the counts of each group of barcodes of a feature-barcode matrix,
+/// returning a feature-by-group matrix. Barcodes without a group are ignored.
+pub(crate) fn sum_counts<N, I>(
+    matrix: &CsMatI<N, I>,
+    groups: &[Option<usize>],
+    num_groups: usize,
+) -> Array2<f64>
+where
+    N: Num + Copy,
+    f64: From<N>,
+    I: SpIndex,
+{
+    assert!(matrix.is_csc());
+    let mut sums = Array2::zeros((matrix.rows(), num_groups));
+    for (barcode, group) in matrix.outer_iterator().zip(groups) {
+        if let &Some(group) = group {
+            for (feature, &count) in barcode.iter() {
+                sums[[feature, group]] += f64::from(count);
+            }
+        }
+    }
+    sums
+}
+
+/// Return the size factor of each sample using the median ratio to the geometric mean of
+/// the genes detected in every sample, or the total count when no gene is.
+fn size_factors(counts: &Array2<f64>) -> Array1<f64> {
+    let num_samples = counts.ncols();
+    let mut log_ratios = vec![Vec::new(); num_samples];
+    for row in counts.rows() {
+        if row.iter().all(|&c| c > 0.0) {
+            let log_mean = row.iter().map(|c| c.ln()).sum::<f64>() / num_samples as f64;
+            for (ratios, &c) in log_ratios.iter_mut().zip(row) {
+                ratios.push(c.ln() - log_mean);
+            }
+        }
+    }
+    if log_ratios[0].is_empty() {
+        let totals = counts.sum_axis(ndarray::Axis(0));
+        let mean = totals.mean().unwrap_or(1.0);
+        return totals.mapv(|t| if t > 0.0 { t / mean } else { 1.0 });
+    }
+    log_ratios
+        .into_iter()
+        .map(|mut ratios| {
+            ratios.sort_by(f64::total_cmp);
+            let n = ratios.len();
+            let median = if n % 2 == 0 {
+                (ratios[n / 2 - 1] + ratios[n / 2]) / 2.0
+            } else {
+                ratios[n / 2]
+            };
+            median.exp()
+        })
+        .collect()
+}
+
+/// Return the moment estimate of the dispersion of a gene from the replicates of each
+/// condition, where Var(y / s) = mu / s + dispersion * mu^2.
+fn raw_dispersion(counts: &[f64], size_factors: &[f64], conditions: &[&[usize]]) -> f64 {
+    let (mut excess, mut scale) = (0.0, 0.0);
+    for samples in conditions {
+        let n = samples.len() as f64;
+        let mean = samples
+            .iter()
+            .map(|&j| counts[j] / size_factors[j])
+            .sum::<f64>()
+            / n;
+        for &j in *samples {
+            let residual = counts[j] / size_factors[j] - mean;
+            excess += residual * residual * n / (n - 1.0) - mean / size_factors[j];
+            scale += mean * mean;
+        }
+    }
+    if scale > 0.0 {
+        (excess / scale).clamp(MIN_DISPERSION, MAX_DISPERSION)
+    } else {
+        MIN_DISPERSION
+    }
+}
+
+/// Fit the trend dispersion = a0 + a1 / mean by least squares, with nonnegative coefficients.
+fn fit_dispersion_trend(base_mean: &[f64], dispersion: &[f64]) -> (f64, f64) {
+    let points: Vec<(f64, f64)> = base_mean
+        .iter()
+        .zip(dispersion)
+        .filter(|(&m, _)| m > 0.0)
+        .map(|(&m, &d)| (1.0 / m, d))
+        .collect();
+    if points.is_empty() {
+        return (MIN_DISPERSION, 0.0);
+    }
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let var_x = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum::<f64>();
+    let cov = points
+        .iter()
+        .map(|p| (p.0 - mean_x) * (p.1 - mean_y))
+        .sum::<f64>();
+    let a1 = if var_x > 0.0 {
+        (cov / var_x).max(0.0)
+    } else {
+        0.0
+    };
+    let a0 = (mean_y - a1 * mean_x).max(MIN_DISPERSION);
+    (a0, a1)
+}
+
+/// Return the maximum likelihood mean of a condition and its Fisher information on the log
+/// scale, by Newton's method on the log mean.
+fn fit_condition_mean(
+    counts: &[f64],
+    size_factors: &[f64],
+    samples: &[usize],
+    dispersion: f64,
+) -> (f64, f64) {
+    let total: f64 = samples.iter().map(|&j| counts[j]).sum();
+    let total_size: f64 = samples.iter().map(|&j| size_factors[j]).sum();
+    let mut log_mean = (total.max(ZERO_COUNT_MEAN) / total_size).ln();
+    let information = |log_mean: f64| {
+        samples
+            .iter()
+            .map(|&j| {
+                let mu = size_factors[j] * log_mean.exp();
+                mu / (1.0 + dispersion * mu)
+            })
+            .sum::<f64>()
+    };
+    if total > 0.0 {
+        for _ in 0..MAX_NEWTON_ITER {
+            let score: f64 = samples
+                .iter()
+                .map(|&j| {
+                    let mu = size_factors[j] * log_mean.exp();
+                    (counts[j] - mu) / (1.0 + dispersion * mu)
+                })
+                .sum();
+            let step = score / information(log_mean);
+            log_mean += step;
+            if step.abs() < 1e-10 {
+                break;
+            }
+        }
+    }
+    (log_mean, information(log_mean))
+}
+
+/// Adjust the p-values for multiple testing using the Benjamini-Hochberg procedure.
+fn adjust_p_values(p_values: &[f64]) -> Vec<f64> {
+    let mut order: Vec<usize> = (0..p_values.len()).collect();
+    order.sort_by(|&a, &b| p_values[b].total_cmp(&p_values[a]));
+    let n = p_values.len() as f64;
+    let mut adjusted = vec![1.0; p_values.len()];
+    let mut running_min: f64 = 1.0;
+    for (rank, &i) in order.iter().enumerate() {
+        let rank = n - rank as f64;
+        running_min = running_min.min(p_values[i] * n / rank);
+        adjusted[i] = running_min;
+    }
+    adjusted
+}
+
+/// Test each gene of a gene-by-sample matrix of pseudobulk counts for differential expression
+/// between the reference and test samples, using a Wald test of the condition coefficient of
+/// a negative binomial GLM. Both conditions require at least two replicate samples.
+pub(crate) fn pseudobulk_diff_exp(
+    counts: &Array2<f64>,
+    reference: &[usize],
+    test: &[usize],
+) -> PseudobulkDeResult {
+    assert!(reference.len() >= 2 && test.len() >= 2);
+    let size_factors = size_factors(counts).to_vec();
+    let samples: Vec<usize> = reference.iter().chain(test).copied().collect();
+    let rows: Vec<Vec<f64>> = counts.rows().into_iter().map(|r| r.to_vec()).collect();
+
+    let base_mean: Vec<f64> = rows
+        .iter()
+        .map(|row| {
+            samples
+                .iter()
+                .map(|&j| row[j] / size_factors[j])
+                .sum::<f64>()
+                / samples.len() as f64
+        })
+        .collect();
+    let raw: Vec<f64> = rows
+        .iter()
+        .map(|row| raw_dispersion(row, &size_factors, &[reference, test]))
+        .collect();
+    let (a0, a1) = fit_dispersion_trend(&base_mean, &raw);
+    let df = (samples.len() - 2) as f64;
+
+    let mut log2_fold_change = Vec::with_capacity(rows.len());
+    let mut p_value = Vec::with_capacity(rows.len());
+    for ((row, &mean), &raw) in rows.iter().zip(&base_mean).zip(&raw) {
+        if mean == 0.0 {
+            log2_fold_change.push(0.0);
+            p_value.push(1.0);
+            continue;
+        }
+        let trend = a0 + a1 / mean;
+        let dispersion = (df * raw + PRIOR_DISPERSION_DF * trend) / (df + PRIOR_DISPERSION_DF);
+        let (log_ref, info_ref) = fit_condition_mean(row, &size_factors, reference, dispersion);
+        let (log_test, info_test) = fit_condition_mean(row, &size_factors, test, dispersion);
+        let beta = log_test - log_ref;
+        let std_err = (1.0 / info_ref + 1.0 / info_test).sqrt();
+        log2_fold_change.push(beta / LN_2);
+        p_value.push(erfc((beta / std_err).abs() / SQRT_2));
+    }
+
+    // Genes without counts are not tested, and are excluded from the multiple testing correction.
+    let tested: Vec<usize> = (0..rows.len()).filter(|&i| base_mean[i] > 0.0).collect();
+    let tested_p_values: Vec<f64> = tested.iter().map(|&i| p_value[i]).collect();
+    let mut adjusted_p_value = vec![1.0; rows.len()];
+    for (&i, adjusted) in tested.iter().zip(adjust_p_values(&tested_p_values)) {
+        adjusted_p_value[i] = adjusted;
+    }
+    PseudobulkDeResult {
+        base_mean,
+        log2_fold_change,
+        p_value,
+        adjusted_p_value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+    use sprs::TriMat;
+
+    #[test]
+    fn test_sum_counts() {
+        let mut tri = TriMat::new((2, 4));
+        tri.add_triplet(0, 0, 1u32);
+        tri.add_triplet(0, 1, 2);
+        tri.add_triplet(1, 2, 3);
+        tri.add_triplet(1, 3, 4);
+        let matrix: CsMatI<u32, usize> = tri.to_csc();
+        let sums = sum_counts(&matrix, &[Some(0), Some(1), Some(1), None], 2);
+        assert_eq!(sums, array![[1.0, 2.0], [0.0, 3.0]]);
+    }
+
+    #[test]
+    fn test_adjust_p_values() {
+        let adjusted = adjust_p_values(&[0.01, 0.04, 0.03, 0.5]);
+        let expected = [0.04, 0.04 * 4.0 / 3.0, 0.04 * 4.0 / 3.0, 0.5];
+        for (a, e) in adjusted.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_pseudobulk_diff_exp() {
+        // Three replicates per condition, with the second sample sequenced twice as deeply.
+        // Gene 0 is four times higher in the test condition, and genes 1-3 are unchanged.
+        let counts = array![
+            [100.0, 220.0, 95.0, 410.0, 820.0, 390.0],
+            [500.0, 1010.0, 490.0, 505.0, 995.0, 510.0],
+            [50.0, 95.0, 55.0, 48.0, 104.0, 52.0],
+            [300.0, 590.0, 310.0, 305.0, 600.0, 295.0],
+        ];
+        let result = pseudobulk_diff_exp(&counts, &[0, 1, 2], &[3, 4, 5]);
+        assert!((result.log2_fold_change[0] - 2.0).abs() < 0.2);
+        assert!(result.adjusted_p_value[0] < 1e-6);
+        for gene in 1..4 {
+            assert!(result.log2_fold_change[gene].abs() < 0.2);
+            assert!(result.adjusted_p_value[gene] > 0.05);
+        }
+
+        // A gene without counts does not change the adjusted p-values of the tested genes.
+        let mut with_zero = counts.clone();
+        with_zero.push_row(ndarray::aview1(&[0.0; 6])).unwrap();
+        let result_with_zero = pseudobulk_diff_exp(&with_zero, &[0, 1, 2], &[3, 4, 5]);
+        assert_eq!(result_with_zero.adjusted_p_value[4], 1.0);
+        for gene in 0..4 {
+            assert!(
+                (result_with_zero.adjusted_p_value[gene] - result.adjusted_p_value[gene]).abs()
+                    < 1e-12
+            );
+        }
+    }
+}
@@ -0,0 +1,268 @@
+//! Martian stage RUN_ANTIBODY_NORMALIZATION
+//! Normalize antibody capture by the ambient background of the empty droplets of the raw matrix
+//! and remove the technical noise of each barcode.
+
+use crate::dsb::{dsb, Background};
+use crate::io::h5;
+use crate::types::H5File;
+use anyhow::{bail, Context, Result};
+use cr_types::reference::feature_reference::FeatureType;
+use cr_types::FeatureBarcodeType;
+use cr_websummary::{RawChartWithHelp, TitleWithHelp};
+use hdf5_io::matrix::read_adaptive_csr_matrix;
+use martian::prelude::*;
+use martian::MartianVoid;
+use martian_derive::{make_mro, MartianStruct};
+use martian_filetypes::json_file::JsonFile;
+use martian_filetypes::FileTypeWrite;
+use ndarray::Array2;
+use num_traits::Num;
+use scan_types::matrix::AdaptiveFeatureBarcodeMatrix as FBM;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sprs::{CsMatI, SpIndex};
+use std::collections::{HashMap, HashSet};
+
+const ANTIBODY: FeatureType = FeatureType::Barcode(FeatureBarcodeType::Antibody);
+/// The fewest antibody UMIs of a background barcode, below which droplets carry too little
+/// signal to estimate the background.
+const MIN_BACKGROUND_UMIS: f64 = 10.0;
+/// Background barcodes have fewer antibody UMIs than this quantile of the cell-associated
+/// barcodes, which excludes cells that were not called.
+const BACKGROUND_MAX_CELL_QUANTILE: f64 = 0.05;
+/// The fewest background barcodes required to normalize.
+const MIN_BACKGROUND_BARCODES: usize = 100;
+/// The quantiles of the whiskers of the web summary box plot.
+const WHISKER_QUANTILES: [f64; 2] = [0.01, 0.99];
+
+#[derive(Clone, Debug, Deserialize, MartianStruct)]
+pub struct AntibodyNormalizationStageInputs {
+    matrix_h5: H5File,
+    /// The cell-associated barcodes, which are excluded from the background.
+    filtered_matrix_h5: H5File,
+    /// The empty droplets of the raw matrix are the background of the normalization.
+    raw_matrix_h5: Option<H5File>,
+    /// No normalization is performed unless set.
+    antibody_normalization: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, MartianStruct)]
+pub struct AntibodyNormalizationStageOutputs {
+    antibody_normalization_h5: Option<H5File>,
+    antibody_normalization_chart: Option<JsonFile<RawChartWithHelp>>,
+}
+
+pub struct AntibodyNormalizationStage;
+
+/// Return the value at the quantile of sorted values.
+fn quantile(sorted: &[f64], q: f64) -> f64 {
+    sorted[((q * (sorted.len() - 1) as f64).round() as usize).min(sorted.len() - 1)]
+}
+
+/// Return the total count of each barcode of a matrix with a column per barcode.
+fn barcode_totals<N, I>(matrix: &CsMatI<N, I>) -> Vec<f64>
+where
+    N: Num + Copy,
+    f64: From<N>,
+    I: SpIndex,
+{
+    matrix
+        .outer_iterator()
+        .map(|barcode| barcode.iter().map(|(_, &c)| f64::from(c)).sum())
+        .collect()
+}
+
+/// Return a box plot of the denoised values of each antibody for the web summary.
+fn box_plot_chart(feature_names: &[String], denoised: &Array2<f64>) -> RawChartWithHelp {
+    let quantiles: Vec<[f64; 5]> = denoised
+        .rows()
+        .into_iter()
+        .map(|row| {
+            let mut sorted = row.to_vec();
+            sorted.sort_by(f64::total_cmp);
+            [WHISKER_QUANTILES[0], 0.25, 0.5, 0.75, WHISKER_QUANTILES[1]]
+                .map(|q| quantile(&sorted, q))
+        })
+        .collect();
+    let column = |i: usize| quantiles.iter().map(|q| q[i]).collect::<Vec<_>>();
+    RawChartWithHelp {
+        plot: json!({
+            "data": [{
+                "x": feature_names,
+                "lowerfence": column(0),
+                "q1": column(1),
+                "median": column(2),
+                "q3": column(3),
+                "upperfence": column(4),
+                "type": "box",
+            }],
+            "layout": {
+                "xaxis": {"title": "Antibody"},
+                "yaxis": {"title": "Denoised expression"},
+            },
+        }),
+        help: TitleWithHelp {
+            title: "Denoised Antibody Expression".to_string(),
+            help: "Distribution of the denoised expression of each antibody across \
+                   cell-associated barcodes. The log counts of each antibody are standardized \
+                   by its ambient background in empty droplets, and a technical component of \
+                   each barcode, estimated from its background level and isotype controls, is \
+                   removed. A value of zero is the mean level of the antibody in empty \
+                   droplets. Whiskers extend from the 1st to the 99th percentile."
+                .to_string(),
+        },
+    }
+}
+
+#[make_mro(stage_name = RUN_ANTIBODY_NORMALIZATION, volatile = strict)]
+impl MartianStage for AntibodyNormalizationStage {
+    type StageInputs = AntibodyNormalizationStageInputs;
+    type StageOutputs = AntibodyNormalizationStageOutputs;
+    type ChunkInputs = MartianVoid;
+    type ChunkOutputs = MartianVoid;
+
+    fn split(
+        &self,
+        args: Self::StageInputs,
+        _rover: MartianRover,
+    ) -> Result<StageDef<Self::ChunkInputs>> {
+        let raw_mem_gib = match &args.raw_matrix_h5 {
+            Some(raw_matrix_h5) if args.antibody_normalization == Some(true) => {
+                h5::estimate_mem_gib_from_nnz(raw_matrix_h5)?
+            }
+            _ => return Ok(StageDef::with_join_resource(Resource::with_mem_gb(1))),
+        };
+        let mem_gib =
+            (2.0 + h5::estimate_mem_gib_from_nnz(&args.matrix_h5)? + raw_mem_gib).ceil() as isize;
+        Ok(StageDef::with_join_resource(
+            Resource::with_mem_gb(mem_gib).threads(4),
+        ))
+    }
+
+    fn main(
+        &self,
+        _args: Self::StageInputs,
+        _chunk_args: Self::ChunkInputs,
+        _rover: MartianRover,
+    ) -> Result<Self::ChunkOutputs> {
+        unreachable!()
+    }
+
+    fn join(
+        &self,
+        args: Self::StageInputs,
+        _chunk_defs: Vec<Self::ChunkInputs>,
+        _chunk_outs: Vec<Self::ChunkOutputs>,
+        rover: MartianRover,
+    ) -> Result<Self::StageOutputs> {
+        let no_outputs = AntibodyNormalizationStageOutputs {
+            antibody_normalization_h5: None,
+            antibody_normalization_chart: None,
+        };
+        if args.antibody_normalization != Some(true) {
+            return Ok(no_outputs);
+        }
+        let Some(raw_matrix_h5) = &args.raw_matrix_h5 else {
+            bail!("antibody normalization requires the raw feature-barcode matrix");
+        };
+        if !h5::matrix_feature_types(&args.matrix_h5)?
+            .get(&ANTIBODY)
+            .is_some_and(|&count| count >= 2)
+        {
+            return Ok(no_outputs);
+        }
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(rover.get_threads())
+            .build_global()?;
+
+        let retained = Some(ANTIBODY.to_string());
+        let FBM {
+            barcodes,
+            feature_ids,
+            feature_names,
+            matrix,
+            ..
+        } = read_adaptive_csr_matrix(&args.matrix_h5, retained.as_deref(), Some(0))?.0;
+        if barcodes.is_empty() {
+            return Ok(no_outputs);
+        }
+        let cells = matrix.to_csmat().to_csc();
+        let cell_barcodes: HashSet<String> =
+            read_adaptive_csr_matrix(&args.filtered_matrix_h5, retained.as_deref(), Some(0))?
+                .0
+                .barcodes
+                .into_iter()
+                .collect();
+
+        let raw = read_adaptive_csr_matrix(raw_matrix_h5, retained.as_deref(), Some(0))?.0;
+        let raw_matrix = raw.matrix.to_csmat().to_csc();
+        let mut cell_totals = barcode_totals(&cells);
+        cell_totals.sort_by(f64::total_cmp);
+        let max_background_umis = quantile(&cell_totals, BACKGROUND_MAX_CELL_QUANTILE);
+        let background_barcodes: Vec<usize> = barcode_totals(&raw_matrix)
+            .into_iter()
+            .enumerate()
+            .filter(|&(i, total)| {
+                (MIN_BACKGROUND_UMIS..max_background_umis).contains(&total)
+                    && !cell_barcodes.contains(&raw.barcodes[i])
+            })
+            .map(|(i, _)| i)
+            .collect();
+        if background_barcodes.len() < MIN_BACKGROUND_BARCODES {
+            log::warn!(
+                "only {} background barcodes with {MIN_BACKGROUND_UMIS} to {max_background_umis} \
+                 antibody UMIs, skipping antibody normalization",
+                background_barcodes.len()
+            );
+            return Ok(no_outputs);
+        }
+        log::info!(
+            "estimating antibody background from {} barcodes",
+            background_barcodes.len()
+        );
+
+        let raw_features: HashMap<&str, usize> = raw
+            .feature_ids
+            .iter()
+            .enumerate()
+            .map(|(i, id)| (id.as_str(), i))
+            .collect();
+        let features: Vec<usize> = feature_ids
+            .iter()
+            .map(|id| {
+                raw_features
+                    .get(id.as_str())
+                    .copied()
+                    .with_context(|| format!("feature {id} is absent from the raw matrix"))
+            })
+            .collect::<Result<_>>()?;
+        let background = Background::new(&raw_matrix, &background_barcodes).select(&features);
+
+        let isotype_control_ids = h5::isotype_control_ids(&args.matrix_h5)?;
+        let isotype_controls: Vec<usize> = feature_ids
+            .iter()
+            .enumerate()
+            .filter(|(_, id)| isotype_control_ids.contains(*id))
+            .map(|(i, _)| i)
+            .collect();
+        log::info!("using {} isotype controls", isotype_controls.len());
+        let result = dsb(&cells, &background, &isotype_controls);
+
+        let antibody_normalization_h5: H5File = rover.make_path("antibody_normalization_h5");
+        h5::save_antibody_normalization(
+            &antibody_normalization_h5,
+            &barcodes,
+            &feature_ids,
+            &result,
+            &background,
+        )?;
+        let antibody_normalization_chart: JsonFile<_> =
+            rover.make_path("antibody_normalization_chart");
+        antibody_normalization_chart.write(&box_plot_chart(&feature_names, &result.denoised))?;
+
+        Ok(AntibodyNormalizationStageOutputs {
+            antibody_normalization_h5: Some(antibody_normalization_h5),
+            antibody_normalization_chart: Some(antibody_normalization_chart),
+        })
+    }
+}
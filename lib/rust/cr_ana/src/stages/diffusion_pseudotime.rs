@@ -0,0 +1,218 @@
+//! Martian stage RUN_DIFFUSION_PSEUDOTIME
+//! Compute the diffusion map of the k-nearest neighbor graph of the PCA projection,
+//! the diffusion pseudotime of each barcode from a root cluster or root barcode,
+//! and the PAGA connectivity of the graph-based clusters.
+
+use crate::diffusion::{
+    diffusion_map, diffusion_pseudotime, extremal_barcode, paga_connectivity, symmetrize,
+};
+use crate::io::{csv, h5};
+use crate::neighbors::{exclude_self, nearest_neighbors};
+use crate::types::{ClusteringType, H5File};
+use anyhow::{bail, ensure, Context, Result};
+use cr_types::reference::feature_reference::FeatureType;
+use cr_types::FeatureBarcodeType;
+use hdf5_io::matrix::read_adaptive_csr_matrix;
+use itertools::Itertools;
+use log::info;
+use martian::prelude::*;
+use martian::MartianVoid;
+use martian_derive::{make_mro, MartianStruct};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// The neighbors of the diffusion kernel, whose width is the median distance to these neighbors.
+/// These are the nearest of the neighbors of each barcode in the k-nearest neighbor graph of
+/// RUN_GRAPH_CLUSTERING, whose k grows with the logarithm of the number of barcodes to the
+/// hundreds, and would smooth the diffusion components over neighboring clusters.
+const NUM_NEIGHBORS: usize = 15;
+const NUM_COMPONENTS: usize = 15;
+const RANDOM_SEED: u64 = 0;
+const FEATURE_TYPES: [FeatureType; 2] = [
+    FeatureType::Gene,
+    FeatureType::Barcode(FeatureBarcodeType::Antibody),
+];
+
+#[derive(Clone, Debug, Deserialize, MartianStruct)]
+pub struct DiffusionPseudotimeStageInputs {
+    matrix_h5: H5File,
+    pca_h5: H5File,
+    /// The graph-based clustering, which defines the root cluster and the PAGA clusters.
+    clustering_h5: H5File,
+    /// The k-nearest neighbor graph of RUN_GRAPH_CLUSTERING. The neighbors are computed when
+    /// null or when the graph has too few neighbors.
+    neighbors_h5: Option<H5File>,
+    num_neighbors: Option<usize>,
+    input_pcs: Option<usize>,
+    num_components: Option<usize>,
+    /// The pseudotime is rooted at the barcode of this cluster that is farthest from the other
+    /// clusters. No pseudotime is computed when neither root is specified.
+    root_cluster: Option<i64>,
+    root_barcode: Option<String>,
+    random_seed: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, MartianStruct)]
+pub struct DiffusionPseudotimeStageOutputs {
+    pseudotime_h5: Option<H5File>,
+    pseudotime_csv: Option<PathBuf>,
+}
+
+pub struct DiffusionPseudotimeStage;
+
+#[make_mro(stage_name = RUN_DIFFUSION_PSEUDOTIME, volatile = strict)]
+impl MartianStage for DiffusionPseudotimeStage {
+    type StageInputs = DiffusionPseudotimeStageInputs;
+    type StageOutputs = DiffusionPseudotimeStageOutputs;
+    type ChunkInputs = MartianVoid;
+    type ChunkOutputs = MartianVoid;
+
+    fn split(
+        &self,
+        args: Self::StageInputs,
+        _rover: MartianRover,
+    ) -> Result<StageDef<Self::ChunkInputs>> {
+        let (_, num_bcs) = h5::matrix_shape(&args.matrix_h5)?;
+        let k = args.num_neighbors.unwrap_or(NUM_NEIGHBORS);
+        let num_components = args.num_components.unwrap_or(NUM_COMPONENTS);
+        // The neighbor lists, the kernel and transitions, and the blocks of subspace iteration.
+        let mem_gib = (2.0
+            + h5::estimate_mem_gib_from_nnz(&args.matrix_h5)?
+            + (8 * num_bcs as usize * (8 * k + 6 * num_components)) as f64 / 1e9)
+            .ceil() as isize;
+        Ok(StageDef::with_join_resource(
+            Resource::with_mem_gb(mem_gib).threads(4),
+        ))
+    }
+
+    fn main(
+        &self,
+        _args: Self::StageInputs,
+        _chunk_args: Self::ChunkInputs,
+        _rover: MartianRover,
+    ) -> Result<Self::ChunkOutputs> {
+        unreachable!()
+    }
+
+    fn join(
+        &self,
+        args: Self::StageInputs,
+        _chunk_defs: Vec<Self::ChunkInputs>,
+        _chunk_outs: Vec<Self::ChunkOutputs>,
+        rover: MartianRover,
+    ) -> Result<Self::StageOutputs> {
+        ensure!(
+            args.root_cluster.is_none() || args.root_barcode.is_none(),
+            "specify either a pseudotime root cluster or a root barcode, not both"
+        );
+        let no_outputs = DiffusionPseudotimeStageOutputs {
+            pseudotime_h5: None,
+            pseudotime_csv: None,
+        };
+        if args.root_cluster.is_none() && args.root_barcode.is_none() {
+            return Ok(no_outputs);
+        }
+        let feature_types = h5::matrix_feature_types(&args.matrix_h5)?;
+        let Some(feature_type) = FEATURE_TYPES
+            .into_iter()
+            .find(|feature_type| feature_types.get(feature_type).is_some_and(|&n| n >= 2))
+        else {
+            return Ok(no_outputs);
+        };
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(rover.get_threads())
+            .build_global()?;
+
+        let proj = h5::load_transformed_pca_matrix(&args.pca_h5, feature_type, args.input_pcs)?;
+        let labels =
+            h5::load_clustering(&args.clustering_h5, ClusteringType::Louvain, feature_type)?.labels;
+        let (matrix, _) = read_adaptive_csr_matrix(&args.matrix_h5, None, None)?;
+        let barcodes = matrix.barcodes;
+        ensure!(
+            proj.nrows() == barcodes.len() && labels.len() == barcodes.len(),
+            "the PCA and clustering do not match the barcodes of the matrix"
+        );
+
+        // The barcodes analyzed may exclude the root barcode, so check it before the diffusion map.
+        let root_barcode = args
+            .root_barcode
+            .as_ref()
+            .map(|root_barcode| {
+                barcodes
+                    .iter()
+                    .position(|barcode| barcode == root_barcode)
+                    .with_context(|| {
+                        format!(
+                            "The pseudotime root barcode {root_barcode} is not one of the {} \
+                             barcodes analyzed. Cells are excluded from the analysis by the \
+                             barcodes CSV, by num_analysis_bcs and by force_cells. Specify a root \
+                             barcode that is analyzed or pseudotime_root_cluster instead.",
+                            barcodes.len()
+                        )
+                    })
+            })
+            .transpose()?;
+
+        let k = args.num_neighbors.unwrap_or(NUM_NEIGHBORS);
+        info!("computing the diffusion map with k = {k}");
+        let graph = match &args.neighbors_h5 {
+            Some(neighbors_h5) => h5::load_neighbors(neighbors_h5, feature_type, k + 1)?,
+            None => None,
+        };
+        let neighbors = match graph {
+            Some(graph) if graph.nrows() == barcodes.len() => {
+                info!("reusing the k-nearest neighbor graph of the graph-based clustering");
+                exclude_self(graph.view(), k)
+            }
+            _ => nearest_neighbors(proj.view(), k),
+        };
+        let map = diffusion_map(
+            proj.view(),
+            &neighbors,
+            args.num_components.unwrap_or(NUM_COMPONENTS),
+            args.random_seed.unwrap_or(RANDOM_SEED),
+        );
+        info!("diffusion map eigenvalues {}", map.eigenvalues);
+
+        let root = match (root_barcode, args.root_cluster) {
+            (Some(root_barcode), _) => root_barcode,
+            (None, Some(root_cluster)) => extremal_barcode(&map, &labels, root_cluster)
+                .with_context(|| format!("root cluster {root_cluster} has no barcodes"))?,
+            (None, None) => bail!("no pseudotime root"),
+        };
+        info!("diffusion pseudotime root barcode {}", barcodes[root]);
+        let adjacency = symmetrize(&neighbors);
+        let pseudotime = diffusion_pseudotime(&map, &adjacency, root);
+
+        let clusters = labels.iter().copied().sorted().dedup().collect::<Vec<_>>();
+        let cluster_indices = labels
+            .iter()
+            .map(|label| clusters.binary_search(label).unwrap())
+            .collect::<Vec<_>>();
+        let connectivity = paga_connectivity(&adjacency, &cluster_indices, clusters.len());
+
+        let pseudotime_h5: H5File = rover.make_path("pseudotime_h5");
+        h5::save_diffusion_pseudotime(
+            &pseudotime_h5,
+            &map,
+            &pseudotime,
+            &barcodes[root],
+            &clusters,
+            &connectivity,
+        )?;
+        let pseudotime_csv: PathBuf = rover.make_path("pseudotime_csv");
+        csv::save_diffusion_pseudotime(
+            &pseudotime_csv,
+            &barcodes,
+            &pseudotime,
+            &map.components,
+            &clusters,
+            &connectivity,
+        )?;
+
+        Ok(DiffusionPseudotimeStageOutputs {
+            pseudotime_h5: Some(pseudotime_h5),
+            pseudotime_csv: Some(pseudotime_csv),
+        })
+    }
+}
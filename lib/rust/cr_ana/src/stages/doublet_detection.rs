@@ -0,0 +1,202 @@
+//! Martian stage RUN_DOUBLET_DETECTION
+//! Score each barcode against simulated doublets in the space of the gene expression PCA.
+
+use crate::doublets::{detect_doublets, DoubletParams, DoubletResult};
+use crate::io::h5;
+use crate::types::H5File;
+use anyhow::Result;
+use cr_types::reference::feature_reference::FeatureType;
+use cr_websummary::{RawChartWithHelp, TitleWithHelp};
+use hdf5_io::matrix::read_adaptive_csr_matrix;
+use martian::prelude::*;
+use martian::MartianVoid;
+use martian_derive::{make_mro, MartianStruct};
+use martian_filetypes::json_file::JsonFile;
+use martian_filetypes::tabular_file::CsvFile;
+use martian_filetypes::FileTypeWrite;
+use scan_types::matrix::AdaptiveFeatureBarcodeMatrix as FBM;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// The number of bins of the web summary histogram of doublet scores.
+const HISTOGRAM_BINS: usize = 50;
+
+#[derive(Clone, Debug, Deserialize, MartianStruct)]
+pub struct DoubletDetectionStageInputs {
+    matrix_h5: H5File,
+    pca_h5: H5File,
+    random_seed: Option<usize>,
+    input_pcs: Option<usize>,
+    num_neighbors: Option<usize>,
+    sim_doublet_ratio: Option<f64>,
+    expected_doublet_rate: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, MartianStruct)]
+pub struct DoubletDetectionStageOutputs {
+    doublets_csv: Option<CsvFile<DoubletRow>>,
+    doublet_histogram: Option<JsonFile<RawChartWithHelp>>,
+}
+
+/// A row of the doublet scores CSV.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DoubletRow {
+    barcode: String,
+    doublet_score: f64,
+    is_doublet: bool,
+}
+
+pub struct DoubletDetectionStage;
+
+/// Return the fraction of scores in each of the equal bins between zero and one.
+fn histogram(scores: &[f64]) -> Vec<f64> {
+    let mut counts = vec![0.0; HISTOGRAM_BINS];
+    for &score in scores {
+        counts[((score * HISTOGRAM_BINS as f64) as usize).min(HISTOGRAM_BINS - 1)] += 1.0;
+    }
+    counts.iter().map(|c| c / scores.len() as f64).collect()
+}
+
+/// Return a histogram of the doublet scores of the barcodes and of the simulated doublets
+/// for the web summary.
+fn histogram_chart(result: &DoubletResult) -> RawChartWithHelp {
+    let x: Vec<f64> = (0..HISTOGRAM_BINS)
+        .map(|i| (i as f64 + 0.5) / HISTOGRAM_BINS as f64)
+        .collect();
+    let trace = |name: &str, scores: &[f64]| {
+        json!({
+            "x": x,
+            "y": histogram(scores),
+            "name": name,
+            "type": "bar",
+            "opacity": 0.6,
+        })
+    };
+    RawChartWithHelp {
+        plot: json!({
+            "data": [
+                trace("Barcodes", &result.scores),
+                trace("Simulated doublets", &result.simulated_scores),
+            ],
+            "layout": {
+                "barmode": "overlay",
+                "xaxis": {"title": "Doublet score", "range": [0, 1]},
+                "yaxis": {"title": "Fraction"},
+                "shapes": [{
+                    "type": "line",
+                    "x0": result.threshold,
+                    "x1": result.threshold,
+                    "y0": 0,
+                    "y1": 1,
+                    "yref": "paper",
+                    "line": {"dash": "dash"},
+                }],
+            },
+        }),
+        help: TitleWithHelp {
+            title: "Doublet Scores".to_string(),
+            help: "Histogram of the doublet scores of the cell-associated barcodes and of \
+                   doublets simulated by summing random pairs of barcodes. The score of a \
+                   barcode increases with the fraction of simulated doublets among its nearest \
+                   neighbors in the principal component space. Barcodes scoring above the \
+                   dashed threshold are called doublets."
+                .to_string(),
+        },
+    }
+}
+
+#[make_mro(stage_name = RUN_DOUBLET_DETECTION, volatile = strict)]
+impl MartianStage for DoubletDetectionStage {
+    type StageInputs = DoubletDetectionStageInputs;
+    type StageOutputs = DoubletDetectionStageOutputs;
+    type ChunkInputs = MartianVoid;
+    type ChunkOutputs = MartianVoid;
+
+    fn split(
+        &self,
+        args: Self::StageInputs,
+        _rover: MartianRover,
+    ) -> Result<StageDef<Self::ChunkInputs>> {
+        // The simulated doublets triple the size of the matrix, which is then normalized.
+        let mem_gib = 3 + (4.0 * h5::estimate_mem_gib_from_nnz(&args.matrix_h5)?).ceil() as isize;
+        Ok(StageDef::with_join_resource(
+            Resource::with_mem_gb(mem_gib).threads(4),
+        ))
+    }
+
+    fn main(
+        &self,
+        _args: Self::StageInputs,
+        _chunk_args: Self::ChunkInputs,
+        _rover: MartianRover,
+    ) -> Result<Self::ChunkOutputs> {
+        unreachable!()
+    }
+
+    fn join(
+        &self,
+        args: Self::StageInputs,
+        _chunk_defs: Vec<Self::ChunkInputs>,
+        _chunk_outs: Vec<Self::ChunkOutputs>,
+        rover: MartianRover,
+    ) -> Result<Self::StageOutputs> {
+        let feature_type = FeatureType::Gene;
+        if !h5::matrix_feature_types(&args.matrix_h5)?
+            .get(&feature_type)
+            .is_some_and(|&count| count >= 2)
+        {
+            return Ok(DoubletDetectionStageOutputs {
+                doublets_csv: None,
+                doublet_histogram: None,
+            });
+        }
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(rover.get_threads())
+            .build_global()?;
+
+        let retained = Some(feature_type.to_string());
+        let FBM {
+            barcodes, matrix, ..
+        } = read_adaptive_csr_matrix(&args.matrix_h5, retained.as_deref(), Some(0))?.0;
+        let components = h5::load_pca_components(&args.pca_h5, feature_type, args.input_pcs)?;
+
+        let defaults = DoubletParams::default();
+        let params = DoubletParams {
+            sim_doublet_ratio: args.sim_doublet_ratio.unwrap_or(defaults.sim_doublet_ratio),
+            expected_doublet_rate: args
+                .expected_doublet_rate
+                .unwrap_or(defaults.expected_doublet_rate),
+            num_neighbors: args.num_neighbors,
+            random_seed: args.random_seed.map_or(defaults.random_seed, |s| s as u64),
+        };
+        let result = detect_doublets(&matrix.to_csmat().to_csc(), &components, &params);
+        log::info!(
+            "called {} of {} barcodes doublets with threshold {}",
+            result.is_doublet().filter(|&d| d).count(),
+            barcodes.len(),
+            result.threshold
+        );
+
+        let doublets_csv: CsvFile<DoubletRow> = rover.make_path("doublets");
+        doublets_csv.write(
+            &barcodes
+                .into_iter()
+                .zip(&result.scores)
+                .zip(result.is_doublet())
+                .map(|((barcode, &doublet_score), is_doublet)| DoubletRow {
+                    barcode,
+                    doublet_score,
+                    is_doublet,
+                })
+                .collect::<Vec<_>>(),
+        )?;
+
+        let doublet_histogram: JsonFile<RawChartWithHelp> = rover.make_path("doublet_histogram");
+        doublet_histogram.write(&histogram_chart(&result))?;
+
+        Ok(DoubletDetectionStageOutputs {
+            doublets_csv: Some(doublets_csv),
+            doublet_histogram: Some(doublet_histogram),
+        })
+    }
+}
@@ -74,6 +74,8 @@ impl GraphClusteringStageInputs {
 pub struct GraphClusteringStageOutputs {
     clusters_h5: H5File,
     clusters_csv: PathBuf,
+    /// The k-nearest neighbor graph of each feature type, reused by RUN_DIFFUSION_PSEUDOTIME.
+    neighbors_h5: H5File,
 }
 
 #[derive(Debug, Serialize, Deserialize, MartianStruct)]
@@ -111,6 +113,9 @@ impl MartianStage for GraphClusteringStage {
             + ((4 + 4 + 8 + 8) * (num_bcs * k)) as f64 / 1e9)
             .ceil() as isize;
 
+        // The join copies the neighbor graph of one feature type at a time.
+        let join_mem_gib = (1.0 + (4 * num_bcs * k) as f64 / 1e9).ceil() as isize;
+
         Ok(ACTIVE_FEATURE_TYPES
             .iter()
             .filter(|feature_type| {
@@ -124,7 +129,8 @@ impl MartianStage for GraphClusteringStage {
                     Resource::with_mem_gb(mem_gib).threads(args.threads.try_into().unwrap()),
                 )
             })
-            .collect())
+            .collect::<StageDef<_>>()
+            .join_resource(Resource::with_mem_gb(join_mem_gib)))
     }
 
     fn main(
@@ -165,9 +171,13 @@ impl MartianStage for GraphClusteringStage {
         let clusters_csv: PathBuf = rover.make_path("clusters_csv");
         csv::save_clustering(&clusters_csv, &result, &matrix.barcodes)?;
 
+        let neighbors_h5 = rover.make_path("neighbors_h5");
+        h5::save_neighbors(&neighbors_h5, chunk_args.feature_type, &neighbors)?;
+
         Ok(Self::ChunkOutputs {
             clusters_h5,
             clusters_csv,
+            neighbors_h5,
         })
     }
 
@@ -184,9 +194,13 @@ impl MartianStage for GraphClusteringStage {
         let clusters_csv: PathBuf = rover.make_path("clusters_csv");
         csv::combine_clusterings(&clusters_csv, chunk_outs.iter().map(|c| &c.clusters_csv))?;
 
+        let neighbors_h5 = rover.make_path("neighbors_h5");
+        h5::combine_neighbors(&neighbors_h5, chunk_outs.iter().map(|c| &c.neighbors_h5))?;
+
         Ok(Self::StageOutputs {
             clusters_h5,
             clusters_csv,
+            neighbors_h5,
         })
     }
 }
@@ -0,0 +1,131 @@
+//! Martian stage RUN_HARMONY
+//! Correct the PCA embedding of an aggregated matrix for a batch covariate.
+
+use crate::aggr::{barcode_gem_group, library_values, AggrLibrary, AggrSampleDef, GEM_GROUP_KEY};
+use crate::harmony::{run_harmony, HarmonyParams};
+use crate::io::h5;
+use crate::types::H5File;
+use anyhow::{Context, Result};
+use hdf5_io::matrix::get_barcodes_between;
+use martian::prelude::*;
+use martian::MartianVoid;
+use martian_derive::{make_mro, MartianStruct};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Clone, Debug, Deserialize, MartianStruct)]
+pub struct HarmonyStageInputs {
+    matrix_h5: H5File,
+    pca_h5: H5File,
+    #[mro_type = "map[]"]
+    library_info: Option<Vec<AggrLibrary>>,
+    #[mro_type = "map[]"]
+    aggr_sample_defs: Option<Vec<AggrSampleDef>>,
+    /// gem_group, library_id or another column of the aggr CSV.
+    /// The PCA is copied unchanged when null.
+    batch_key: Option<String>,
+    theta: Option<f64>,
+    sigma: Option<f64>,
+    max_iter: Option<usize>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, MartianStruct)]
+pub struct HarmonyStageOutputs {
+    pca_h5: H5File,
+}
+
+pub struct HarmonyStage;
+
+/// Return the batch index of each barcode of the matrix.
+fn barcode_batches(args: &HarmonyStageInputs, key: &str) -> Result<Vec<usize>> {
+    let names = library_values(
+        args.library_info.as_deref().unwrap_or_default(),
+        args.aggr_sample_defs.as_deref().unwrap_or_default(),
+        key,
+    )?;
+    let mut index: HashMap<&str, usize> = HashMap::new();
+    for name in names.values() {
+        let next = index.len();
+        index.entry(name.as_str()).or_insert(next);
+    }
+
+    let matrix = hdf5::File::open(&args.matrix_h5)?.group(h5::matrix::GROUP)?;
+    get_barcodes_between(0, None, &matrix)?
+        .iter()
+        .map(|barcode| {
+            let gem_group = barcode_gem_group(barcode)?;
+            if names.is_empty() && key == GEM_GROUP_KEY {
+                // Not an aggr matrix, so number the batches by gem group.
+                return Ok(gem_group as usize - 1);
+            }
+            let name = names
+                .get(&gem_group)
+                .with_context(|| format!("unknown gem group {gem_group}"))?;
+            Ok(index[name.as_str()])
+        })
+        .collect()
+}
+
+#[make_mro(stage_name = RUN_HARMONY, volatile = strict)]
+impl MartianStage for HarmonyStage {
+    type StageInputs = HarmonyStageInputs;
+    type StageOutputs = HarmonyStageOutputs;
+    type ChunkInputs = MartianVoid;
+    type ChunkOutputs = MartianVoid;
+
+    fn split(
+        &self,
+        args: Self::StageInputs,
+        _rover: MartianRover,
+    ) -> Result<StageDef<Self::ChunkInputs>> {
+        let (_, num_bcs) = h5::matrix_shape(&args.matrix_h5)?;
+        // A few copies of the embedding of up to 100 PCs, plus the cluster assignments.
+        let mem_gib = 2 + num_bcs * 100 * 8 * 4 / 1024 / 1024 / 1024;
+        Ok(StageDef::with_join_resource(
+            Resource::with_mem_gb(mem_gib).threads(1),
+        ))
+    }
+
+    fn main(
+        &self,
+        _args: Self::StageInputs,
+        _chunk_args: Self::ChunkInputs,
+        _rover: MartianRover,
+    ) -> Result<Self::ChunkOutputs> {
+        unreachable!()
+    }
+
+    fn join(
+        &self,
+        args: Self::StageInputs,
+        _chunk_defs: Vec<Self::ChunkInputs>,
+        _chunk_outs: Vec<Self::ChunkOutputs>,
+        rover: MartianRover,
+    ) -> Result<Self::StageOutputs> {
+        let Some(key) = args.batch_key.as_deref() else {
+            return Ok(HarmonyStageOutputs {
+                pca_h5: args.pca_h5,
+            });
+        };
+
+        let pca_h5: H5File = rover.make_path("pca");
+        let batches = barcode_batches(&args, key)?;
+        let defaults = HarmonyParams::default();
+        let params = HarmonyParams {
+            theta: args.theta.unwrap_or(defaults.theta),
+            sigma: args.sigma.unwrap_or(defaults.sigma),
+            max_iter_harmony: args.max_iter.unwrap_or(defaults.max_iter_harmony),
+            ..defaults
+        };
+        h5::copy_corrected_pca(&args.pca_h5, &pca_h5, |z| {
+            anyhow::ensure!(
+                z.nrows() == batches.len(),
+                "PCA has {} barcodes but the matrix has {}",
+                z.nrows(),
+                batches.len()
+            );
+            Ok(run_harmony(&z, &batches, &params))
+        })?;
+        Ok(HarmonyStageOutputs { pca_h5 })
+    }
+}
@@ -0,0 +1,282 @@
+//! Martian stage RUN_MARKER_ANNOTATION
+//! Annotate the cell type of each barcode and graph-based cluster from a CSV of marker genes.
+
+use crate::io::h5;
+use crate::marker_annotation::{
+    assign_cells, assign_clusters, score_cells, Assignment, MarkerSet, UNASSIGNED,
+};
+use crate::types::{ClusteringType, H5File};
+use anyhow::{ensure, Result};
+use cr_types::reference::feature_reference::FeatureType;
+use cr_websummary::{RawChartWithHelp, TitleWithHelp};
+use hdf5_io::matrix::read_adaptive_csr_matrix;
+use itertools::Itertools;
+use martian::prelude::*;
+use martian::MartianVoid;
+use martian_derive::{make_mro, MartianStruct};
+use martian_filetypes::json_file::JsonFile;
+use martian_filetypes::tabular_file::CsvFile;
+use martian_filetypes::{FileTypeRead, FileTypeWrite};
+use scan_types::matrix::AdaptiveFeatureBarcodeMatrix as FBM;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+
+#[derive(Clone, Debug, Deserialize, MartianStruct)]
+pub struct MarkerAnnotationStageInputs {
+    matrix_h5: H5File,
+    clustering_h5: H5File,
+    /// The marker genes of each cell type. No annotation is performed when null.
+    marker_gene_sets: Option<CsvFile<MarkerGeneSetRow>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, MartianStruct)]
+pub struct MarkerAnnotationStageOutputs {
+    cell_types_csv: Option<CsvFile<CellTypeRow>>,
+    cluster_cell_types_csv: Option<CsvFile<ClusterCellTypeRow>>,
+    cell_type_chart: Option<JsonFile<RawChartWithHelp>>,
+}
+
+/// A row of the marker gene sets CSV. Markers are gene IDs or names separated by semicolons.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MarkerGeneSetRow {
+    cell_type: String,
+    positive_markers: String,
+    #[serde(default)]
+    negative_markers: String,
+}
+
+/// A row of the cell types CSV.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CellTypeRow {
+    barcode: String,
+    cell_type: String,
+    score: f64,
+    confidence: f64,
+}
+
+/// A row of the cluster cell types CSV.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClusterCellTypeRow {
+    cluster: i64,
+    cell_type: String,
+    num_barcodes: usize,
+    score: f64,
+    confidence: f64,
+}
+
+pub struct MarkerAnnotationStage;
+
+/// Return the marker sets of the rows of the marker gene sets CSV, matching each marker to the
+/// ID or else the name of a feature.
+fn marker_sets(
+    rows: Vec<MarkerGeneSetRow>,
+    feature_ids: &[String],
+    feature_names: &[String],
+) -> Result<Vec<MarkerSet>> {
+    let mut index: HashMap<&str, usize> = HashMap::new();
+    for (i, name) in feature_names.iter().enumerate() {
+        index.entry(name.as_str()).or_insert(i);
+    }
+    for (i, id) in feature_ids.iter().enumerate() {
+        index.insert(id.as_str(), i);
+    }
+    let mut missing = Vec::new();
+    let mut features = |markers: &str| -> Vec<usize> {
+        markers
+            .split(';')
+            .map(str::trim)
+            .filter(|m| !m.is_empty())
+            .filter_map(|m| {
+                let feature = index.get(m).copied();
+                if feature.is_none() {
+                    missing.push(m.to_string());
+                }
+                feature
+            })
+            .collect()
+    };
+    let sets: Vec<_> = rows
+        .into_iter()
+        .map(|row| MarkerSet {
+            positive: features(&row.positive_markers),
+            negative: features(&row.negative_markers),
+            cell_type: row.cell_type,
+        })
+        .collect();
+    if !missing.is_empty() {
+        log::warn!(
+            "ignoring {} marker genes absent from the matrix: {}",
+            missing.len(),
+            missing.iter().join(", ")
+        );
+    }
+    ensure!(!sets.is_empty(), "the marker gene sets CSV is empty");
+    for set in &sets {
+        ensure!(
+            !set.positive.is_empty(),
+            "none of the positive markers of cell type {} are in the matrix",
+            set.cell_type
+        );
+    }
+    ensure!(
+        sets.iter().map(|set| &set.cell_type).all_unique(),
+        "the marker gene sets CSV has duplicate cell types"
+    );
+    Ok(sets)
+}
+
+/// Return a bar chart of the number of barcodes assigned each cell type for the web summary.
+fn cell_type_bar_chart(cell_types: &[&str], cells: &[Assignment]) -> RawChartWithHelp {
+    let counts = cells.iter().counts_by(|cell| cell.cell_type);
+    let (x, y): (Vec<&str>, Vec<usize>) = (0..cell_types.len())
+        .map(Some)
+        .chain([None])
+        .map(|cell_type| {
+            let name = cell_type.map_or(UNASSIGNED, |i| cell_types[i]);
+            (name, counts.get(&cell_type).copied().unwrap_or(0))
+        })
+        .unzip();
+    RawChartWithHelp {
+        plot: json!({
+            "data": [{
+                "x": x,
+                "y": y,
+                "type": "bar",
+            }],
+            "layout": {
+                "xaxis": {"title": "Cell type"},
+                "yaxis": {"title": "Barcodes"},
+            },
+        }),
+        help: TitleWithHelp {
+            title: "Cell Types".to_string(),
+            help: "Number of cell-associated barcodes assigned each cell type of the marker gene \
+                   sets CSV. Each barcode is scored for each cell type by the mean scaled \
+                   expression of its positive markers minus that of its negative markers, and \
+                   assigned the cell type of its highest score. Barcodes without a positive \
+                   score are unassigned."
+                .to_string(),
+        },
+    }
+}
+
+#[make_mro(stage_name = RUN_MARKER_ANNOTATION, volatile = strict)]
+impl MartianStage for MarkerAnnotationStage {
+    type StageInputs = MarkerAnnotationStageInputs;
+    type StageOutputs = MarkerAnnotationStageOutputs;
+    type ChunkInputs = MartianVoid;
+    type ChunkOutputs = MartianVoid;
+
+    fn split(
+        &self,
+        args: Self::StageInputs,
+        _rover: MartianRover,
+    ) -> Result<StageDef<Self::ChunkInputs>> {
+        let mem_gib = 2 + h5::estimate_mem_gib_from_nnz(&args.matrix_h5)?.ceil() as isize;
+        Ok(StageDef::with_join_resource(
+            Resource::with_mem_gb(mem_gib).threads(1),
+        ))
+    }
+
+    fn main(
+        &self,
+        _args: Self::StageInputs,
+        _chunk_args: Self::ChunkInputs,
+        _rover: MartianRover,
+    ) -> Result<Self::ChunkOutputs> {
+        unreachable!()
+    }
+
+    fn join(
+        &self,
+        args: Self::StageInputs,
+        _chunk_defs: Vec<Self::ChunkInputs>,
+        _chunk_outs: Vec<Self::ChunkOutputs>,
+        rover: MartianRover,
+    ) -> Result<Self::StageOutputs> {
+        let Some(marker_gene_sets) = &args.marker_gene_sets else {
+            return Ok(MarkerAnnotationStageOutputs {
+                cell_types_csv: None,
+                cluster_cell_types_csv: None,
+                cell_type_chart: None,
+            });
+        };
+
+        let feature_type = FeatureType::Gene;
+        let retained = Some(feature_type.to_string());
+        let FBM {
+            barcodes,
+            feature_ids,
+            feature_names,
+            matrix,
+            ..
+        } = read_adaptive_csr_matrix(&args.matrix_h5, retained.as_deref(), Some(0))?.0;
+        let sets = marker_sets(marker_gene_sets.read()?, &feature_ids, &feature_names)?;
+        let labels =
+            h5::load_clustering(&args.clustering_h5, ClusteringType::Louvain, feature_type)?.labels;
+        ensure!(
+            labels.len() == barcodes.len(),
+            "clustering has {} barcodes but the matrix has {}",
+            labels.len(),
+            barcodes.len()
+        );
+
+        let scores = score_cells(&matrix, &sets);
+        let cells = assign_cells(&scores);
+        let clusters: Vec<i64> = labels.iter().copied().sorted().dedup().collect();
+        let cluster_indices: Vec<usize> = labels
+            .iter()
+            .map(|label| clusters.binary_search(label).unwrap())
+            .collect();
+        let cluster_assignments =
+            assign_clusters(&scores, &cells, &cluster_indices, clusters.len());
+
+        let cell_types: Vec<&str> = sets.iter().map(|set| set.cell_type.as_str()).collect();
+        let name = |assignment: &Assignment| {
+            assignment
+                .cell_type
+                .map_or(UNASSIGNED, |i| cell_types[i])
+                .to_string()
+        };
+        let cell_types_csv: CsvFile<_> = rover.make_path("cell_types");
+        cell_types_csv.write(
+            &barcodes
+                .into_iter()
+                .zip(&cells)
+                .map(|(barcode, cell)| CellTypeRow {
+                    barcode,
+                    cell_type: name(cell),
+                    score: cell.score,
+                    confidence: cell.confidence,
+                })
+                .collect::<Vec<_>>(),
+        )?;
+
+        let cluster_sizes = cluster_indices.iter().counts();
+        let cluster_cell_types_csv: CsvFile<_> = rover.make_path("cluster_cell_types");
+        cluster_cell_types_csv.write(
+            &clusters
+                .iter()
+                .zip(&cluster_assignments)
+                .enumerate()
+                .map(|(i, (&cluster, assignment))| ClusterCellTypeRow {
+                    cluster,
+                    cell_type: name(assignment),
+                    num_barcodes: cluster_sizes[&i],
+                    score: assignment.score,
+                    confidence: assignment.confidence,
+                })
+                .collect::<Vec<_>>(),
+        )?;
+
+        let cell_type_chart: JsonFile<_> = rover.make_path("cell_type_chart");
+        cell_type_chart.write(&cell_type_bar_chart(&cell_types, &cells))?;
+
+        Ok(MarkerAnnotationStageOutputs {
+            cell_types_csv: Some(cell_types_csv),
+            cluster_cell_types_csv: Some(cluster_cell_types_csv),
+            cell_type_chart: Some(cell_type_chart),
+        })
+    }
+}
@@ -1,7 +1,15 @@
+pub mod antibody_normalization;
 pub mod diff_exp_stage;
+pub mod diffusion_pseudotime;
+pub mod doublet_detection;
 pub mod graph_clustering;
+pub mod harmony;
 pub mod hierarchical_clustering;
+pub mod marker_annotation;
 pub mod pca;
 pub mod pca2;
+pub mod pseudobulk_diff_exp;
+pub mod reference_projection;
 pub mod tsne;
 pub mod umap;
+pub mod wnn_clustering;
@@ -1,11 +1,12 @@
 //! PCA stage code
 
 use crate::io::{csv, h5};
-use crate::pca::run_pca;
+use crate::pca::{run_dense_pca, run_pca};
 use crate::types::H5File;
 use crate::EXCLUDED_FEATURE_TYPES;
-use anyhow::Result;
+use anyhow::{ensure, Result};
 use cr_types::reference::feature_reference::FeatureType;
+use cr_types::FeatureBarcodeType;
 use hdf5_io::matrix::read_adaptive_csr_matrix;
 use martian::prelude::{MartianRover, MartianStage, Resource, StageDef};
 use martian_derive::{make_mro, MartianStruct};
@@ -30,6 +31,8 @@ pub struct PcaStageInputs {
     pub num_principal_comps: Option<usize>,
     pub is_spatial: bool,
     pub pca_map: Option<HashMap<String, PcaOutputs>>,
+    /// The denoised antibody values, which replace the normalized antibody counts when present.
+    pub antibody_normalization_h5: Option<H5File>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, MartianStruct)]
@@ -94,30 +97,43 @@ impl MartianStage for PcaStage {
         } = read_adaptive_csr_matrix(&args.matrix_h5, retained.as_deref(), Some(0))?.0;
         let max_features = args.num_pca_genes.unwrap_or_else(|| matrix.rows());
         let num_pcs = args.num_principal_comps.unwrap_or(PCA_COMPONENTS);
-        let result = run_pca(
-            &matrix,
-            &feature_ids,
-            chunk_args.feature_type,
-            max_features,
-            num_pcs,
-            PCA_THRESHOLD,
-            args.is_spatial,
-        )
-        .or_else(|_| {
-            log::warn!(
-                "PCA with threshold = {} failed, reattempting without threshold",
-                PCA_THRESHOLD
+        let antibody_normalization_h5 = args.antibody_normalization_h5.as_ref().filter(|_| {
+            chunk_args.feature_type == FeatureType::Barcode(FeatureBarcodeType::Antibody)
+        });
+        let result = if let Some(antibody_normalization_h5) = antibody_normalization_h5 {
+            let (denoised_feature_ids, denoised) =
+                h5::load_denoised_antibodies(antibody_normalization_h5)?;
+            ensure!(
+                denoised_feature_ids == feature_ids,
+                "the denoised antibodies differ from the antibodies of the matrix"
             );
+            run_dense_pca(&denoised, &feature_ids, chunk_args.feature_type, num_pcs)
+        } else {
             run_pca(
                 &matrix,
                 &feature_ids,
                 chunk_args.feature_type,
                 max_features,
                 num_pcs,
-                0.0,
+                PCA_THRESHOLD,
                 args.is_spatial,
             )
-        })?;
+            .or_else(|_| {
+                log::warn!(
+                    "PCA with threshold = {} failed, reattempting without threshold",
+                    PCA_THRESHOLD
+                );
+                run_pca(
+                    &matrix,
+                    &feature_ids,
+                    chunk_args.feature_type,
+                    max_features,
+                    num_pcs,
+                    0.0,
+                    args.is_spatial,
+                )
+            })?
+        };
 
         let pca_h5 = rover.make_path("pca_h5");
         h5::save_pca(&pca_h5, &result)?;
@@ -0,0 +1,260 @@
+//! Martian stage RUN_PSEUDOBULK_DIFFERENTIAL_EXPRESSION
+//! Test each graph-based cluster of an aggregated matrix for differential expression
+//! between two conditions, using the samples of the aggr CSV as replicates.
+
+use crate::aggr::{barcode_gem_group, library_values, AggrLibrary, AggrSampleDef, LIBRARY_ID_KEY};
+use crate::io::h5;
+use crate::pseudobulk::{pseudobulk_diff_exp, sum_counts};
+use crate::types::{ClusteringType, H5File};
+use anyhow::{bail, ensure, Context, Result};
+use cr_types::reference::feature_reference::FeatureType;
+use hdf5_io::matrix::read_adaptive_csr_matrix;
+use itertools::Itertools;
+use martian::prelude::*;
+use martian::MartianVoid;
+use martian_derive::{make_mro, MartianStruct};
+use martian_filetypes::tabular_file::CsvFile;
+use martian_filetypes::FileTypeWrite;
+use ndarray::Axis;
+use scan_types::matrix::AdaptiveFeatureBarcodeMatrix as FBM;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Clone, Debug, Deserialize, MartianStruct)]
+pub struct PseudobulkDiffExpStageInputs {
+    matrix_h5: H5File,
+    clustering_h5: H5File,
+    #[mro_type = "map[]"]
+    library_info: Option<Vec<AggrLibrary>>,
+    #[mro_type = "map[]"]
+    aggr_sample_defs: Option<Vec<AggrSampleDef>>,
+    /// The column of the aggr CSV identifying the replicate samples, library_id by default.
+    sample_key: Option<String>,
+    /// The column of the aggr CSV identifying the condition of each sample.
+    /// No test is performed when null.
+    condition_key: Option<String>,
+    reference_condition: Option<String>,
+    test_condition: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, MartianStruct)]
+pub struct PseudobulkDiffExpStageOutputs {
+    pseudobulk_diffexp_csv: Option<CsvFile<PseudobulkDiffExpRow>>,
+}
+
+/// A row of the pseudobulk differential expression CSV.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PseudobulkDiffExpRow {
+    cluster: i64,
+    feature_id: String,
+    feature_name: String,
+    base_mean: f64,
+    log2_fold_change: f64,
+    p_value: f64,
+    adjusted_p_value: f64,
+}
+
+pub struct PseudobulkDiffExpStage;
+
+/// The samples of the reference and test conditions.
+struct Design {
+    /// The sample of each gem group.
+    gem_group_samples: BTreeMap<u32, usize>,
+    num_samples: usize,
+    reference: Vec<usize>,
+    test: Vec<usize>,
+}
+
+impl Design {
+    fn new(
+        args: &PseudobulkDiffExpStageInputs,
+        condition_key: &str,
+        reference_condition: &str,
+        test_condition: &str,
+    ) -> Result<Self> {
+        let libraries = args.library_info.as_deref().unwrap_or_default();
+        let sample_defs = args.aggr_sample_defs.as_deref().unwrap_or_default();
+        let sample_key = args.sample_key.as_deref().unwrap_or(LIBRARY_ID_KEY);
+        let samples = library_values(libraries, sample_defs, sample_key)?;
+        let conditions = library_values(libraries, sample_defs, condition_key)?;
+
+        let sample_names: Vec<&String> = samples.values().unique().collect();
+        let mut sample_conditions: HashMap<&str, &str> = HashMap::new();
+        for (gem_group, sample) in &samples {
+            let condition = conditions[gem_group].as_str();
+            if *sample_conditions
+                .entry(sample.as_str())
+                .or_insert(condition)
+                != condition
+            {
+                bail!("sample {sample} has more than one value of {condition_key} in the aggr CSV");
+            }
+        }
+        let samples_of = |condition: &str| -> Vec<usize> {
+            sample_names
+                .iter()
+                .positions(|&s| sample_conditions[s.as_str()] == condition)
+                .collect()
+        };
+        let (reference, test) = (samples_of(reference_condition), samples_of(test_condition));
+        ensure!(
+            !reference.is_empty() && !test.is_empty(),
+            "column {condition_key} of the aggr CSV must include both {reference_condition} and \
+             {test_condition}"
+        );
+        let gem_group_samples = samples
+            .iter()
+            .map(|(&gem_group, sample)| {
+                let index = sample_names.iter().position(|&s| s == sample).unwrap();
+                (gem_group, index)
+            })
+            .collect();
+        Ok(Design {
+            gem_group_samples,
+            num_samples: sample_names.len(),
+            reference,
+            test,
+        })
+    }
+}
+
+#[make_mro(stage_name = RUN_PSEUDOBULK_DIFFERENTIAL_EXPRESSION, volatile = strict)]
+impl MartianStage for PseudobulkDiffExpStage {
+    type StageInputs = PseudobulkDiffExpStageInputs;
+    type StageOutputs = PseudobulkDiffExpStageOutputs;
+    type ChunkInputs = MartianVoid;
+    type ChunkOutputs = MartianVoid;
+
+    fn split(
+        &self,
+        args: Self::StageInputs,
+        _rover: MartianRover,
+    ) -> Result<StageDef<Self::ChunkInputs>> {
+        let mem_gib = 2 + h5::estimate_mem_gib_from_nnz(&args.matrix_h5)?.ceil() as isize;
+        Ok(StageDef::with_join_resource(
+            Resource::with_mem_gb(mem_gib).threads(1),
+        ))
+    }
+
+    fn main(
+        &self,
+        _args: Self::StageInputs,
+        _chunk_args: Self::ChunkInputs,
+        _rover: MartianRover,
+    ) -> Result<Self::ChunkOutputs> {
+        unreachable!()
+    }
+
+    fn join(
+        &self,
+        args: Self::StageInputs,
+        _chunk_defs: Vec<Self::ChunkInputs>,
+        _chunk_outs: Vec<Self::ChunkOutputs>,
+        rover: MartianRover,
+    ) -> Result<Self::StageOutputs> {
+        let Some(condition_key) = args.condition_key.as_deref() else {
+            return Ok(PseudobulkDiffExpStageOutputs {
+                pseudobulk_diffexp_csv: None,
+            });
+        };
+        let (Some(reference_condition), Some(test_condition)) = (
+            args.reference_condition.as_deref(),
+            args.test_condition.as_deref(),
+        ) else {
+            bail!("both the reference and test conditions are required to test {condition_key}");
+        };
+        let design = Design::new(&args, condition_key, reference_condition, test_condition)?;
+
+        let feature_type = FeatureType::Gene;
+        let retained = Some(feature_type.to_string());
+        let FBM {
+            barcodes,
+            feature_ids,
+            feature_names,
+            matrix,
+            ..
+        } = read_adaptive_csr_matrix(&args.matrix_h5, retained.as_deref(), Some(0))?.0;
+        let matrix = matrix.to_csmat().to_csc();
+        let labels =
+            h5::load_clustering(&args.clustering_h5, ClusteringType::Louvain, feature_type)?.labels;
+        ensure!(
+            labels.len() == barcodes.len(),
+            "clustering has {} barcodes but the matrix has {}",
+            labels.len(),
+            barcodes.len()
+        );
+        let barcode_samples: Vec<usize> = barcodes
+            .iter()
+            .map(|barcode| {
+                let gem_group = barcode_gem_group(barcode)?;
+                design
+                    .gem_group_samples
+                    .get(&gem_group)
+                    .copied()
+                    .with_context(|| format!("unknown gem group {gem_group}"))
+            })
+            .try_collect()?;
+
+        let mut rows = Vec::new();
+        for cluster in labels.iter().copied().sorted().dedup() {
+            let groups: Vec<Option<usize>> = labels
+                .iter()
+                .zip(&barcode_samples)
+                .map(|(&label, &sample)| (label == cluster).then_some(sample))
+                .collect();
+            let counts = sum_counts(&matrix, &groups, design.num_samples);
+            // Drop the samples without any barcodes of this cluster.
+            let present: Vec<bool> = counts
+                .columns()
+                .into_iter()
+                .map(|c| c.sum() > 0.0)
+                .collect();
+            let kept = |samples: &[usize]| -> Vec<usize> {
+                samples.iter().copied().filter(|&j| present[j]).collect()
+            };
+            let (reference, test) = (kept(&design.reference), kept(&design.test));
+            if reference.len() < 2 || test.len() < 2 {
+                log::warn!(
+                    "skipping cluster {cluster} with {} {reference_condition} and {} \
+                     {test_condition} samples",
+                    reference.len(),
+                    test.len()
+                );
+                continue;
+            }
+
+            // Keep only the samples of the two conditions, reference first.
+            let columns: Vec<usize> = reference.iter().chain(&test).copied().collect();
+            let counts = counts.select(Axis(1), &columns);
+            let num_reference = reference.len();
+            let result = pseudobulk_diff_exp(
+                &counts,
+                &(0..num_reference).collect::<Vec<_>>(),
+                &(num_reference..columns.len()).collect::<Vec<_>>(),
+            );
+            for (i, (feature_id, feature_name)) in
+                feature_ids.iter().zip(&feature_names).enumerate()
+            {
+                if result.base_mean[i] == 0.0 {
+                    continue;
+                }
+                rows.push(PseudobulkDiffExpRow {
+                    cluster,
+                    feature_id: feature_id.clone(),
+                    feature_name: feature_name.clone(),
+                    base_mean: result.base_mean[i],
+                    log2_fold_change: result.log2_fold_change[i],
+                    p_value: result.p_value[i],
+                    adjusted_p_value: result.adjusted_p_value[i],
+                });
+            }
+        }
+
+        let pseudobulk_diffexp_csv: CsvFile<_> =
+            rover.make_path("pseudobulk_differential_expression");
+        pseudobulk_diffexp_csv.write(&rows)?;
+        Ok(PseudobulkDiffExpStageOutputs {
+            pseudobulk_diffexp_csv: Some(pseudobulk_diffexp_csv),
+        })
+    }
+}
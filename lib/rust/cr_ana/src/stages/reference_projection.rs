@@ -0,0 +1,295 @@
+//! Martian stage RUN_REFERENCE_PROJECTION
+//! Project a matrix onto the PCA of a previous analysis, and transfer its graph-based clusters
+//! and UMAP coordinates from the nearest reference barcodes.
+
+use crate::io::{csv, h5};
+use crate::pca::{feature_mean_scale, project_pca, size_factor_target};
+use crate::projection::{nearest_neighbors, transfer_embedding, transfer_labels};
+use crate::types::{ClusteringType, EmbeddingResult, EmbeddingType, H5File};
+use anyhow::{bail, ensure, Context, Result};
+use cr_types::reference::feature_reference::FeatureType;
+use hdf5_io::matrix::read_adaptive_csr_matrix;
+use martian::prelude::*;
+use martian::MartianVoid;
+use martian_derive::{make_mro, MartianStruct};
+use ndarray::{Array1, Array2, Axis};
+use scan_types::matrix::AdaptiveFeatureBarcodeMatrix as FBM;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+
+const NUM_NEIGHBORS: usize = 20;
+/// The filtered matrix of a run, next to its analysis folder, in the outs of count, aggr and
+/// reanalyze and in the per-sample outs of multi.
+const REFERENCE_MATRIX_NAMES: [&str; 2] = [
+    "filtered_feature_bc_matrix.h5",
+    "sample_filtered_feature_bc_matrix.h5",
+];
+
+#[derive(Clone, Debug, Deserialize, MartianStruct)]
+pub struct ReferenceProjectionStageInputs {
+    matrix_h5: H5File,
+    /// The analysis CSV directory of a previous run. No projection is performed when null.
+    reference_analysis: Option<PathBuf>,
+    num_neighbors: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, MartianStruct)]
+pub struct ReferenceProjectionStageOutputs {
+    reference_projection: Option<PathBuf>,
+}
+
+pub struct ReferenceProjectionStage;
+
+/// Return the index of each reference barcode among the barcodes of another reference output.
+fn barcode_rows(barcodes: &[String], other_barcodes: &[String], what: &str) -> Result<Vec<usize>> {
+    let index: HashMap<&str, usize> = other_barcodes
+        .iter()
+        .enumerate()
+        .map(|(i, bc)| (bc.as_str(), i))
+        .collect();
+    barcodes
+        .iter()
+        .map(|bc| {
+            index
+                .get(bc.as_str())
+                .copied()
+                .with_context(|| format!("barcode {bc} of the reference PCA is missing {what}"))
+        })
+        .collect()
+}
+
+/// Return the filtered matrix next to the analysis folder of a run.
+fn reference_matrix(reference: &Path) -> Result<PathBuf> {
+    let outs = reference.parent().unwrap_or(reference);
+    REFERENCE_MATRIX_NAMES
+        .iter()
+        .map(|name| outs.join(name))
+        .find(|path| path.exists())
+        .with_context(|| {
+            format!(
+                "the reference analysis {} has no feature_scaling.csv with the UMI count target, \
+                 and no {} next to it from which to compute it",
+                reference.display(),
+                REFERENCE_MATRIX_NAMES.join(" or ")
+            )
+        })
+}
+
+/// Return the mean and scale of each reference feature and the UMI count target of the
+/// reference PCA, from its feature_scaling.csv, or recomputed from the filtered matrix of the
+/// reference when feature_scaling.csv is absent or was written by an earlier version.
+fn load_feature_scaling(
+    reference: &Path,
+    reference_feature_ids: &[String],
+    feature_type: FeatureType,
+) -> Result<(Array1<f64>, Array1<f64>, f64)> {
+    if let Some((feature_ids, feature_mean, feature_scale, target)) =
+        csv::load_pca_feature_scaling(&reference.join("pca"), feature_type)?
+    {
+        ensure!(
+            feature_ids == reference_feature_ids,
+            "the features of feature_scaling.csv differ from those of components.csv"
+        );
+        return Ok((feature_mean, feature_scale, target));
+    }
+
+    let matrix_h5 = reference_matrix(reference)?;
+    log::warn!(
+        "recomputing the feature scaling of the reference PCA from {}",
+        matrix_h5.display()
+    );
+    let retained = Some(feature_type.to_string());
+    let FBM {
+        feature_ids,
+        matrix,
+        ..
+    } = read_adaptive_csr_matrix(&matrix_h5, retained.as_deref(), Some(0))?.0;
+    let feature_index: HashMap<&str, usize> = feature_ids
+        .iter()
+        .enumerate()
+        .map(|(i, id)| (id.as_str(), i))
+        .collect();
+    let rows: Vec<usize> = reference_feature_ids
+        .iter()
+        .map(|id| {
+            feature_index.get(id.as_str()).copied().with_context(|| {
+                format!(
+                    "feature {id} of the reference PCA is missing from {}",
+                    matrix_h5.display()
+                )
+            })
+        })
+        .collect::<Result<_>>()?;
+    let target = size_factor_target(&matrix);
+    let (feature_mean, feature_scale) = feature_mean_scale(&matrix, target);
+    Ok((
+        feature_mean.select(Axis(0), &rows),
+        feature_scale.select(Axis(0), &rows),
+        target,
+    ))
+}
+
+#[make_mro(stage_name = RUN_REFERENCE_PROJECTION, volatile = strict)]
+impl MartianStage for ReferenceProjectionStage {
+    type StageInputs = ReferenceProjectionStageInputs;
+    type StageOutputs = ReferenceProjectionStageOutputs;
+    type ChunkInputs = MartianVoid;
+    type ChunkOutputs = MartianVoid;
+
+    fn split(
+        &self,
+        args: Self::StageInputs,
+        _rover: MartianRover,
+    ) -> Result<StageDef<Self::ChunkInputs>> {
+        let mut mem_gib = 2.0 * h5::estimate_mem_gib_from_nnz(&args.matrix_h5)?;
+        if let Some(reference) = args.reference_analysis.as_deref() {
+            let scaling = csv::load_pca_feature_scaling(&reference.join("pca"), FeatureType::Gene);
+            if matches!(scaling, Ok(None)) {
+                mem_gib += 2.0 * h5::estimate_mem_gib_from_nnz(&reference_matrix(reference)?)?;
+            }
+        }
+        let mem_gib = 2 + mem_gib.ceil() as isize;
+        Ok(StageDef::with_join_resource(
+            Resource::with_mem_gb(mem_gib).threads(4),
+        ))
+    }
+
+    fn main(
+        &self,
+        _args: Self::StageInputs,
+        _chunk_args: Self::ChunkInputs,
+        _rover: MartianRover,
+    ) -> Result<Self::ChunkOutputs> {
+        unreachable!()
+    }
+
+    fn join(
+        &self,
+        args: Self::StageInputs,
+        _chunk_defs: Vec<Self::ChunkInputs>,
+        _chunk_outs: Vec<Self::ChunkOutputs>,
+        rover: MartianRover,
+    ) -> Result<Self::StageOutputs> {
+        let Some(reference) = args.reference_analysis.as_deref() else {
+            return Ok(ReferenceProjectionStageOutputs {
+                reference_projection: None,
+            });
+        };
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(rover.get_threads())
+            .build_global()?;
+
+        let feature_type = FeatureType::Gene;
+        let (reference_feature_ids, components) =
+            csv::load_pca_components(&reference.join("pca"), feature_type)?;
+        let features_selected =
+            csv::load_pca_features_selected(&reference.join("pca"), feature_type)?;
+        let (feature_mean, feature_scale, target) =
+            load_feature_scaling(reference, &reference_feature_ids, feature_type)?;
+
+        let retained = Some(feature_type.to_string());
+        let FBM {
+            barcodes,
+            feature_ids,
+            matrix,
+            ..
+        } = read_adaptive_csr_matrix(&args.matrix_h5, retained.as_deref(), Some(0))?.0;
+
+        // Match the features of the matrix to those of the reference.
+        let feature_index: HashMap<&str, usize> = feature_ids
+            .iter()
+            .enumerate()
+            .map(|(i, id)| (id.as_str(), i))
+            .collect();
+        let (rows, columns): (Vec<usize>, Vec<usize>) = reference_feature_ids
+            .iter()
+            .enumerate()
+            .filter_map(|(j, id)| feature_index.get(id.as_str()).map(|&i| (i, j)))
+            .unzip();
+        let shared: HashSet<&str> = rows.iter().map(|&i| feature_ids[i].as_str()).collect();
+        let num_missing = features_selected
+            .iter()
+            .filter(|id| !shared.contains(id.as_str()))
+            .count();
+        if num_missing == features_selected.len() {
+            bail!("the matrix has none of the features selected for the reference PCA");
+        } else if num_missing > 0 {
+            log::warn!(
+                "{num_missing} of {} features selected for the reference PCA are missing",
+                features_selected.len()
+            );
+        }
+        // Each barcode is scaled by its UMI count over all its features, as in the reference.
+        // The features missing from the reference have a zero component and scale, and do not
+        // contribute to the projection.
+        let mut matrix_components = Array2::zeros((components.nrows(), matrix.rows()));
+        let mut matrix_mean = Array1::zeros(matrix.rows());
+        let mut matrix_scale = Array1::zeros(matrix.rows());
+        for (&i, &j) in rows.iter().zip(&columns) {
+            matrix_components
+                .column_mut(i)
+                .assign(&components.column(j));
+            matrix_mean[i] = feature_mean[j];
+            matrix_scale[i] = feature_scale[j];
+        }
+        let projected = project_pca(
+            &matrix,
+            &matrix_components,
+            &matrix_mean,
+            &matrix_scale,
+            target,
+        );
+
+        let (reference_barcodes, reference_pca) =
+            csv::load_projection(&reference.join("pca"), feature_type)?;
+        ensure!(
+            reference_pca.ncols() == projected.ncols(),
+            "the reference PCA projection has {} components but components.csv has {}",
+            reference_pca.ncols(),
+            projected.ncols()
+        );
+        let (cluster_barcodes, cluster_labels) = csv::load_clustering(
+            &reference.join("clustering"),
+            ClusteringType::Louvain,
+            feature_type,
+        )?;
+        let labels: Vec<i64> = barcode_rows(
+            &reference_barcodes,
+            &cluster_barcodes,
+            "from the clustering",
+        )?
+        .into_iter()
+        .map(|i| cluster_labels[i])
+        .collect();
+        let (umap_barcodes, umap) = csv::load_projection(&reference.join("umap"), feature_type)?;
+        let umap = umap.select(
+            Axis(0),
+            &barcode_rows(&reference_barcodes, &umap_barcodes, "from the UMAP")?,
+        );
+
+        let neighbors = nearest_neighbors(
+            &reference_pca,
+            &projected,
+            args.num_neighbors.unwrap_or(NUM_NEIGHBORS),
+        );
+        let mapping = transfer_labels(&neighbors, &labels);
+        let embedding = transfer_embedding(&neighbors, &umap);
+
+        let reference_projection: PathBuf = rover.make_path("reference_projection");
+        create_dir_all(&reference_projection)?;
+        csv::save_reference_mapping(
+            &reference_projection.join("clusters.csv"),
+            &barcodes,
+            &mapping,
+        )?;
+        csv::save_embedding(
+            &reference_projection.join("umap"),
+            &EmbeddingResult::new(&barcodes, embedding, EmbeddingType::Umap, feature_type),
+        )?;
+        Ok(ReferenceProjectionStageOutputs {
+            reference_projection: Some(reference_projection),
+        })
+    }
+}
@@ -21,6 +21,9 @@ const N_COMPONENTS: usize = 2;
 pub struct UmapStageInputs {
     pub matrix_h5: H5File,
     pub pca_h5: H5File,
+    /// The joint embedding of gene expression and antibody capture, embedded in addition to
+    /// each feature type when not null.
+    pub wnn_h5: Option<H5File>,
     pub random_seed: Option<u64>,
     pub n_neighbors: Option<usize>,
     pub input_pcs: Option<usize>,
@@ -49,6 +52,7 @@ pub struct UmapStageOutputs {
 pub struct UmapChunkInputs {
     umap_dims: usize,
     feature_type: FeatureType,
+    wnn: bool,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, MartianStruct)]
@@ -80,6 +84,14 @@ impl MartianStage for UmapStage {
             Parallel => 4,
         };
 
+        let chunk_mem_gb = ((ncells as f64 / 125_000.0).ceil() as isize).max(4);
+        let chunk_vmem_gb = chunk_mem_gb * 8;
+        let chunk_resource = || {
+            Resource::new()
+                .threads(threads)
+                .mem_gb(chunk_mem_gb)
+                .vmem_gb(chunk_vmem_gb)
+        };
         for umap_dims in N_COMPONENTS..=args.max_dims.unwrap_or(N_COMPONENTS) {
             for (&feature_type, &count) in &feature_types {
                 // if we have only 1 feature, skip!
@@ -89,14 +101,17 @@ impl MartianStage for UmapStage {
                 let chunk_inputs = Self::ChunkInputs {
                     umap_dims,
                     feature_type,
+                    wnn: false,
+                };
+                stage_def.add_chunk_with_resource(chunk_inputs, chunk_resource());
+            }
+            if args.wnn_h5.is_some() {
+                let chunk_inputs = Self::ChunkInputs {
+                    umap_dims,
+                    feature_type: FeatureType::Gene,
+                    wnn: true,
                 };
-                let chunk_mem_gb = ((ncells as f64 / 125_000.0).ceil() as isize).max(4);
-                let chunk_vmem_gb = chunk_mem_gb * 8;
-                let chunk_resource = Resource::new()
-                    .threads(threads)
-                    .mem_gb(chunk_mem_gb)
-                    .vmem_gb(chunk_vmem_gb);
-                stage_def.add_chunk_with_resource(chunk_inputs, chunk_resource);
+                stage_def.add_chunk_with_resource(chunk_inputs, chunk_resource());
             }
         }
         Ok(stage_def)
@@ -109,18 +124,26 @@ impl MartianStage for UmapStage {
         rover: MartianRover,
     ) -> Result<Self::ChunkOutputs> {
         let (proj, barcodes) = {
-            let proj = h5::load_transformed_pca_matrix(
-                &args.pca_h5,
-                chunk_args.feature_type,
-                args.input_pcs,
-            )?;
+            let proj = match &args.wnn_h5 {
+                Some(wnn_h5) if chunk_args.wnn => h5::load_wnn_embedding(wnn_h5)?,
+                _ => h5::load_transformed_pca_matrix(
+                    &args.pca_h5,
+                    chunk_args.feature_type,
+                    args.input_pcs,
+                )?,
+            };
             let matrix = hdf5::File::open(&args.matrix_h5)?.group("matrix")?;
             let barcodes = get_barcodes_between(0, None, &matrix)?;
             (proj, barcodes)
         };
         let (num_bcs, _) = proj.dim();
 
-        let metric = match args.metric.as_deref() {
+        // The joint embedding concatenates the scaled modalities, so compare by distance.
+        let metric = args
+            .metric
+            .as_deref()
+            .or(chunk_args.wnn.then_some("euclidean"));
+        let metric = match metric {
             Some("correlation") | Some("pearson") | None => DistanceType::pearson(),
             Some("cosine") => DistanceType::cosine(),
             Some("euclidean") => DistanceType::euclidean(),
@@ -150,12 +173,16 @@ impl MartianStage for UmapStage {
             }
         };
 
-        let result = EmbeddingResult::new(
-            &barcodes,
-            embedding,
-            EmbeddingType::Umap,
-            chunk_args.feature_type,
-        );
+        let result = if chunk_args.wnn {
+            EmbeddingResult::new_wnn(&barcodes, embedding, EmbeddingType::Umap)
+        } else {
+            EmbeddingResult::new(
+                &barcodes,
+                embedding,
+                EmbeddingType::Umap,
+                chunk_args.feature_type,
+            )
+        };
 
         let umap_h5 = rover.make_path("umap_h5");
         h5::save_embedding(&umap_h5, &result)?;
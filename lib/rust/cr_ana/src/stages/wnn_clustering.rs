@@ -0,0 +1,142 @@
+//! Martian stage RUN_WNN_CLUSTERING
+//! Cluster the weighted nearest neighbor graph of gene expression and antibody capture,
+//! and save their joint embedding for UMAP.
+
+use crate::io::{csv, h5};
+use crate::louvain::run_louvain;
+use crate::types::{ClusteringResult, ClusteringType, H5File};
+use crate::wnn::weighted_nearest_neighbors;
+use anyhow::Result;
+use cr_types::reference::feature_reference::FeatureType;
+use cr_types::FeatureBarcodeType;
+use hdf5_io::matrix::read_adaptive_csr_matrix;
+use log::info;
+use martian::prelude::*;
+use martian::MartianVoid;
+use martian_derive::{make_mro, MartianStruct};
+use scan_rs::merge_clusters::merge_clusters;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+const NUM_NEIGHBORS: usize = 20;
+const RESOLUTION: f64 = 1.0;
+const RANDOM_SEED: usize = 0xBADC0FFEE0DDF00D;
+const MODALITIES: [FeatureType; 2] = [
+    FeatureType::Gene,
+    FeatureType::Barcode(FeatureBarcodeType::Antibody),
+];
+
+#[derive(Clone, Debug, Deserialize, MartianStruct)]
+pub struct WnnClusteringStageInputs {
+    matrix_h5: H5File,
+    pca_h5: H5File,
+    num_neighbors: Option<usize>,
+    input_pcs: Option<usize>,
+    resolution: Option<f64>,
+    random_seed: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, MartianStruct)]
+pub struct WnnClusteringStageOutputs {
+    clusters_h5: Option<H5File>,
+    clusters_csv: Option<PathBuf>,
+    wnn_h5: Option<H5File>,
+}
+
+pub struct WnnClusteringStage;
+
+#[make_mro(stage_name = RUN_WNN_CLUSTERING, volatile = strict)]
+impl MartianStage for WnnClusteringStage {
+    type StageInputs = WnnClusteringStageInputs;
+    type StageOutputs = WnnClusteringStageOutputs;
+    type ChunkInputs = MartianVoid;
+    type ChunkOutputs = MartianVoid;
+
+    fn split(
+        &self,
+        args: Self::StageInputs,
+        _rover: MartianRover,
+    ) -> Result<StageDef<Self::ChunkInputs>> {
+        let (_, num_bcs) = h5::matrix_shape(&args.matrix_h5)?;
+        let k = args.num_neighbors.unwrap_or(NUM_NEIGHBORS);
+        // Two neighbor lists per modality and the weighted neighbor graph.
+        let mem_gib = (2.5
+            + h5::estimate_mem_gib_from_nnz(&args.matrix_h5)?
+            + (3 * 8 * num_bcs as usize * k) as f64 / 1e9)
+            .ceil() as isize;
+        Ok(StageDef::with_join_resource(
+            Resource::with_mem_gb(mem_gib).threads(4),
+        ))
+    }
+
+    fn main(
+        &self,
+        _args: Self::StageInputs,
+        _chunk_args: Self::ChunkInputs,
+        _rover: MartianRover,
+    ) -> Result<Self::ChunkOutputs> {
+        unreachable!()
+    }
+
+    fn join(
+        &self,
+        args: Self::StageInputs,
+        _chunk_defs: Vec<Self::ChunkInputs>,
+        _chunk_outs: Vec<Self::ChunkOutputs>,
+        rover: MartianRover,
+    ) -> Result<Self::StageOutputs> {
+        let feature_types = h5::matrix_feature_types(&args.matrix_h5)?;
+        if !MODALITIES
+            .iter()
+            .all(|feature_type| feature_types.get(feature_type).is_some_and(|&n| n >= 2))
+        {
+            return Ok(WnnClusteringStageOutputs {
+                clusters_h5: None,
+                clusters_csv: None,
+                wnn_h5: None,
+            });
+        }
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(rover.get_threads())
+            .build_global()?;
+
+        let [gex, antibody] = MODALITIES.map(|feature_type| {
+            h5::load_transformed_pca_matrix(&args.pca_h5, feature_type, args.input_pcs)
+        });
+        let (gex, antibody) = (gex?, antibody?);
+        let k = args.num_neighbors.unwrap_or(NUM_NEIGHBORS);
+        info!("computing weighted nearest neighbors with k = {k}");
+        let result = weighted_nearest_neighbors(gex.view(), antibody.view(), k);
+        info!(
+            "mean gene expression weight {}",
+            result.weights.iter().sum::<f64>() / result.weights.len() as f64
+        );
+
+        info!("running louvain");
+        let seed = args.random_seed.or(Some(RANDOM_SEED));
+        let labels = run_louvain(
+            &result.neighbors,
+            args.resolution.unwrap_or(RESOLUTION),
+            seed,
+        );
+        let (matrix, _) = read_adaptive_csr_matrix(&args.matrix_h5, None, None)?;
+        let labels = merge_clusters(&matrix, &result.embedding, labels)
+            .into_iter()
+            .map(|v| v as i64 + 1)
+            .collect::<Vec<_>>();
+        let clustering = ClusteringResult::new(ClusteringType::Wnn, FeatureType::Gene, labels);
+
+        let clusters_h5: H5File = rover.make_path("clusters_h5");
+        h5::save_clustering(&clusters_h5, &clustering)?;
+        let clusters_csv: PathBuf = rover.make_path("clusters_csv");
+        csv::save_clustering(&clusters_csv, &clustering, &matrix.barcodes)?;
+        let wnn_h5: H5File = rover.make_path("wnn_h5");
+        h5::save_wnn(&wnn_h5, &result.embedding, &result.weights)?;
+
+        Ok(WnnClusteringStageOutputs {
+            clusters_h5: Some(clusters_h5),
+            clusters_csv: Some(clusters_csv),
+            wnn_h5: Some(wnn_h5),
+        })
+    }
+}
@@ -40,6 +40,7 @@ impl MartianMain for SecondaryTest {
             num_principal_comps: None,
             is_spatial: false,
             pca_map: None,
+            antibody_normalization_h5: None,
         };
         let pca_outs = run_stage(PcaStage, pca_args, &rover)?;
 
@@ -47,6 +48,7 @@ impl MartianMain for SecondaryTest {
         let umap_args = UmapStageInputs {
             matrix_h5: args.matrix_h5.clone(),
             pca_h5: pca_outs.pca_h5,
+            wnn_h5: None,
             random_seed: None,
             n_neighbors: None,
             input_pcs: None,
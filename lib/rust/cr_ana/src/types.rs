@@ -19,10 +19,18 @@ pub(crate) struct PcaResult<'a> {
     pub features_selected: Vec<&'a str>,
     pub transformed_pca_matrix: Array2<f64>,
     pub variance_explained: Array1<f64>,
+    /// The mean of each feature, by which the features are centered before the decomposition.
+    pub feature_mean: Array1<f64>,
+    /// The scale of each feature, by which the features are divided before the decomposition.
+    pub feature_scale: Array1<f64>,
+    /// The UMI count to which each barcode is scaled before the log transform, if the matrix
+    /// was normalized by its UMI counts.
+    pub size_factor_target: Option<f64>,
     pub key: String,
 }
 
 impl<'a> PcaResult<'a> {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         components: Array2<f64>,
         dispersion: Array1<f64>,
@@ -30,6 +38,9 @@ impl<'a> PcaResult<'a> {
         features_selected: Vec<&'a str>,
         transformed_pca_matrix: Array2<f64>,
         variance_explained: Array1<f64>,
+        feature_mean: Array1<f64>,
+        feature_scale: Array1<f64>,
+        size_factor_target: Option<f64>,
     ) -> Self {
         let num_pcs = transformed_pca_matrix.dim().1;
         let key = format!("{}_{num_pcs}", feature_type.as_snake_case());
@@ -39,6 +50,9 @@ impl<'a> PcaResult<'a> {
             features_selected,
             transformed_pca_matrix,
             variance_explained,
+            feature_mean,
+            feature_scale,
+            size_factor_target,
             key,
         }
     }
@@ -56,23 +70,28 @@ pub enum ClusteringType {
     KMeans(usize),
     Louvain,
     Hierarchical(usize),
+    /// Louvain clustering of the weighted nearest neighbor graph of gene expression and
+    /// antibody capture.
+    Wnn,
 }
 
 impl ClusteringType {
     pub(crate) fn lc(&self) -> Cow<'static, str> {
-        use ClusteringType::{Hierarchical, KMeans, Louvain};
+        use ClusteringType::{Hierarchical, KMeans, Louvain, Wnn};
         match self {
             KMeans(k) => Cow::Owned(format!("kmeans_{k}_clusters")),
             Louvain => Cow::Borrowed("graphclust"),
             Hierarchical(num_clusters) => Cow::Owned(format!("hcluster_{num_clusters}_clusters")),
+            Wnn => Cow::Borrowed("wnn_graphclust"),
         }
     }
     pub(crate) fn desc(&self) -> Cow<'static, str> {
-        use ClusteringType::{Hierarchical, KMeans, Louvain};
+        use ClusteringType::{Hierarchical, KMeans, Louvain, Wnn};
         match self {
             KMeans(k) => Cow::Owned(format!("K-means (K={k})")),
             Louvain => Cow::Borrowed("Graph-based"),
             Hierarchical(num_clusters) => Cow::Owned(format!("Hclust (K={num_clusters})")),
+            Wnn => Cow::Borrowed("Weighted Nearest Neighbor Graph-based"),
         }
     }
 }
@@ -83,6 +102,7 @@ impl FromStr for ClusteringType {
     fn from_str(s: &str) -> Result<Self> {
         Ok(match s.strip_prefix('_').unwrap_or(s) {
             "graphclust" => ClusteringType::Louvain,
+            "wnn_graphclust" => ClusteringType::Wnn,
             t if t.starts_with("kmeans") => {
                 let k = t
                     .split('_')
@@ -132,7 +152,7 @@ impl FromStr for ClusteringKey {
         let parts = t.split('_').collect::<Vec<_>>();
         let clustering_key = match parts
             .iter()
-            .position(|&s| s == "graphclust" || s == "kmeans" || s == "hcluster")
+            .position(|&s| s == "graphclust" || s == "kmeans" || s == "hcluster" || s == "wnn")
         {
             None => {
                 bail!("invalid clustering key: {s}");
@@ -161,8 +181,13 @@ pub(crate) struct ClusteringResult {
     pub key: String,
 }
 
+/// Return the key of a clustering. The joint clustering of several feature types is not
+/// prefixed by a feature type.
 pub(crate) fn clustering_key(clustering_type: ClusteringType, feature_type: FeatureType) -> String {
-    format!("{}_{}", feature_type.as_snake_case(), clustering_type.lc())
+    match clustering_type {
+        ClusteringType::Wnn => clustering_type.lc().into_owned(),
+        _ => format!("{}_{}", feature_type.as_snake_case(), clustering_type.lc()),
+    }
 }
 
 impl ClusteringResult {
@@ -220,7 +245,7 @@ pub(crate) struct EmbeddingResult<'a> {
     pub barcodes: Vec<Cow<'a, String>>,
     pub embedding: Array2<f64>,
     pub embedding_type: EmbeddingType,
-    pub feature_type: FeatureType,
+    /// The feature type and number of dimensions, like gene_expression_2.
     pub key: String,
 }
 
@@ -237,8 +262,21 @@ impl<'a> EmbeddingResult<'a> {
             barcodes: barcodes.iter().map(Cow::Borrowed).collect::<Vec<_>>(),
             embedding,
             embedding_type,
-            feature_type,
             key,
         }
     }
+
+    /// Return the embedding of the weighted nearest neighbor integration of gene expression
+    /// and antibody capture.
+    pub(crate) fn new_wnn(
+        barcodes: &'a [String],
+        embedding: Array2<f64>,
+        embedding_type: EmbeddingType,
+    ) -> Self {
+        let dims = embedding.dim().1;
+        EmbeddingResult {
+            key: format!("wnn_{dims}"),
+            ..Self::new(barcodes, embedding, embedding_type, FeatureType::Gene)
+        }
+    }
 }
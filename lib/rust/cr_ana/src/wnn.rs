@@ -0,0 +1,200 @@
+//! Weighted nearest neighbor integration of two modalities
+//! Hao et al. (2021) Integrated analysis of multimodal single-cell data.
+//! The weight of each modality of a barcode is learned from how well the neighbors of the
+//! barcode in that modality predict its position, relative to the neighbors in the other.
+
+use crate::neighbors::{distance, nearest_neighbors, MIN_KERNEL_WIDTH};
+use ndarray::{Array1, Array2, ArrayView1, ArrayView2, Axis};
+use rayon::prelude::*;
+
+/// Added to the cross-modality affinity to avoid dividing by zero.
+const AFFINITY_EPSILON: f64 = 1e-4;
+
+pub(crate) struct WnnResult {
+    /// The weight of the first modality of each barcode. The weight of the second is its
+    /// complement.
+    pub weights: Vec<f64>,
+    /// The k nearest neighbors of each barcode by weighted similarity.
+    pub neighbors: Array2<u32>,
+    /// The concatenation of the two modalities, each scaled to a comparable range and by
+    /// the square root of its weight.
+    pub embedding: Array2<f64>,
+}
+
+/// The nearest neighbors of each barcode in the reduced space of one modality.
+struct Modality<'a> {
+    proj: ArrayView2<'a, f64>,
+    neighbors: Vec<Vec<usize>>,
+    /// The distance of each barcode to its nearest neighbor.
+    nearest: Vec<f64>,
+    /// The mean distance of each barcode to its k nearest neighbors.
+    bandwidth: Vec<f64>,
+}
+
+impl<'a> Modality<'a> {
+    fn new(proj: ArrayView2<'a, f64>, k: usize) -> Self {
+        let neighbors = nearest_neighbors(proj, k);
+        let distances: Vec<Vec<f64>> = neighbors
+            .iter()
+            .enumerate()
+            .map(|(i, row)| {
+                row.iter()
+                    .map(|&j| distance(proj.row(i), proj.row(j)))
+                    .collect()
+            })
+            .collect();
+        let nearest = distances
+            .iter()
+            .map(|d| d.iter().copied().fold(f64::INFINITY, f64::min))
+            .map(|d| if d.is_finite() { d } else { 0.0 })
+            .collect();
+        let bandwidth = distances
+            .iter()
+            .map(|d| d.iter().sum::<f64>() / d.len().max(1) as f64)
+            .collect();
+        Modality {
+            proj,
+            neighbors,
+            nearest,
+            bandwidth,
+        }
+    }
+
+    /// Return the affinity of barcode i to a point, with a kernel adapted to the local density
+    /// of barcode i.
+    fn affinity(&self, i: usize, point: ArrayView1<'_, f64>) -> f64 {
+        let d = distance(self.proj.row(i), point);
+        let width = (self.bandwidth[i] - self.nearest[i]).max(MIN_KERNEL_WIDTH);
+        (-(d - self.nearest[i]).max(0.0) / width).exp()
+    }
+
+    /// Return the mean position of the barcodes.
+    fn predict(&self, barcodes: &[usize]) -> Array1<f64> {
+        self.proj
+            .select(Axis(0), barcodes)
+            .mean_axis(Axis(0))
+            .unwrap_or_else(|| Array1::zeros(self.proj.ncols()))
+    }
+
+    /// Return the ratio of the affinities of barcode i to the predictions of its neighbors in
+    /// this modality and in the other.
+    fn prediction_ratio(&self, other: &Modality<'_>, i: usize) -> f64 {
+        let within = self.affinity(i, self.predict(&self.neighbors[i]).view());
+        let cross = self.affinity(i, self.predict(&other.neighbors[i]).view());
+        within / (cross + AFFINITY_EPSILON)
+    }
+
+    /// Return the median bandwidth, used to scale the modality in the joint embedding.
+    fn scale(&self) -> f64 {
+        let mut bandwidth = self.bandwidth.clone();
+        bandwidth.sort_by(f64::total_cmp);
+        bandwidth
+            .get(bandwidth.len() / 2)
+            .copied()
+            .filter(|&b| b > 0.0)
+            .unwrap_or(1.0)
+    }
+}
+
+/// Integrate the reduced spaces of two modalities of the same barcodes, returning the weight of
+/// each modality, the k nearest neighbors of each barcode by weighted similarity, and a joint
+/// embedding.
+pub(crate) fn weighted_nearest_neighbors(
+    a: ArrayView2<'_, f64>,
+    b: ArrayView2<'_, f64>,
+    k: usize,
+) -> WnnResult {
+    assert_eq!(a.nrows(), b.nrows());
+    let num_barcodes = a.nrows();
+    let k = k.clamp(1, num_barcodes.saturating_sub(1).max(1));
+    let (ma, mb) = (Modality::new(a, k), Modality::new(b, k));
+
+    let weights: Vec<f64> = (0..num_barcodes)
+        .into_par_iter()
+        .map(|i| {
+            let (ra, rb) = (ma.prediction_ratio(&mb, i), mb.prediction_ratio(&ma, i));
+            1.0 / (1.0 + (rb - ra).exp())
+        })
+        .collect();
+
+    // Choose the k most similar barcodes among the neighbors in either modality.
+    let rows: Vec<Vec<u32>> = (0..num_barcodes)
+        .into_par_iter()
+        .map(|i| {
+            let mut candidates: Vec<usize> = ma.neighbors[i]
+                .iter()
+                .chain(&mb.neighbors[i])
+                .copied()
+                .collect();
+            candidates.sort_unstable();
+            candidates.dedup();
+            let mut similarities: Vec<(usize, f64)> = candidates
+                .into_iter()
+                .map(|j| {
+                    let similarity = weights[i] * ma.affinity(i, a.row(j))
+                        + (1.0 - weights[i]) * mb.affinity(i, b.row(j));
+                    (j, similarity)
+                })
+                .collect();
+            similarities.sort_by(|x, y| y.1.total_cmp(&x.1));
+            similarities
+                .into_iter()
+                .take(k)
+                .map(|(j, _)| j as u32)
+                .collect()
+        })
+        .collect();
+    let mut neighbors = Array2::from_elem((num_barcodes, k), u32::MAX);
+    for (mut row, barcode_neighbors) in neighbors.rows_mut().into_iter().zip(rows) {
+        for (x, j) in row.iter_mut().zip(barcode_neighbors) {
+            *x = j;
+        }
+    }
+
+    let (scale_a, scale_b) = (ma.scale(), mb.scale());
+    let mut embedding = Array2::zeros((num_barcodes, a.ncols() + b.ncols()));
+    for (i, mut row) in embedding.rows_mut().into_iter().enumerate() {
+        let (wa, wb) = (weights[i].sqrt(), (1.0 - weights[i]).sqrt());
+        for (x, &y) in row.iter_mut().zip(a.row(i)) {
+            *x = wa * y / scale_a;
+        }
+        for (x, &y) in row.iter_mut().skip(a.ncols()).zip(b.row(i)) {
+            *x = wb * y / scale_b;
+        }
+    }
+
+    WnnResult {
+        weights,
+        neighbors,
+        embedding,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_pcg::Pcg64;
+
+    #[test]
+    fn test_weighted_nearest_neighbors() {
+        // The first modality separates two groups of barcodes, and the second is noise.
+        let num_barcodes = 60;
+        let mut rng = Pcg64::seed_from_u64(0);
+        let a = Array2::from_shape_fn((num_barcodes, 3), |(i, _)| {
+            (i % 2) as f64 * 10.0 + rng.gen_range(-1.0..1.0)
+        });
+        let b = Array2::from_shape_fn((num_barcodes, 3), |_| rng.gen_range(-1.0..1.0));
+
+        let result = weighted_nearest_neighbors(a.view(), b.view(), 5);
+        assert_eq!(result.neighbors.dim(), (num_barcodes, 5));
+        assert_eq!(result.embedding.dim(), (num_barcodes, 6));
+        let mean_weight = result.weights.iter().sum::<f64>() / num_barcodes as f64;
+        assert!(mean_weight > 0.5);
+        for (i, row) in result.neighbors.rows().into_iter().enumerate() {
+            assert!(row
+                .iter()
+                .all(|&j| j as usize != i && j as usize % 2 == i % 2));
+        }
+    }
+}
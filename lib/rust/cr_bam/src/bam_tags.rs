@@ -9,6 +9,8 @@ pub const FEATURE_SEQ_TAG: &[u8] = b"fb";
 pub const FEATURE_IDS_TAG: &[u8] = b"fx";
 pub const PROBE_TAG: &[u8] = b"pr";
 pub const EXTRA_FLAGS_TAG: &[u8] = b"xf";
+/// The sample assigned to the barcode of a multiplexed library.
+pub const SAMPLE_ASSIGNMENT_TAG: &[u8] = b"sa";
 
 pub const RAW_UMI_SEQ_TAG: &[u8] = b"UR";
 pub const RAW_UMI_QUAL_TAG: &[u8] = b"UY";
@@ -56,5 +58,7 @@ bitflags! {
         /// This read's (BC, UMI, feature) combination was not counted only because the read
         /// count was too low. Implies not a LOW_SUPPORT_UMI
         const FILTERED_TARGET_UMI = 32u32;
+        /// This read's barcode was called as a cell.
+        const CELL_BARCODE = 64u32;
     }
 }
@@ -0,0 +1,429 @@
+//! Write a feature-barcode count matrix as an AnnData HDF5 file (h5ad).
+//!
+//! The layout follows the AnnData on-disk specification:
+//! /X     (csr_matrix, one row per barcode and one column per feature)
+//! /obs   (dataframe indexed by barcode)
+//! /var   (dataframe indexed by feature name, like scanpy.read_10x_h5)
+//! /obsm  (dict of 2-d arrays, e.g. X_pca and X_umap)
+//! /layers (dict of csr_matrix, e.g. spliced and unspliced)
+//! /uns   (dict of the library and sample metadata)
+//! /obsp, /varm, /varp (empty dicts)
+
+use crate::count_matrix::CountMatrix;
+use anyhow::{ensure, Result};
+use cr_types::reference::feature_reference::FeatureReference;
+use hdf5::types::VarLenUnicode;
+use hdf5::{Group, H5Type, Location};
+use itertools::Itertools;
+use martian_derive::martian_filetype;
+use ndarray::Array2;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+martian_filetype!(H5adFile, "h5ad");
+
+const ENCODING_TYPE: &str = "encoding-type";
+const ENCODING_VERSION: &str = "encoding-version";
+
+/// A column of an obs or var dataframe.
+pub enum Column {
+    Int(Vec<i64>),
+    Float(Vec<f64>),
+    Bool(Vec<bool>),
+    /// Stored as integer codes into the sorted distinct values.
+    Categorical(Vec<String>),
+}
+
+impl Column {
+    fn len(&self) -> usize {
+        match self {
+            Column::Int(x) => x.len(),
+            Column::Float(x) => x.len(),
+            Column::Bool(x) => x.len(),
+            Column::Categorical(x) => x.len(),
+        }
+    }
+}
+
+/// A dataframe with a string index, stored as the obs or var of an AnnData.
+pub struct DataFrame {
+    pub index: Vec<String>,
+    pub columns: Vec<(String, Column)>,
+}
+
+impl DataFrame {
+    pub fn new(index: Vec<String>) -> Self {
+        DataFrame {
+            index,
+            columns: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, name: &str, column: Column) {
+        assert_eq!(column.len(), self.index.len(), "length of column {name}");
+        self.columns.push((name.to_string(), column));
+    }
+
+    /// The per-feature annotations, matching the var of scanpy.read_10x_h5.
+    pub fn from_feature_reference(feature_ref: &FeatureReference) -> Self {
+        let defs = &feature_ref.feature_defs;
+        let mut var = DataFrame::new(defs.iter().map(|x| x.name.clone()).collect());
+        var.push(
+            "gene_ids",
+            Column::Categorical(defs.iter().map(|x| x.id.clone()).collect()),
+        );
+        var.push(
+            "feature_types",
+            Column::Categorical(defs.iter().map(|x| x.feature_type.to_string()).collect()),
+        );
+        var.push(
+            "genome",
+            Column::Categorical(defs.iter().map(|x| x.genome.to_string()).collect()),
+        );
+        for tag in defs.iter().flat_map(|x| x.tags.keys()).unique().sorted() {
+            let values = defs
+                .iter()
+                .map(|x| x.tags.get(tag).cloned().unwrap_or_default())
+                .collect();
+            var.push(tag, Column::Categorical(values));
+        }
+        var
+    }
+}
+
+/// An unstructured annotation, stored in uns.
+pub enum UnsValue {
+    String(String),
+    Strings(Vec<String>),
+    Ints(Vec<i64>),
+}
+
+/// A sparse count matrix with the same shape as X, stored in layers.
+pub struct Layer {
+    pub name: String,
+    /// The (feature index, count) pairs of each barcode, sorted by feature.
+    pub rows: Vec<Vec<(i32, i32)>>,
+}
+
+/// Write the count matrix and the per-barcode annotations to an h5ad file.
+/// The index of `obs` and the rows of each `obsm` array and layer must match the matrix barcodes.
+pub fn write_h5ad(
+    path: impl AsRef<Path>,
+    matrix: &CountMatrix,
+    obs: &DataFrame,
+    obsm: &[(String, Array2<f64>)],
+    layers: &[Layer],
+    uns: &[(String, UnsValue)],
+) -> Result<()> {
+    let num_barcodes = matrix.num_barcodes();
+    ensure!(
+        obs.index.len() == num_barcodes,
+        "obs has {} rows but the matrix has {num_barcodes} barcodes",
+        obs.index.len()
+    );
+    for (name, array) in obsm {
+        ensure!(
+            array.nrows() == num_barcodes,
+            "obsm {name} has {} rows but the matrix has {num_barcodes} barcodes",
+            array.nrows()
+        );
+    }
+    for layer in layers {
+        ensure!(
+            layer.rows.len() == num_barcodes,
+            "layer {} has {} rows but the matrix has {num_barcodes} barcodes",
+            layer.name,
+            layer.rows.len()
+        );
+    }
+    let shape = [num_barcodes as i64, matrix.num_features() as i64];
+
+    let f = hdf5::File::create(path)?;
+    set_encoding(&f, "anndata", "0.1.0")?;
+
+    let (counts, feature_indices, barcode_count_offsets) = matrix.csr();
+    write_csr(
+        &f.create_group("X")?,
+        shape,
+        counts,
+        feature_indices,
+        barcode_count_offsets,
+    )?;
+
+    write_dataframe(&f.create_group("obs")?, obs)?;
+    write_dataframe(
+        &f.create_group("var")?,
+        &DataFrame::from_feature_reference(matrix.feature_reference()),
+    )?;
+
+    let obsm_group = f.create_group("obsm")?;
+    set_encoding(&obsm_group, "dict", "0.1.0")?;
+    for (name, array) in obsm {
+        let ds = obsm_group
+            .new_dataset::<f64>()
+            .shape(array.dim())
+            .create(name.as_str())?;
+        ds.write(array)?;
+        set_encoding(&ds, "array", "0.2.0")?;
+    }
+
+    let layers_group = f.create_group("layers")?;
+    set_encoding(&layers_group, "dict", "0.1.0")?;
+    for layer in layers {
+        let (indices, counts): (Vec<i32>, Vec<i32>) = layer.rows.iter().flatten().copied().unzip();
+        let indptr: Vec<i64> = std::iter::once(0)
+            .chain(layer.rows.iter().scan(0, |offset, row| {
+                *offset += row.len() as i64;
+                Some(*offset)
+            }))
+            .collect();
+        write_csr(
+            &layers_group.create_group(&layer.name)?,
+            shape,
+            &counts,
+            &indices,
+            &indptr,
+        )?;
+    }
+
+    let uns_group = f.create_group("uns")?;
+    set_encoding(&uns_group, "dict", "0.1.0")?;
+    for (name, value) in uns {
+        match value {
+            UnsValue::String(x) => {
+                let ds = uns_group
+                    .new_dataset::<VarLenUnicode>()
+                    .shape(())
+                    .create(name.as_str())?;
+                ds.write_scalar(&x.parse::<VarLenUnicode>()?)?;
+                set_encoding(&ds, "string", "0.2.0")?;
+            }
+            UnsValue::Strings(x) => write_strings(&uns_group, name, x)?,
+            UnsValue::Ints(x) => write_numeric(&uns_group, name, x)?,
+        }
+    }
+
+    for name in ["obsp", "varm", "varp"] {
+        set_encoding(&f.create_group(name)?, "dict", "0.1.0")?;
+    }
+    Ok(())
+}
+
+fn write_csr(
+    group: &Group,
+    shape: [i64; 2],
+    data: &[i32],
+    indices: &[i32],
+    indptr: &[i64],
+) -> Result<()> {
+    set_encoding(group, "csr_matrix", "0.1.0")?;
+    group
+        .new_attr::<i64>()
+        .shape(2)
+        .create("shape")?
+        .write(&shape[..])?;
+    write_numeric(group, "data", data)?;
+    write_numeric(group, "indices", indices)?;
+    write_numeric(group, "indptr", indptr)
+}
+
+fn set_encoding(loc: &Location, encoding_type: &str, encoding_version: &str) -> Result<()> {
+    str_attr(loc, ENCODING_TYPE, encoding_type)?;
+    str_attr(loc, ENCODING_VERSION, encoding_version)
+}
+
+fn str_attr(loc: &Location, name: &str, value: &str) -> Result<()> {
+    loc.new_attr::<VarLenUnicode>()
+        .shape(())
+        .create(name)?
+        .write_scalar(&value.parse::<VarLenUnicode>()?)?;
+    Ok(())
+}
+
+fn to_varlen(values: &[String]) -> Result<Vec<VarLenUnicode>> {
+    Ok(values
+        .iter()
+        .map(|x| x.parse::<VarLenUnicode>())
+        .try_collect()?)
+}
+
+fn write_numeric<T: H5Type>(group: &Group, name: &str, data: &[T]) -> Result<()> {
+    let mut builder = group.new_dataset::<T>();
+    // Compression requires chunking, which fails for an empty dataset.
+    if !data.is_empty() {
+        builder = builder.shuffle().deflate(1);
+    }
+    let ds = builder.shape((data.len(),)).create(name)?;
+    ds.write(data)?;
+    set_encoding(&ds, "array", "0.2.0")
+}
+
+fn write_strings(group: &Group, name: &str, values: &[String]) -> Result<()> {
+    let ds = group
+        .new_dataset::<VarLenUnicode>()
+        .shape((values.len(),))
+        .create(name)?;
+    ds.write(&to_varlen(values)?)?;
+    set_encoding(&ds, "string-array", "0.2.0")
+}
+
+/// Return the sorted distinct values and the code of each value.
+fn categorical_codes(values: &[String]) -> (Vec<String>, Vec<i32>) {
+    let categories: BTreeMap<&str, i32> = values
+        .iter()
+        .map(String::as_str)
+        .sorted()
+        .dedup()
+        .zip(0..)
+        .collect();
+    let codes = values.iter().map(|x| categories[x.as_str()]).collect();
+    (categories.into_keys().map(String::from).collect(), codes)
+}
+
+fn write_dataframe(group: &Group, df: &DataFrame) -> Result<()> {
+    const INDEX: &str = "_index";
+    set_encoding(group, "dataframe", "0.2.0")?;
+    str_attr(group, INDEX, INDEX)?;
+    let column_order = to_varlen(
+        &df.columns
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect_vec(),
+    )?;
+    group
+        .new_attr::<VarLenUnicode>()
+        .shape(column_order.len())
+        .create("column-order")?
+        .write(&column_order)?;
+
+    write_strings(group, INDEX, &df.index)?;
+    for (name, column) in &df.columns {
+        match column {
+            Column::Int(x) => write_numeric(group, name, x)?,
+            Column::Float(x) => write_numeric(group, name, x)?,
+            Column::Bool(x) => write_numeric(group, name, x)?,
+            Column::Categorical(x) => {
+                let (categories, codes) = categorical_codes(x);
+                let categorical = group.create_group(name)?;
+                set_encoding(&categorical, "categorical", "0.2.0")?;
+                categorical
+                    .new_attr::<bool>()
+                    .shape(())
+                    .create("ordered")?
+                    .write_scalar(&false)?;
+                write_numeric(&categorical, "codes", &codes)?;
+                write_strings(&categorical, "categories", &categories)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::count_matrix::CountMatrixFile;
+    use ndarray::array;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_categorical_codes() {
+        let values = ["b", "a", "b", "c", "a"].map(String::from);
+        let (categories, codes) = categorical_codes(&values);
+        assert_eq!(categories, ["a", "b", "c"]);
+        assert_eq!(codes, [1, 0, 1, 2, 0]);
+
+        let (categories, codes) = categorical_codes(&[]);
+        assert!(categories.is_empty());
+        assert!(codes.is_empty());
+    }
+
+    #[test]
+    fn test_write_h5ad() -> Result<()> {
+        let mat = CountMatrixFile::from_path("test/h5/diff_test_fbm_a.h5").read()?;
+        // Keep a few barcodes with counts.
+        let kept: HashSet<_> = mat
+            .filtered_barcode_counts(|_| true)
+            .filter_map(|(bc, count)| (count > 0).then_some(*bc))
+            .take(50)
+            .collect();
+        let matrix = mat.aggregate_barcodes(|bc| Ok(kept.contains(bc).then(|| bc.to_string())))?;
+        let barcodes: Vec<String> = matrix.barcodes().iter().map(ToString::to_string).collect();
+        let num_barcodes = barcodes.len();
+        assert!(num_barcodes > 1);
+
+        let mut obs = DataFrame::new(barcodes.clone());
+        obs.push("gem_group", Column::Int(vec![1; num_barcodes]));
+        obs.push(
+            "sample",
+            Column::Categorical(vec!["sample1".to_string(); num_barcodes]),
+        );
+        let umap = Array2::from_shape_fn((num_barcodes, 2), |(i, j)| (2 * i + j) as f64);
+        let obsm = [("X_umap".to_string(), umap.clone())];
+        let uns = [
+            (
+                "sample_id".to_string(),
+                UnsValue::String("sample1".to_string()),
+            ),
+            (
+                "library_ids".to_string(),
+                UnsValue::Strings(vec!["library1".to_string()]),
+            ),
+            ("original_gem_groups".to_string(), UnsValue::Ints(vec![1])),
+        ];
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("matrix.h5ad");
+        write_h5ad(&path, &matrix, &obs, &obsm, &[], &uns)?;
+
+        let f = hdf5::File::open(&path)?;
+        let shape = f.group("X")?.attr("shape")?.read_raw::<i64>()?;
+        assert_eq!(shape, [num_barcodes as i64, matrix.num_features() as i64]);
+        let indptr = f.group("X")?.dataset("indptr")?.read_raw::<i64>()?;
+        assert_eq!(indptr.len(), num_barcodes + 1);
+        assert_eq!(*indptr.last().unwrap() as usize, matrix.counts().count());
+
+        let index: Vec<String> = f
+            .group("obs")?
+            .dataset("_index")?
+            .read_raw::<VarLenUnicode>()?
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(index, barcodes);
+        let var_index = f
+            .group("var")?
+            .dataset("_index")?
+            .read_raw::<VarLenUnicode>()?;
+        assert_eq!(var_index.len(), matrix.num_features());
+
+        let obsm_group = f.group("obsm")?;
+        assert_eq!(obsm_group.member_names()?, ["X_umap"]);
+        assert_eq!(obsm_group.dataset("X_umap")?.read_2d::<f64>()?, umap);
+
+        let uns_group = f.group("uns")?;
+        assert_eq!(
+            uns_group
+                .dataset("sample_id")?
+                .read_scalar::<VarLenUnicode>()?
+                .as_str(),
+            "sample1"
+        );
+        assert_eq!(
+            uns_group.dataset("original_gem_groups")?.read_1d::<i64>()?,
+            array![1]
+        );
+        assert_eq!(
+            uns_group
+                .dataset("library_ids")?
+                .read_raw::<VarLenUnicode>()?
+                .len(),
+            1
+        );
+
+        // The number of obs rows must match the matrix.
+        let short_obs = DataFrame::new(barcodes[1..].to_vec());
+        assert!(write_h5ad(&path, &matrix, &short_obs, &[], &[], &[]).is_err());
+        Ok(())
+    }
+}
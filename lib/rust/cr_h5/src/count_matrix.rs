@@ -8,17 +8,20 @@ use barcode::MAX_BARCODE_AND_GEM_GROUP_LENGTH;
 use cr_types::barcode_index::BarcodeIndex;
 use cr_types::reference::feature_reference::{FeatureDef, FeatureReference, FeatureType};
 use cr_types::{BarcodeThenFeatureOrder, CountShardFile, FeatureBarcodeCount, GemWell, H5File};
-use hdf5::types::{FixedAscii, VarLenAscii, VarLenUnicode};
+use hdf5::types::{FixedAscii, TypeDescriptor, VarLenAscii, VarLenUnicode};
 use hdf5::Group;
 use itertools::{process_results, Itertools};
 use martian_derive::martian_filetype;
+use metric::TxHashMap;
 use serde::{Deserialize, Serialize};
 use shardio::ShardReader;
 use std::cell::OnceCell;
+use std::collections::BTreeMap;
 use std::fmt::Display;
 use std::iter::zip;
 use std::mem::size_of;
 use std::ops::Range;
+use std::path::Path;
 use std::str::FromStr;
 
 // Group keys.
@@ -97,6 +100,43 @@ impl CountMatrixFile {
             .context("Ran into trouble reading barcodes from feature bc matrix.")
     }
 
+    /// Read the library metadata stored in the attributes of the file.
+    /// Attributes missing from older files are left empty.
+    pub fn read_metadata(&self) -> Result<MatrixMetadata> {
+        let f = hdf5::File::open(self).with_context(|| format!("While opening {self:?}"))?;
+        let strings = |name: &str| -> Result<Vec<String>> {
+            let Ok(attr) = f.attr(name) else {
+                return Ok(Vec::new());
+            };
+            Ok(match attr.dtype()?.to_descriptor()? {
+                TypeDescriptor::VarLenAscii => attr
+                    .read_raw::<VarLenAscii>()?
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect(),
+                TypeDescriptor::VarLenUnicode => attr
+                    .read_raw::<VarLenUnicode>()?
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect(),
+                TypeDescriptor::FixedAscii(_) | TypeDescriptor::FixedUnicode(_) => attr
+                    .read_raw::<FixedAscii<256>>()?
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect(),
+                descriptor => bail!("unexpected type {descriptor:?} of attribute {name}"),
+            })
+        };
+        Ok(MatrixMetadata {
+            software_version: strings(SOFTWARE_H5_VERSION_KEY)?.into_iter().next(),
+            chemistry_description: strings(H5_CHEMISTRY_DESC_KEY)?.into_iter().next(),
+            library_ids: strings(H5_LIBRARY_ID_MAPPING_KEY)?,
+            original_gem_groups: f
+                .attr(H5_ORIG_GEM_GROUP_MAPPING_KEY)
+                .map_or(Ok(Vec::new()), |attr| attr.read_raw::<i64>())?,
+        })
+    }
+
     fn read_inner(&self) -> Result<CountMatrix> {
         let matrix = self.matrix_group()?;
         Ok(CountMatrix {
@@ -109,6 +149,17 @@ impl CountMatrixFile {
     }
 }
 
+/// The library metadata of a matrix h5 file.
+#[derive(Debug, Default)]
+pub struct MatrixMetadata {
+    pub software_version: Option<String>,
+    pub chemistry_description: Option<String>,
+    /// The library ID of each GEM well
+    pub library_ids: Vec<String>,
+    /// The original GEM well of each GEM well
+    pub original_gem_groups: Vec<i64>,
+}
+
 pub struct MatrixDimensions {
     pub num_features: usize,
     pub num_barcodes: usize,
@@ -252,6 +303,16 @@ impl CountMatrix {
         &self.feature_reference
     }
 
+    /// Return the compressed sparse rows of the matrix, one row per barcode:
+    /// the counts, their feature indices, and the offset of each barcode into both.
+    pub(crate) fn csr(&self) -> (&[Count], &[FeatureIdx], &[BarcodeCountOffset]) {
+        (
+            &self.counts,
+            &self.feature_indices,
+            &self.barcode_count_offsets,
+        )
+    }
+
     pub fn raw_counts(&self) -> impl Iterator<Item = RawCount> + '_ {
         self.barcode_count_offsets
             .iter()
@@ -265,6 +326,128 @@ impl CountMatrix {
                 })
             })
     }
+
+    /// Aggregate the barcodes of this matrix into bins, summing the counts of each feature.
+    /// `bin` returns the aggregated barcode of a barcode, or None to drop its counts.
+    /// The aggregated barcodes are sorted and include only bins with at least one member.
+    pub fn aggregate_barcodes<F>(&self, bin: F) -> Result<CountMatrix>
+    where
+        F: Fn(&BarcodeWithGemGroup) -> Result<Option<String>>,
+    {
+        let mut members: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+        for (i, barcode) in self.barcodes.iter().enumerate() {
+            if let Some(binned) = bin(barcode)? {
+                members.entry(binned).or_default().push(i);
+            }
+        }
+
+        let mut barcodes = Vec::with_capacity(members.len());
+        let mut counts = Vec::new();
+        let mut feature_indices = Vec::new();
+        let mut barcode_count_offsets = vec![0];
+        let mut feature_counts: TxHashMap<FeatureIdx, Count> = TxHashMap::default();
+        for (binned, indices) in members {
+            for i in indices {
+                let range = self.barcode_count_offsets[i] as usize
+                    ..self.barcode_count_offsets[i + 1] as usize;
+                for j in range {
+                    *feature_counts.entry(self.feature_indices[j]).or_default() += self.counts[j];
+                }
+            }
+            for (feature_idx, count) in feature_counts.drain().sorted() {
+                feature_indices.push(feature_idx);
+                counts.push(count);
+            }
+            barcode_count_offsets.push(counts.len() as BarcodeCountOffset);
+            barcodes.push(
+                BarcodeWithGemGroup::from_ascii(&binned)
+                    .with_context(|| format!("invalid aggregated barcode {binned}"))?,
+            );
+        }
+
+        Ok(CountMatrix {
+            counts,
+            barcodes,
+            feature_indices,
+            barcode_count_offsets,
+            feature_reference: self.feature_reference.clone(),
+        })
+    }
+
+    /// Replace the counts of each barcode by the counts returned by `correct`, which is passed the
+    /// barcode index, its feature indices and their counts, and returns a count for each feature.
+    /// Features whose corrected count is zero are dropped.
+    pub fn map_barcode_counts<F>(&self, mut correct: F) -> CountMatrix
+    where
+        F: FnMut(usize, &[FeatureIdx], &[Count]) -> Vec<Count>,
+    {
+        let mut counts = Vec::with_capacity(self.counts.len());
+        let mut feature_indices = Vec::with_capacity(self.feature_indices.len());
+        let mut barcode_count_offsets = Vec::with_capacity(self.barcode_count_offsets.len());
+        barcode_count_offsets.push(0);
+        for (barcode_idx, (&start, &end)) in self
+            .barcode_count_offsets
+            .iter()
+            .tuple_windows()
+            .enumerate()
+        {
+            let range = start as usize..end as usize;
+            let features = &self.feature_indices[range.clone()];
+            let corrected = correct(barcode_idx, features, &self.counts[range]);
+            assert_eq!(corrected.len(), features.len());
+            for (&feature_idx, count) in zip(features, corrected) {
+                if count != 0 {
+                    feature_indices.push(feature_idx);
+                    counts.push(count);
+                }
+            }
+            barcode_count_offsets.push(counts.len() as BarcodeCountOffset);
+        }
+
+        CountMatrix {
+            counts,
+            barcodes: self.barcodes.clone(),
+            feature_indices,
+            barcode_count_offsets,
+            feature_reference: self.feature_reference.clone(),
+        }
+    }
+
+    /// Write this matrix to a feature-barcode matrix h5 file.
+    pub fn write_h5(&self, path: impl AsRef<Path>, software_version: &str) -> Result<()> {
+        let f = hdf5::File::create(path)?;
+        let group = f.create_group(MATRIX_GROUP)?;
+        write_column_ds(&group, DATASET_BARCODES, &self.barcodes)?;
+        write_column_ds(&group, DATASET_DATA, &self.counts)?;
+        write_column_ds(&group, DATASET_FEATURE_INDICES, &self.feature_indices)?;
+        write_column_ds(
+            &group,
+            DATASET_BARCODE_COUNT_OFFSETS,
+            &self.barcode_count_offsets,
+        )?;
+        write_column_ds(
+            &group,
+            MATRIX_SHAPE_DATASET,
+            &[self.num_features() as i32, self.num_barcodes() as i32],
+        )?;
+        feature_reference_io::to_h5(
+            &self.feature_reference,
+            &mut group.create_group(FEATURE_REF_GROUP)?,
+        )?;
+
+        scalar_attribute(
+            &f,
+            H5_FILETYPE_KEY,
+            VarLenUnicode::from_str(MATRIX_H5_FILETYPE)?,
+        )?;
+        scalar_attribute(&f, MATRIX_H5_VERSION_KEY, MATRIX_H5_VERSION)?;
+        scalar_attribute(
+            &f,
+            SOFTWARE_H5_VERSION_KEY,
+            VarLenUnicode::from_str(software_version)?,
+        )?;
+        Ok(())
+    }
 }
 
 pub struct RawCount {
@@ -508,6 +691,46 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_aggregate_barcodes() -> Result<()> {
+        let mat = CountMatrixFile::from_path(&matrix_path("diff_test_fbm_a.h5")).read()?;
+        let total: i64 = mat.counts.iter().map(|&x| x as i64).sum();
+
+        // Aggregate every other barcode into a single bin and drop the rest.
+        let kept: HashSet<_> = mat.barcodes().iter().step_by(2).copied().collect();
+        let kept_total: i64 = mat
+            .iter_barcode_ranges()
+            .step_by(2)
+            .flat_map(|(_, range)| range.map(|i| mat.counts[i] as i64))
+            .sum();
+        let binned =
+            mat.aggregate_barcodes(|bc| Ok(kept.contains(bc).then(|| "ALL-1".to_string())))?;
+        assert_eq!(binned.barcodes().len(), 1);
+        assert_eq!(binned.barcode_count_offsets.len(), 2);
+        assert_eq!(
+            binned.counts().map(|c| c.count as i64).sum::<i64>(),
+            kept_total
+        );
+        assert!(binned.feature_indices.windows(2).all(|w| w[0] < w[1]));
+
+        // The identity aggregation preserves the matrix.
+        let identity = mat.aggregate_barcodes(|bc| Ok(Some(bc.to_string())))?;
+        assert_eq!(
+            identity.counts.iter().map(|&x| x as i64).sum::<i64>(),
+            total
+        );
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("binned.h5");
+        binned.write_h5(&path, "test")?;
+        let reread = CountMatrixFile::from_path(&path).read()?;
+        assert_eq!(reread.barcodes(), binned.barcodes());
+        assert_eq!(reread.counts, binned.counts);
+        assert_eq!(reread.feature_indices, binned.feature_indices);
+        assert_eq!(reread.feature_reference(), binned.feature_reference());
+        Ok(())
+    }
+
     #[test]
     fn test_load_dimensions() -> Result<()> {
         let mat = CountMatrixFile::from_path(&matrix_path("diff_test_fbm_a.h5"));
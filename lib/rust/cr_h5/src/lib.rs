@@ -1,11 +1,14 @@
 use anyhow::Result;
 use hdf5::{Dataset, Extents, Group, H5Type};
 
+pub mod anndata;
 pub mod compare;
 pub mod count_matrix;
 pub mod feature_reference_io;
 pub mod molecule_info;
+pub mod parquet_export;
 pub mod probe_reference_io;
+pub mod semantic_compare;
 
 /// Write a scalar attribute to the given group, with the given attribute name.
 pub fn scalar_attribute<T: H5Type>(group: &Group, name: &str, value: T) -> Result<()> {
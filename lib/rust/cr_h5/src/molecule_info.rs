@@ -179,6 +179,120 @@ impl UniformDownSampler {
     }
 }
 
+/// Read count distribution of the molecules in the cell barcodes of a library, used to predict
+/// the UMI counts and the sequencing saturation after subsampling the reads of the library
+/// at a given rate. A molecule with `c` reads survives subsampling at rate `r` with probability
+/// `1 - (1 - r)^c`.
+#[derive(Clone, Debug, Default)]
+pub struct LibraryDepthProfile {
+    /// For each cell barcode, the number of molecules with each read count
+    per_cell: Vec<Vec<(CountType, u64)>>,
+    /// The cell barcode of the last element of `per_cell`
+    last_barcode: Option<BarcodeIdxType>,
+    /// The number of molecules with each read count across all the cell barcodes
+    total: TxHashMap<CountType, u64>,
+}
+
+impl LibraryDepthProfile {
+    /// Compute the depth profile of each library in the molecule info, indexed by library_idx,
+    /// in a single pass over the molecules.
+    pub fn from_molecule_info(path: &Path) -> Result<Vec<LibraryDepthProfile>> {
+        let num_libraries = MoleculeInfoReader::read_library_info(path)?.len();
+        let mut profiles = vec![LibraryDepthProfile::default(); num_libraries];
+        for umi in MoleculeInfoIterator::new(path)?.cell_barcodes_only(true)? {
+            profiles[umi.umi_data.library_idx as usize]
+                .observe(umi.barcode_idx, umi.umi_data.read_count);
+        }
+        Ok(profiles)
+    }
+
+    /// Observe a molecule. The molecules of a barcode must be contiguous, as they are in a
+    /// molecule info sorted by gem group and barcode.
+    pub fn observe(&mut self, barcode_idx: BarcodeIdxType, read_count: CountType) {
+        if read_count == 0 {
+            return;
+        }
+        if self.last_barcode != Some(barcode_idx) {
+            self.per_cell.push(Vec::new());
+            self.last_barcode = Some(barcode_idx);
+        }
+        let cell = self.per_cell.last_mut().unwrap();
+        match cell.iter_mut().find(|(c, _)| *c == read_count) {
+            Some((_, n)) => *n += 1,
+            None => cell.push((read_count, 1)),
+        }
+        *self.total.entry(read_count).or_default() += 1;
+    }
+
+    fn expected_umis<'a>(
+        counts: impl IntoIterator<Item = (&'a CountType, &'a u64)>,
+        rate: f64,
+    ) -> f64 {
+        counts
+            .into_iter()
+            .map(|(&c, &n)| n as f64 * (1.0 - (1.0 - rate).powi(c as i32)))
+            .sum()
+    }
+
+    /// Expected sequencing saturation, `1 - UMIs / reads`, after subsampling at `rate`
+    pub fn expected_saturation(&self, rate: f64) -> f64 {
+        let total_reads: f64 = self.total.iter().map(|(&c, &n)| c as f64 * n as f64).sum();
+        let reads = rate * total_reads;
+        if reads == 0.0 {
+            return 0.0;
+        }
+        1.0 - Self::expected_umis(&self.total, rate) / reads
+    }
+
+    /// Expected median UMIs per cell after subsampling at `rate`
+    pub fn expected_median_umis_per_cell(&self, rate: f64) -> f64 {
+        let mut umis: Vec<f64> = self
+            .per_cell
+            .iter()
+            .map(|counts| Self::expected_umis(counts.iter().map(|(c, n)| (c, n)), rate))
+            .collect();
+        if umis.is_empty() {
+            return 0.0;
+        }
+        umis.sort_by(f64::total_cmp);
+        let mid = umis.len() / 2;
+        if umis.len() % 2 == 0 {
+            (umis[mid - 1] + umis[mid]) / 2.0
+        } else {
+            umis[mid]
+        }
+    }
+
+    /// Subsampling rate at which the expected sequencing saturation is `target`
+    pub fn rate_for_saturation(&self, target: f64) -> f64 {
+        bisect_rate(|rate| self.expected_saturation(rate), target)
+    }
+
+    /// Subsampling rate at which the expected median UMIs per cell is `target`
+    pub fn rate_for_median_umis_per_cell(&self, target: f64) -> f64 {
+        bisect_rate(|rate| self.expected_median_umis_per_cell(rate), target)
+    }
+}
+
+/// Find the rate in (0, 1] at which the increasing function `f` reaches `target`.
+/// Returns 1.0 if `f(1.0)` does not exceed the target.
+fn bisect_rate(f: impl Fn(f64) -> f64, target: f64) -> f64 {
+    const ITERATIONS: usize = 50;
+    if f(1.0) <= target {
+        return 1.0;
+    }
+    let (mut lo, mut hi) = (0.0, 1.0);
+    for _ in 0..ITERATIONS {
+        let mid = (lo + hi) / 2.0;
+        if f(mid) < target {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    hi
+}
+
 // Workaround for the rust hdf5 bindings not seeming to have a
 // an easy way to iterate through the data.
 pub struct MolInfoCache {
@@ -570,7 +684,12 @@ impl MoleculeInfoReader {
                 LibraryInfo::Count(x) => {
                     (x.library_type == library_type).then_some((x.library_id, x.gem_group))
                 }
-                LibraryInfo::Aggr(_) => unimplemented!(),
+                LibraryInfo::Aggr(x) => {
+                    let aggr_library_type =
+                        x.library_type.as_deref().map(str::parse::<LibraryType>);
+                    matches!(aggr_library_type, Some(Ok(t)) if t == library_type)
+                        .then_some((x.library_id, x.gem_group))
+                }
             })
             .collect();
 
@@ -1274,6 +1393,39 @@ mod molecule_info_tests {
         );
     }
 
+    #[test]
+    fn test_library_depth_profile() {
+        let mut profile = LibraryDepthProfile::default();
+        // Cell 0: 4 molecules with 1 read, cell 1: 2 molecules with 2 reads
+        // and cell 2: 1 molecule with 4 reads
+        for _ in 0..4 {
+            profile.observe(0, 1);
+        }
+        profile.observe(1, 2);
+        profile.observe(1, 2);
+        profile.observe(2, 4);
+        profile.observe(2, 0);
+
+        // 7 UMIs and 12 reads
+        assert!((profile.expected_saturation(1.0) - 5.0 / 12.0).abs() < 1e-9);
+        assert_eq!(profile.expected_median_umis_per_cell(1.0), 2.0);
+
+        // Cell 1 has 2 * (1 - 0.25) = 1.5 UMIs at rate 0.5
+        assert!((profile.expected_median_umis_per_cell(0.5) - 1.5).abs() < 1e-9);
+        assert!(profile.expected_saturation(0.5) < profile.expected_saturation(1.0));
+
+        let rate = profile.rate_for_median_umis_per_cell(1.5);
+        assert!((rate - 0.5).abs() < 1e-6);
+        assert_eq!(profile.rate_for_median_umis_per_cell(10.0), 1.0);
+
+        let rate = profile.rate_for_saturation(0.3);
+        assert!((profile.expected_saturation(rate) - 0.3).abs() < 1e-6);
+
+        let empty = LibraryDepthProfile::default();
+        assert_eq!(empty.expected_saturation(1.0), 0.0);
+        assert_eq!(empty.expected_median_umis_per_cell(1.0), 0.0);
+    }
+
     #[test]
     fn test_mol_info_reader() {
         let mol_info_path = Path::new("test/h5/pbmc_1k_v2_molecule_info.h5");
@@ -0,0 +1,520 @@
+//! Export molecule_info.h5 and barcode_summary.h5 as Parquet, to query them with DuckDB or Polars.
+//!
+//! The molecules are written as a hive-partitioned dataset with one directory per library:
+//! molecule_info_parquet/library_idx=0/part-0.parquet
+//! molecule_info_parquet/library_idx=1/part-0.parquet
+//!
+//! The barcode summary is written as a single Parquet file with one row per barcode.
+//! Both start with a sample_id column, so that the exports of many runs can be queried together.
+
+use crate::count_matrix::BarcodeWithGemGroup;
+use crate::molecule_info::{
+    BarcodeIdxType, FullUmiCount, GemGroupType, LibraryIdxType, MoleculeInfoIterator,
+    MoleculeInfoReader,
+};
+use anyhow::{bail, ensure, Context, Result};
+use arrow::array::{
+    Array, ArrayRef, BooleanBuilder, PrimitiveArray, StringArray, StringBuilder, UInt16Builder,
+    UInt32Builder,
+};
+use arrow::datatypes::{
+    ArrowPrimitiveType, DataType, Field, Float32Type, Float64Type, Int16Type, Int32Type, Int64Type,
+    Int8Type, Schema, SchemaRef, UInt16Type, UInt32Type, UInt64Type, UInt8Type,
+};
+use arrow::record_batch::RecordBatch;
+use barcode::{Barcode, BarcodeContent};
+use cr_types::reference::feature_reference::FeatureReference;
+use hdf5::types::{FloatSize, IntSize, TypeDescriptor};
+use hdf5::H5Type;
+use itertools::Itertools;
+use martian_derive::martian_filetype;
+use metric::{TxHashMap, TxHashSet};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use std::collections::hash_map::Entry;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use umi::{UmiType, MAX_UMI_LENGTH};
+
+martian_filetype!(ParquetFile, "parquet");
+
+/// The number of rows buffered before a record batch is handed to the writer.
+const BATCH_SIZE: usize = 1 << 16;
+/// The number of rows of each row group.
+const ROW_GROUP_SIZE: usize = 1 << 20;
+
+/// The column of the sample of every row.
+const SAMPLE_ID: &str = "sample_id";
+/// The hive partition column of the molecule dataset.
+const LIBRARY_IDX_PARTITION: &str = "library_idx";
+/// The barcode dataset of barcode_summary.h5.
+const BC_SEQUENCE: &str = "bc_sequence";
+/// The UMI definition of the chemistry in the molecule_info.h5 metrics.
+const CHEMISTRY_UMI_METRIC: &str = "chemistry_umi";
+
+fn writer_properties() -> WriterProperties {
+    WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .set_max_row_group_size(ROW_GROUP_SIZE)
+        .build()
+}
+
+/// The columns of the molecule dataset. The library is stored in the partition path.
+fn molecule_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new(SAMPLE_ID, DataType::Utf8, false),
+        Field::new("barcode", DataType::Utf8, false),
+        Field::new("gem_group", DataType::UInt16, false),
+        Field::new("is_cell", DataType::Boolean, false),
+        Field::new("umi", DataType::Utf8, false),
+        Field::new("feature_idx", DataType::UInt32, false),
+        Field::new("feature_id", DataType::Utf8, false),
+        Field::new("feature_name", DataType::Utf8, false),
+        Field::new("feature_type", DataType::Utf8, false),
+        Field::new("reads", DataType::UInt32, false),
+        Field::new("umi_type", DataType::Utf8, false),
+    ]))
+}
+
+/// Return the UMI length of the chemistry, which is the sum of the lengths of its UMI parts.
+fn read_umi_length(molecule_info: &Path) -> Result<usize> {
+    let metrics: serde_json::Value =
+        serde_json::from_str(&MoleculeInfoReader::read_metrics(molecule_info)?)?;
+    let umi = &metrics[CHEMISTRY_UMI_METRIC];
+    let parts = match umi {
+        serde_json::Value::Array(parts) => parts.iter().collect(),
+        _ => vec![umi],
+    };
+    let umi_length = parts
+        .into_iter()
+        .map(|part| part["length"].as_u64())
+        .sum::<Option<u64>>()
+        .with_context(|| format!("{CHEMISTRY_UMI_METRIC} has no length: {umi}"))?
+        as usize;
+    ensure!(
+        (1..=MAX_UMI_LENGTH).contains(&umi_length),
+        "unsupported UMI length {umi_length}"
+    );
+    Ok(umi_length)
+}
+
+/// Decode a UMI packed two bits per base, with the first base in the most significant bits,
+/// as stored in molecule_info.h5.
+fn decode_umi(umi: u32, umi_length: usize, seq: &mut String) {
+    seq.clear();
+    seq.extend(
+        (0..umi_length)
+            .rev()
+            .map(|i| b"ACGT"[(umi >> (2 * i)) as usize & 3] as char),
+    );
+}
+
+fn umi_type_str(umi_type: UmiType) -> &'static str {
+    match umi_type {
+        UmiType::Txomic => "txomic",
+        UmiType::NonTxomic => "non_txomic",
+    }
+}
+
+/// Accumulate the molecules of one library into record batches.
+#[derive(Default)]
+struct MoleculeBatchBuilder {
+    sample_id: StringBuilder,
+    barcode: StringBuilder,
+    gem_group: UInt16Builder,
+    is_cell: BooleanBuilder,
+    umi: StringBuilder,
+    feature_idx: UInt32Builder,
+    feature_id: StringBuilder,
+    feature_name: StringBuilder,
+    feature_type: StringBuilder,
+    reads: UInt32Builder,
+    umi_type: StringBuilder,
+    len: usize,
+}
+
+impl MoleculeBatchBuilder {
+    fn push(
+        &mut self,
+        sample_id: &str,
+        barcode: &str,
+        is_cell: bool,
+        umi_seq: &str,
+        umi: &FullUmiCount,
+        features: &FeatureReference,
+    ) {
+        let feature = &features.feature_defs[umi.umi_data.feature_idx as usize];
+        self.sample_id.append_value(sample_id);
+        self.barcode.append_value(barcode);
+        self.gem_group.append_value(umi.gem_group);
+        self.is_cell.append_value(is_cell);
+        self.umi.append_value(umi_seq);
+        self.feature_idx.append_value(umi.umi_data.feature_idx);
+        self.feature_id.append_value(&feature.id);
+        self.feature_name.append_value(&feature.name);
+        self.feature_type
+            .append_value(feature.feature_type.to_string());
+        self.reads.append_value(umi.umi_data.read_count);
+        self.umi_type.append_value(umi_type_str(umi.umi_data.utype));
+        self.len += 1;
+    }
+
+    /// Return the buffered rows as a record batch and reset the builder.
+    fn finish(&mut self, schema: &SchemaRef) -> Result<RecordBatch> {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(self.sample_id.finish()),
+            Arc::new(self.barcode.finish()),
+            Arc::new(self.gem_group.finish()),
+            Arc::new(self.is_cell.finish()),
+            Arc::new(self.umi.finish()),
+            Arc::new(self.feature_idx.finish()),
+            Arc::new(self.feature_id.finish()),
+            Arc::new(self.feature_name.finish()),
+            Arc::new(self.feature_type.finish()),
+            Arc::new(self.reads.finish()),
+            Arc::new(self.umi_type.finish()),
+        ];
+        self.len = 0;
+        Ok(RecordBatch::try_new(schema.clone(), columns)?)
+    }
+}
+
+/// The Parquet writer of one library partition.
+struct LibraryPartition {
+    writer: ArrowWriter<File>,
+    batch: MoleculeBatchBuilder,
+    path: PathBuf,
+}
+
+impl LibraryPartition {
+    fn create(dir: &Path, library_idx: LibraryIdxType, schema: &SchemaRef) -> Result<Self> {
+        let partition_dir = dir.join(format!("{LIBRARY_IDX_PARTITION}={library_idx}"));
+        std::fs::create_dir_all(&partition_dir)?;
+        let path = partition_dir.join("part-0.parquet");
+        let writer = ArrowWriter::try_new(
+            File::create(&path)?,
+            schema.clone(),
+            Some(writer_properties()),
+        )?;
+        Ok(LibraryPartition {
+            writer,
+            batch: MoleculeBatchBuilder::default(),
+            path,
+        })
+    }
+
+    fn flush(&mut self, schema: &SchemaRef) -> Result<()> {
+        if self.batch.len > 0 {
+            self.writer.write(&self.batch.finish(schema)?)?;
+        }
+        Ok(())
+    }
+}
+
+/// Format the barcode of a molecule, reusing the previous string
+/// since the molecules are sorted by barcode.
+struct BarcodeFormatter {
+    barcodes: Vec<BarcodeContent>,
+    last: Option<(BarcodeIdxType, GemGroupType, String)>,
+}
+
+impl BarcodeFormatter {
+    fn format(&mut self, barcode_idx: BarcodeIdxType, gem_group: GemGroupType) -> &str {
+        if !matches!(&self.last, Some((idx, gg, _)) if *idx == barcode_idx && *gg == gem_group) {
+            let barcode =
+                Barcode::with_content(gem_group, self.barcodes[barcode_idx as usize], true);
+            self.last = Some((barcode_idx, gem_group, barcode.to_string()));
+        }
+        &self.last.as_ref().unwrap().2
+    }
+}
+
+/// Write the molecules of a molecule_info.h5 file of the sample `sample_id` to a Parquet dataset
+/// partitioned by library in the directory `dir`.
+/// Return the paths of the Parquet files, one per library having molecules.
+pub fn write_molecule_info_parquet(
+    molecule_info: &Path,
+    sample_id: &str,
+    dir: &Path,
+) -> Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(dir)?;
+    let schema = molecule_schema();
+
+    let (pass_filter, _genomes) = MoleculeInfoReader::read_barcode_info(molecule_info)?;
+    let cells: TxHashSet<(BarcodeIdxType, LibraryIdxType)> = pass_filter
+        .outer_iter()
+        .map(|row| (row[0], row[1] as LibraryIdxType))
+        .collect();
+    let mut barcodes = BarcodeFormatter {
+        barcodes: MoleculeInfoReader::read_barcodes(molecule_info)?,
+        last: None,
+    };
+
+    let umi_length = read_umi_length(molecule_info)?;
+    let mut umi_seq = String::with_capacity(umi_length);
+
+    let molecules = MoleculeInfoIterator::new(molecule_info)?;
+    let features = molecules.feature_ref.clone();
+    let mut partitions: TxHashMap<LibraryIdxType, LibraryPartition> = TxHashMap::default();
+    for umi in molecules {
+        let library_idx = umi.umi_data.library_idx;
+        let partition = match partitions.entry(library_idx) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(LibraryPartition::create(dir, library_idx, &schema)?)
+            }
+        };
+        let is_cell = cells.contains(&(umi.barcode_idx, library_idx));
+        let barcode = barcodes.format(umi.barcode_idx, umi.gem_group);
+        decode_umi(umi.umi_data.umi, umi_length, &mut umi_seq);
+        partition
+            .batch
+            .push(sample_id, barcode, is_cell, &umi_seq, &umi, &features);
+        if partition.batch.len >= BATCH_SIZE {
+            partition.flush(&schema)?;
+        }
+    }
+
+    partitions
+        .into_iter()
+        .sorted_by_key(|(library_idx, _)| *library_idx)
+        .map(|(_, mut partition)| {
+            partition.flush(&schema)?;
+            partition.writer.close()?;
+            Ok(partition.path)
+        })
+        .collect()
+}
+
+fn read_primitive<T: ArrowPrimitiveType>(dataset: &hdf5::Dataset) -> Result<ArrayRef>
+where
+    T::Native: H5Type,
+{
+    Ok(Arc::new(PrimitiveArray::<T>::from_iter_values(
+        dataset.read_raw::<T::Native>()?,
+    )))
+}
+
+/// Read a numeric dataset as an Arrow array of the same type.
+fn read_numeric_column(dataset: &hdf5::Dataset) -> Result<ArrayRef> {
+    match dataset.dtype()?.to_descriptor()? {
+        TypeDescriptor::Unsigned(IntSize::U1) => read_primitive::<UInt8Type>(dataset),
+        TypeDescriptor::Unsigned(IntSize::U2) => read_primitive::<UInt16Type>(dataset),
+        TypeDescriptor::Unsigned(IntSize::U4) => read_primitive::<UInt32Type>(dataset),
+        TypeDescriptor::Unsigned(IntSize::U8) => read_primitive::<UInt64Type>(dataset),
+        TypeDescriptor::Integer(IntSize::U1) => read_primitive::<Int8Type>(dataset),
+        TypeDescriptor::Integer(IntSize::U2) => read_primitive::<Int16Type>(dataset),
+        TypeDescriptor::Integer(IntSize::U4) => read_primitive::<Int32Type>(dataset),
+        TypeDescriptor::Integer(IntSize::U8) => read_primitive::<Int64Type>(dataset),
+        TypeDescriptor::Float(FloatSize::U4) => read_primitive::<Float32Type>(dataset),
+        TypeDescriptor::Float(FloatSize::U8) => read_primitive::<Float64Type>(dataset),
+        td => bail!("{} has unsupported type {td:?}", dataset.name()),
+    }
+}
+
+/// Write the per-barcode counts of a barcode_summary.h5 file of the sample `sample_id` to a
+/// Parquet file. The first columns are the sample and the barcode, followed by one column per
+/// dataset of the HDF5 file, of the type of that dataset.
+pub fn write_barcode_summary_parquet(
+    barcode_summary: &Path,
+    sample_id: &str,
+    path: &Path,
+) -> Result<()> {
+    let file = hdf5::File::open(barcode_summary)?;
+    let barcodes = file
+        .dataset(BC_SEQUENCE)?
+        .read_raw::<BarcodeWithGemGroup>()?;
+
+    let mut fields = vec![
+        Field::new(SAMPLE_ID, DataType::Utf8, false),
+        Field::new("barcode", DataType::Utf8, false),
+    ];
+    let mut columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from(vec![sample_id; barcodes.len()])),
+        Arc::new(
+            barcodes
+                .iter()
+                .map(|barcode| Some(barcode.as_str()))
+                .collect::<StringArray>(),
+        ),
+    ];
+    for name in file.member_names()?.into_iter().sorted() {
+        if name == BC_SEQUENCE {
+            continue;
+        }
+        let column = read_numeric_column(&file.dataset(&name)?)?;
+        ensure!(
+            column.len() == barcodes.len(),
+            "{name} has {} rows but {BC_SEQUENCE} has {}",
+            column.len(),
+            barcodes.len()
+        );
+        fields.push(Field::new(&name, column.data_type().clone(), false));
+        columns.push(column);
+    }
+
+    let batch = RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)?;
+    let mut writer = ArrowWriter::try_new(
+        File::create(path)?,
+        batch.schema(),
+        Some(writer_properties()),
+    )?;
+    writer.write(&batch)?;
+    writer.close()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{BooleanArray, UInt32Array};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    #[test]
+    fn test_molecule_batch() -> Result<()> {
+        let schema = molecule_schema();
+        let mut batch = MoleculeBatchBuilder::default();
+        let batch = batch.finish(&schema)?;
+        assert_eq!(batch.num_rows(), 0);
+        assert_eq!(batch.num_columns(), schema.fields().len());
+        assert_eq!(umi_type_str(UmiType::NonTxomic), "non_txomic");
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_umi() {
+        let mut seq = String::new();
+        decode_umi(0b00_01_10_11, 4, &mut seq);
+        assert_eq!(seq, "ACGT");
+        decode_umi(0b11_00_01_10_11, 6, &mut seq);
+        assert_eq!(seq, "ATACGT");
+    }
+
+    #[test]
+    fn test_write_barcode_summary_parquet() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let h5_path = dir.path().join("barcode_summary.h5");
+        {
+            let file = hdf5::File::create(&h5_path)?;
+            let barcodes = ["AAAC-1", "AAAG-1"]
+                .map(|barcode| BarcodeWithGemGroup::from_ascii(barcode).unwrap());
+            file.new_dataset::<BarcodeWithGemGroup>()
+                .shape((barcodes.len(),))
+                .create(BC_SEQUENCE)?
+                .write(&barcodes)?;
+            file.new_dataset::<u64>()
+                .shape((2,))
+                .create("reads")?
+                .write(&[5_000_000_000_u64, 7])?;
+            file.new_dataset::<u32>()
+                .shape((2,))
+                .create("umis")?
+                .write(&[3_u32, 4])?;
+        }
+        let parquet_path = dir.path().join("barcode_summary.parquet");
+        write_barcode_summary_parquet(&h5_path, "sample1", &parquet_path)?;
+
+        let batches: Vec<RecordBatch> =
+            ParquetRecordBatchReaderBuilder::try_new(File::open(&parquet_path)?)?
+                .build()?
+                .try_collect()?;
+        let batch = &batches[0];
+        let schema = batch.schema();
+        assert_eq!(
+            schema
+                .fields()
+                .iter()
+                .map(|field| (field.name().as_str(), field.data_type().clone()))
+                .collect::<Vec<_>>(),
+            [
+                (SAMPLE_ID, DataType::Utf8),
+                ("barcode", DataType::Utf8),
+                ("reads", DataType::UInt64),
+                ("umis", DataType::UInt32),
+            ]
+        );
+        let sample_id = batch
+            .column(0)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert_eq!(sample_id.value(1), "sample1");
+        let reads = batch
+            .column(2)
+            .as_any()
+            .downcast_ref::<arrow::array::UInt64Array>()
+            .unwrap();
+        assert_eq!(reads.value(0), 5_000_000_000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_molecule_info_parquet() -> Result<()> {
+        let mol_info_path = Path::new("test/h5/pbmc_1k_v2_molecule_info.h5");
+        let dir = tempfile::tempdir()?;
+        let paths = write_molecule_info_parquet(mol_info_path, "pbmc_1k", dir.path())?;
+        assert_eq!(
+            paths,
+            [dir.path().join("library_idx=0").join("part-0.parquet")]
+        );
+
+        let batches: Vec<RecordBatch> =
+            ParquetRecordBatchReaderBuilder::try_new(File::open(&paths[0])?)?
+                .build()?
+                .try_collect()?;
+        assert_eq!(
+            batches.iter().map(RecordBatch::num_rows).sum::<usize>(),
+            MoleculeInfoIterator::new(mol_info_path)?.count()
+        );
+        let num_cells = batches
+            .iter()
+            .map(|batch| {
+                batch
+                    .column_by_name("is_cell")
+                    .unwrap()
+                    .as_any()
+                    .downcast_ref::<BooleanArray>()
+                    .unwrap()
+                    .true_count()
+            })
+            .sum::<usize>();
+        assert!(num_cells > 0);
+
+        // Compare the first batch with the molecules of the molecule info.
+        let batch = &batches[0];
+        let column = |name: &str| batch.column_by_name(name).unwrap().clone();
+        let sample_id = column("sample_id");
+        let sample_id = sample_id.as_any().downcast_ref::<StringArray>().unwrap();
+        let barcode = column("barcode");
+        let barcode = barcode.as_any().downcast_ref::<StringArray>().unwrap();
+        let umi = column("umi");
+        let umi = umi.as_any().downcast_ref::<StringArray>().unwrap();
+        let feature_idx = column("feature_idx");
+        let feature_idx = feature_idx.as_any().downcast_ref::<UInt32Array>().unwrap();
+        let reads = column("reads");
+        let reads = reads.as_any().downcast_ref::<UInt32Array>().unwrap();
+        let barcodes = MoleculeInfoReader::read_barcodes(mol_info_path)?;
+        let umi_length = read_umi_length(mol_info_path)?;
+        assert_eq!(umi_length, 10);
+        let mut umi_seq = String::new();
+        for (i, molecule) in MoleculeInfoIterator::new(mol_info_path)?
+            .take(batch.num_rows())
+            .enumerate()
+        {
+            let expected_barcode = Barcode::with_content(
+                molecule.gem_group,
+                barcodes[molecule.barcode_idx as usize],
+                true,
+            );
+            assert_eq!(sample_id.value(i), "pbmc_1k");
+            assert_eq!(barcode.value(i), expected_barcode.to_string());
+            decode_umi(molecule.umi_data.umi, umi_length, &mut umi_seq);
+            assert_eq!(umi.value(i), umi_seq);
+            assert_eq!(feature_idx.value(i), molecule.umi_data.feature_idx);
+            assert_eq!(reads.value(i), molecule.umi_data.read_count);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,691 @@
+//! Semantic comparison of feature-barcode matrices and molecule_info files.
+//!
+//! Unlike `compare`, which compares the raw datasets, the counts are aligned by barcode string
+//! and feature ID, so that two files listing the same barcodes or features in a different order
+//! compare equal. The counts of a molecule_info file are the UMI counts of each barcode and
+//! feature, and its cells are the Gene Expression cell calls. The cells of a matrix are its
+//! barcodes, so that two filtered matrices compare their cell calls. Either default may be
+//! replaced by a filtered barcodes CSV.
+
+use crate::count_matrix::CountMatrixFile;
+use crate::molecule_info::{
+    BarcodeIdxType, GemGroupType, MoleculeInfoIterator, MoleculeInfoReader,
+};
+use anyhow::{bail, ensure, Result};
+use barcode::Barcode;
+use cr_types::filtered_barcodes::{read_filtered_barcodes_set, FilteredBarcodesCsv};
+use cr_types::LibraryType;
+use itertools::{EitherOrBoth, Itertools};
+use martian::MartianFileType;
+use metric::{TxHashMap, TxHashSet};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+
+/// The number of barcodes and features with the largest discrepancies to report.
+const NUM_DISCREPANCIES: usize = 20;
+/// The number of added and removed barcodes and features to list.
+const NUM_EXAMPLES: usize = 10;
+/// Count the barcodes and features whose correlation is below this threshold.
+const CORRELATION_THRESHOLD: f64 = 0.99;
+
+/// The kind of HDF5 file being compared.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum H5Kind {
+    Matrix,
+    MoleculeInfo,
+}
+
+impl H5Kind {
+    /// Detect whether the file is a feature-barcode matrix or a molecule_info file.
+    pub fn detect(path: &Path) -> Result<Self> {
+        let members = hdf5::File::open(path)?.member_names()?;
+        if members.iter().any(|m| m == "matrix") {
+            Ok(H5Kind::Matrix)
+        } else if members.iter().any(|m| m == "barcode_info") {
+            Ok(H5Kind::MoleculeInfo)
+        } else {
+            bail!(
+                "{} is neither a feature-barcode matrix nor a molecule_info file",
+                path.display()
+            )
+        }
+    }
+}
+
+impl fmt::Display for H5Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            H5Kind::Matrix => "feature-barcode matrix",
+            H5Kind::MoleculeInfo => "molecule_info",
+        })
+    }
+}
+
+/// The counts of a file keyed by barcode and feature.
+struct SparseCounts {
+    barcodes: Vec<String>,
+    features: Vec<String>,
+    /// The (barcode index, feature index, count) triples, one per barcode and feature.
+    counts: Vec<(u32, u32, i64)>,
+    /// The cell barcodes.
+    cells: TxHashSet<String>,
+}
+
+fn read_matrix(path: &Path) -> Result<SparseCounts> {
+    let matrix = CountMatrixFile::from_path(path).read()?;
+    let barcodes: Vec<String> = matrix
+        .barcodes()
+        .iter()
+        .map(|bc| bc.as_str().to_string())
+        .collect();
+    Ok(SparseCounts {
+        cells: barcodes.iter().cloned().collect(),
+        barcodes,
+        features: matrix
+            .feature_reference()
+            .feature_defs
+            .iter()
+            .map(|f| f.id.clone())
+            .collect(),
+        counts: matrix
+            .raw_counts()
+            .map(|c| (c.barcode_idx as u32, c.feature_idx as u32, c.count as i64))
+            .collect(),
+    })
+}
+
+fn read_molecule_info(path: &Path) -> Result<SparseCounts> {
+    let barcode_contents = MoleculeInfoReader::read_barcodes(path)?;
+    let molecules = MoleculeInfoIterator::new(path)?;
+    let features = molecules
+        .feature_ref
+        .feature_defs
+        .iter()
+        .map(|f| f.id.clone())
+        .collect();
+
+    let mut barcodes = Vec::new();
+    let mut barcode_idxs: TxHashMap<(BarcodeIdxType, GemGroupType), u32> = TxHashMap::default();
+    let mut umis: TxHashMap<(u32, u32), i64> = TxHashMap::default();
+    for umi in molecules {
+        let next_idx = barcodes.len() as u32;
+        let barcode_idx = *barcode_idxs
+            .entry((umi.barcode_idx, umi.gem_group))
+            .or_insert_with(|| {
+                let content = barcode_contents[umi.barcode_idx as usize];
+                barcodes.push(Barcode::with_content(umi.gem_group, content, true).to_string());
+                next_idx
+            });
+        *umis
+            .entry((barcode_idx, umi.umi_data.feature_idx))
+            .or_default() += 1;
+    }
+
+    let cells = MoleculeInfoReader::read_filtered_barcodes(path, LibraryType::Gex)?
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    Ok(SparseCounts {
+        barcodes,
+        features,
+        counts: umis
+            .into_iter()
+            .map(|((barcode, feature), count)| (barcode, feature, count))
+            .collect(),
+        cells,
+    })
+}
+
+/// The barcodes, features or cells present in either file.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SetDiff {
+    pub num_a: usize,
+    pub num_b: usize,
+    pub num_shared: usize,
+    /// The number present only in file B
+    pub num_added: usize,
+    /// The number present only in file A
+    pub num_removed: usize,
+    pub jaccard: f64,
+    /// The first few added elements, sorted
+    pub added: Vec<String>,
+    /// The first few removed elements, sorted
+    pub removed: Vec<String>,
+}
+
+impl SetDiff {
+    fn new<'a>(a: impl IntoIterator<Item = &'a str>, b: impl IntoIterator<Item = &'a str>) -> Self {
+        let a: TxHashSet<&str> = a.into_iter().collect();
+        let b: TxHashSet<&str> = b.into_iter().collect();
+        let num_shared = a.intersection(&b).count();
+        let num_union = a.len() + b.len() - num_shared;
+        let examples = |x: &TxHashSet<&str>, y: &TxHashSet<&str>| {
+            x.difference(y)
+                .sorted()
+                .take(NUM_EXAMPLES)
+                .map(|s| s.to_string())
+                .collect()
+        };
+        SetDiff {
+            num_a: a.len(),
+            num_b: b.len(),
+            num_shared,
+            num_added: b.len() - num_shared,
+            num_removed: a.len() - num_shared,
+            jaccard: if num_union == 0 {
+                1.0
+            } else {
+                num_shared as f64 / num_union as f64
+            },
+            added: examples(&b, &a),
+            removed: examples(&a, &b),
+        }
+    }
+
+    fn is_identical(&self) -> bool {
+        self.num_added == 0 && self.num_removed == 0
+    }
+}
+
+/// The distribution of the correlation of the counts of each barcode or feature.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CorrelationSummary {
+    /// The number of barcodes or features with a defined correlation
+    pub num_compared: usize,
+    pub median: Option<f64>,
+    pub min: Option<f64>,
+    pub num_below_threshold: usize,
+    pub threshold: f64,
+}
+
+impl CorrelationSummary {
+    fn new(mut correlations: Vec<f64>) -> Self {
+        correlations.sort_by(f64::total_cmp);
+        CorrelationSummary {
+            num_compared: correlations.len(),
+            median: (!correlations.is_empty()).then(|| correlations[correlations.len() / 2]),
+            min: correlations.first().copied(),
+            num_below_threshold: correlations
+                .iter()
+                .take_while(|&&r| r < CORRELATION_THRESHOLD)
+                .count(),
+            threshold: CORRELATION_THRESHOLD,
+        }
+    }
+}
+
+/// A barcode or feature whose total counts differ between the two files.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Discrepancy {
+    pub name: String,
+    pub count_a: i64,
+    pub count_b: i64,
+    pub difference: i64,
+}
+
+/// Return the barcodes or features with the largest absolute difference of their total counts.
+fn largest_discrepancies(names: &[&str], totals_a: &[i64], totals_b: &[i64]) -> Vec<Discrepancy> {
+    itertools::izip!(names, totals_a, totals_b)
+        .filter(|(_, a, b)| a != b)
+        .map(|(name, &count_a, &count_b)| Discrepancy {
+            name: name.to_string(),
+            count_a,
+            count_b,
+            difference: count_b - count_a,
+        })
+        .sorted_by(|x, y| {
+            y.difference
+                .abs()
+                .cmp(&x.difference.abs())
+                .then_with(|| x.name.cmp(&y.name))
+        })
+        .take(NUM_DISCREPANCIES)
+        .collect()
+}
+
+/// Accumulate the sums of a Pearson correlation of sparse vectors.
+#[derive(Clone, Copy, Default)]
+struct Pearson {
+    sx: f64,
+    sy: f64,
+    sxx: f64,
+    syy: f64,
+    sxy: f64,
+}
+
+impl Pearson {
+    fn add(&mut self, x: f64, y: f64) {
+        self.sx += x;
+        self.sy += y;
+        self.sxx += x * x;
+        self.syy += y * y;
+        self.sxy += x * y;
+    }
+
+    /// Return the correlation of two vectors of length `n`,
+    /// whose entries missing from the sums are zero.
+    /// Return None when either vector is constant.
+    fn correlation(&self, n: usize) -> Option<f64> {
+        let n = n as f64;
+        let var_x = self.sxx - self.sx * self.sx / n;
+        let var_y = self.syy - self.sy * self.sy / n;
+        let cov = self.sxy - self.sx * self.sy / n;
+        (var_x > 0.0 && var_y > 0.0).then(|| cov / (var_x * var_y).sqrt())
+    }
+
+    fn dense(totals_a: &[i64], totals_b: &[i64]) -> Option<f64> {
+        let mut pearson = Pearson::default();
+        for (&x, &y) in totals_a.iter().zip(totals_b) {
+            pearson.add(x as f64, y as f64);
+        }
+        pearson.correlation(totals_a.len())
+    }
+}
+
+/// Map the names of file A and of file B to the index of the names present in both.
+fn shared_indices<'a>(
+    a: &'a [String],
+    b: &'a [String],
+) -> (Vec<&'a str>, Vec<Option<u32>>, Vec<Option<u32>>) {
+    let b_idx: TxHashMap<&str, usize> =
+        b.iter().enumerate().map(|(i, s)| (s.as_str(), i)).collect();
+    let mut shared = Vec::new();
+    let mut a_to_shared = vec![None; a.len()];
+    let mut b_to_shared = vec![None; b.len()];
+    for (i, name) in a.iter().enumerate() {
+        if let Some(&j) = b_idx.get(name.as_str()) {
+            a_to_shared[i] = Some(shared.len() as u32);
+            b_to_shared[j] = Some(shared.len() as u32);
+            shared.push(name.as_str());
+        }
+    }
+    (shared, a_to_shared, b_to_shared)
+}
+
+/// Return the counts of the shared barcodes and features, indexed by their shared index
+/// and sorted by barcode then feature.
+fn align_counts(
+    counts: &SparseCounts,
+    barcode_map: &[Option<u32>],
+    feature_map: &[Option<u32>],
+) -> Vec<(u32, u32, i64)> {
+    counts
+        .counts
+        .iter()
+        .filter_map(|&(bc, feature, count)| {
+            Some((
+                barcode_map[bc as usize]?,
+                feature_map[feature as usize]?,
+                count,
+            ))
+        })
+        .sorted_unstable()
+        .collect()
+}
+
+/// The semantic comparison of two feature-barcode matrices or molecule_info files A and B.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SemanticDiff {
+    pub kind: H5Kind,
+    /// True when both files have the same barcodes, features, counts and cells
+    pub identical: bool,
+    pub total_counts_a: i64,
+    pub total_counts_b: i64,
+    pub barcodes: SetDiff,
+    pub features: SetDiff,
+    pub cells: SetDiff,
+    /// The number of shared barcode and feature pairs whose counts differ
+    pub num_differing_counts: usize,
+    /// The correlation of the total counts of the shared barcodes
+    pub barcode_total_correlation: Option<f64>,
+    /// The correlation of the total counts of the shared features
+    pub feature_total_correlation: Option<f64>,
+    /// The correlation of the counts of each shared barcode across the shared features
+    pub per_barcode_correlation: CorrelationSummary,
+    /// The correlation of the counts of each shared feature across the shared barcodes
+    pub per_feature_correlation: CorrelationSummary,
+    pub barcode_discrepancies: Vec<Discrepancy>,
+    pub feature_discrepancies: Vec<Discrepancy>,
+}
+
+impl SemanticDiff {
+    fn new(kind: H5Kind, a: &SparseCounts, b: &SparseCounts) -> Self {
+        let (barcodes, bc_a, bc_b) = shared_indices(&a.barcodes, &b.barcodes);
+        let (features, feat_a, feat_b) = shared_indices(&a.features, &b.features);
+
+        let mut barcode_totals = (vec![0; barcodes.len()], vec![0; barcodes.len()]);
+        let mut feature_totals = (vec![0; features.len()], vec![0; features.len()]);
+        let mut per_barcode = vec![Pearson::default(); barcodes.len()];
+        let mut per_feature = vec![Pearson::default(); features.len()];
+        let mut num_differing_counts = 0;
+        for entry in align_counts(a, &bc_a, &feat_a)
+            .into_iter()
+            .merge_join_by(align_counts(b, &bc_b, &feat_b), |x, y| {
+                (x.0, x.1).cmp(&(y.0, y.1))
+            })
+        {
+            let (bc, feature, x, y) = match entry {
+                EitherOrBoth::Both((bc, feature, x), (_, _, y)) => (bc, feature, x, y),
+                EitherOrBoth::Left((bc, feature, x)) => (bc, feature, x, 0),
+                EitherOrBoth::Right((bc, feature, y)) => (bc, feature, 0, y),
+            };
+            let (bc, feature) = (bc as usize, feature as usize);
+            if x != y {
+                num_differing_counts += 1;
+            }
+            barcode_totals.0[bc] += x;
+            barcode_totals.1[bc] += y;
+            feature_totals.0[feature] += x;
+            feature_totals.1[feature] += y;
+            per_barcode[bc].add(x as f64, y as f64);
+            per_feature[feature].add(x as f64, y as f64);
+        }
+
+        let barcode_diff = SetDiff::new(
+            a.barcodes.iter().map(String::as_str),
+            b.barcodes.iter().map(String::as_str),
+        );
+        let feature_diff = SetDiff::new(
+            a.features.iter().map(String::as_str),
+            b.features.iter().map(String::as_str),
+        );
+        let cells = SetDiff::new(
+            a.cells.iter().map(String::as_str),
+            b.cells.iter().map(String::as_str),
+        );
+        let identical = barcode_diff.is_identical()
+            && feature_diff.is_identical()
+            && num_differing_counts == 0
+            && cells.is_identical();
+
+        SemanticDiff {
+            kind,
+            identical,
+            total_counts_a: a.counts.iter().map(|c| c.2).sum(),
+            total_counts_b: b.counts.iter().map(|c| c.2).sum(),
+            barcodes: barcode_diff,
+            features: feature_diff,
+            cells,
+            num_differing_counts,
+            barcode_total_correlation: Pearson::dense(&barcode_totals.0, &barcode_totals.1),
+            feature_total_correlation: Pearson::dense(&feature_totals.0, &feature_totals.1),
+            per_barcode_correlation: CorrelationSummary::new(
+                per_barcode
+                    .iter()
+                    .filter_map(|p| p.correlation(features.len()))
+                    .collect(),
+            ),
+            per_feature_correlation: CorrelationSummary::new(
+                per_feature
+                    .iter()
+                    .filter_map(|p| p.correlation(barcodes.len()))
+                    .collect(),
+            ),
+            barcode_discrepancies: largest_discrepancies(
+                &barcodes,
+                &barcode_totals.0,
+                &barcode_totals.1,
+            ),
+            feature_discrepancies: largest_discrepancies(
+                &features,
+                &feature_totals.0,
+                &feature_totals.1,
+            ),
+        }
+    }
+}
+
+/// Compare two feature-barcode matrices or two molecule_info files,
+/// aligning their counts by barcode and feature ID.
+/// Compare the cells of the filtered barcodes CSVs when specified.
+pub fn semantic_compare(
+    a: &Path,
+    b: &Path,
+    filtered_barcodes_a: Option<&FilteredBarcodesCsv>,
+    filtered_barcodes_b: Option<&FilteredBarcodesCsv>,
+) -> Result<SemanticDiff> {
+    let kind = H5Kind::detect(a)?;
+    let kind_b = H5Kind::detect(b)?;
+    ensure!(
+        kind == kind_b,
+        "cannot compare the {kind} {} to the {kind_b} {}",
+        a.display(),
+        b.display()
+    );
+    let read = match kind {
+        H5Kind::Matrix => read_matrix,
+        H5Kind::MoleculeInfo => read_molecule_info,
+    };
+    let read_with_cells = |path: &Path, filtered_barcodes: Option<&FilteredBarcodesCsv>| {
+        let mut counts = read(path)?;
+        if let Some(filtered_barcodes) = filtered_barcodes {
+            counts.cells = read_filtered_barcodes_set(filtered_barcodes)?
+                .iter()
+                .map(ToString::to_string)
+                .collect();
+        }
+        anyhow::Ok(counts)
+    };
+    Ok(SemanticDiff::new(
+        kind,
+        &read_with_cells(a, filtered_barcodes_a)?,
+        &read_with_cells(b, filtered_barcodes_b)?,
+    ))
+}
+
+fn fmt_correlation(r: Option<f64>) -> String {
+    r.map_or_else(|| "n/a".to_string(), |r| format!("{r:.6}"))
+}
+
+fn fmt_set_diff(f: &mut fmt::Formatter<'_>, title: &str, diff: &SetDiff) -> fmt::Result {
+    writeln!(
+        f,
+        "{title}: {} in A, {} in B, {} shared, {} added, {} removed (Jaccard {:.4})",
+        diff.num_a, diff.num_b, diff.num_shared, diff.num_added, diff.num_removed, diff.jaccard
+    )?;
+    if !diff.added.is_empty() {
+        writeln!(f, "    added: {}", diff.added.join(", "))?;
+    }
+    if !diff.removed.is_empty() {
+        writeln!(f, "    removed: {}", diff.removed.join(", "))?;
+    }
+    Ok(())
+}
+
+fn fmt_correlation_summary(
+    f: &mut fmt::Formatter<'_>,
+    title: &str,
+    summary: &CorrelationSummary,
+) -> fmt::Result {
+    writeln!(
+        f,
+        "{title}: median {}, min {}, {} of {} below {}",
+        fmt_correlation(summary.median),
+        fmt_correlation(summary.min),
+        summary.num_below_threshold,
+        summary.num_compared,
+        summary.threshold
+    )
+}
+
+fn fmt_discrepancies(
+    f: &mut fmt::Formatter<'_>,
+    title: &str,
+    discrepancies: &[Discrepancy],
+) -> fmt::Result {
+    if discrepancies.is_empty() {
+        return Ok(());
+    }
+    writeln!(f, "\n{title}:")?;
+    writeln!(
+        f,
+        "    {:<40} {:>12} {:>12} {:>12}",
+        "name", "A", "B", "B - A"
+    )?;
+    for d in discrepancies {
+        writeln!(
+            f,
+            "    {:<40} {:>12} {:>12} {:>12}",
+            d.name, d.count_a, d.count_b, d.difference
+        )?;
+    }
+    Ok(())
+}
+
+/// A human-readable report of the comparison.
+impl fmt::Display for SemanticDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Semantic comparison of two {} files: {}\n",
+            self.kind,
+            if self.identical {
+                "identical"
+            } else {
+                "different"
+            }
+        )?;
+        writeln!(
+            f,
+            "Total counts: {} in A, {} in B",
+            self.total_counts_a, self.total_counts_b
+        )?;
+        fmt_set_diff(f, "Barcodes", &self.barcodes)?;
+        fmt_set_diff(f, "Features", &self.features)?;
+        fmt_set_diff(f, "Cells", &self.cells)?;
+        writeln!(
+            f,
+            "Differing counts of shared barcodes and features: {}",
+            self.num_differing_counts
+        )?;
+        writeln!(
+            f,
+            "Correlation of the barcode total counts: {}",
+            fmt_correlation(self.barcode_total_correlation)
+        )?;
+        writeln!(
+            f,
+            "Correlation of the feature total counts: {}",
+            fmt_correlation(self.feature_total_correlation)
+        )?;
+        fmt_correlation_summary(f, "Per-barcode correlation", &self.per_barcode_correlation)?;
+        fmt_correlation_summary(f, "Per-feature correlation", &self.per_feature_correlation)?;
+        fmt_discrepancies(
+            f,
+            "Barcodes with the largest discrepancies",
+            &self.barcode_discrepancies,
+        )?;
+        fmt_discrepancies(
+            f,
+            "Features with the largest discrepancies",
+            &self.feature_discrepancies,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sparse(barcodes: &[&str], features: &[&str], counts: &[(u32, u32, i64)]) -> SparseCounts {
+        SparseCounts {
+            barcodes: barcodes.iter().map(ToString::to_string).collect(),
+            features: features.iter().map(ToString::to_string).collect(),
+            counts: counts.to_vec(),
+            cells: barcodes.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    #[test]
+    fn test_reordered_is_identical() {
+        let a = sparse(
+            &["AAAC-1", "CCCG-1"],
+            &["g1", "g2", "g3"],
+            &[(0, 0, 1), (0, 2, 3), (1, 1, 2), (1, 2, 5)],
+        );
+        let b = sparse(
+            &["CCCG-1", "AAAC-1"],
+            &["g3", "g1", "g2"],
+            &[(0, 2, 2), (0, 0, 5), (1, 1, 1), (1, 0, 3)],
+        );
+        let diff = SemanticDiff::new(H5Kind::Matrix, &a, &b);
+        assert!(diff.identical);
+        assert_eq!(diff.num_differing_counts, 0);
+        assert_eq!(diff.per_barcode_correlation.num_compared, 2);
+        assert_eq!(diff.per_barcode_correlation.num_below_threshold, 0);
+        assert!(diff.barcode_discrepancies.is_empty());
+    }
+
+    #[test]
+    fn test_differences() {
+        let a = sparse(
+            &["AAAC-1", "CCCG-1", "GGGT-1"],
+            &["g1", "g2"],
+            &[(0, 0, 10), (1, 1, 4), (2, 0, 1)],
+        );
+        let b = sparse(
+            &["AAAC-1", "CCCG-1", "TTTA-1"],
+            &["g1", "g2", "g3"],
+            &[(0, 0, 10), (1, 1, 7), (2, 2, 9)],
+        );
+        let diff = SemanticDiff::new(H5Kind::Matrix, &a, &b);
+        assert!(!diff.identical);
+        assert_eq!(diff.barcodes.added, vec!["TTTA-1"]);
+        assert_eq!(diff.barcodes.removed, vec!["GGGT-1"]);
+        assert_eq!(diff.barcodes.jaccard, 0.5);
+        assert_eq!(diff.features.num_added, 1);
+        assert_eq!(diff.num_differing_counts, 1);
+        assert_eq!(
+            diff.barcode_discrepancies,
+            vec![Discrepancy {
+                name: "CCCG-1".to_string(),
+                count_a: 4,
+                count_b: 7,
+                difference: 3,
+            }]
+        );
+        assert_eq!(diff.total_counts_b, 26);
+        assert!(diff.to_string().contains("added: TTTA-1"));
+    }
+
+    #[test]
+    fn test_cells() {
+        let a = sparse(&["AAAC-1", "CCCG-1"], &["g1"], &[(0, 0, 1), (1, 0, 2)]);
+        let mut b = sparse(&["AAAC-1", "CCCG-1"], &["g1"], &[(0, 0, 1), (1, 0, 2)]);
+        b.cells = ["AAAC-1".to_string()].into_iter().collect();
+        let diff = SemanticDiff::new(H5Kind::Matrix, &a, &b);
+        assert!(!diff.identical);
+        assert_eq!(diff.num_differing_counts, 0);
+        assert_eq!(diff.cells.num_shared, 1);
+        assert_eq!(diff.cells.removed, vec!["CCCG-1"]);
+        assert!(diff.to_string().contains("Cells: 2 in A, 1 in B"));
+    }
+
+    #[test]
+    fn test_pearson() {
+        let mut p = Pearson::default();
+        p.add(1.0, 2.0);
+        p.add(3.0, 6.0);
+        // The third entry is zero in both vectors.
+        assert!((p.correlation(3).unwrap() - 1.0).abs() < 1e-12);
+        assert_eq!(Pearson::default().correlation(3), None);
+        assert_eq!(Pearson::dense(&[1, 2, 3], &[3, 2, 1]), Some(-1.0));
+    }
+
+    #[test]
+    fn test_semantic_compare_files() -> Result<()> {
+        hdf5::silence_errors(true);
+        let diff = semantic_compare(
+            Path::new("test/h5/diff_test_fbm_a.h5"),
+            Path::new("test/h5/diff_test_fbm_b.h5"),
+            None,
+            None,
+        )?;
+        assert_eq!(diff.kind, H5Kind::Matrix);
+        assert!(diff.identical);
+        assert!(diff.cells.is_identical());
+        Ok(())
+    }
+}
@@ -298,9 +298,14 @@ impl Aligner {
             let pd_align_feature_bc_read =
                 args.is_pd && self.aligner.is_some() && pd_rng.gen_bool(PD_FRAC_FB_READS_TO_ALIGN);
             let ann = self.annotate_read(args, read, pd_align_feature_bc_read);
+            let library_type = ann.read.library_type;
             dup_builder
-                .entry(ann.read.library_type)
-                .or_default()
+                .entry(library_type)
+                .or_insert_with(|| {
+                    // Splice types are only meaningful for Gene Expression molecules.
+                    DupBuilder::new()
+                        .with_splice_types(args.velocity_layers && library_type == LibraryType::Gex)
+                })
                 .observe(&ann, &self.reference);
             annotations.push(ann)?;
         }
@@ -82,6 +82,7 @@ fn main() -> Result<()> {
     let (stage_registry, mro_registry) = martian_stages![
         cr_lib::stages::align_and_count::AlignAndCount,
         cr_lib::stages::barcode_correction::BarcodeCorrection,
+        cr_lib::stages::bin_count_matrix::BinCountMatrix,
         cr_lib::stages::build_per_sample_vdj_ws_contents::BuildPerSampleVdjWsContents,
         cr_lib::stages::call_tags_overhang::CallTagsOH,
         cr_lib::stages::call_tags_rtl::CallTagsRTL,
@@ -96,6 +97,8 @@ fn main() -> Result<()> {
         cr_lib::stages::demux_probe_bc_matrix::DemuxProbeBcMatrix,
         cr_lib::stages::detect_chemistry::DetectChemistry,
         cr_lib::stages::detect_vdj_receptor::DetectVdjReceptor,
+        cr_lib::stages::diff_h5_files::DiffH5Files,
+        cr_lib::stages::estimate_ambient_rna::EstimateAmbientRna,
         cr_lib::stages::extract_single_chemistry::ExtractSingleChemistry,
         cr_lib::stages::get_chemistry_def::GetChemistryDef,
         cr_lib::stages::get_gdna_metrics::GetGdnaMetrics,
@@ -112,14 +115,19 @@ fn main() -> Result<()> {
         cr_lib::stages::set_targeted_umi_filter::SetTargetedUmiFilter,
         cr_lib::stages::setup_vdj_analysis::SetupVdjAnalysis,
         cr_lib::stages::setup_vdj_demux::SetupVDJDemux,
+        cr_lib::stages::split_bam::SplitBam,
         cr_lib::stages::subsample_barcodes::SubsampleBarcodes,
+        cr_lib::stages::tag_bam_cells::TagBamCells,
         cr_lib::stages::write_barcode_index::WriteBarcodeIndex,
         cr_lib::stages::write_barcode_summary::WriteBarcodeSummary,
+        cr_lib::stages::write_effective_parameters::WriteEffectiveParameters,
         cr_lib::stages::write_gene_index::WriteGeneIndex,
         cr_lib::stages::write_h5_matrix::WriteH5Matrix,
+        cr_lib::stages::write_h5ad::WriteH5ad,
         cr_lib::stages::write_matrix_market::WriteMatrixMarket,
         cr_lib::stages::write_molecule_info::WriteMoleculeInfo,
         cr_lib::stages::write_multi_web_summary_json::WriteMultiWebSummaryJson,
+        cr_lib::stages::write_parquet::WriteParquet,
         cr_lib::stages::write_pos_bam::WritePosBam,
     ];
 
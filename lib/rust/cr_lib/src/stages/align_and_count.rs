@@ -26,6 +26,7 @@ use cr_types::rna_read::{RnaChunk, RnaRead, HIGH_CONF_MAPQ};
 use cr_types::spill_vec::SpillVec;
 use cr_types::types::{
     BarcodeSetFormat, BcUmiInfo, FeatureBarcodeCount, GemWell, ProbeBarcodeCount,
+    SpliceBarcodeCount, TomlFile,
 };
 use cr_types::{
     AlignerParam, BarcodeThenFeatureOrder, CountShardFile, FeatureCountFormat, TotalBcCountFormat,
@@ -105,6 +106,9 @@ pub struct StageInputs {
     pub is_pd: bool,
     pub no_bam: bool,
 
+    /// Count spliced, unspliced and ambiguous Gene Expression molecules for RNA velocity.
+    pub velocity_layers: bool,
+
     /// If this is Some(r), we filter out (UMI, genes) pairs
     /// with **less than** r reads and not include them in UMI counts.
     /// Useful in targeting
@@ -135,6 +139,10 @@ pub struct StageInputs {
     /// set of changes and is deferred to the future
     pub barcode_subset: Option<BarcodeSetFormat>,
 
+    /// The effective parameters.toml of WRITE_EFFECTIVE_PARAMETERS,
+    /// which sets the command line parameters of STAR.
+    pub parameters: Option<TomlFile>,
+
     #[allow(unused)]
     pub chevron_correction_factor: Option<f64>,
     #[allow(unused)]
@@ -152,6 +160,7 @@ pub struct ChunkInputs {
 pub struct ChunkOutputs {
     counts_bc_order_shard: CountShardFile,
     probe_barcode_counts_shard: Option<CountShardFile>,
+    splice_counts_bc_order_shard: Option<CountShardFile>,
     bc_umi_info_shard: BcUmiInfoShardFile,
     pos_sorted_shard: AlignShardFile,
     bam_header: Option<PathBuf>,
@@ -169,6 +178,10 @@ pub struct StageOutputs {
     /// probe x barcode counts in barcode order
     pub probe_barcode_counts: Option<Vec<CountShardFile>>,
 
+    /// feature x barcode counts of spliced, unspliced and ambiguous molecules
+    /// in barcode order, used for RNA velocity
+    pub splice_counts_bc_order: Option<Vec<CountShardFile>>,
+
     /// UMI/molecule information for each barcode
     /// This includes read count per UMI and used
     /// to construct the molecule_info.h5 file.
@@ -261,6 +274,7 @@ where
     bc_umi_info_sender: ShardSender<BcUmiInfo, BcUmiInfo>,
     bc_counts_sender: ShardSender<FeatureBarcodeCount, BarcodeThenFeatureOrder>,
     bc_probe_counts_sender: Option<ShardSender<ProbeBarcodeCount>>,
+    bc_splice_counts_sender: Option<ShardSender<SpliceBarcodeCount, BarcodeThenFeatureOrder>>,
     pos_reads_sender: ShardSender<Record, BamPosSort>,
     visitor: V,
     // set of barcodes to align/annotate
@@ -296,11 +310,15 @@ where
             barcode_subsample_rate,
         )?;
         let mut umi_counts = Vec::new();
+        let mut umi_splice_types = Vec::new();
 
         for ann in annotations_iter {
             let ann = ann?;
             if let Some(count) = ann.umi_count() {
                 umi_counts.push(count);
+                if let Some(splice_type) = ann.umi_splice_type() {
+                    umi_splice_types.push((count.feature_idx, splice_type));
+                }
             }
 
             self.visitor.visit_read_annotation(&ann);
@@ -322,6 +340,13 @@ where
                 self.bc_counts_sender.send(c)?;
             }
 
+            // Generate individual spliced, unspliced and ambiguous counts & send
+            if let Some(bc_splice_counts_sender) = self.bc_splice_counts_sender.as_mut() {
+                for c in SpliceBarcodeCount::count_umis(bc, umi_splice_types) {
+                    bc_splice_counts_sender.send(c)?;
+                }
+            }
+
             // Generate individual probe counts & send
             if let Some(bc_probe_counts_sender_unwrapped) = self.bc_probe_counts_sender.as_mut() {
                 for c in bc_umi_info.probe_counts() {
@@ -469,6 +494,7 @@ impl MartianStage for AlignAndCount {
         args: Self::StageInputs,
         _rover: MartianRover,
     ) -> Result<StageDef<Self::ChunkInputs>> {
+        parameters_toml::load_overrides(args.parameters.as_deref())?;
         let ref_gb = if args.no_bam && choose_aligner_from_args(&args)? == AlignerParam::Hurtle {
             // For probe assays, STAR is needed only to produce alignments for the BAM file.
             // The memory used by the Hurtle reference is non-zero, but it's less than the
@@ -555,6 +581,8 @@ impl MartianStage for AlignAndCount {
         chunk_args: Self::ChunkInputs,
         rover: MartianRover,
     ) -> Result<Self::ChunkOutputs> {
+        parameters_toml::load_overrides(args.parameters.as_deref())?;
+
         // Disable polyA and TSO trimming for 5' gene expression assay.
         let args = if args.chemistry_defs.endedness() == Some(WhichEnd::FivePrime) {
             Self::StageInputs {
@@ -600,6 +628,11 @@ impl MartianStage for AlignAndCount {
         } else {
             None
         };
+        let splice_counts_bc_order_shard: Option<CountShardFile> = if args.velocity_layers {
+            Some(rover.make_path("bc_splice_sort"))
+        } else {
+            None
+        };
         let bc_umi_info_shard: BcUmiInfoShardFile = rover.make_path("bc_umi_info");
         let pos_sorted_shard: AlignShardFile = rover.make_path("pos_sorted");
         let metrics_shard: BarcodeMetricsShardFile = rover.make_path("metrics_shard");
@@ -624,6 +657,18 @@ impl MartianStage for AlignAndCount {
                 .unwrap()
             });
 
+        // Spliced, unspliced and ambiguous count data ordered by barcode.
+        let mut bc_splice_counts: Option<ShardWriter<SpliceBarcodeCount, BarcodeThenFeatureOrder>> =
+            splice_counts_bc_order_shard.as_ref().map(|x| {
+                ShardWriter::new(
+                    x,
+                    ALN_BC_SEND_BUFFER_SZ,
+                    ALN_BC_DISK_CHUNK_SZ,
+                    ALN_BC_ITEM_BUFFER_SZ,
+                )
+                .unwrap()
+            });
+
         // umi info data
         let mut bc_umi_info: ShardWriter<BcUmiInfo, BcUmiInfo> =
             ShardWriter::new(&bc_umi_info_shard, 1, 256, 2048)?;
@@ -712,6 +757,7 @@ impl MartianStage for AlignAndCount {
                     bc_counts_sender: bc_counts.get_sender(),
                     pos_reads_sender: pos_reads.get_sender(),
                     bc_probe_counts_sender: bc_probe_counts.as_mut().map(|x| x.get_sender()),
+                    bc_splice_counts_sender: bc_splice_counts.as_mut().map(|x| x.get_sender()),
                     visitor: if is_pd {
                         let rng = ChaCha20Rng::seed_from_u64(idx as u64);
                         StageVisitor::with_ann_writer_sample(
@@ -755,6 +801,9 @@ impl MartianStage for AlignAndCount {
             if let Some(x) = p.bc_probe_counts_sender.as_mut() {
                 x.finished().unwrap();
             };
+            if let Some(x) = p.bc_splice_counts_sender.as_mut() {
+                x.finished().unwrap();
+            };
             p.pos_reads_sender.finished()?;
         }
 
@@ -762,6 +811,7 @@ impl MartianStage for AlignAndCount {
         bc_umi_info.finish()?;
         bc_counts.finish()?;
         bc_probe_counts.as_mut().map(|x| x.finish().unwrap());
+        bc_splice_counts.as_mut().map(|x| x.finish().unwrap());
         pos_reads.finish()?;
         metrics_writer.finish()?;
 
@@ -771,6 +821,7 @@ impl MartianStage for AlignAndCount {
         Ok(ChunkOutputs {
             counts_bc_order_shard,
             probe_barcode_counts_shard,
+            splice_counts_bc_order_shard,
             bc_umi_info_shard,
             pos_sorted_shard,
             bam_header,
@@ -838,6 +889,12 @@ impl MartianStage for AlignAndCount {
                     Some(x)
                 }
             },
+            splice_counts_bc_order: args.velocity_layers.then(|| {
+                chunk_outs
+                    .iter()
+                    .filter_map(|v| v.splice_counts_bc_order_shard.clone())
+                    .collect()
+            }),
             bc_umi_info: chunk_outs
                 .iter()
                 .map(|v| v.bc_umi_info_shard.clone())
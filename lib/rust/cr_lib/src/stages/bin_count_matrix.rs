@@ -0,0 +1,142 @@
+//! Martian stage BIN_COUNT_MATRIX
+//! Aggregate the unbinned Visium HD count matrix into square, hexagonal and polygon bins.
+
+use anyhow::{bail, Context, Result};
+use barcode::binned::{BinningScheme, Polygon, PolygonIndex, SquareBinIndex};
+use cr_h5::count_matrix::CountMatrixFile;
+use martian::prelude::{MartianRover, MartianStage};
+use martian::{MartianVoid, Resource, StageDef};
+use martian_derive::{make_mro, MartianStruct};
+use martian_filetypes::json_file::JsonFile;
+use martian_filetypes::FileTypeRead;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Clone, Deserialize, MartianStruct)]
+pub struct BinCountMatrixStageInputs {
+    /// The count matrix of the unbinned barcodes.
+    pub raw_matrix_h5: CountMatrixFile,
+    /// Sizes of square bins in micrometers, which need not be multiples of the pitch.
+    pub square_bin_sizes_um: Option<Vec<u32>>,
+    /// Distances between the centers of adjacent hexagonal bins in micrometers.
+    pub hexagonal_bin_sizes_um: Option<Vec<u32>>,
+    /// Polygons, such as segmented cells, in micrometers of the barcode grid.
+    pub segmentation_polygons: Option<JsonFile<Vec<Polygon>>>,
+}
+
+#[derive(Serialize, Deserialize, MartianStruct)]
+pub struct BinCountMatrixStageOutputs {
+    /// The aggregated count matrix of each binning scheme, keyed by scheme name.
+    pub binned_matrices: HashMap<String, CountMatrixFile>,
+}
+
+/// Martian stage BIN_COUNT_MATRIX
+pub struct BinCountMatrix;
+
+impl BinCountMatrixStageInputs {
+    /// Return the binning schemes requested by these inputs.
+    fn schemes(&self) -> Result<Vec<BinningScheme>> {
+        let mut schemes = Vec::new();
+        for &size_um in self.square_bin_sizes_um.iter().flatten() {
+            if size_um == 0 {
+                bail!("square bin size must be positive");
+            }
+            schemes.push(BinningScheme::Square { size_um });
+        }
+        for &size_um in self.hexagonal_bin_sizes_um.iter().flatten() {
+            if size_um == 0 {
+                bail!("hexagonal bin size must be positive");
+            }
+            schemes.push(BinningScheme::Hexagonal { size_um });
+        }
+        if let Some(polygons) = &self.segmentation_polygons {
+            schemes.push(BinningScheme::Polygons(PolygonIndex::new(
+                polygons.read()?,
+            )?));
+        }
+        Ok(schemes)
+    }
+}
+
+/// Return the binned barcode, including the GEM group, of an unbinned barcode.
+fn bin_barcode(scheme: &BinningScheme, barcode: &str) -> Result<Option<String>> {
+    let (barcode, gem_group) = barcode.split_once('-').unwrap_or((barcode, "1"));
+    let index: SquareBinIndex = barcode.parse()?;
+    Ok(scheme.bin(index).map(|bin| format!("{bin}-{gem_group}")))
+}
+
+#[make_mro(volatile = strict)]
+impl MartianStage for BinCountMatrix {
+    type StageInputs = BinCountMatrixStageInputs;
+    type StageOutputs = BinCountMatrixStageOutputs;
+    type ChunkInputs = MartianVoid;
+    type ChunkOutputs = MartianVoid;
+
+    fn split(
+        &self,
+        args: Self::StageInputs,
+        _rover: MartianRover,
+    ) -> Result<StageDef<Self::ChunkInputs>> {
+        let matrix_gib = args.raw_matrix_h5.estimate_mem_gib()?;
+        println!("matrix_gib={matrix_gib:.1}");
+        Ok(StageDef::with_join_resource(Resource::with_mem_gb(
+            2 + (2.5 * matrix_gib).ceil() as isize,
+        )))
+    }
+
+    fn main(
+        &self,
+        _args: Self::StageInputs,
+        _chunk_args: Self::ChunkInputs,
+        _rover: MartianRover,
+    ) -> Result<Self::ChunkOutputs> {
+        unreachable!()
+    }
+
+    fn join(
+        &self,
+        args: Self::StageInputs,
+        _chunk_defs: Vec<Self::ChunkInputs>,
+        _chunk_outs: Vec<Self::ChunkOutputs>,
+        rover: MartianRover,
+    ) -> Result<Self::StageOutputs> {
+        let schemes = args.schemes()?;
+        let mut binned_matrices = HashMap::new();
+        if schemes.is_empty() {
+            return Ok(BinCountMatrixStageOutputs { binned_matrices });
+        }
+        let matrix = args.raw_matrix_h5.read()?;
+        for scheme in schemes {
+            let name = scheme.name();
+            let binned = matrix
+                .aggregate_barcodes(|barcode| bin_barcode(&scheme, barcode.as_str()))
+                .with_context(|| format!("binning scheme {name}"))?;
+            println!("{name}: {} bins", binned.num_barcodes());
+            let h5: CountMatrixFile = rover.make_path(format!("{name}_feature_bc_matrix"));
+            binned.write_h5(&h5, &rover.pipelines_version())?;
+            binned_matrices.insert(name, h5);
+        }
+        Ok(BinCountMatrixStageOutputs { binned_matrices })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bin_barcode() -> Result<()> {
+        let scheme = BinningScheme::Square { size_um: 8 };
+        assert_eq!(
+            bin_barcode(&scheme, "s_002um_00005_00009-1")?.as_deref(),
+            Some("s_008um_00001_00002-1")
+        );
+        let scheme = BinningScheme::Hexagonal { size_um: 10 };
+        assert_eq!(
+            bin_barcode(&scheme, "s_002um_00000_00000-2")?.as_deref(),
+            Some("h_010um_00000_00000-2")
+        );
+        assert!(bin_barcode(&scheme, "ACGTACGT-1").is_err());
+        Ok(())
+    }
+}
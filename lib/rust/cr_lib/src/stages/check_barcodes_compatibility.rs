@@ -43,13 +43,18 @@
 //! 1. Sample upto 1 million reads for each library and create a histogram of the number of reads
 //! associated with each valid barcode.
 //! 2. Compute a modified [cosine similarity](https://en.wikipedia.org/wiki/Cosine_similarity) of
-//! the histogram of each library with the histogram of the gene expression library. Before applying
-//! the similarity measure, we first cap each count to a threshold value to reduces the impact of
-//! very high count outliers.
-//!     - If translation is available (`3' v3`), we compute similarity using the translated barcode.
-//! If the similarity is improved, we flag the library for translation.
-//! 3. The libraries are compatible if their modified cosine similarity is at least `0.1` (empirical
-//! threshold).
+//! the histograms of each pair of libraries. Before applying the similarity measure, we first cap
+//! each count to a threshold value to reduces the impact of very high count outliers.
+//!     - If translation is available (`3' v3`), we compare each library with an anchor library,
+//! which is the gene expression library if any, using the translated barcode. If the similarity
+//! is improved, we flag the library for translation.
+//! 3. Two libraries are compatible if their modified cosine similarity is at least `0.1`
+//! (empirical threshold).
+//! 4. The consensus is the largest set of mutually compatible libraries, preferring a set that
+//! includes the gene expression library. The libraries outside of the consensus are reported as
+//! likely originating from a different gem well.
+//! 5. The stage fails when any library is outside of the consensus. When the check is disabled,
+//! these libraries are instead reported by a metric and an alert of the web summary.
 //!
 //! ## NOTES
 //! - No compatibility check is performed if we have only a single library. With the current
@@ -59,20 +64,24 @@
 //! translation by default for 3' v3 antibody only samples. This is not the "correct" thing to do
 //! TotalSeqA. A consequence of this is that the cellranger output for TotalSeqA Antibody only 3' v3
 //! will **not be barcode compatible** with a GEX only run from the same gem well.
-//! - There needs to be a GEX library if there are >1 library types.
+//! - Without a GEX library, the anchor library is translated when translation is available,
+//! following the same choice.
+//! - With only two libraries, a swap cannot be attributed to either library. The library other
+//! than GEX, or other than the anchor, is reported.
 
-use anyhow::{ensure, Result};
+use anyhow::{bail, Result};
 use barcode::{BarcodeConstruct, BcSegSeq, Whitelist};
 use cr_types::chemistry::{ChemistryDefs, ChemistryName};
 use cr_types::sample_def::SampleDef;
-use cr_types::LibraryType;
+use cr_types::types::TomlFile;
+use cr_types::{LibraryType, MetricsFile};
 use fastq_set::filenames::FindFastqs;
 use fastq_set::read_pair::{ReadPart, RpRange};
 use fastq_set::read_pair_iter::ReadPairIter;
 use itertools::Itertools;
 use martian::prelude::*;
 use martian_derive::{make_mro, MartianStruct};
-use metric::{set, Metric, SimpleHistogram, TxHashMap, TxHashSet};
+use metric::{set, JsonReporter, Metric, SimpleHistogram, TxHashMap, TxHashSet};
 use parameters_toml::min_barcode_similarity;
 use serde::{Deserialize, Serialize};
 
@@ -84,12 +93,16 @@ pub struct CheckBarcodesCompatibilityStageInputs {
     pub chemistry_defs: ChemistryDefs,
     pub sample_def: Vec<SampleDef>,
     pub check_library_compatibility: bool,
+    /// The effective parameters.toml of WRITE_EFFECTIVE_PARAMETERS.
+    pub parameters: Option<TomlFile>,
 }
 
 #[derive(Clone, Serialize, Deserialize, MartianStruct, PartialEq, Eq)]
 #[cfg_attr(test, derive(Debug))]
 pub struct CheckBarcodesCompatibilityStageOutputs {
     pub libraries_to_translate: TxHashSet<LibraryType>,
+    /// The barcode similarity of each pair of libraries and the suspected swapped libraries.
+    pub summary: Option<MetricsFile>,
 }
 
 // This is our stage struct
@@ -157,11 +170,114 @@ fn robust_cosine_similarity(c1: &SimpleHistogram<BcSegSeq>, c2: &SimpleHistogram
     dot_prod / (mag1 * mag2)
 }
 
+/// Return the histogram of a library, translating its barcodes if the library is translated.
+fn translate_histogram(
+    hist: &SimpleHistogram<BcSegSeq>,
+    translation_map: Option<&TxHashMap<BcSegSeq, BcSegSeq>>,
+    libraries_to_translate: &TxHashSet<LibraryType>,
+    lib_type: LibraryType,
+) -> SimpleHistogram<BcSegSeq> {
+    match translation_map {
+        Some(translate) if libraries_to_translate.contains(&lib_type) => {
+            hist.clone().map_key(|key| translate[&key])
+        }
+        _ => hist.clone(),
+    }
+}
+
+/// Return the name of a library type used in the barcode similarity metrics.
+fn library_metric_name(library_type: LibraryType) -> &'static str {
+    library_type.as_metric_prefix_static().unwrap_or("GEX")
+}
+
+/// The barcode compatibility of every pair of library types.
+struct LibraryCompatibility {
+    /// The sorted library types.
+    library_types: Vec<LibraryType>,
+    /// The similarity of each pair of library types, indexed into library_types.
+    similarity: TxHashMap<(usize, usize), f64>,
+    min_similarity: f64,
+}
+
+impl LibraryCompatibility {
+    fn is_compatible(&self, i: usize, j: usize) -> bool {
+        self.similarity[&(i.min(j), i.max(j))] >= self.min_similarity
+    }
+
+    /// Return the largest set of mutually compatible library types as a bit mask.
+    /// Ties are broken in favor of the set including gene expression, and then
+    /// the set of the library types that sort first.
+    fn consensus(&self) -> u32 {
+        let n = self.library_types.len();
+        let gex = self.library_types.iter().position(LibraryType::is_gex);
+        let mut best = 0;
+        let mut best_key = (0, false);
+        for mask in (1u32..(1 << n)).rev() {
+            let members = (0..n).filter(|i| mask & (1 << i) != 0).collect::<Vec<_>>();
+            let key = (members.len(), gex.is_some_and(|g| mask & (1 << g) != 0));
+            if key >= best_key
+                && members
+                    .iter()
+                    .tuple_combinations()
+                    .all(|(&i, &j)| self.is_compatible(i, j))
+            {
+                best = mask;
+                best_key = key;
+            }
+        }
+        best
+    }
+
+    /// Return the library types outside of the consensus, which likely originated
+    /// from a different gem well, together with a consensus library type that is
+    /// incompatible with each of them.
+    fn suspected_swaps(&self) -> Vec<(LibraryType, LibraryType)> {
+        let consensus = self.consensus();
+        let n = self.library_types.len();
+        (0..n)
+            .filter(|i| consensus & (1 << i) == 0)
+            .map(|i| {
+                let j = (0..n)
+                    .find(|&j| consensus & (1 << j) != 0 && !self.is_compatible(i, j))
+                    .unwrap();
+                (self.library_types[j], self.library_types[i])
+            })
+            .collect()
+    }
+
+    /// Return the similarity metrics and the suspected swapped library types.
+    fn to_metrics(&self) -> JsonReporter {
+        let mut metrics: JsonReporter = self
+            .similarity
+            .iter()
+            .map(|(&(i, j), &similarity)| {
+                (
+                    format!(
+                        "barcode_similarity_{}_vs_{}",
+                        library_metric_name(self.library_types[i]),
+                        library_metric_name(self.library_types[j])
+                    ),
+                    similarity,
+                )
+            })
+            .collect();
+        metrics.insert(
+            "suspected_swapped_libraries",
+            self.suspected_swaps()
+                .into_iter()
+                .map(|(_, suspect)| suspect.to_string())
+                .collect::<Vec<_>>(),
+        );
+        metrics
+    }
+}
+
 #[make_mro(mem_gb = 2)]
 impl MartianMain for CheckBarcodesCompatibility {
     type StageInputs = CheckBarcodesCompatibilityStageInputs;
     type StageOutputs = CheckBarcodesCompatibilityStageOutputs;
-    fn main(&self, args: Self::StageInputs, _rover: MartianRover) -> Result<Self::StageOutputs> {
+    fn main(&self, args: Self::StageInputs, rover: MartianRover) -> Result<Self::StageOutputs> {
+        parameters_toml::load_overrides(args.parameters.as_deref())?;
         let unique_lib_types: TxHashSet<_> = args.chemistry_defs.keys().collect();
 
         // -----------------------------------------------------------------------------------------
@@ -172,6 +288,7 @@ impl MartianMain for CheckBarcodesCompatibility {
         {
             return Ok(CheckBarcodesCompatibilityStageOutputs {
                 libraries_to_translate: TxHashSet::default(),
+                summary: None,
             });
         }
 
@@ -193,14 +310,10 @@ impl MartianMain for CheckBarcodesCompatibility {
             };
             return Ok(CheckBarcodesCompatibilityStageOutputs {
                 libraries_to_translate,
+                summary: None,
             });
         }
 
-        // -----------------------------------------------------------------------------------------
-        // There needs to be a Gene expression library if there are >1 library type. This will
-        // possibly be relaxed in the future
-        ensure!(unique_lib_types.contains(&LibraryType::Gex), MISSING_GEX);
-
         // Assert that all the chemistries have a matching gel bead whitelist.
         let gb_whitelist_spec = args
             .chemistry_defs
@@ -225,44 +338,91 @@ impl MartianMain for CheckBarcodesCompatibility {
                 .merge(this_hist);
         }
 
-        let gex_hist = per_lib_bc_histogram.remove(&LibraryType::Gex).unwrap(); // This is guaranteed to succeed due to the check above
-
         // -----------------------------------------------------------------------------------------
-        // Check similarity with the GEX library and infer if we need translation.
+        // Infer translation relative to the anchor library, which is the gene expression library
+        // if any, and otherwise the first library type.
         let translation_map = match gb_whitelist_spec.as_source(true) {
             Ok(source) => Some(source.as_translation()?),
             Err(_) => None,
         };
+        let library_types: Vec<_> = per_lib_bc_histogram.keys().copied().sorted().collect();
+        let anchor = library_types[0];
         let mut libraries_to_translate = set![];
-        let min_barcode_similarity = *min_barcode_similarity()?;
-        for (lib_type, this_hist) in per_lib_bc_histogram {
-            let mut similarity = robust_cosine_similarity(&gex_hist, &this_hist);
-            println!("Without translation: {lib_type} - {similarity:?}");
+        if !anchor.is_gex() && translation_map.is_some() {
+            libraries_to_translate.insert(anchor);
+        }
+        let anchor_hist = translate_histogram(
+            &per_lib_bc_histogram[&anchor],
+            translation_map.as_ref(),
+            &libraries_to_translate,
+            anchor,
+        );
+        for &lib_type in &library_types[1..] {
+            let this_hist = &per_lib_bc_histogram[&lib_type];
+            let similarity = robust_cosine_similarity(&anchor_hist, this_hist);
+            println!("Without translation: {anchor} vs {lib_type} - {similarity:?}");
             if let Some(ref translate) = translation_map {
-                let trans_similarity =
-                    robust_cosine_similarity(&gex_hist, &this_hist.map_key(|key| translate[&key]));
-                println!("With translation   : {lib_type} - {trans_similarity:?}");
+                let trans_similarity = robust_cosine_similarity(
+                    &anchor_hist,
+                    &this_hist.clone().map_key(|key| translate[&key]),
+                );
+                println!("With translation   : {anchor} vs {lib_type} - {trans_similarity:?}");
                 if trans_similarity > similarity {
                     libraries_to_translate.insert(lib_type);
-                    similarity = trans_similarity;
                 }
             }
-            if args.check_library_compatibility {
-                ensure!(
-                    similarity >= min_barcode_similarity,
-                    incompatible_message(LibraryType::Gex, lib_type),
+        }
+
+        // -----------------------------------------------------------------------------------------
+        // Compute the similarity of each pair of libraries and identify swapped libraries.
+        let histograms: Vec<_> = library_types
+            .iter()
+            .map(|&lib_type| {
+                translate_histogram(
+                    &per_lib_bc_histogram[&lib_type],
+                    translation_map.as_ref(),
+                    &libraries_to_translate,
+                    lib_type,
+                )
+            })
+            .collect();
+        let similarity = (0..library_types.len())
+            .tuple_combinations()
+            .map(|(i, j)| {
+                let similarity = robust_cosine_similarity(&histograms[i], &histograms[j]);
+                println!(
+                    "Similarity: {} vs {} - {similarity:?}",
+                    library_types[i], library_types[j]
                 );
+                ((i, j), similarity)
+            })
+            .collect();
+        let compatibility = LibraryCompatibility {
+            library_types,
+            similarity,
+            min_similarity: *min_barcode_similarity()?,
+        };
+        let suspected_swaps = compatibility.suspected_swaps();
+        if args.check_library_compatibility {
+            if let Some(&(consensus, suspect)) = suspected_swaps.first() {
+                bail!(incompatible_message(consensus, suspect));
             }
         }
+        for (consensus, suspect) in &suspected_swaps {
+            println!("Suspected swap: {suspect} is incompatible with {consensus}");
+        }
 
         Ok(CheckBarcodesCompatibilityStageOutputs {
             libraries_to_translate,
+            summary: Some(MetricsFile::from_reporter(
+                &rover,
+                "summary",
+                &compatibility.to_metrics(),
+            )?),
         })
     }
 }
 
-const MISSING_GEX: &str = "Gene expression data is required if there are multiple library types.";
-
 fn incompatible_message(lib_0_type: LibraryType, lib_1_type: LibraryType) -> String {
     format!(
         "Barcodes from the [{lib_0_type}] library and the [{lib_1_type}] library have insufficient overlap. \
@@ -295,6 +455,7 @@ mod barcode_compatibility_tests {
                 .collect(),
             sample_def: samples,
             check_library_compatibility: true,
+            parameters: None,
         }
     }
 
@@ -475,22 +636,44 @@ mod barcode_compatibility_tests {
     }
 
     #[test]
-    fn test_missing_gex() {
+    fn test_one_swap_of_three() {
+        // Antibody and GEX from pbmc_1k_protein_v3, which agree with each other
+        // CRISPR from K562_5k_crispr_v3, which is swapped
         let args = inputs(
             ChemistryName::ThreePrimeV3,
             vec![
                 SampleDef {
+                    fastq_mode: FastqMode::ILMN_BCL2FASTQ,
+                    read_path:
+                        "../dui_tests/test_resources/cellranger-count/pbmc_1k_protein_v3_antibody"
+                            .into(),
+                    sample_names: Some(vec!["pbmc_1k_protein_v3_antibody".into()]),
+                    lanes: Some(vec![4]),
                     library_type: Some(LibraryType::Antibody),
                     ..Default::default()
                 },
                 SampleDef {
+                    fastq_mode: FastqMode::ILMN_BCL2FASTQ,
+                    read_path:
+                        "../dui_tests/test_resources/cellranger-count/pbmc_1k_protein_v3_gex".into(),
+                    sample_names: Some(vec!["pbmc_1k_protein_v3_gex".into()]),
+                    lanes: Some(vec![4]),
+                    library_type: Some(LibraryType::Gex),
+                    ..Default::default()
+                },
+                SampleDef {
+                    fastq_mode: FastqMode::ILMN_BCL2FASTQ,
+                    read_path:
+                        "../dui_tests/test_resources/cellranger-count/K562_5k_crispr_v3_crispr"
+                            .into(),
+                    sample_names: Some(vec!["K562_5k_crispr_v3_crispr".into()]),
                     library_type: Some(LibraryType::Crispr),
                     ..Default::default()
                 },
             ],
         );
         assert_eq!(
-            MISSING_GEX.to_string(),
+            incompatible_message(LibraryType::Gex, LibraryType::Crispr),
             CheckBarcodesCompatibility
                 .test_run_tmpdir(args)
                 .unwrap_err()
@@ -498,6 +681,76 @@ mod barcode_compatibility_tests {
         );
     }
 
+    fn compatibility(
+        library_types: Vec<LibraryType>,
+        similarity: &[((usize, usize), f64)],
+    ) -> LibraryCompatibility {
+        LibraryCompatibility {
+            library_types,
+            similarity: similarity.iter().copied().collect(),
+            min_similarity: 0.1,
+        }
+    }
+
+    #[test]
+    fn test_suspected_swaps_without_gex() {
+        // The CRISPR library is incompatible with both the Antibody and Multiplexing libraries.
+        let compat = compatibility(
+            vec![
+                LibraryType::Antibody,
+                LibraryType::Crispr,
+                LibraryType::Cellplex,
+            ],
+            &[((0, 1), 0.01), ((0, 2), 0.8), ((1, 2), 0.02)],
+        );
+        assert_eq!(
+            compat.suspected_swaps(),
+            [(LibraryType::Antibody, LibraryType::Crispr)]
+        );
+        let metrics = compat.to_metrics();
+        assert_eq!(
+            metrics.get("barcode_similarity_ANTIBODY_vs_MULTIPLEXING"),
+            Some(&serde_json::json!(0.8))
+        );
+        assert_eq!(
+            metrics.get("suspected_swapped_libraries"),
+            Some(&serde_json::json!(["Antibody Capture"]))
+        );
+    }
+
+    #[test]
+    fn test_suspected_swaps_prefer_gex() {
+        // With two incompatible libraries, the library other than GEX is suspected.
+        let compat = compatibility(
+            vec![LibraryType::Gex, LibraryType::Antibody],
+            &[((0, 1), 0.01)],
+        );
+        assert_eq!(
+            compat.suspected_swaps(),
+            [(LibraryType::Gex, LibraryType::Antibody)]
+        );
+
+        // A library compatible with GEX and incompatible with another library.
+        let compat = compatibility(
+            vec![LibraryType::Gex, LibraryType::Antibody, LibraryType::Crispr],
+            &[((0, 1), 0.9), ((0, 2), 0.01), ((1, 2), 0.7)],
+        );
+        assert_eq!(
+            compat.suspected_swaps(),
+            [(LibraryType::Gex, LibraryType::Crispr)]
+        );
+
+        let compat = compatibility(
+            vec![LibraryType::Gex, LibraryType::Antibody, LibraryType::Crispr],
+            &[((0, 1), 0.9), ((0, 2), 0.5), ((1, 2), 0.7)],
+        );
+        assert!(compat.suspected_swaps().is_empty());
+        assert_eq!(
+            compat.to_metrics().get("suspected_swapped_libraries"),
+            Some(&serde_json::json!([]))
+        );
+    }
+
     #[test]
     fn test_similarity() {
         let mut c1 = SimpleHistogram::default();
@@ -21,6 +21,7 @@ use cr_types::chemistry::{
 };
 use cr_types::reference::feature_reference::{FeatureConfig, FeatureReferenceFile};
 use cr_types::sample_def::SampleDef;
+use cr_types::types::TomlFile;
 use cr_types::LibraryType;
 use fastq_set::read_pair::ReadPair;
 use itertools::Itertools;
@@ -57,6 +58,8 @@ pub struct DetectChemistryStageInputs {
     pub is_pd: bool,
     pub custom_chemistry_def: Option<ChemistryDef>,
     pub feature_config: Option<FeatureConfig>,
+    /// The effective parameters.toml of WRITE_EFFECTIVE_PARAMETERS.
+    pub parameters: Option<TomlFile>,
 }
 
 impl DetectChemistryStageInputs {
@@ -103,6 +106,8 @@ impl MartianMain for DetectChemistry {
     type StageOutputs = DetectChemistryStageOutputs;
 
     fn main(&self, args: Self::StageInputs, rover: MartianRover) -> Result<Self::StageOutputs> {
+        parameters_toml::load_overrides(args.parameters.as_deref())?;
+
         // Bail out if any of chemistry_specs are not in the optional list of allowed
         // chemistry names.
         for spec in args.chemistry_specs.values() {
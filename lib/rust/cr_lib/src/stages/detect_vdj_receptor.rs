@@ -16,7 +16,7 @@ use std::fmt;
 use std::iter::zip;
 use std::path::PathBuf;
 use std::str::FromStr;
-use vdj_reference::{KmerClassify, VdjReceptor, VdjReference};
+use vdj_reference::{KmerClassify, VdjReceptor, VdjReference, TRGD_INNER_PRIMERS_REQUIRED};
 
 /// How many reads to use to decide if the library is TCR or Ig
 const MAX_READS_RECEPTOR_CLASSIFICATION: usize = 1_000_000;
@@ -33,6 +33,7 @@ pub struct DetectVdjReceptorStageInputs {
     pub vdj_sample_def: Vec<SampleDef>,
     pub is_multi: bool,
     pub feature_config: Option<FeatureConfig>,
+    pub inner_enrichment_primers: Option<PathBuf>,
 }
 
 #[derive(Clone, Serialize, Deserialize, MartianStruct)]
@@ -49,6 +50,7 @@ pub struct DetectVdjReceptor;
 #[derive(Default)]
 struct ClassificationStats {
     tcr_reads: i64,
+    tcr_gd_reads: i64,
     ig_reads: i64,
     total_reads: i64,
 }
@@ -57,6 +59,7 @@ impl fmt::Display for ClassificationStats {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "{:20} = {}", "Total Reads", self.total_reads)?;
         writeln!(f, "{:20} = {}", "Reads mapped to TR", self.tcr_reads)?;
+        writeln!(f, "{:20} = {}", "Reads mapped to TR_GD", self.tcr_gd_reads)?;
         writeln!(f, "{:20} = {}", "Reads mapped to IG", self.ig_reads)
     }
 }
@@ -67,7 +70,8 @@ impl ClassificationStats {
         if self.total_reads < (MIN_READS_RECEPTOR_CLASSIFICATION as i64) {
             return None;
         }
-        let tcr_mapping_frac = (self.tcr_reads as f64) / (self.total_reads as f64);
+        let tcr_mapping_frac =
+            ((self.tcr_reads + self.tcr_gd_reads) as f64) / (self.total_reads as f64);
         let ig_mapping_frac = (self.ig_reads as f64) / (self.total_reads as f64);
         // Not enough mapped reads
         if tcr_mapping_frac < MIN_FRAC_MAPPED_RECEPTOR_CLASSIFICATION
@@ -76,7 +80,14 @@ impl ClassificationStats {
             return None;
         }
         if tcr_mapping_frac > MIN_MARGIN_RECEPTOR_CLASSIFICATION * ig_mapping_frac {
-            Some(VdjReceptor::TR)
+            // Within TR, call gamma/delta only if it clearly dominates alpha/beta
+            if (self.tcr_gd_reads as f64)
+                > MIN_MARGIN_RECEPTOR_CLASSIFICATION * (self.tcr_reads as f64)
+            {
+                Some(VdjReceptor::TRGD)
+            } else {
+                Some(VdjReceptor::TR)
+            }
         } else if ig_mapping_frac > MIN_MARGIN_RECEPTOR_CLASSIFICATION * tcr_mapping_frac {
             Some(VdjReceptor::IG)
         } else {
@@ -91,9 +102,12 @@ impl ClassificationStats {
             - A minimum of {} total reads\n\
             - A minimum of {:.1}% of the total reads needs to map to TR or IG\n\
             - The number of reads mapped to TR should be at least {:.1}x compared to the number of \
-            reads mapped to IG or vice versa",
+            reads mapped to IG or vice versa\n\
+            Reads mapped to TR are further classified as gamma/delta (TR_GD) if the number of reads \
+            mapped to TRG/TRD is at least {:.1}x compared to the number of reads mapped to TRA/TRB",
             MIN_READS_RECEPTOR_CLASSIFICATION,
             100.0 * MIN_FRAC_MAPPED_RECEPTOR_CLASSIFICATION,
+            MIN_MARGIN_RECEPTOR_CLASSIFICATION,
             MIN_MARGIN_RECEPTOR_CLASSIFICATION
         )
     }
@@ -151,8 +165,6 @@ fn check_feature_ref_and_config(
     Ok(beam_mode)
 }
 
-// Note: We do not support auto-detection of G/D mode.
-// User needs to specifically choose to run a G/D pipeline by selecting the correct chain_type
 #[make_mro(volatile = strict)]
 impl MartianMain for DetectVdjReceptor {
     type StageInputs = DetectVdjReceptorStageInputs;
@@ -192,8 +204,7 @@ impl MartianMain for DetectVdjReceptor {
         let units = detect_chemistry_units(&args.vdj_sample_def, None, None)?;
         let resolution_text = match args.is_multi {
             true => {
-                "Please specify the feature_types more specifically as either VDJ-T or VDJ-B. \
-            If you are using our unsupported workflow for gamma/delta, then please use feature_type VDJ-T-GD."
+                "Please specify the feature_types more specifically as either VDJ-T, VDJ-T-GD or VDJ-B."
             }
             false => {
                 "Please check the input data and/or specify the chain via the --chain argument."
@@ -211,7 +222,7 @@ impl MartianMain for DetectVdjReceptor {
                         .classify_rc(read_pair?.get(WhichRead::R2, ReadPart::Seq).unwrap())
                     {
                         Some(VdjReceptor::TR) => stats.tcr_reads += 1,
-                        Some(VdjReceptor::TRGD) => stats.tcr_reads += 1,
+                        Some(VdjReceptor::TRGD) => stats.tcr_gd_reads += 1,
                         Some(VdjReceptor::IG) => stats.ig_reads += 1,
                         None => {}
                     }
@@ -238,6 +249,9 @@ impl MartianMain for DetectVdjReceptor {
         let receptor_choices: TxHashSet<_> = per_unit_receptors.iter().collect();
         if receptor_choices.len() == 1 {
             let receptor = Some(*receptor_choices.into_iter().next().unwrap());
+            if receptor == Some(VdjReceptor::TRGD) && args.inner_enrichment_primers.is_none() {
+                bail!("Detected gamma/delta chains. {TRGD_INNER_PRIMERS_REQUIRED}");
+            }
             let beam_mode = check_feature_ref_and_config(
                 args.feature_reference.as_ref(),
                 receptor,
@@ -269,6 +283,37 @@ mod tests {
     use std::collections::HashMap;
     use strum::IntoEnumIterator;
 
+    #[test]
+    fn test_compatible_receptor() {
+        let stats = |tcr_reads, tcr_gd_reads, ig_reads| ClassificationStats {
+            tcr_reads,
+            tcr_gd_reads,
+            ig_reads,
+            total_reads: 10_000,
+        };
+        assert_eq!(
+            stats(5000, 100, 10).compatible_receptor(),
+            Some(VdjReceptor::TR)
+        );
+        assert_eq!(
+            stats(100, 5000, 10).compatible_receptor(),
+            Some(VdjReceptor::TRGD)
+        );
+        assert_eq!(
+            stats(10, 10, 5000).compatible_receptor(),
+            Some(VdjReceptor::IG)
+        );
+        // Ambiguous between alpha/beta and gamma/delta defaults to alpha/beta
+        assert_eq!(
+            stats(2000, 3000, 10).compatible_receptor(),
+            Some(VdjReceptor::TR)
+        );
+        // Ambiguous between TR and IG
+        assert_eq!(stats(1000, 1000, 1000).compatible_receptor(), None);
+        // Too few mapped reads
+        assert_eq!(stats(100, 100, 10).compatible_receptor(), None);
+    }
+
     #[test]
     fn test_denovo_no_ref() {
         let outs = DetectVdjReceptor
@@ -280,6 +325,7 @@ mod tests {
                 vdj_sample_def: vec![],
                 is_multi: false,
                 feature_config: None,
+                inner_enrichment_primers: None,
             })
             .unwrap();
         assert_eq!(outs.receptor, None);
@@ -297,6 +343,7 @@ mod tests {
                     vdj_sample_def: vec![],
                     is_multi: false,
                     feature_config: None,
+                    inner_enrichment_primers: None,
                 })
                 .unwrap();
             assert_eq!(outs.receptor, Some(receptor));
@@ -321,6 +368,7 @@ mod tests {
             }],
             is_multi: false,
             feature_config: None,
+            inner_enrichment_primers: None,
         };
         let outs = DetectVdjReceptor.test_run_tmpdir(args).unwrap();
         assert_eq!(outs.receptor, Some(VdjReceptor::IG));
@@ -343,6 +391,7 @@ mod tests {
             }],
             is_multi: false,
             feature_config: None,
+            inner_enrichment_primers: None,
         };
         let outs = DetectVdjReceptor.test_run_tmpdir(args).unwrap();
         assert_eq!(outs.receptor, Some(VdjReceptor::TR));
@@ -372,6 +421,7 @@ mod tests {
                 }],
                 is_multi: false,
                 feature_config: None,
+                inner_enrichment_primers: None,
             },
         );
         Ok(())
@@ -402,6 +452,7 @@ mod tests {
                 }],
                 is_multi: true,
                 feature_config: None,
+                inner_enrichment_primers: None,
             },
         );
         Ok(())
@@ -439,6 +490,7 @@ mod tests {
                 ],
                 is_multi: false,
                 feature_config: None,
+                inner_enrichment_primers: None,
             },
         );
         Ok(())
@@ -476,6 +528,7 @@ mod tests {
                     beam_mode: None,
                     functional_map: None,
                 }),
+                inner_enrichment_primers: None,
             },
         );
         Ok(())
@@ -0,0 +1,54 @@
+//! Martian stage DIFF_H5_FILES
+//! Compare two feature-barcode matrices or molecule_info files by barcode and feature.
+
+use anyhow::Result;
+use cr_h5::semantic_compare::{semantic_compare, SemanticDiff};
+use cr_types::filtered_barcodes::FilteredBarcodesCsv;
+use cr_types::H5File;
+use martian::prelude::{MartianMain, MartianRover};
+use martian_derive::{make_mro, martian_filetype, MartianStruct};
+use martian_filetypes::json_file::JsonFile;
+use martian_filetypes::FileTypeWrite;
+use serde::{Deserialize, Serialize};
+
+martian_filetype!(TxtFile, "txt");
+
+#[derive(Clone, Deserialize, MartianStruct)]
+pub struct DiffH5FilesStageInputs {
+    pub h5_a: H5File,
+    pub h5_b: H5File,
+    pub filtered_barcodes_a: Option<FilteredBarcodesCsv>,
+    pub filtered_barcodes_b: Option<FilteredBarcodesCsv>,
+}
+
+#[derive(Serialize, Deserialize, MartianStruct)]
+pub struct DiffH5FilesStageOutputs {
+    pub summary: JsonFile<SemanticDiff>,
+    pub report: TxtFile,
+}
+
+/// Martian stage DIFF_H5_FILES
+pub struct DiffH5Files;
+
+#[make_mro(mem_gb = 16, volatile = strict)]
+impl MartianMain for DiffH5Files {
+    type StageInputs = DiffH5FilesStageInputs;
+    type StageOutputs = DiffH5FilesStageOutputs;
+
+    fn main(&self, args: Self::StageInputs, rover: MartianRover) -> Result<Self::StageOutputs> {
+        let diff = semantic_compare(
+            &args.h5_a,
+            &args.h5_b,
+            args.filtered_barcodes_a.as_ref(),
+            args.filtered_barcodes_b.as_ref(),
+        )?;
+
+        let report: TxtFile = rover.make_path("report");
+        std::fs::write(&report, diff.to_string())?;
+
+        let summary: JsonFile<SemanticDiff> = rover.make_path("summary");
+        summary.write(&diff)?;
+
+        Ok(DiffH5FilesStageOutputs { summary, report })
+    }
+}
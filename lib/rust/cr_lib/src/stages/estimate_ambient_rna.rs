@@ -0,0 +1,426 @@
+//! Martian stage ESTIMATE_AMBIENT_RNA
+//! Estimate the fraction of the gene expression UMIs of each cluster of cells that originate from
+//! ambient RNA, whose profile is learned from the low-UMI barcodes of the raw matrix, and write the
+//! filtered matrix with the ambient counts removed.
+
+use anyhow::{ensure, Result};
+use cr_h5::count_matrix::{CountMatrix, CountMatrixFile};
+use cr_types::reference::feature_reference::FeatureType;
+use cr_types::H5File;
+use cr_websummary::{RawChartWithHelp, TitleWithHelp};
+use itertools::Itertools;
+use martian::prelude::{MartianRover, MartianStage};
+use martian::{MartianVoid, Resource, StageDef};
+use martian_derive::{make_mro, MartianStruct};
+use martian_filetypes::json_file::JsonFile;
+use martian_filetypes::tabular_file::CsvFile;
+use martian_filetypes::FileTypeWrite;
+use metric::TxHashSet;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::iter::zip;
+
+/// Barcodes with fewer gene expression UMIs than this contribute to the ambient profile.
+const MAX_AMBIENT_BARCODE_UMIS: i64 = 100;
+/// The fewest UMIs of the ambient profile required to estimate contamination.
+const MIN_AMBIENT_PROFILE_UMIS: f64 = 1000.0;
+/// The number of the most abundant genes of the ambient profile used to estimate contamination.
+const NUM_ESTIMATION_GENES: usize = 100;
+/// The fraction of the estimation genes with the lowest ratio of observed to ambient expression in
+/// a cluster, which are assumed not to be expressed by its cells.
+const NON_EXPRESSED_GENE_FRACTION: f64 = 0.1;
+/// The fewest UMIs of the non-expressed genes that a cluster entirely of ambient RNA would have,
+/// below which its contamination is too noisy to estimate.
+const MIN_EXPECTED_AMBIENT_UMIS: f64 = 100.0;
+/// The number of rounds that redistribute the ambient counts of exhausted genes.
+const MAX_SUBTRACTION_ROUNDS: usize = 10;
+/// The group of the clustering h5 file that contains one subgroup per clustering.
+const CLUSTERING_GROUP: &str = "clustering";
+/// The clustering of the secondary analysis used to estimate contamination.
+const GRAPHCLUST: &str = "graphclust";
+
+#[derive(Clone, Deserialize, MartianStruct)]
+pub struct EstimateAmbientRnaStageInputs {
+    pub raw_matrix_h5: CountMatrixFile,
+    pub filtered_matrix_h5: CountMatrixFile,
+    /// The clustering of the secondary analysis. All cells form a single cluster when null.
+    pub clustering_h5: Option<H5File>,
+}
+
+#[derive(Serialize, Deserialize, MartianStruct)]
+pub struct EstimateAmbientRnaStageOutputs {
+    pub ambient_rna_metrics: Option<JsonFile<AmbientRnaMetrics>>,
+    pub ambient_rna_csv: Option<CsvFile<AmbientRnaRow>>,
+    pub ambient_corrected_matrix_h5: Option<CountMatrixFile>,
+    pub ambient_rna_chart: Option<JsonFile<RawChartWithHelp>>,
+}
+
+/// Ambient RNA metrics.
+#[derive(Serialize, Deserialize)]
+pub struct AmbientRnaMetrics {
+    /// The estimated fraction of the gene expression UMIs of cells from ambient RNA.
+    estimated_fraction_ambient_rna: f64,
+    /// The number of barcodes of the ambient profile.
+    ambient_profile_barcodes: usize,
+    /// The number of UMIs of the ambient profile.
+    ambient_profile_umis: i64,
+}
+
+/// The estimated ambient RNA fraction of a cell.
+#[derive(Serialize, Deserialize)]
+pub struct AmbientRnaRow {
+    #[serde(rename = "Barcode")]
+    barcode: String,
+    #[serde(rename = "Cluster")]
+    cluster: i64,
+    #[serde(rename = "Ambient RNA Fraction")]
+    ambient_rna_fraction: f64,
+}
+
+/// Martian stage ESTIMATE_AMBIENT_RNA
+pub struct EstimateAmbientRna;
+
+/// Return whether each feature of the matrix is a gene.
+fn gene_features(matrix: &CountMatrix) -> Vec<bool> {
+    matrix
+        .feature_reference()
+        .feature_defs
+        .iter()
+        .map(|feature| feature.feature_type == FeatureType::Gene)
+        .collect()
+}
+
+/// Return the gene expression counts of each feature summed over the selected barcodes.
+fn sum_gene_counts(
+    matrix: &CountMatrix,
+    is_gene: &[bool],
+    barcodes: &TxHashSet<usize>,
+) -> Vec<f64> {
+    let mut sums = vec![0.0; matrix.num_features()];
+    for count in matrix.raw_counts() {
+        if is_gene[count.feature_idx] && barcodes.contains(&count.barcode_idx) {
+            sums[count.feature_idx] += f64::from(count.count);
+        }
+    }
+    sums
+}
+
+/// Return the path of the gene expression graph-based clusters in the clustering h5 file, which
+/// follows the clustering key of the secondary analysis, `{feature_type}_{clustering_type}`,
+/// stored in a subgroup named with a leading underscore.
+fn clusters_dataset() -> String {
+    format!(
+        "{CLUSTERING_GROUP}/_{}_{GRAPHCLUST}/clusters",
+        FeatureType::Gene.as_snake_case()
+    )
+}
+
+/// Return the graph-based cluster of each cell, or a single cluster when the clustering is absent
+/// or does not match the cells.
+fn read_clusters(clustering_h5: Option<&H5File>, num_cells: usize) -> Result<Vec<i64>> {
+    if let Some(clustering_h5) = clustering_h5 {
+        let file = hdf5::File::open(clustering_h5)?;
+        let dataset = clusters_dataset();
+        if !file.link_exists(&dataset) {
+            println!("Ignoring clustering without {dataset}");
+            return Ok(vec![1; num_cells]);
+        }
+        let clusters: Vec<i64> = file.dataset(&dataset)?.read_raw()?;
+        if clusters.len() == num_cells {
+            return Ok(clusters);
+        }
+        println!(
+            "Ignoring clustering of {} barcodes for {num_cells} cells",
+            clusters.len()
+        );
+    }
+    Ok(vec![1; num_cells])
+}
+
+/// Estimate the ambient RNA fraction of a cluster from its gene expression counts, the ambient
+/// profile normalized to sum to one, and the indices of the estimation genes.
+/// The estimation genes with the lowest ratio of observed to ambient expression are assumed not to
+/// be expressed by the cluster, so that all of their counts are ambient. Return None when the
+/// cluster has too few counts.
+fn estimate_contamination(counts: &[f64], profile: &[f64], genes: &[usize]) -> Option<f64> {
+    let total: f64 = counts.iter().sum();
+    let num_non_expressed = (NON_EXPRESSED_GENE_FRACTION * genes.len() as f64).ceil() as usize;
+    let non_expressed = genes
+        .iter()
+        .filter(|&&g| profile[g] > 0.0)
+        .sorted_by(|&&a, &&b| (counts[a] / profile[a]).total_cmp(&(counts[b] / profile[b])))
+        .take(num_non_expressed);
+    let (observed, expected) = non_expressed.fold((0.0, 0.0), |(observed, expected), &g| {
+        (observed + counts[g], expected + total * profile[g])
+    });
+    (expected >= MIN_EXPECTED_AMBIENT_UMIS).then(|| (observed / expected).min(1.0))
+}
+
+/// Remove the given number of ambient counts from the counts of a cell, in proportion to the
+/// ambient profile of each feature. The ambient counts of a feature that exceed its count are
+/// redistributed to the remaining features.
+fn subtract_ambient(counts: &[f64], profile: &[f64], mut to_remove: f64) -> Vec<f64> {
+    let mut corrected = counts.to_vec();
+    for _ in 0..MAX_SUBTRACTION_ROUNDS {
+        let weight: f64 = zip(&corrected, profile)
+            .filter(|(&count, _)| count > 0.0)
+            .map(|(_, &p)| p)
+            .sum();
+        if to_remove <= 0.0 || weight <= 0.0 {
+            break;
+        }
+        let mut removed = 0.0;
+        for (count, &p) in zip(&mut corrected, profile) {
+            if *count > 0.0 && p > 0.0 {
+                let r = (to_remove * p / weight).min(*count);
+                *count -= r;
+                removed += r;
+            }
+        }
+        to_remove -= removed;
+    }
+    corrected
+}
+
+/// Return a bar chart of the ambient RNA fraction of each cluster for the web summary.
+fn contamination_chart(
+    cluster_fractions: &BTreeMap<i64, f64>,
+    estimated_fraction: f64,
+) -> RawChartWithHelp {
+    let (clusters, fractions): (Vec<_>, Vec<_>) = cluster_fractions
+        .iter()
+        .map(|(cluster, fraction)| (format!("Cluster {cluster}"), 100.0 * fraction))
+        .unzip();
+    RawChartWithHelp {
+        plot: json!({
+            "data": [{
+                "x": clusters,
+                "y": fractions,
+                "type": "bar",
+            }],
+            "layout": {
+                "xaxis": {"title": "Cluster"},
+                "yaxis": {"title": "Ambient RNA (%)", "rangemode": "tozero"},
+            },
+        }),
+        help: TitleWithHelp {
+            title: "Ambient RNA Contamination".to_string(),
+            help: format!(
+                "An estimated {:.1}% of the gene expression UMIs of cell-associated barcodes \
+                 originate from ambient RNA. The ambient profile is the gene expression of \
+                 barcodes with fewer than {MAX_AMBIENT_BARCODE_UMIS} UMIs. The ambient RNA \
+                 fraction of each graph-based cluster is estimated from the abundant ambient \
+                 genes with the lowest expression in that cluster, which are assumed not to be \
+                 expressed by its cells. Clusters with too few UMIs for an estimate are assigned \
+                 the mean fraction of the other clusters. The ambient counts are removed from the \
+                 ambient-corrected feature-barcode matrix.",
+                100.0 * estimated_fraction
+            ),
+        },
+    }
+}
+
+#[make_mro(volatile = strict)]
+impl MartianStage for EstimateAmbientRna {
+    type StageInputs = EstimateAmbientRnaStageInputs;
+    type StageOutputs = EstimateAmbientRnaStageOutputs;
+    type ChunkInputs = MartianVoid;
+    type ChunkOutputs = MartianVoid;
+
+    fn split(
+        &self,
+        args: Self::StageInputs,
+        _rover: MartianRover,
+    ) -> Result<StageDef<Self::ChunkInputs>> {
+        let raw_gib = args.raw_matrix_h5.estimate_mem_gib()?;
+        let filtered_gib = args.filtered_matrix_h5.estimate_mem_gib()?;
+        println!("raw_gib={raw_gib:.1},filtered_gib={filtered_gib:.1}");
+        Ok(StageDef::with_join_resource(Resource::with_mem_gb(
+            2 + (raw_gib + 2.0 * filtered_gib).ceil() as isize,
+        )))
+    }
+
+    fn main(
+        &self,
+        _args: Self::StageInputs,
+        _chunk_args: Self::ChunkInputs,
+        _rover: MartianRover,
+    ) -> Result<Self::ChunkOutputs> {
+        unreachable!()
+    }
+
+    fn join(
+        &self,
+        args: Self::StageInputs,
+        _chunk_defs: Vec<Self::ChunkInputs>,
+        _chunk_outs: Vec<Self::ChunkOutputs>,
+        rover: MartianRover,
+    ) -> Result<Self::StageOutputs> {
+        let no_outputs = EstimateAmbientRnaStageOutputs {
+            ambient_rna_metrics: None,
+            ambient_rna_csv: None,
+            ambient_corrected_matrix_h5: None,
+            ambient_rna_chart: None,
+        };
+        let cells = args.filtered_matrix_h5.read()?;
+        if cells.num_barcodes() == 0 {
+            return Ok(no_outputs);
+        }
+        let is_gene = gene_features(&cells);
+
+        // Build the ambient profile from the low-UMI barcodes of the raw matrix.
+        let raw = args.raw_matrix_h5.read()?;
+        ensure!(
+            raw.num_features() == cells.num_features(),
+            "raw matrix has {} features and filtered matrix {}",
+            raw.num_features(),
+            cells.num_features()
+        );
+        let cell_barcodes: TxHashSet<&str> =
+            cells.barcodes().iter().map(|bc| bc.as_str()).collect();
+        let ambient_barcodes: TxHashSet<usize> = raw
+            .barcode_counts_for_feature_type(FeatureType::Gene)
+            .enumerate()
+            .filter(|&(_, (barcode, umis))| {
+                (1..MAX_AMBIENT_BARCODE_UMIS).contains(&umis)
+                    && !cell_barcodes.contains(barcode.as_str())
+            })
+            .map(|(i, _)| i)
+            .collect();
+        let mut profile = sum_gene_counts(&raw, &is_gene, &ambient_barcodes);
+        drop(raw);
+        let ambient_profile_umis: f64 = profile.iter().sum();
+        if ambient_profile_umis < MIN_AMBIENT_PROFILE_UMIS {
+            println!(
+                "Only {ambient_profile_umis} UMIs in {} ambient barcodes, skipping ambient RNA \
+                 estimation",
+                ambient_barcodes.len()
+            );
+            return Ok(no_outputs);
+        }
+        profile.iter_mut().for_each(|p| *p /= ambient_profile_umis);
+        let estimation_genes: Vec<usize> = (0..profile.len())
+            .filter(|&g| profile[g] > 0.0)
+            .sorted_by(|&a, &b| profile[b].total_cmp(&profile[a]))
+            .take(NUM_ESTIMATION_GENES)
+            .collect();
+
+        // Estimate the ambient RNA fraction of each cluster.
+        let clusters = read_clusters(args.clustering_h5.as_ref(), cells.num_barcodes())?;
+        let mut cluster_counts: BTreeMap<i64, Vec<f64>> = BTreeMap::new();
+        for count in cells.raw_counts() {
+            if is_gene[count.feature_idx] {
+                cluster_counts
+                    .entry(clusters[count.barcode_idx])
+                    .or_insert_with(|| vec![0.0; cells.num_features()])[count.feature_idx] +=
+                    f64::from(count.count);
+            }
+        }
+        let estimated: BTreeMap<i64, Option<f64>> = clusters
+            .iter()
+            .unique()
+            .map(|cluster| {
+                let fraction = cluster_counts
+                    .get(cluster)
+                    .and_then(|counts| estimate_contamination(counts, &profile, &estimation_genes));
+                (*cluster, fraction)
+            })
+            .collect();
+        let cell_umis: Vec<f64> = cells
+            .barcode_counts_for_feature_type(FeatureType::Gene)
+            .map(|(_, umis)| umis as f64)
+            .collect();
+        let (weighted_sum, weight) = zip(&clusters, &cell_umis)
+            .filter_map(|(cluster, &umis)| estimated[cluster].map(|fraction| (fraction, umis)))
+            .fold((0.0, 0.0), |(sum, total), (fraction, umis)| {
+                (sum + fraction * umis, total + umis)
+            });
+        if weight == 0.0 {
+            println!("No cluster has enough UMIs to estimate ambient RNA");
+            return Ok(no_outputs);
+        }
+        let fallback = weighted_sum / weight;
+        let cluster_fractions: BTreeMap<i64, f64> = estimated
+            .into_iter()
+            .map(|(cluster, fraction)| (cluster, fraction.unwrap_or(fallback)))
+            .collect();
+        let estimated_fraction = zip(&clusters, &cell_umis)
+            .map(|(cluster, umis)| cluster_fractions[cluster] * umis)
+            .sum::<f64>()
+            / cell_umis.iter().sum::<f64>();
+        println!("Estimated ambient RNA fraction: {estimated_fraction:.4}");
+
+        // Remove the ambient counts of each cell.
+        let corrected = cells.map_barcode_counts(|bc, features, counts| {
+            let counts: Vec<f64> = counts.iter().map(|&c| f64::from(c)).collect();
+            let feature_profile: Vec<f64> = features.iter().map(|&f| profile[f as usize]).collect();
+            let to_remove = cluster_fractions[&clusters[bc]] * cell_umis[bc];
+            subtract_ambient(&counts, &feature_profile, to_remove)
+                .into_iter()
+                .map(|c| c.round() as i32)
+                .collect()
+        });
+        let ambient_corrected_matrix_h5: CountMatrixFile =
+            rover.make_path("ambient_corrected_feature_bc_matrix");
+        corrected.write_h5(&ambient_corrected_matrix_h5, &rover.pipelines_version())?;
+
+        let ambient_rna_csv: CsvFile<_> = rover.make_path("ambient_rna");
+        let rows: Vec<_> = zip(cells.barcodes(), &clusters)
+            .map(|(barcode, &cluster)| AmbientRnaRow {
+                barcode: barcode.as_str().to_string(),
+                cluster,
+                ambient_rna_fraction: cluster_fractions[&cluster],
+            })
+            .collect();
+        ambient_rna_csv.write(&rows)?;
+        let ambient_rna_metrics: JsonFile<_> = rover.make_path("ambient_rna_metrics");
+        ambient_rna_metrics.write(&AmbientRnaMetrics {
+            estimated_fraction_ambient_rna: estimated_fraction,
+            ambient_profile_barcodes: ambient_barcodes.len(),
+            ambient_profile_umis: ambient_profile_umis as i64,
+        })?;
+        let ambient_rna_chart: JsonFile<_> = rover.make_path("ambient_rna_chart");
+        ambient_rna_chart.write(&contamination_chart(&cluster_fractions, estimated_fraction))?;
+
+        Ok(EstimateAmbientRnaStageOutputs {
+            ambient_rna_metrics: Some(ambient_rna_metrics),
+            ambient_rna_csv: Some(ambient_rna_csv),
+            ambient_corrected_matrix_h5: Some(ambient_corrected_matrix_h5),
+            ambient_rna_chart: Some(ambient_rna_chart),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_contamination() {
+        // Genes 0-9 are abundant in the ambient profile and gene 10 is expressed by the cluster.
+        let mut profile = vec![0.1; 10];
+        profile.push(0.0);
+        let genes: Vec<usize> = (0..10).collect();
+        // The cluster expresses genes 1-9 but not gene 0, whose counts are all ambient.
+        let mut counts = vec![500.0; 10];
+        counts[0] = 200.0;
+        counts.push(5300.0);
+        let total: f64 = counts.iter().sum();
+        let fraction = estimate_contamination(&counts, &profile, &genes).unwrap();
+        assert!((fraction - 200.0 / (0.1 * total)).abs() < 1e-12);
+
+        assert_eq!(estimate_contamination(&[1.0; 11], &profile, &genes), None);
+    }
+
+    #[test]
+    fn test_subtract_ambient() {
+        let profile = [0.5, 0.25, 0.25, 0.0];
+        // Gene 0 is exhausted, and its remaining ambient counts are redistributed.
+        let corrected = subtract_ambient(&[1.0, 10.0, 10.0, 7.0], &profile, 6.0);
+        assert_eq!(corrected, vec![0.0, 7.5, 7.5, 7.0]);
+
+        let corrected = subtract_ambient(&[4.0, 4.0, 4.0, 7.0], &profile, 4.0);
+        assert_eq!(corrected, vec![2.0, 3.0, 3.0, 7.0]);
+    }
+}
@@ -14,7 +14,7 @@ use cr_types::reference::feature_reference::{
 };
 use cr_types::rna_read::RnaChunk;
 use cr_types::types::{
-    BcCountFormat, BcSegmentCountFormat, FeatureBarcodeType, GemWell, LibraryType,
+    BcCountFormat, BcSegmentCountFormat, FeatureBarcodeType, GemWell, LibraryType, TomlFile,
 };
 use cr_types::{FeatureCountFormat, MetricsFile};
 use cr_websummary::multi::tables::SequencingMetricsTable;
@@ -51,6 +51,8 @@ pub struct MakeShardStageInputs {
     pub target_set_name: Option<String>,
     pub libraries_to_translate: TxHashSet<LibraryType>,
     pub feature_config: Option<FeatureConfig>,
+    /// The effective parameters.toml of WRITE_EFFECTIVE_PARAMETERS.
+    pub parameters: Option<TomlFile>,
 }
 
 #[derive(Clone, Serialize, Deserialize, MartianStruct)]
@@ -172,6 +174,7 @@ impl MartianStage for MakeShard {
         chunk_args: Self::ChunkInputs,
         rover: MartianRover,
     ) -> Result<Self::ChunkOutputs> {
+        parameters_toml::load_overrides(args.parameters.as_deref())?;
         let id = chunk_args.chunk_id;
 
         let rna_chunk = {
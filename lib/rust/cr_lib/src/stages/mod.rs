@@ -1,5 +1,6 @@
 pub mod align_and_count;
 pub mod barcode_correction;
+pub mod bin_count_matrix;
 pub mod build_per_sample_vdj_ws_contents;
 pub mod call_tags_overhang;
 pub mod call_tags_rtl;
@@ -15,6 +16,8 @@ pub mod demux_probe_bc_matrix;
 pub mod detect_chemistry;
 pub mod detect_chemistry_test;
 pub mod detect_vdj_receptor;
+pub mod diff_h5_files;
+pub mod estimate_ambient_rna;
 pub mod extract_single_chemistry;
 pub mod get_chemistry_def;
 pub mod get_gdna_metrics;
@@ -31,14 +34,19 @@ pub mod rust_bridge;
 pub mod set_targeted_umi_filter;
 pub mod setup_vdj_analysis;
 pub mod setup_vdj_demux;
+pub mod split_bam;
 pub mod subsample_barcodes;
+pub mod tag_bam_cells;
 pub mod write_barcode_index;
 pub mod write_barcode_summary;
+pub mod write_effective_parameters;
 pub mod write_gene_index;
 pub mod write_h5_matrix;
+pub mod write_h5ad;
 pub mod write_matrix_market;
 pub mod write_molecule_info;
 pub mod write_multi_web_summary_json;
+pub mod write_parquet;
 pub mod write_pos_bam;
 
 #[cfg(feature = "tenx_internal")]
@@ -9,8 +9,9 @@ use anyhow::{bail, ensure, Result};
 use cr_types::chemistry::ChemistryName;
 use cr_types::reference::feature_reference::FeatureType;
 use cr_types::reference::reference_info::ReferenceInfo;
-use cr_types::types::FileOrBytes;
+use cr_types::types::{FileOrBytes, TomlFile};
 use cr_types::FeatureBarcodeType;
+use cr_websummary::alert::UserAlertConfig;
 use martian::prelude::*;
 use martian_derive::{make_mro, MartianStruct};
 use multi::config::preflight::{
@@ -21,11 +22,16 @@ use multi::config::{multiconst, ChemistryParam, MultiConfigCsv, MultiConfigCsvFi
 use parameters_toml::max_multiplexing_tags;
 use serde::{Deserialize, Serialize};
 use std::io::Cursor;
+use std::path::PathBuf;
 
 #[derive(Clone, Serialize, Deserialize, MartianStruct)]
 pub struct MultiPreflightInputs {
     pub config: FileOrBytes,
     pub is_pd: bool,
+    /// The web summary alerts config, validated before running the pipeline.
+    pub alerts_config: Option<PathBuf>,
+    /// The per-run overrides of parameters.toml of --parameters.
+    pub parameter_overrides: Option<TomlFile>,
 }
 
 #[derive(Clone, Serialize, Deserialize, MartianStruct)]
@@ -43,6 +49,9 @@ impl MartianMain for MultiPreflight {
     fn main(&self, args: Self::StageInputs, _rover: MartianRover) -> Result<Self::StageOutputs> {
         let hostname = hostname();
         check_resource_limits()?;
+        if let Some(alerts_config) = &args.alerts_config {
+            UserAlertConfig::from_path(alerts_config)?;
+        }
         // make a new chunk per sample_def to do chemistry detection
         let cfg = match args.config {
             FileOrBytes {
@@ -65,6 +74,9 @@ impl MartianMain for MultiPreflight {
                 file: Some(_),
             } => bail!("exactly one of either config file or config bytes must be provided"),
         }?;
+        parameters_toml::set_overrides(
+            cfg.parameter_overrides(args.parameter_overrides.as_deref())?,
+        )?;
         let gene_expression = cfg.gene_expression.as_ref();
         let (transcriptome, target_genes) = if let Some(gex) = gene_expression {
             ensure!(
@@ -189,6 +201,8 @@ mod tests {
                 file: Some(csv_file.as_ref().into()),
             },
             is_pd,
+            alerts_config: None,
+            parameter_overrides: None,
         })
     }
 
@@ -7,7 +7,7 @@ use cr_types::chemistry::{
 };
 use cr_types::reference::feature_reference::{FeatureConfig, FeatureReferenceFile};
 use cr_types::sample_def::SampleDef;
-use cr_types::types::FileOrBytes;
+use cr_types::types::{FileOrBytes, TomlFile};
 use cr_types::{AlignerParam, LibraryType, TargetingMethod, VdjChainType};
 use martian::prelude::*;
 use martian_derive::{make_mro, MartianStruct, MartianType};
@@ -50,6 +50,8 @@ pub struct ParseMultiConfigStageInputs {
     pub config_hash: Option<String>,
     pub params: Option<MultiParams>,
     pub is_pd: bool,
+    /// The per-run overrides of parameters.toml of --parameters.
+    pub parameter_overrides: Option<TomlFile>,
 }
 
 /// The `per_gem_well` parameter is applied at the library-level (CMO-multiplexing, standard GEX).
@@ -148,11 +150,17 @@ pub struct CountInputs {
     pub throughput: Option<String>,
     pub check_library_compatibility: bool,
     pub no_bam: bool,
+    pub no_h5ad: bool,
+    pub no_parquet: bool,
+    pub velocity_layers: bool,
+    pub tag_bam: bool,
     pub force_sample_barcodes: BarcodeAssignments,
     pub tenx_cmos: Option<bool>,
     pub min_assignment_confidence: Option<f64>,
     pub annotations: Option<Vec<CsvFile<()>>>,
     pub cas_model: Option<String>,
+    pub marker_gene_sets: Option<CsvFile<()>>,
+    pub antibody_normalization: bool,
 }
 
 /// General VdjInputs which are not chain specific
@@ -270,6 +278,9 @@ impl MartianMain for ParseMultiConfig {
             file
         };
         let cfg = config_file.read()?;
+        parameters_toml::set_overrides(
+            cfg.parameter_overrides(args.parameter_overrides.as_deref())?,
+        )?;
 
         let MultiParams {
             initial_reads,
@@ -496,6 +507,10 @@ impl MartianMain for ParseMultiConfig {
                     throughput: None,
                     check_library_compatibility: gex.check_library_compatibility,
                     no_bam: !gex.create_bam,
+                    no_h5ad: !gex.create_h5ad,
+                    no_parquet: !gex.create_parquet,
+                    velocity_layers: gex.velocity_layers,
+                    tag_bam: gex.tag_bam,
                     force_sample_barcodes: BarcodeAssignments {
                         sample_barcodes: sample_barcodes.clone(),
                         non_singlet_barcodes: non_singlet_barcodes.clone(),
@@ -505,6 +520,11 @@ impl MartianMain for ParseMultiConfig {
                     min_assignment_confidence: gex.min_assignment_confidence,
                     annotations: None,
                     cas_model: gex.cas_model.clone(),
+                    marker_gene_sets: gex.marker_gene_sets.as_ref().map(CsvFile::from),
+                    antibody_normalization: cfg
+                        .feature
+                        .as_ref()
+                        .is_some_and(|feature| feature.antibody_normalization),
                 });
 
                 (
@@ -554,9 +574,7 @@ impl MartianMain for ParseMultiConfig {
                             VdjChainType::VdjTGD => {
                                 // In gamma/delta mode we need inner-enrichment-primers to be present in multi config
                                 if vdj.inner_enrichment_primers.is_none() {
-                                    bail!(
-                                        "VDJ-T-GD library requires inner enrichment primers to be specified in the multi config file."
-                                    );
+                                    bail!(vdj_reference::TRGD_INNER_PRIMERS_REQUIRED);
                                 }
                                 Some("TR_GD")
                             }
@@ -565,7 +583,8 @@ impl MartianMain for ParseMultiConfig {
                         }
                     } else {
                         None
-                    }.map(ToString::to_string);
+                    }
+                    .map(ToString::to_string);
 
                     vdj_inputs.push(VdjInputs {
                         chemistry_spec: AutoOrRefinedChemistry::Auto(AutoChemistryName::Vdj),
@@ -673,6 +692,7 @@ mod tests {
             config_hash: None,
             params: None,
             is_pd,
+            parameter_overrides: None,
         })
     }
 
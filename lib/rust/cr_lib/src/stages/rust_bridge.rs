@@ -6,7 +6,7 @@ use anyhow::Result;
 use barcode::Barcode;
 use cr_types::chemistry::ChemistryDefs;
 use cr_types::rna_read::RnaRead;
-use cr_types::types::{GemWell, LibraryType};
+use cr_types::types::{GemWell, LibraryType, TomlFile};
 use cr_types::{BcCountFormat, MetricsFile};
 use itertools::{GroupBy, Itertools};
 use martian::prelude::*;
@@ -39,6 +39,8 @@ pub struct RustBridgeStageInputs {
     pub valid_corrected: Vec<ReadShardFile>,
     pub raw_barcode_counts: BcCountFormat,
     pub corrected_barcode_counts: BcCountFormat,
+    /// The effective parameters.toml of WRITE_EFFECTIVE_PARAMETERS.
+    pub parameters: Option<TomlFile>,
 }
 
 #[derive(Clone, Serialize, Deserialize, MartianStruct)]
@@ -131,6 +133,7 @@ impl MartianStage for RustBridge {
         chunk_args: Self::ChunkInputs,
         rover: MartianRover,
     ) -> Result<Self::ChunkOutputs> {
+        parameters_toml::load_overrides(args.parameters.as_deref())?;
         let reader: ShardReader<RnaRead, BarcodeOrder> =
             ShardReader::open_set(&chunk_args.valid_shards)?;
 
@@ -0,0 +1,370 @@
+//! Martian stage SPLIT_BAM
+//! Split a position-sorted BAM file into one indexed BAM file per group of barcodes,
+//! such as a sample, a cluster or a list of cells.
+
+use crate::stages::write_pos_bam::{concat_bams, index_bam, use_csi_instead_of_bai};
+use crate::BamFile;
+use anyhow::{bail, ensure, Context, Result};
+use cr_bam::bam::get_read_barcode;
+use itertools::Itertools;
+use martian::prelude::*;
+use martian_derive::{make_mro, MartianStruct};
+use martian_filetypes::tabular_file::CsvFile;
+use martian_filetypes::FileTypeRead;
+use metric::{TxHashMap, TxHashSet};
+use rust_htslib::bam::{self, FetchDefinition, Header, Read, Record};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// The group of barcodes listed without a group, to write a single filtered BAM file.
+const DEFAULT_GROUP: &str = "filtered";
+
+/// The number of chunks into which the reference contigs are partitioned.
+const NUM_CHUNKS: usize = 64;
+
+const SPLIT_BAM_THREADS: isize = 2;
+
+/// The maximum number of BAM files that a chunk writes at once.
+/// A chunk with more groups reads its contigs once per batch of groups.
+const MAX_OPEN_WRITERS: usize = 256;
+
+/// A row of the barcode groups CSV with the columns barcode and group.
+/// The group column is optional.
+#[derive(Serialize, Deserialize)]
+struct BarcodeGroupRow {
+    barcode: String,
+    #[serde(default)]
+    group: Option<String>,
+}
+
+#[derive(Clone, Deserialize, MartianStruct)]
+pub struct SplitBamStageInputs {
+    /// A position-sorted BAM file with an index file .bam.bai or .bam.csi.
+    pub bam: BamFile,
+    /// A CSV file with the columns barcode and optionally group.
+    pub barcode_groups: CsvFile<()>,
+}
+
+#[derive(Serialize, Deserialize, MartianStruct)]
+pub struct SplitBamStageOutputs {
+    /// A folder of the indexed BAM files named after their group.
+    pub split_bams: PathBuf,
+}
+
+#[derive(Clone, Serialize, Deserialize, MartianStruct)]
+pub struct SplitBamChunkInputs {
+    /// The reference contigs of this chunk.
+    pub tids: Vec<u32>,
+    /// Whether this chunk includes the unmapped reads without a position.
+    pub unmapped: bool,
+}
+
+#[derive(Serialize, Deserialize, MartianStruct)]
+pub struct SplitBamChunkOutputs {
+    /// The BAM file of each group, in the sorted order of the groups.
+    pub group_bams: Vec<BamFile>,
+}
+
+/// The barcodes of each group.
+struct BarcodeGroups {
+    /// The sorted names of the groups.
+    groups: Vec<String>,
+    /// The index into groups of each barcode.
+    barcode_to_group: TxHashMap<Vec<u8>, usize>,
+}
+
+impl BarcodeGroups {
+    fn read(path: &CsvFile<()>) -> Result<Self> {
+        let rows = CsvFile::<BarcodeGroupRow>::from_path(path).read()?;
+        ensure!(!rows.is_empty(), "{} lists no barcodes", path.display());
+        let groups: Vec<String> = rows
+            .iter()
+            .map(|row| row.group.as_deref().unwrap_or(DEFAULT_GROUP).to_string())
+            .unique()
+            .sorted()
+            .collect();
+        let mut barcode_to_group = TxHashMap::default();
+        for row in rows {
+            let group = row.group.as_deref().unwrap_or(DEFAULT_GROUP);
+            let index = groups.binary_search_by(|x| x.as_str().cmp(group)).unwrap();
+            if let Some(previous) = barcode_to_group.insert(row.barcode.clone().into_bytes(), index)
+            {
+                if previous != index {
+                    bail!(
+                        "barcode {} is listed in both groups {} and {group}",
+                        row.barcode,
+                        groups[previous]
+                    );
+                }
+            }
+        }
+        Ok(BarcodeGroups {
+            groups,
+            barcode_to_group,
+        })
+    }
+
+    /// Return the group of a read, if any.
+    fn group_of(&self, record: &Record) -> Option<usize> {
+        self.barcode_to_group
+            .get(&get_read_barcode(record)?)
+            .copied()
+    }
+}
+
+/// Return a file name for a group, replacing the characters other than
+/// letters, digits, dash, underscore and period.
+fn group_file_name(group: &str) -> String {
+    group
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "-_.".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Return a distinct file name for each group. Groups whose file names collide,
+/// such as "Sample 1" and "Sample_1", are disambiguated by a numeric suffix.
+fn group_file_names(groups: &[String]) -> Vec<String> {
+    let mut used = TxHashSet::default();
+    groups
+        .iter()
+        .map(|group| {
+            let base = group_file_name(group);
+            let mut name = base.clone();
+            let mut suffix = 1;
+            while !used.insert(name.clone()) {
+                suffix += 1;
+                name = format!("{base}_{suffix}");
+            }
+            if name != base {
+                println!("Writing group {group} to {name}.bam");
+            }
+            name
+        })
+        .collect()
+}
+
+/// Partition the reference contigs into chunks of similar total length.
+fn partition_contigs(lengths: &[u64], num_chunks: usize) -> Vec<Vec<u32>> {
+    let total: u64 = lengths.iter().sum();
+    let target = (total / num_chunks.max(1) as u64).max(1);
+    let mut chunks = Vec::new();
+    let mut chunk = Vec::new();
+    let mut chunk_len = 0;
+    for (tid, &len) in lengths.iter().enumerate() {
+        chunk.push(tid as u32);
+        chunk_len += len;
+        if chunk_len >= target {
+            chunks.push(std::mem::take(&mut chunk));
+            chunk_len = 0;
+        }
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+/// Write the reads of the given records to the BAM file of their group.
+/// The writers are those of the groups starting at first_group, and the reads
+/// of the other groups are skipped.
+fn split_records(
+    records: impl Iterator<Item = rust_htslib::errors::Result<Record>>,
+    groups: &BarcodeGroups,
+    first_group: usize,
+    writers: &mut [bam::Writer],
+) -> Result<()> {
+    for record in records {
+        let record = record?;
+        let writer = groups
+            .group_of(&record)
+            .and_then(|group| group.checked_sub(first_group))
+            .and_then(|i| writers.get_mut(i));
+        if let Some(writer) = writer {
+            writer.write(&record)?;
+        }
+    }
+    Ok(())
+}
+
+/// Martian stage SPLIT_BAM
+pub struct SplitBam;
+
+#[make_mro(volatile = strict)]
+impl MartianStage for SplitBam {
+    type StageInputs = SplitBamStageInputs;
+    type StageOutputs = SplitBamStageOutputs;
+    type ChunkInputs = SplitBamChunkInputs;
+    type ChunkOutputs = SplitBamChunkOutputs;
+
+    fn split(
+        &self,
+        args: Self::StageInputs,
+        _rover: MartianRover,
+    ) -> Result<StageDef<Self::ChunkInputs>> {
+        // Fail early when the BAM file is not indexed.
+        let reader = bam::IndexedReader::from_path(&args.bam)
+            .with_context(|| format!("{} is not indexed", args.bam.display()))?;
+        let header = reader.header();
+        let lengths: Vec<u64> = (0..header.target_count())
+            .map(|tid| header.target_len(tid).unwrap_or(0))
+            .collect();
+
+        let num_barcodes = BarcodeGroups::read(&args.barcode_groups)?
+            .barcode_to_group
+            .len();
+        let mem_gb = 1 + (num_barcodes * 200).div_ceil(1 << 30) as isize;
+
+        Ok(partition_contigs(&lengths, NUM_CHUNKS)
+            .into_iter()
+            .map(|tids| SplitBamChunkInputs {
+                tids,
+                unmapped: false,
+            })
+            .chain(std::iter::once(SplitBamChunkInputs {
+                tids: Vec::new(),
+                unmapped: true,
+            }))
+            .map(|chunk| {
+                (
+                    chunk,
+                    Resource::with_mem_gb(mem_gb).threads(SPLIT_BAM_THREADS),
+                )
+            })
+            .collect::<StageDef<_>>()
+            .join_resource(Resource::with_mem_gb(1).threads(4)))
+    }
+
+    fn main(
+        &self,
+        args: Self::StageInputs,
+        chunk_args: Self::ChunkInputs,
+        rover: MartianRover,
+    ) -> Result<Self::ChunkOutputs> {
+        let groups = BarcodeGroups::read(&args.barcode_groups)?;
+        let mut reader = bam::IndexedReader::from_path(&args.bam)?;
+        // Keep the header including its comments, such as library_info used by bamtofastq.
+        let header = Header::from_template(reader.header());
+
+        let threads = rover.get_threads();
+        let threadpool = rust_htslib::tpool::ThreadPool::new(threads.max(1) as u32)?;
+        reader.set_thread_pool(&threadpool)?;
+        let group_bams: Vec<BamFile> = (0..groups.groups.len())
+            .map(|i| rover.make_path(format!("group_{i}")))
+            .collect();
+
+        // Bound the number of open files by writing one batch of groups at a time.
+        for (batch, batch_bams) in group_bams.chunks(MAX_OPEN_WRITERS).enumerate() {
+            let first_group = batch * MAX_OPEN_WRITERS;
+            let mut writers: Vec<_> = batch_bams
+                .iter()
+                .map(|path| {
+                    let mut writer = bam::Writer::from_path(path, &header, bam::Format::Bam)?;
+                    writer.set_thread_pool(&threadpool)?;
+                    anyhow::Ok(writer)
+                })
+                .try_collect()?;
+
+            for &tid in &chunk_args.tids {
+                reader.fetch(FetchDefinition::CompleteTid(tid as i32))?;
+                split_records(reader.records(), &groups, first_group, &mut writers)?;
+            }
+            if chunk_args.unmapped {
+                reader.fetch(FetchDefinition::Unmapped)?;
+                split_records(reader.records(), &groups, first_group, &mut writers)?;
+            }
+        }
+        Ok(SplitBamChunkOutputs { group_bams })
+    }
+
+    fn join(
+        &self,
+        args: Self::StageInputs,
+        _chunk_defs: Vec<Self::ChunkInputs>,
+        chunk_outs: Vec<Self::ChunkOutputs>,
+        rover: MartianRover,
+    ) -> Result<Self::StageOutputs> {
+        let groups = BarcodeGroups::read(&args.barcode_groups)?.groups;
+        let use_csi = use_csi_instead_of_bai(Header::from_template(
+            bam::Reader::from_path(&args.bam)?.header(),
+        ));
+        let index_extension = if use_csi { "bam.csi" } else { "bam.bai" };
+
+        let split_bams: PathBuf = rover.make_path("split_bams");
+        std::fs::create_dir(&split_bams)?;
+        let threads = (rover.get_threads() - 1).max(1);
+        for (i, name) in group_file_names(&groups).into_iter().enumerate() {
+            let bam_path = split_bams.join(format!("{name}.bam"));
+            let chunks: Vec<BamFile> = chunk_outs
+                .iter()
+                .map(|chunk| chunk.group_bams[i].clone())
+                .collect();
+            concat_bams(&chunks, &bam_path, threads)?;
+            index_bam(
+                &bam_path,
+                &split_bams.join(format!("{name}.{index_extension}")),
+                threads,
+                use_csi,
+            );
+            for chunk in chunks {
+                std::fs::remove_file(chunk)?;
+            }
+        }
+        Ok(SplitBamStageOutputs { split_bams })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partition_contigs() {
+        assert_eq!(
+            partition_contigs(&[100, 10, 10, 80, 50, 50], 3),
+            vec![vec![0], vec![1, 2, 3], vec![4, 5]]
+        );
+        assert_eq!(partition_contigs(&[5, 5], 64), vec![vec![0], vec![1]]);
+        assert!(partition_contigs(&[], 64).is_empty());
+    }
+
+    #[test]
+    fn test_group_file_name() {
+        assert_eq!(group_file_name("Sample 1/A"), "Sample_1_A");
+        assert_eq!(group_file_name("cluster-2.x_y"), "cluster-2.x_y");
+
+        let groups = ["Sample 1", "Sample_1", "Sample_1_2", "Sample/1"].map(String::from);
+        assert_eq!(
+            group_file_names(&groups),
+            ["Sample_1", "Sample_1_2", "Sample_1_2_2", "Sample_1_3"]
+        );
+    }
+
+    #[test]
+    fn test_barcode_groups() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let csv = dir.path().join("groups.csv");
+        std::fs::write(
+            &csv,
+            "barcode,group\nAAAC-1,tumor\nAAAG-1,normal\nAAAT-1,tumor\n",
+        )?;
+        let groups = BarcodeGroups::read(&CsvFile::from_path(&csv))?;
+        assert_eq!(groups.groups, ["normal", "tumor"]);
+        assert_eq!(groups.barcode_to_group[b"AAAG-1".as_slice()], 0);
+        assert_eq!(groups.barcode_to_group[b"AAAT-1".as_slice()], 1);
+
+        std::fs::write(&csv, "barcode\nAAAC-1\nAAAG-1\n")?;
+        let groups = BarcodeGroups::read(&CsvFile::from_path(&csv))?;
+        assert_eq!(groups.groups, [DEFAULT_GROUP]);
+        assert_eq!(groups.barcode_to_group.len(), 2);
+
+        std::fs::write(&csv, "barcode,group\nAAAC-1,a\nAAAC-1,b\n")?;
+        assert!(BarcodeGroups::read(&CsvFile::from_path(&csv)).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+//! Martian stage TAG_BAM_CELLS
+//! Annotate each read of a BAM file with the cell call and the sample assignment of its barcode.
+//! Rewriting the BAM file is opt-in; otherwise the BAM file is passed through unchanged.
+
+use crate::stages::write_pos_bam::{
+    index_bam, use_csi_instead_of_bai, BaiIndexFile, CsiIndexFile, SampleBamFile,
+};
+use crate::utils::hard_link_file;
+use crate::BamFile;
+use anyhow::Result;
+use barcode::Barcode;
+use cr_bam::bam::CrRecord;
+use cr_bam::bam_tags::{ExtraFlags, EXTRA_FLAGS_TAG, SAMPLE_ASSIGNMENT_TAG};
+use cr_types::filtered_barcodes::{read_filtered_barcodes_set, FilteredBarcodesCsv};
+use cr_types::types::{SampleAssignment, SampleBarcodes};
+use cr_types::{BarcodeToSample, SampleBarcodesFile};
+use martian::prelude::*;
+use martian_derive::{make_mro, MartianStruct};
+use metric::TxHashSet;
+use rust_htslib::bam::record::Aux;
+use rust_htslib::bam::{self, Header, Read, Record};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Deserialize, MartianStruct)]
+pub struct TagBamCellsStageInputs {
+    /// The position-sorted BAM file of WRITE_POS_BAM, of either the library or one sample.
+    /// The output is null when this input is null.
+    pub pos_sorted_bam: Option<SampleBamFile>,
+    /// Tag the reads when true, and otherwise hard link the input BAM file and its index.
+    pub tag_bam: bool,
+    pub filtered_barcodes: FilteredBarcodesCsv,
+    /// The barcodes of each sample, which is null for a non-multiplexed library.
+    pub sample_barcodes: Option<SampleBarcodesFile>,
+}
+
+#[derive(Serialize, Deserialize, MartianStruct)]
+pub struct TagBamCellsStageOutputs {
+    pub tagged_pos_sorted_bam: Option<SampleBamFile>,
+}
+
+/// Add the cell call to the extra flags xf:i of a read, using the bit CELL_BARCODE,
+/// and add the assigned sample as the tag sa:Z for a multiplexed library.
+/// Reads without a corrected barcode are not cells and are not assigned to a sample.
+/// Existing annotations are replaced, so that a BAM file may be tagged again.
+fn tag_record(
+    record: &mut Record,
+    cells: &TxHashSet<Barcode>,
+    barcode_to_sample: &BarcodeToSample<'_>,
+) -> Result<()> {
+    let barcode = record.processed_barcode();
+    let is_cell = barcode.as_ref().is_some_and(|bc| cells.contains(bc));
+
+    let mut flags = record.cr_extra_flags();
+    flags.set(ExtraFlags::CELL_BARCODE, is_cell);
+    let _ = record.remove_aux(EXTRA_FLAGS_TAG);
+    record.push_aux(EXTRA_FLAGS_TAG, Aux::I32(flags.bits() as i32))?;
+
+    let _ = record.remove_aux(SAMPLE_ASSIGNMENT_TAG);
+    if let Some(barcode) = barcode {
+        match barcode_to_sample.get_sample(&barcode) {
+            SampleAssignment::NonMultiplexed => (),
+            sample => {
+                record.push_aux(SAMPLE_ASSIGNMENT_TAG, Aux::String(&sample.to_string()))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Hard link the BAM file and its index into the stage directory without rewriting it.
+fn link_bam(pos_sorted_bam: SampleBamFile, rover: &MartianRover) -> Result<SampleBamFile> {
+    let bam_file: BamFile = rover.make_path("tagged_possorted_genome_bam");
+    hard_link_file(&pos_sorted_bam.bam_file, &bam_file)?;
+    let bai_index_file = pos_sorted_bam
+        .bai_index_file
+        .map(|index| {
+            let link: BaiIndexFile = rover.make_path("tagged_possorted_genome_bam");
+            hard_link_file(&index, &link).map(|()| link)
+        })
+        .transpose()?;
+    let csi_index_file = pos_sorted_bam
+        .csi_index_file
+        .map(|index| {
+            let link: CsiIndexFile = rover.make_path("tagged_possorted_genome_bam");
+            hard_link_file(&index, &link).map(|()| link)
+        })
+        .transpose()?;
+    Ok(SampleBamFile {
+        sample: pos_sorted_bam.sample,
+        bam_file,
+        bai_index_file,
+        csi_index_file,
+    })
+}
+
+/// Martian stage TAG_BAM_CELLS
+pub struct TagBamCells;
+
+#[make_mro(mem_gb = 4, threads = 4, volatile = strict)]
+impl MartianMain for TagBamCells {
+    type StageInputs = TagBamCellsStageInputs;
+    type StageOutputs = TagBamCellsStageOutputs;
+
+    fn main(&self, args: Self::StageInputs, rover: MartianRover) -> Result<Self::StageOutputs> {
+        let Some(pos_sorted_bam) = args.pos_sorted_bam else {
+            return Ok(TagBamCellsStageOutputs {
+                tagged_pos_sorted_bam: None,
+            });
+        };
+        if !args.tag_bam {
+            return Ok(TagBamCellsStageOutputs {
+                tagged_pos_sorted_bam: Some(link_bam(pos_sorted_bam, &rover)?),
+            });
+        }
+
+        let cells = read_filtered_barcodes_set(&args.filtered_barcodes)?;
+        let sample_barcodes = SampleBarcodes::read_from_json(args.sample_barcodes.as_ref())?;
+        let barcode_to_sample = BarcodeToSample::construct(&sample_barcodes);
+
+        let threads = rover.get_threads();
+        let mut reader = bam::Reader::from_path(&pos_sorted_bam.bam_file)?;
+        reader.set_threads(threads)?;
+        let header = Header::from_template(reader.header());
+        let use_csi = use_csi_instead_of_bai(header.clone());
+
+        let tagged_bam: BamFile = rover.make_path("tagged_possorted_genome_bam");
+        {
+            let mut writer = bam::Writer::from_path(&tagged_bam, &header, bam::Format::Bam)?;
+            writer.set_threads(threads)?;
+            let mut record = Record::new();
+            while let Some(result) = reader.read(&mut record) {
+                result?;
+                tag_record(&mut record, &cells, &barcode_to_sample)?;
+                writer.write(&record)?;
+            }
+        }
+
+        let (bai_index_file, csi_index_file) = if use_csi {
+            let index: CsiIndexFile = rover.make_path("tagged_possorted_genome_bam");
+            index_bam(&tagged_bam, &index, threads, use_csi);
+            (None, Some(index))
+        } else {
+            let index: BaiIndexFile = rover.make_path("tagged_possorted_genome_bam");
+            index_bam(&tagged_bam, &index, threads, use_csi);
+            (Some(index), None)
+        };
+
+        Ok(TagBamCellsStageOutputs {
+            tagged_pos_sorted_bam: Some(SampleBamFile {
+                sample: pos_sorted_bam.sample,
+                bam_file: tagged_bam,
+                bai_index_file,
+                csi_index_file,
+            }),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cr_bam::bam_tags::PROC_BC_SEQ_TAG;
+
+    fn record_with_barcode(barcode: Option<&str>) -> Record {
+        let mut record = Record::new();
+        record.set(b"read", None, b"ACGT", &[30; 4]);
+        if let Some(barcode) = barcode {
+            record
+                .push_aux(PROC_BC_SEQ_TAG, Aux::String(barcode))
+                .unwrap();
+        }
+        record
+            .push_aux(
+                EXTRA_FLAGS_TAG,
+                Aux::I32(ExtraFlags::CONF_MAPPED.bits() as i32),
+            )
+            .unwrap();
+        record
+    }
+
+    #[test]
+    fn test_tag_record() -> Result<()> {
+        let cell: Barcode = "AAACCTGCATCCCATC-1".parse()?;
+        let cells: TxHashSet<_> = [cell].into_iter().collect();
+        let sample_barcodes = SampleBarcodes::read_from_json(None)?;
+        let barcode_to_sample = BarcodeToSample::construct(&sample_barcodes);
+
+        let mut record = record_with_barcode(Some("AAACCTGCATCCCATC-1"));
+        tag_record(&mut record, &cells, &barcode_to_sample)?;
+        assert_eq!(
+            record.cr_extra_flags(),
+            ExtraFlags::CONF_MAPPED | ExtraFlags::CELL_BARCODE
+        );
+        assert!(record.aux(SAMPLE_ASSIGNMENT_TAG).is_err());
+
+        // Tagging again is idempotent.
+        tag_record(&mut record, &cells, &barcode_to_sample)?;
+        assert_eq!(
+            record.cr_extra_flags(),
+            ExtraFlags::CONF_MAPPED | ExtraFlags::CELL_BARCODE
+        );
+
+        for barcode in [Some("AAAGCAACACCGAAAG-1"), None] {
+            let mut record = record_with_barcode(barcode);
+            tag_record(&mut record, &cells, &barcode_to_sample)?;
+            assert_eq!(record.cr_extra_flags(), ExtraFlags::CONF_MAPPED);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_tag_record_multiplexed() -> Result<()> {
+        let cell: Barcode = "AAACCTGCATCCCATC-1".parse()?;
+        let cells: TxHashSet<_> = [cell].into_iter().collect();
+        let dir = tempfile::tempdir()?;
+        let json = dir.path().join("sample_barcodes.json");
+        std::fs::write(
+            &json,
+            r#"{"sample1": ["AAACCTGCATCCCATC-1"], "sample2": ["AAAGCAACACCGAAAG-1"]}"#,
+        )?;
+        let sample_barcodes =
+            SampleBarcodes::read_from_json(Some(&SampleBarcodesFile::from_path(&json)))?;
+        let barcode_to_sample = BarcodeToSample::construct(&sample_barcodes);
+
+        let sample_of = |barcode| -> Result<Option<String>> {
+            let mut record = record_with_barcode(barcode);
+            tag_record(&mut record, &cells, &barcode_to_sample)?;
+            // Tagging again replaces the sample assignment rather than adding a second tag.
+            tag_record(&mut record, &cells, &barcode_to_sample)?;
+            Ok(match record.aux(SAMPLE_ASSIGNMENT_TAG) {
+                Ok(Aux::String(sample)) => Some(sample.to_string()),
+                _ => None,
+            })
+        };
+        assert_eq!(
+            sample_of(Some("AAACCTGCATCCCATC-1"))?.as_deref(),
+            Some("sample1")
+        );
+        assert_eq!(
+            sample_of(Some("AAAGCAACACCGAAAG-1"))?.as_deref(),
+            Some("sample2")
+        );
+        assert_eq!(
+            sample_of(Some("AAAGCAAGTCCGAAAG-1"))?.as_deref(),
+            Some("unassigned")
+        );
+        assert_eq!(sample_of(None)?, None);
+        Ok(())
+    }
+}
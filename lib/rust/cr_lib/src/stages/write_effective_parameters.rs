@@ -0,0 +1,51 @@
+//! Martian stage WRITE_EFFECTIVE_PARAMETERS
+//! Record the parameters of parameters.toml used by this run, including the per-run overrides.
+//! The stages that read a parameter load this file.
+
+use anyhow::Result;
+use cr_types::types::TomlFile;
+use martian::prelude::{MartianMain, MartianRover};
+use martian_derive::{make_mro, MartianStruct};
+use multi::config::MultiConfigCsvFile;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Deserialize, MartianStruct)]
+pub struct WriteEffectiveParametersStageInputs {
+    /// The per-run overrides of parameters.toml of --parameters.
+    pub parameter_overrides: Option<TomlFile>,
+    /// The multi config CSV, whose [parameters] section is overridden by --parameters.
+    pub multi_config: Option<MultiConfigCsvFile>,
+}
+
+#[derive(Serialize, Deserialize, MartianStruct)]
+pub struct WriteEffectiveParametersStageOutputs {
+    pub parameters: TomlFile,
+}
+
+/// Martian stage WRITE_EFFECTIVE_PARAMETERS
+pub struct WriteEffectiveParameters;
+
+#[make_mro(volatile = strict)]
+impl MartianMain for WriteEffectiveParameters {
+    type StageInputs = WriteEffectiveParametersStageInputs;
+    type StageOutputs = WriteEffectiveParametersStageOutputs;
+
+    fn main(&self, args: Self::StageInputs, rover: MartianRover) -> Result<Self::StageOutputs> {
+        let overrides = match args.multi_config {
+            Some(multi_config) => multi_config
+                .read()?
+                .parameter_overrides(args.parameter_overrides.as_deref())?,
+            None => args
+                .parameter_overrides
+                .as_deref()
+                .map(parameters_toml::read_overrides)
+                .transpose()?
+                .unwrap_or_default(),
+        };
+        parameters_toml::set_overrides(overrides)?;
+
+        let parameters: TomlFile = rover.make_path("parameters");
+        std::fs::write(&parameters, parameters_toml::effective_parameters_toml()?)?;
+        Ok(WriteEffectiveParametersStageOutputs { parameters })
+    }
+}
@@ -0,0 +1,289 @@
+//! Martian stage WRITE_H5AD
+//! Write the filtered feature-barcode matrix, the secondary analysis and the sample and library
+//! metadata as an AnnData h5ad file.
+//! The spliced, unspliced and ambiguous counts used by RNA velocity are optionally written as layers.
+
+use anyhow::Result;
+use barcode::Barcode;
+use cr_h5::anndata::{write_h5ad, Column, DataFrame, H5adFile, Layer, UnsValue};
+use cr_h5::count_matrix::{CountMatrixFile, MatrixMetadata};
+use cr_types::filtered_barcodes::FilteredBarcodesCsv;
+use cr_types::{BarcodeThenFeatureOrder, CountShardFile, SpliceBarcodeCount, SpliceType};
+use itertools::Itertools;
+use martian::prelude::{MartianRover, MartianStage};
+use martian::{MartianVoid, Resource, StageDef};
+use martian_derive::{make_mro, MartianStruct};
+use martian_filetypes::LazyFileTypeIO;
+use metric::{TxHashMap, TxHashSet};
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+use shardio::ShardReader;
+use std::path::{Path, PathBuf};
+use strum::IntoEnumIterator;
+
+/// The embeddings of the analysis h5 that are copied to obsm.
+const EMBEDDINGS: [&str; 3] = ["pca", "tsne", "umap"];
+const CLUSTERING_GROUP: &str = "clustering";
+
+#[derive(Clone, Deserialize, MartianStruct)]
+pub struct WriteH5adStageInputs {
+    pub sample_id: String,
+    pub filtered_matrix_h5: CountMatrixFile,
+    pub filtered_barcodes: FilteredBarcodesCsv,
+    /// The secondary analysis folder, which is null when it is disabled.
+    pub analysis: Option<PathBuf>,
+    /// Shard files of spliced, unspliced and ambiguous counts sorted by barcode.
+    pub splice_counts: Option<Vec<CountShardFile>>,
+    /// Write the spliced, unspliced and ambiguous counts as layers for RNA velocity.
+    pub velocity_layers: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, MartianStruct)]
+pub struct WriteH5adStageOutputs {
+    pub h5ad: H5adFile,
+}
+
+/// Martian stage WRITE_H5AD
+pub struct WriteH5ad;
+
+/// Return the obs column holding the cell calls of a genome.
+fn is_cell_column(genome: &str, num_genomes: usize) -> String {
+    if num_genomes == 1 || genome.is_empty() {
+        "is_cell".to_string()
+    } else {
+        format!("is_cell_{genome}")
+    }
+}
+
+/// Return the obsm key of an embedding of the analysis h5.
+/// The gene expression embedding uses the name expected by scanpy, such as X_umap.
+fn obsm_key(embedding: &str, member: &str) -> String {
+    let member = member.trim_start_matches('_');
+    if member.starts_with("gene_expression_") {
+        format!("X_{embedding}")
+    } else {
+        format!("X_{embedding}_{member}")
+    }
+}
+
+/// Return the sample and library metadata stored in uns.
+fn uns_metadata(sample_id: &str, metadata: MatrixMetadata) -> Vec<(String, UnsValue)> {
+    let MatrixMetadata {
+        software_version,
+        chemistry_description,
+        library_ids,
+        original_gem_groups,
+    } = metadata;
+    [
+        ("sample_id", Some(UnsValue::String(sample_id.to_string()))),
+        ("software_version", software_version.map(UnsValue::String)),
+        (
+            "chemistry_description",
+            chemistry_description.map(UnsValue::String),
+        ),
+        ("library_ids", Some(UnsValue::Strings(library_ids))),
+        (
+            "original_gem_groups",
+            Some(UnsValue::Ints(original_gem_groups)),
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| Some((name.to_string(), value?)))
+    .collect()
+}
+
+/// Load the spliced, unspliced and ambiguous counts of the matrix barcodes as layers.
+fn load_splice_layers(splice_counts: &[CountShardFile], barcodes: &[String]) -> Result<Vec<Layer>> {
+    let barcode_rows: TxHashMap<Barcode, usize> = barcodes
+        .iter()
+        .enumerate()
+        .map(|(i, bc)| Ok((bc.parse()?, i)))
+        .collect::<Result<_>>()?;
+    let mut layers: Vec<Layer> = SpliceType::iter()
+        .map(|splice_type| Layer {
+            name: splice_type.layer_name().to_string(),
+            rows: vec![Vec::new(); barcodes.len()],
+        })
+        .collect();
+    let reader: ShardReader<SpliceBarcodeCount, BarcodeThenFeatureOrder> =
+        ShardReader::open_set(splice_counts)?;
+    // Counts are sorted by barcode and then by feature, as required by each layer row.
+    for count in reader.iter()? {
+        let count = count?;
+        if let Some(&row) = barcode_rows.get(&count.barcode) {
+            layers[count.splice_type as usize].rows[row].push((
+                i32::try_from(count.feature_idx)?,
+                i32::try_from(count.umi_count)?,
+            ));
+        }
+    }
+    Ok(layers)
+}
+
+/// Load the embeddings and clusterings of the analysis h5 having one row per barcode.
+fn load_analysis(
+    analysis_h5: &Path,
+    num_barcodes: usize,
+) -> Result<(Vec<(String, Array2<f64>)>, Vec<(String, Column)>)> {
+    let file = hdf5::File::open(analysis_h5)?;
+    let mut obsm = Vec::new();
+    for embedding in EMBEDDINGS {
+        let Ok(group) = file.group(embedding) else {
+            continue;
+        };
+        for member in group.member_names()? {
+            let array = group
+                .group(&member)?
+                .dataset(&format!("transformed_{embedding}_matrix"))?
+                .read_2d::<f64>()?;
+            if array.nrows() == num_barcodes {
+                obsm.push((obsm_key(embedding, &member), array));
+            } else {
+                println!("Skipping {embedding} {member} with {} rows", array.nrows());
+            }
+        }
+    }
+
+    let mut clusterings = Vec::new();
+    if let Ok(group) = file.group(CLUSTERING_GROUP) {
+        for member in group.member_names()? {
+            let labels = group
+                .group(&member)?
+                .dataset("clusters")?
+                .read_raw::<i64>()?;
+            if labels.len() == num_barcodes {
+                clusterings.push((
+                    member.trim_start_matches('_').to_string(),
+                    Column::Categorical(labels.iter().map(i64::to_string).collect()),
+                ));
+            } else {
+                println!("Skipping clustering {member} with {} rows", labels.len());
+            }
+        }
+    }
+    Ok((obsm, clusterings))
+}
+
+#[make_mro(volatile = strict)]
+impl MartianStage for WriteH5ad {
+    type StageInputs = WriteH5adStageInputs;
+    type StageOutputs = WriteH5adStageOutputs;
+    type ChunkInputs = MartianVoid;
+    type ChunkOutputs = MartianVoid;
+
+    fn split(
+        &self,
+        args: Self::StageInputs,
+        _rover: MartianRover,
+    ) -> Result<StageDef<Self::ChunkInputs>> {
+        let matrix_gib = args.filtered_matrix_h5.estimate_mem_gib()?;
+        println!("matrix_gib={matrix_gib:.1}");
+        // The velocity layers together hold about as many counts as the matrix.
+        let copies = if args.velocity_layers.unwrap_or(false) {
+            3.0
+        } else {
+            2.0
+        };
+        Ok(StageDef::with_join_resource(Resource::with_mem_gb(
+            2 + (copies * matrix_gib).ceil() as isize,
+        )))
+    }
+
+    fn main(
+        &self,
+        _args: Self::StageInputs,
+        _chunk_args: Self::ChunkInputs,
+        _rover: MartianRover,
+    ) -> Result<Self::ChunkOutputs> {
+        unreachable!()
+    }
+
+    fn join(
+        &self,
+        args: Self::StageInputs,
+        _chunk_defs: Vec<Self::ChunkInputs>,
+        _chunk_outs: Vec<Self::ChunkOutputs>,
+        rover: MartianRover,
+    ) -> Result<Self::StageOutputs> {
+        let matrix = args.filtered_matrix_h5.read()?;
+        let barcodes: Vec<String> = matrix
+            .barcodes()
+            .iter()
+            .map(|bc| bc.as_str().to_string())
+            .collect();
+        let num_barcodes = barcodes.len();
+
+        let mut cells_per_genome: TxHashMap<String, TxHashSet<String>> = TxHashMap::default();
+        for row in args.filtered_barcodes.lazy_reader()? {
+            let row = row?;
+            cells_per_genome
+                .entry(row.genome.to_string())
+                .or_default()
+                .insert(row.barcode.to_string());
+        }
+
+        let mut obs = DataFrame::new(barcodes);
+        obs.push(
+            "gem_group",
+            Column::Int(
+                obs.index
+                    .iter()
+                    .map(|bc| bc.rsplit_once('-').map_or(Ok(1), |(_, gg)| gg.parse()))
+                    .try_collect()?,
+            ),
+        );
+        obs.push(
+            "sample",
+            Column::Categorical(vec![args.sample_id.clone(); num_barcodes]),
+        );
+        let num_genomes = cells_per_genome.len();
+        for (genome, cells) in cells_per_genome.iter().sorted_by_key(|(genome, _)| *genome) {
+            let is_cell = obs.index.iter().map(|bc| cells.contains(bc)).collect();
+            obs.push(&is_cell_column(genome, num_genomes), Column::Bool(is_cell));
+        }
+
+        let analysis_h5 = args
+            .analysis
+            .map(|analysis| analysis.join("analysis.h5"))
+            .filter(|analysis_h5| analysis_h5.exists());
+        let obsm = if let Some(analysis_h5) = analysis_h5 {
+            let (obsm, clusterings) = load_analysis(&analysis_h5, num_barcodes)?;
+            for (name, column) in clusterings {
+                obs.push(&name, column);
+            }
+            obsm
+        } else {
+            Vec::new()
+        };
+
+        let layers = match args.splice_counts {
+            Some(splice_counts) if args.velocity_layers.unwrap_or(false) => {
+                load_splice_layers(&splice_counts, &obs.index)?
+            }
+            _ => Vec::new(),
+        };
+
+        let uns = uns_metadata(&args.sample_id, args.filtered_matrix_h5.read_metadata()?);
+
+        let h5ad: H5adFile = rover.make_path("filtered_feature_bc_matrix");
+        write_h5ad(&h5ad, &matrix, &obs, &obsm, &layers, &uns)?;
+        Ok(WriteH5adStageOutputs { h5ad })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_column_names() {
+        assert_eq!(is_cell_column("GRCh38", 1), "is_cell");
+        assert_eq!(is_cell_column("GRCh38", 2), "is_cell_GRCh38");
+        assert_eq!(obsm_key("umap", "_gene_expression_2"), "X_umap");
+        assert_eq!(obsm_key("pca", "gene_expression_10"), "X_pca");
+        assert_eq!(
+            obsm_key("pca", "_antibody_capture_10"),
+            "X_pca_antibody_capture_10"
+        );
+    }
+}
@@ -16,7 +16,7 @@ use cr_types::{
     AlignerParam, CellMultiplexingType, CrMultiGraph, Fingerprint, LibraryType, Sample,
     SampleAssignment, TargetingMethod,
 };
-use cr_websummary::alert::AlertContext;
+use cr_websummary::alert::{AlertContext, TriggeredAlert, UserAlertConfig};
 use cr_websummary::multi::antigen::{clonotype_specificity_heatmap, AntigenSpecificityRow};
 use cr_websummary::multi::plots::{
     format_barcode_rank_plot, format_histogram, format_jibes_biplots, format_tags_on_tsne_plot,
@@ -81,6 +81,7 @@ use std::cmp::Ordering;
 use std::collections::HashSet;
 use std::hash::Hash;
 use std::iter::FromIterator;
+use std::path::PathBuf;
 use std::string::ToString;
 
 pub const GEM_BARCODE_OVERLAP_ALERT_THRESHOLD: f64 = 0.6;
@@ -118,6 +119,14 @@ pub struct StageInputs {
     pub antibody_histograms: Option<JsonFile<RawChartWithHelp>>,
     pub sample_antibody_histograms:
         Option<TxHashMap<SampleAssignment, Option<JsonFile<RawChartWithHelp>>>>,
+    pub sample_doublet_histograms:
+        Option<TxHashMap<SampleAssignment, Option<JsonFile<RawChartWithHelp>>>>,
+    pub sample_cell_type_charts:
+        Option<TxHashMap<SampleAssignment, Option<JsonFile<RawChartWithHelp>>>>,
+    pub sample_antibody_normalization_charts:
+        Option<TxHashMap<SampleAssignment, Option<JsonFile<RawChartWithHelp>>>>,
+    pub sample_ambient_rna_charts:
+        Option<TxHashMap<SampleAssignment, Option<JsonFile<RawChartWithHelp>>>>,
     pub antigen_histograms: Option<JsonFile<RawChartWithHelp>>,
     pub targeted_per_feature_metrics: Option<CsvFile<()>>,
     pub cmo_tsne_plot: Option<JsonFile<MultiplexingTsnePlots>>,
@@ -133,6 +142,8 @@ pub struct StageInputs {
     pub chemistry_defs: Option<ChemistryDefs>,
     pub detected_probe_barcode_pairing: Option<DetectedProbeBarcodePairingFile>,
     pub no_preflight: bool,
+    /// User-provided alert thresholds and alerts, in TOML or JSON.
+    pub alerts_config: Option<PathBuf>,
 }
 
 #[derive(Clone, Serialize, Deserialize, MartianStruct)]
@@ -142,6 +153,8 @@ pub struct StageOutputs {
     pub metrics_summary_csv: TxHashMap<SampleAssignment, CsvFile<()>>,
     #[mro_retain]
     pub metrics_summary_json: TxHashMap<SampleAssignment, JsonFile<Vec<JsonMetricSummary>>>,
+    /// The alerts raised in the web summary of each sample.
+    pub alerts_json: TxHashMap<SampleAssignment, JsonFile<Vec<TriggeredAlert>>>,
 }
 
 struct LibWsBuilder {
@@ -434,6 +447,12 @@ impl LibWsBuilder {
             dropped_tags: self.dropped_tags.clone(),
             probe_barcodes_high_gem_overlap: self.probe_barcodes_high_gem_overlap.clone(),
             mismatched_probe_barcode_pairings: self.mismatched_probe_barcode_pairings.clone(),
+            suspected_swapped_libraries: self
+                .lib_metrics
+                .get("suspected_swapped_libraries")
+                .map(|x| serde_json::from_value(x.clone()))
+                .transpose()?
+                .unwrap_or_default(),
             unspecified_probe_barcodes_detected,
             specified_probe_barcodes_missing,
         })
@@ -1342,6 +1361,10 @@ struct MultiWsBuilder {
         Option<TxHashMap<SampleAssignment, TxHashMap<LibraryType, RawChartWithHelp>>>,
     sample_tsne_plots: TxHashMap<SampleAssignment, SampleTsnePlots>,
     sample_antibody_histograms: Option<TxHashMap<SampleAssignment, RawChartWithHelp>>,
+    sample_doublet_histograms: Option<TxHashMap<SampleAssignment, RawChartWithHelp>>,
+    sample_cell_type_charts: Option<TxHashMap<SampleAssignment, RawChartWithHelp>>,
+    sample_antibody_normalization_charts: Option<TxHashMap<SampleAssignment, RawChartWithHelp>>,
+    sample_ambient_rna_charts: Option<TxHashMap<SampleAssignment, RawChartWithHelp>>,
     svg_str: String,
     csv_str: String,
     diagnostics: MultiDiagnostics,
@@ -1812,6 +1835,18 @@ impl MultiWsBuilder {
                 .unwrap_or_default()
                 .gex_diffexp_clustering_plots,
             barcode_rank_plot,
+            doublet_histogram: self
+                .sample_doublet_histograms
+                .as_ref()
+                .and_then(|histos_per_sample| histos_per_sample.get(sample_assignment).cloned()),
+            cell_type_chart: self
+                .sample_cell_type_charts
+                .as_ref()
+                .and_then(|charts_per_sample| charts_per_sample.get(sample_assignment).cloned()),
+            ambient_rna_chart: self
+                .sample_ambient_rna_charts
+                .as_ref()
+                .and_then(|charts_per_sample| charts_per_sample.get(sample_assignment).cloned()),
         })
     }
 
@@ -1883,6 +1918,10 @@ impl MultiWsBuilder {
                 .sample_antibody_histograms
                 .as_ref()
                 .map(|histos_per_sample| histos_per_sample[sample_assignment].clone()),
+            denoised_antibody_chart: self
+                .sample_antibody_normalization_charts
+                .as_ref()
+                .and_then(|charts_per_sample| charts_per_sample.get(sample_assignment).cloned()),
         })
     }
 
@@ -2337,6 +2376,26 @@ impl MartianMain for WriteMultiWebSummaryJson {
                 .as_ref()
                 .map(read_optional_file_map)
                 .transpose()?,
+            sample_doublet_histograms: args
+                .sample_doublet_histograms
+                .as_ref()
+                .map(read_optional_file_map)
+                .transpose()?,
+            sample_cell_type_charts: args
+                .sample_cell_type_charts
+                .as_ref()
+                .map(read_optional_file_map)
+                .transpose()?,
+            sample_antibody_normalization_charts: args
+                .sample_antibody_normalization_charts
+                .as_ref()
+                .map(read_optional_file_map)
+                .transpose()?,
+            sample_ambient_rna_charts: args
+                .sample_ambient_rna_charts
+                .as_ref()
+                .map(read_optional_file_map)
+                .transpose()?,
             svg_str: std::fs::read_to_string(args.multi_graph_svg)
                 .expect("Error reading  multi graph svg"),
             csv_str: std::fs::read_to_string(&args.multi_config)?
@@ -2367,6 +2426,12 @@ impl MartianMain for WriteMultiWebSummaryJson {
                     .as_ref()
                     .is_some_and(|inputs| inputs.include_introns),
                 no_preflight: args.no_preflight,
+                user_alerts: args
+                    .alerts_config
+                    .as_deref()
+                    .map(UserAlertConfig::from_path)
+                    .transpose()?
+                    .unwrap_or_default(),
             },
             multiplexing_method: multi_graph.cell_multiplexing_type(),
             targeting_method: args
@@ -2411,6 +2476,7 @@ impl MartianMain for WriteMultiWebSummaryJson {
 
         let per_sample_ws = multi_ws_builder.build()?;
         let mut sample_to_json_summary = TxHashMap::default();
+        let mut sample_to_alerts = TxHashMap::default();
 
         for (sample, sample_ws) in per_sample_ws {
             // Write the web summary data to JSON
@@ -2428,6 +2494,13 @@ impl MartianMain for WriteMultiWebSummaryJson {
                     .with_content(&sample_ws.to_json_summary())?,
             );
 
+            sample_to_alerts.insert(
+                sample.clone(),
+                rover
+                    .make_path::<JsonFile<_>>(format!("{}_alerts_json", &sample))
+                    .with_content(&sample_ws.triggered_alerts())?,
+            );
+
             let csv_file: CsvFile<()> = rover.make_path(format!("{}_metric_summary_csv", &sample));
             sample_ws.to_csv(&csv_file)?;
             sample_to_metrics_csv.insert(sample.clone(), csv_file);
@@ -2437,6 +2510,7 @@ impl MartianMain for WriteMultiWebSummaryJson {
             web_summary_json: sample_to_web_summary,
             metrics_summary_csv: sample_to_metrics_csv,
             metrics_summary_json: sample_to_json_summary,
+            alerts_json: sample_to_alerts,
         })
     }
 }
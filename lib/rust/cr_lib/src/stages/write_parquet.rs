@@ -0,0 +1,58 @@
+//! Martian stage WRITE_PARQUET
+//! Export the molecule info and the barcode summary as Parquet.
+
+use anyhow::Result;
+use cr_h5::parquet_export::{
+    write_barcode_summary_parquet, write_molecule_info_parquet, ParquetFile,
+};
+use cr_types::H5File;
+use martian::prelude::{MartianMain, MartianRover};
+use martian_derive::{make_mro, MartianStruct};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+#[derive(Clone, Deserialize, MartianStruct)]
+pub struct WriteParquetStageInputs {
+    /// The value of the sample_id column of every row.
+    pub sample_id: String,
+    pub molecule_info: H5File,
+    pub barcode_summary: H5File,
+}
+
+#[derive(Serialize, Deserialize, MartianStruct)]
+pub struct WriteParquetStageOutputs {
+    /// Parquet dataset of the molecules, partitioned by library.
+    pub molecule_info_parquet: PathBuf,
+    pub barcode_summary_parquet: ParquetFile,
+}
+
+/// Martian stage WRITE_PARQUET
+pub struct WriteParquet;
+
+#[make_mro(mem_gb = 4, volatile = strict)]
+impl MartianMain for WriteParquet {
+    type StageInputs = WriteParquetStageInputs;
+    type StageOutputs = WriteParquetStageOutputs;
+
+    fn main(&self, args: Self::StageInputs, rover: MartianRover) -> Result<Self::StageOutputs> {
+        let molecule_info_parquet: PathBuf = rover.make_path("molecule_info_parquet");
+        let partitions = write_molecule_info_parquet(
+            &args.molecule_info,
+            &args.sample_id,
+            &molecule_info_parquet,
+        )?;
+        println!("Wrote {} library partitions", partitions.len());
+
+        let barcode_summary_parquet: ParquetFile = rover.make_path("barcode_summary");
+        write_barcode_summary_parquet(
+            &args.barcode_summary,
+            &args.sample_id,
+            &barcode_summary_parquet,
+        )?;
+
+        Ok(WriteParquetStageOutputs {
+            molecule_info_parquet,
+            barcode_summary_parquet,
+        })
+    }
+}
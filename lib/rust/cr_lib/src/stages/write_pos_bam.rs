@@ -22,7 +22,7 @@ use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
-fn use_csi_instead_of_bai(header: Header) -> bool {
+pub fn use_csi_instead_of_bai(header: Header) -> bool {
     // The standard BAM index file can only handle chromosomes up to 512 MB in length,
     // this function examines the BAM header and decides if we'll need to use a CSI
     // index file instead by finding the largest reference chromosome
@@ -61,6 +61,9 @@ pub type BarcodeIndexFormat = BinaryFormat<BarcodeIndexFile, BarcodeIndex>;
 martian_filetype!(_FingerprintFile, "fprint");
 pub type FingerprintFile = JsonFormat<_FingerprintFile, Vec<Fingerprint>>;
 
+// Pipeline parameters of parameters.toml
+martian_filetype!(TomlFile, "toml");
+
 // End File Types
 
 /// A genome name.
@@ -145,6 +148,74 @@ pub struct ProbeBarcodeCount {
     pub umi_count: u32,
 }
 
+/// The splicing state of a molecule, as used by RNA velocity.
+#[derive(
+    Serialize, Deserialize, Clone, Copy, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, EnumIter,
+)]
+pub enum SpliceType {
+    /// Every read of the molecule is exonic, or its probe spans a splice junction.
+    Spliced,
+    /// Every read of the molecule is intronic, or its probe does not span a splice junction.
+    Unspliced,
+    /// The reads of the molecule disagree, or their region is unknown.
+    Ambiguous,
+}
+
+impl SpliceType {
+    /// Return the splicing state of a molecule supported by reads of both states.
+    pub fn combine(self, other: SpliceType) -> SpliceType {
+        if self == other {
+            self
+        } else {
+            SpliceType::Ambiguous
+        }
+    }
+
+    /// Return the name of the layer holding the counts of this splicing state.
+    pub fn layer_name(self) -> &'static str {
+        match self {
+            SpliceType::Spliced => "spliced",
+            SpliceType::Unspliced => "unspliced",
+            SpliceType::Ambiguous => "ambiguous",
+        }
+    }
+}
+
+/// Count of the number of UMIs observed for one feature in one barcode with one splicing
+/// state. Corresponds to a single entry in the spliced, unspliced or ambiguous layer.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Ord, PartialOrd, Eq, PartialEq)]
+pub struct SpliceBarcodeCount {
+    pub barcode: Barcode,
+    pub feature_idx: u32,
+    pub splice_type: SpliceType,
+    pub umi_count: u32,
+}
+
+impl SpliceBarcodeCount {
+    /// Count the UMIs of one barcode per feature and splicing state.
+    pub fn count_umis(
+        barcode: Barcode,
+        umis: impl IntoIterator<Item = (u32, SpliceType)>,
+    ) -> impl Iterator<Item = SpliceBarcodeCount> {
+        SimpleHistogram::from_iter_owned(umis).into_iter().map(
+            move |((feature_idx, splice_type), umi_count)| SpliceBarcodeCount {
+                barcode,
+                feature_idx,
+                splice_type,
+                umi_count: u32::try_from(umi_count.count()).unwrap(),
+            },
+        )
+    }
+}
+
+impl shardio::SortKey<SpliceBarcodeCount> for BarcodeThenFeatureOrder {
+    type Key = (Barcode, u32, SpliceType);
+
+    fn sort_key(sbc: &SpliceBarcodeCount) -> Cow<'_, Self::Key> {
+        Cow::Owned((sbc.barcode, sbc.feature_idx, sbc.splice_type))
+    }
+}
+
 /// A single UMI count - corresponds to a single entry
 /// in the `molecule_info.h5` file. Must be be nested
 /// inside a `BcUmiInfo` to know the barcode associated
@@ -1328,3 +1399,39 @@ mod py_api_tests {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_splice_barcode_count_umis() {
+        let barcode = Barcode::from_bytes(b"ACGTACGT-1").unwrap();
+        let umis = [
+            (0, SpliceType::Spliced),
+            (1, SpliceType::Unspliced),
+            (0, SpliceType::Spliced),
+            (0, SpliceType::Ambiguous),
+            (1, SpliceType::Unspliced),
+            (1, SpliceType::Unspliced),
+        ];
+        let counts: Vec<_> = SpliceBarcodeCount::count_umis(barcode, umis)
+            .sorted()
+            .collect();
+        let expected = |feature_idx, splice_type, umi_count| SpliceBarcodeCount {
+            barcode,
+            feature_idx,
+            splice_type,
+            umi_count,
+        };
+        assert_eq!(
+            counts,
+            [
+                expected(0, SpliceType::Spliced, 2),
+                expected(0, SpliceType::Ambiguous, 1),
+                expected(1, SpliceType::Unspliced, 3),
+            ]
+        );
+        assert!(SpliceBarcodeCount::count_umis(barcode, []).next().is_none());
+    }
+}
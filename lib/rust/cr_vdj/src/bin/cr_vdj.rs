@@ -81,6 +81,7 @@ fn main() -> Result<()> {
         cr_vdj::copy_vdj_reference::CopyVdjReference,
         cr_vdj::summarize_vdj_filters::SummarizeVdjFilters,
         cr_vdj::create_barcode_csv::CreateBarcodeCsv,
+        cr_vdj::discover_novel_alleles::DiscoverNovelAlleles,
         clonotype_assigner::Assigner,
         clonotype_assigner::write_clonotype_outs::WriteClonotypeOuts,
         clonotype_assigner::fill_clonotype_info::FillClonotypeInfo,
@@ -0,0 +1,394 @@
+//! Martian stage DISCOVER_NOVEL_ALLELES
+//!
+//! Pool the annotated contigs across cells and look for V alleles which are missing from the
+//! reference. A novel allele shows up as the same set of substitutions relative to the best-hit
+//! V gene in many otherwise unmutated cells, with many distinct CDR3s so that a single expanded
+//! clone cannot explain it.
+
+use anyhow::{Context, Result};
+use itertools::Itertools;
+use martian::prelude::*;
+use martian_derive::{make_mro, martian_filetype, MartianStruct};
+use martian_filetypes::json_file::JsonFile;
+use martian_filetypes::tabular_file::CsvFile;
+use martian_filetypes::{FileTypeWrite, LazyFileTypeIO};
+use metric::{TxHashMap, TxHashSet};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use vdj_ann::annotate::ContigAnnotation;
+use vdj_reference::{VdjReference, VdjReferenceEntry, VdjRegion};
+
+martian_filetype! {FaFile, "fa"}
+
+/// Minimum number of UMIs supporting a contig for it to be used.
+const MIN_CONTIG_UMIS: usize = 3;
+/// Contigs with more substitutions than this in the V region are considered mutated.
+const MAX_SUBSTITUTIONS: usize = 10;
+/// Minimum number of cells carrying the exact substitution pattern.
+const MIN_SUPPORTING_CELLS: usize = 10;
+/// Minimum number of distinct CDR3s among the supporting cells.
+const MIN_DISTINCT_CDR3: usize = 5;
+/// Minimum fraction of the cells assigned to the V gene which carry the pattern.
+const MIN_ALLELE_FRACTION: f64 = 0.1;
+/// Number of bases at the 3' end of the V region which are ignored, because they overlap the
+/// junction and are often trimmed or extended by non-templated bases.
+const V_3PRIME_IGNORED_BASES: usize = 5;
+
+#[derive(Debug, Clone, Serialize, Deserialize, MartianStruct)]
+pub struct DiscoverNovelAllelesStageInputs {
+    pub vdj_reference_path: Option<PathBuf>,
+    pub contig_annotations: JsonFile<Vec<ContigAnnotation>>,
+    pub min_contig_umis: Option<usize>,
+    pub min_supporting_cells: Option<usize>,
+    pub write_personalized_reference: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, MartianStruct)]
+pub struct DiscoverNovelAllelesStageOutputs {
+    pub novel_alleles: Option<CsvFile<NovelAlleleRow>>,
+    pub personalized_reference: Option<FaFile>,
+}
+
+/// A single row of `novel_alleles.csv`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NovelAlleleRow {
+    pub novel_allele: String,
+    pub novel_feature_id: Option<u32>,
+    pub gene_name: String,
+    pub chain: String,
+    pub reference_allele: String,
+    pub reference_feature_id: u32,
+    /// Semicolon separated substitutions using 1-based positions on the reference record,
+    /// e.g. `G234T;C301A`
+    pub substitutions: String,
+    pub num_cells: usize,
+    pub num_distinct_cdr3: usize,
+    pub num_gene_cells: usize,
+    pub fraction_of_gene_cells: f64,
+}
+
+/// A substitution relative to a reference record, at a 0-based position on the record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct Substitution {
+    pos: usize,
+    ref_base: u8,
+    alt_base: u8,
+}
+
+impl std::fmt::Display for Substitution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}{}{}",
+            self.ref_base as char,
+            self.pos + 1,
+            self.alt_base as char
+        )
+    }
+}
+
+/// The V region of a single contig, as seen by the allele discovery.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct VObservation {
+    barcode: String,
+    feature_id: u32,
+    cdr3: Option<String>,
+    substitutions: Vec<Substitution>,
+}
+
+/// A substitution pattern which passed all the filters.
+#[derive(Debug, Clone, PartialEq)]
+struct NovelAlleleCall {
+    feature_id: u32,
+    substitutions: Vec<Substitution>,
+    num_cells: usize,
+    num_distinct_cdr3: usize,
+    num_gene_cells: usize,
+}
+
+struct DiscoveryParams {
+    min_supporting_cells: usize,
+    min_distinct_cdr3: usize,
+    min_allele_fraction: f64,
+}
+
+/// Return the substitutions of an ungapped alignment of `contig[contig_start..]` to
+/// `reference[ref_start..ref_end]`, ignoring the last `V_3PRIME_IGNORED_BASES` bases of the
+/// reference record. Returns `None` if the alignment contains indels or ambiguous bases.
+fn find_substitutions(
+    contig: &[u8],
+    contig_start: usize,
+    reference: &[u8],
+    ref_start: usize,
+    ref_end: usize,
+    cigar: &str,
+) -> Option<Vec<Substitution>> {
+    if cigar.contains(['I', 'D', 'N']) || contig_start + (ref_end - ref_start) > contig.len() {
+        return None;
+    }
+    let ref_end = ref_end.min(reference.len().saturating_sub(V_3PRIME_IGNORED_BASES));
+    let mut substitutions = Vec::new();
+    for pos in ref_start..ref_end {
+        let ref_base = reference[pos].to_ascii_uppercase();
+        let alt_base = contig[contig_start + pos - ref_start].to_ascii_uppercase();
+        if !b"ACGT".contains(&ref_base) || !b"ACGT".contains(&alt_base) {
+            return None;
+        }
+        if ref_base != alt_base {
+            substitutions.push(Substitution {
+                pos,
+                ref_base,
+                alt_base,
+            });
+        }
+    }
+    Some(substitutions)
+}
+
+/// Extract the V region observation from a contig, if it is usable for allele discovery.
+fn observe_v_region(
+    ann: &ContigAnnotation,
+    v_entries: &TxHashMap<u32, &VdjReferenceEntry>,
+    min_contig_umis: usize,
+) -> Option<VObservation> {
+    if !(ann.is_cell && ann.high_confidence && ann.is_productive())
+        || ann.umi_count < min_contig_umis
+    {
+        return None;
+    }
+    let v_region = ann.get_region(VdjRegion::V)?;
+    let feature_id = v_region.feature.feature_id as u32;
+    let entry = v_entries.get(&feature_id)?;
+    let substitutions = find_substitutions(
+        ann.sequence.as_bytes(),
+        v_region.contig_match_start,
+        entry.seq(),
+        v_region.annotation_match_start,
+        v_region.annotation_match_end,
+        &v_region.cigar,
+    )?;
+    (substitutions.len() <= MAX_SUBSTITUTIONS).then(|| VObservation {
+        barcode: ann.barcode.clone(),
+        feature_id,
+        cdr3: ann.cdr3.clone(),
+        substitutions,
+    })
+}
+
+/// Group the observations by V gene and substitution pattern and call the patterns which are
+/// shared by enough cells as novel alleles.
+fn call_novel_alleles(
+    observations: &[VObservation],
+    params: &DiscoveryParams,
+) -> Vec<NovelAlleleCall> {
+    let mut gene_cells: TxHashMap<u32, TxHashSet<&str>> = TxHashMap::default();
+    let mut pattern_support: BTreeMap<(u32, &[Substitution]), (TxHashSet<&str>, TxHashSet<&str>)> =
+        BTreeMap::new();
+    for obs in observations {
+        gene_cells
+            .entry(obs.feature_id)
+            .or_default()
+            .insert(&obs.barcode);
+        if obs.substitutions.is_empty() {
+            continue;
+        }
+        let (cells, cdr3s) = pattern_support
+            .entry((obs.feature_id, &obs.substitutions))
+            .or_default();
+        cells.insert(&obs.barcode);
+        if let Some(cdr3) = &obs.cdr3 {
+            cdr3s.insert(cdr3);
+        }
+    }
+
+    pattern_support
+        .into_iter()
+        .filter_map(|((feature_id, substitutions), (cells, cdr3s))| {
+            let num_gene_cells = gene_cells[&feature_id].len();
+            let passes = cells.len() >= params.min_supporting_cells
+                && cdr3s.len() >= params.min_distinct_cdr3
+                && cells.len() as f64 >= params.min_allele_fraction * num_gene_cells as f64;
+            passes.then(|| NovelAlleleCall {
+                feature_id,
+                substitutions: substitutions.to_vec(),
+                num_cells: cells.len(),
+                num_distinct_cdr3: cdr3s.len(),
+                num_gene_cells,
+            })
+        })
+        .collect()
+}
+
+pub struct DiscoverNovelAlleles;
+
+#[make_mro(mem_gb = 4)]
+impl MartianMain for DiscoverNovelAlleles {
+    type StageInputs = DiscoverNovelAllelesStageInputs;
+    type StageOutputs = DiscoverNovelAllelesStageOutputs;
+
+    fn main(&self, args: Self::StageInputs, rover: MartianRover) -> Result<Self::StageOutputs> {
+        let Some(vdj_reference_path) = args.vdj_reference_path else {
+            return Ok(DiscoverNovelAllelesStageOutputs {
+                novel_alleles: None,
+                personalized_reference: None,
+            });
+        };
+        let reference = VdjReference::from_reference_folder(&vdj_reference_path)?;
+        let v_entries: TxHashMap<u32, &VdjReferenceEntry> = reference
+            .iter_region_filtered(VdjRegion::V)
+            .map(|entry| (entry.feature_id(), entry))
+            .collect();
+
+        let min_contig_umis = args.min_contig_umis.unwrap_or(MIN_CONTIG_UMIS);
+        let observations: Vec<_> = args
+            .contig_annotations
+            .lazy_reader()?
+            .filter_map_ok(|ann: ContigAnnotation| {
+                observe_v_region(&ann, &v_entries, min_contig_umis)
+            })
+            .try_collect()?;
+
+        let calls = call_novel_alleles(
+            &observations,
+            &DiscoveryParams {
+                min_supporting_cells: args.min_supporting_cells.unwrap_or(MIN_SUPPORTING_CELLS),
+                min_distinct_cdr3: MIN_DISTINCT_CDR3,
+                min_allele_fraction: MIN_ALLELE_FRACTION,
+            },
+        );
+
+        let mut novel_entries = Vec::new();
+        let mut rows = Vec::new();
+        for (feature_id, call) in (reference.max_feature_id() + 1..).zip(&calls) {
+            let entry = v_entries[&call.feature_id];
+            let substitutions = call.substitutions.iter().join("_");
+            let header = entry.with_allele(
+                feature_id,
+                &format!("{}_{substitutions}", entry.allele_name().unwrap_or("00")),
+            );
+            let mut sequence = entry.seq().to_vec();
+            for sub in &call.substitutions {
+                sequence[sub.pos] = sub.alt_base;
+            }
+            rows.push(NovelAlleleRow {
+                novel_allele: header.display_name().to_string(),
+                novel_feature_id: args
+                    .write_personalized_reference
+                    .unwrap_or(false)
+                    .then_some(feature_id),
+                gene_name: entry.gene_name().to_string(),
+                chain: entry.chain().to_string(),
+                reference_allele: entry.display_name().to_string(),
+                reference_feature_id: call.feature_id,
+                substitutions: call.substitutions.iter().join(";"),
+                num_cells: call.num_cells,
+                num_distinct_cdr3: call.num_distinct_cdr3,
+                num_gene_cells: call.num_gene_cells,
+                fraction_of_gene_cells: call.num_cells as f64 / call.num_gene_cells as f64,
+            });
+            novel_entries.push(VdjReferenceEntry::new(header, sequence));
+        }
+
+        let novel_alleles: CsvFile<_> = rover.make_path("novel_alleles");
+        novel_alleles.write(&rows)?;
+
+        let personalized_reference = if args.write_personalized_reference.unwrap_or(false) {
+            let fasta: FaFile = rover.make_path("personalized_regions");
+            let personalized = VdjReference::from_entries(
+                reference.iter().cloned().chain(novel_entries).collect(),
+            );
+            personalized.write_fasta(BufWriter::new(File::create(&fasta).with_context(
+                || format!("Error: unable to create {}", fasta.as_ref().display()),
+            )?))?;
+            Some(fasta)
+        } else {
+            None
+        };
+
+        Ok(DiscoverNovelAllelesStageOutputs {
+            novel_alleles: Some(novel_alleles),
+            personalized_reference,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sub(pos: usize, ref_base: u8, alt_base: u8) -> Substitution {
+        Substitution {
+            pos,
+            ref_base,
+            alt_base,
+        }
+    }
+
+    fn obs(barcode: usize, feature_id: u32, cdr3: usize, subs: &[Substitution]) -> VObservation {
+        VObservation {
+            barcode: format!("BC{barcode}-1"),
+            feature_id,
+            cdr3: Some(format!("CDR3_{cdr3}")),
+            substitutions: subs.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_find_substitutions() {
+        let reference = b"ACGTACGTACGTACGTACGT";
+        let contig = b"NNACGTACCTACGTACGTACGT";
+        assert_eq!(
+            find_substitutions(contig, 2, reference, 0, 20, "2S20M"),
+            Some(vec![sub(6, b'G', b'C')])
+        );
+        // Differences in the last bases of the V region are ignored
+        let contig = b"ACGTACGTACGTACGTAAAA";
+        assert_eq!(
+            find_substitutions(contig, 0, reference, 0, 20, "20M"),
+            Some(vec![])
+        );
+        assert_eq!(
+            find_substitutions(contig, 0, reference, 0, 20, "10M1I9M"),
+            None
+        );
+        assert_eq!(
+            find_substitutions(b"ACGTNCGTACGTACGTACGT", 0, reference, 0, 20, "20M"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_call_novel_alleles() {
+        let pattern = [sub(10, b'A', b'G'), sub(42, b'C', b'T')];
+        let mut observations: Vec<_> = (0..12).map(|i| obs(i, 1, i, &pattern)).collect();
+        // Germline cells and one hypermutated cell for the same gene
+        observations.extend((12..40).map(|i| obs(i, 1, i, &[])));
+        observations.push(obs(40, 1, 40, &[sub(5, b'T', b'A')]));
+        // A single expanded clone sharing a mutation on another gene
+        observations.extend((41..60).map(|i| obs(i, 2, 0, &[sub(7, b'G', b'A')])));
+
+        let params = DiscoveryParams {
+            min_supporting_cells: MIN_SUPPORTING_CELLS,
+            min_distinct_cdr3: MIN_DISTINCT_CDR3,
+            min_allele_fraction: MIN_ALLELE_FRACTION,
+        };
+        assert_eq!(
+            call_novel_alleles(&observations, &params),
+            vec![NovelAlleleCall {
+                feature_id: 1,
+                substitutions: pattern.to_vec(),
+                num_cells: 12,
+                num_distinct_cdr3: 12,
+                num_gene_cells: 41,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_substitution_display() {
+        assert_eq!(sub(233, b'G', b'T').to_string(), "G234T");
+    }
+}
@@ -48,5 +48,6 @@
 /// Copy the VDJ reference
 pub mod copy_vdj_reference;
 pub mod create_barcode_csv;
+pub mod discover_novel_alleles;
 pub mod matrix;
 pub mod summarize_vdj_filters;
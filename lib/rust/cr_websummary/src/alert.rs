@@ -2,9 +2,12 @@
 //! Alarms in the websummary (aka alerts)
 //!
 
-use serde::Serialize;
+use anyhow::{bail, ensure, Context, Result};
+use cr_types::websummary::AlertIfMetricIs;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "UPPERCASE")]
 pub enum AlertLevel {
     Error,
@@ -51,6 +54,7 @@ pub struct AlertContext {
     pub is_antigen: bool,
     pub include_introns: bool,
     pub no_preflight: bool,
+    pub user_alerts: UserAlertConfig,
 }
 
 pub trait Alert {
@@ -59,3 +63,338 @@ pub trait Alert {
         vec![]
     }
 }
+
+/// An alert raised in a web summary, as written to the machine-readable alerts JSON.
+#[derive(Debug, Clone, Serialize)]
+pub struct TriggeredAlert {
+    /// Either "Library" or "Cells"
+    pub category: String,
+    pub library_type: String,
+    #[serde(flatten)]
+    pub alert: AlertSpec,
+}
+
+/// User-provided alert configuration, loaded from a TOML or JSON file.
+/// The aggr web summary reads the same file with cellranger/websummary/alerts_config.py.
+///
+/// ```toml
+/// # Change the thresholds of an existing alert
+/// [[override]]
+/// metric = "multi_vdj_assembly_contig_pair_productive_full_len_bc_frac"
+/// error_threshold = 0.1
+/// warn_threshold = 0.2
+///
+/// # Silence an alert that is not attached to a metric
+/// [[override]]
+/// title = "Analysis preflight checks were skipped"
+/// disabled = true
+///
+/// # Define a new alert
+/// [[alert]]
+/// metric = "median_genes_per_singlet"
+/// if_metric_is = "less_than_or_equal"
+/// warn_threshold = 500
+/// title = "Low Median Genes per Cell"
+/// detail = "Below the expected range for this tissue."
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserAlertConfig {
+    #[serde(default, rename = "override")]
+    pub overrides: Vec<AlertOverride>,
+    #[serde(default, rename = "alert")]
+    pub alerts: Vec<UserAlert>,
+}
+
+/// Change the thresholds or the level of an existing alert, or disable it.
+/// An alert is selected either by its metric key or by its title.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertOverride {
+    pub metric: Option<String>,
+    pub title: Option<String>,
+    pub error_threshold: Option<f64>,
+    pub warn_threshold: Option<f64>,
+    pub level: Option<AlertLevel>,
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+/// A new alert raised when a metric crosses a threshold.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserAlert {
+    pub metric: String,
+    pub if_metric_is: AlertIfMetricIs,
+    pub error_threshold: Option<f64>,
+    pub warn_threshold: Option<f64>,
+    pub title: String,
+    #[serde(default)]
+    pub detail: String,
+}
+
+impl UserAlertConfig {
+    /// Load the configuration from a .toml or .json file.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read alerts config {}", path.display()))?;
+        let config: Self = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&contents)?,
+            Some("json") => serde_json::from_str(&contents)?,
+            _ => bail!(
+                "the alerts config {} must have a .toml or .json extension",
+                path.display()
+            ),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        for o in &self.overrides {
+            match (&o.metric, &o.title) {
+                (Some(_), None) => {}
+                (None, Some(title)) => ensure!(
+                    o.error_threshold.is_none() && o.warn_threshold.is_none(),
+                    "the override of the alert \"{title}\" selects the alert by title \
+                     and cannot change its thresholds, select it by metric instead"
+                ),
+                _ => bail!("an alert override must specify exactly one of metric or title"),
+            }
+        }
+        for a in &self.alerts {
+            ensure!(
+                a.error_threshold.is_some() || a.warn_threshold.is_some(),
+                "the alert \"{}\" must specify an error_threshold or a warn_threshold",
+                a.title
+            );
+        }
+        Ok(())
+    }
+
+    fn metric_overrides<'a>(&'a self, metric: &'a str) -> impl Iterator<Item = &'a AlertOverride> {
+        self.overrides
+            .iter()
+            .filter(move |o| o.metric.as_deref() == Some(metric))
+    }
+
+    /// Return the error and warn thresholds of the alerts of this metric.
+    pub fn thresholds(
+        &self,
+        metric: &str,
+        error_threshold: Option<f64>,
+        warn_threshold: Option<f64>,
+    ) -> (Option<f64>, Option<f64>) {
+        self.metric_overrides(metric)
+            .fold((error_threshold, warn_threshold), |(e, w), o| {
+                (o.error_threshold.or(e), o.warn_threshold.or(w))
+            })
+    }
+
+    /// Return true if the alerts of this metric are disabled.
+    pub fn is_disabled(&self, metric: &str) -> bool {
+        self.metric_overrides(metric).any(|o| o.disabled)
+    }
+
+    /// Return the level of the alerts of this metric, or `default` if it is not overridden.
+    pub fn level(&self, metric: &str, default: AlertLevel) -> AlertLevel {
+        self.metric_overrides(metric)
+            .filter_map(|o| o.level)
+            .last()
+            .unwrap_or(default)
+    }
+
+    /// Evaluate the user-defined alerts of this metric.
+    pub fn metric_alerts(&self, metric: &str, val: f64, formatted_value: &str) -> Vec<AlertSpec> {
+        self.alerts
+            .iter()
+            .filter(|a| a.metric == metric)
+            .filter_map(|a| {
+                let is_met = |threshold: f64| match a.if_metric_is {
+                    AlertIfMetricIs::GreaterThanOrEqual => val >= threshold,
+                    AlertIfMetricIs::LessThanOrEqual => val <= threshold,
+                };
+                let level = if a.error_threshold.is_some_and(is_met) {
+                    AlertLevel::Error
+                } else if a.warn_threshold.is_some_and(is_met) {
+                    AlertLevel::Warn
+                } else {
+                    return None;
+                };
+                Some(AlertSpec {
+                    level,
+                    title: a.title.clone(),
+                    formatted_value: formatted_value.to_string(),
+                    message: a.detail.clone(),
+                })
+            })
+            .collect()
+    }
+
+    /// Apply the overrides that select alerts by title.
+    pub fn apply_title_overrides(&self, alerts: &mut Vec<AlertSpec>) {
+        for o in &self.overrides {
+            let Some(title) = &o.title else {
+                continue;
+            };
+            if o.disabled {
+                alerts.retain(|alert| &alert.title != title);
+            } else if let Some(level) = o.level {
+                for alert in alerts.iter_mut().filter(|alert| &alert.title == title) {
+                    alert.level = level;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn load(contents: &str, suffix: &str) -> Result<UserAlertConfig> {
+        let mut file = tempfile::Builder::new().suffix(suffix).tempfile()?;
+        file.write_all(contents.as_bytes())?;
+        UserAlertConfig::from_path(file.path())
+    }
+
+    const CONFIG: &str = r#"
+[[override]]
+metric = "fraction_reads_in_aggregate_barcodes"
+warn_threshold = 0.3
+level = "ERROR"
+
+[[override]]
+metric = "fraction_reads_in_aggregate_barcodes"
+error_threshold = 0.9
+
+[[override]]
+metric = "sequencing_saturation"
+disabled = true
+
+[[override]]
+title = "Analysis preflight checks were skipped"
+disabled = true
+
+[[override]]
+title = "Low Fraction Reads in Cells"
+level = "INFO"
+
+[[alert]]
+metric = "median_genes_per_singlet"
+if_metric_is = "less_than_or_equal"
+error_threshold = 100
+warn_threshold = 500
+title = "Low Median Genes per Cell"
+detail = "Below the expected range for this tissue."
+"#;
+
+    #[test]
+    fn test_parse_user_alert_config() -> Result<()> {
+        let config = load(CONFIG, ".toml")?;
+        assert_eq!(config.overrides.len(), 5);
+        assert_eq!(config.alerts.len(), 1);
+        assert!(matches!(
+            config.alerts[0].if_metric_is,
+            AlertIfMetricIs::LessThanOrEqual
+        ));
+
+        let json = r#"{"alert": [{"metric": "median_genes_per_singlet",
+            "if_metric_is": "greater_than_or_equal", "warn_threshold": 5000, "title": "High"}]}"#;
+        let config = load(json, ".json")?;
+        assert!(config.overrides.is_empty());
+        assert_eq!(config.alerts[0].warn_threshold, Some(5000.0));
+        assert_eq!(config.alerts[0].detail, "");
+
+        assert!(load(CONFIG, ".csv").is_err());
+        assert!(load("[[override]]\nmetric = \"x\"\nwarn = 0.1\n", ".toml").is_err());
+        assert!(load("[[overrides]]\nmetric = \"x\"\n", ".toml").is_err());
+        assert!(load("[[override]]\nlevel = \"WARN\"\n", ".toml").is_err());
+        assert!(load("[[override]]\nmetric = \"x\"\ntitle = \"y\"\n", ".toml").is_err());
+        assert!(load("[[override]]\ntitle = \"y\"\nwarn_threshold = 1\n", ".toml").is_err());
+        assert!(load("[[override]]\nmetric = \"x\"\nlevel = \"FATAL\"\n", ".toml").is_err());
+        assert!(load(
+            "[[alert]]\nmetric = \"x\"\nif_metric_is = \"less_than_or_equal\"\ntitle = \"y\"\n",
+            ".toml"
+        )
+        .is_err());
+        assert!(load(
+            "[[alert]]\nmetric = \"x\"\nif_metric_is = \"equal\"\nwarn_threshold = 1\ntitle = \"y\"\n",
+            ".toml"
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_user_alert_thresholds() -> Result<()> {
+        let config = load(CONFIG, ".toml")?;
+        let metric = "fraction_reads_in_aggregate_barcodes";
+        assert_eq!(
+            config.thresholds(metric, Some(1.0), Some(0.2)),
+            (Some(0.9), Some(0.3))
+        );
+        assert_eq!(
+            config.thresholds("other", Some(1.0), Some(0.2)),
+            (Some(1.0), Some(0.2))
+        );
+        assert_eq!(config.level(metric, AlertLevel::Warn), AlertLevel::Error);
+        assert_eq!(config.level("other", AlertLevel::Warn), AlertLevel::Warn);
+        assert!(config.is_disabled("sequencing_saturation"));
+        assert!(!config.is_disabled(metric));
+        Ok(())
+    }
+
+    #[test]
+    fn test_user_metric_alerts() -> Result<()> {
+        let config = load(CONFIG, ".toml")?;
+        let metric = "median_genes_per_singlet";
+        assert!(config.metric_alerts(metric, 501.0, "501").is_empty());
+        assert!(config.metric_alerts("other", 50.0, "50").is_empty());
+
+        let alerts = config.metric_alerts(metric, 500.0, "500");
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].level, AlertLevel::Warn);
+        assert_eq!(alerts[0].title, "Low Median Genes per Cell");
+        assert_eq!(alerts[0].formatted_value, "500");
+        assert_eq!(
+            alerts[0].message,
+            "Below the expected range for this tissue."
+        );
+
+        // Only the error is raised when both thresholds are met.
+        let alerts = config.metric_alerts(metric, 100.0, "100");
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].level, AlertLevel::Error);
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_title_overrides() -> Result<()> {
+        let config = load(CONFIG, ".toml")?;
+        let alert = |title: &str| AlertSpec {
+            level: AlertLevel::Warn,
+            title: title.to_string(),
+            formatted_value: String::new(),
+            message: String::new(),
+        };
+        let mut alerts = vec![
+            alert("Analysis preflight checks were skipped"),
+            alert("Low Fraction Reads in Cells"),
+            alert("Low Sequencing Saturation"),
+        ];
+        config.apply_title_overrides(&mut alerts);
+        assert_eq!(
+            alerts
+                .iter()
+                .map(|alert| (alert.title.as_str(), alert.level))
+                .collect::<Vec<_>>(),
+            [
+                ("Low Fraction Reads in Cells", AlertLevel::Info),
+                ("Low Sequencing Saturation", AlertLevel::Warn),
+            ]
+        );
+        Ok(())
+    }
+}
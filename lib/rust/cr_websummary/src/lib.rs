@@ -109,8 +109,13 @@ impl<C: Alert + ToCsvRows + ToJsonSummary> Tab<C> {
                 message: "Your analysis was run without preflight checks and may have used non-standard settings. Please carefully review your results.".to_string(),
             });
         }
+        context.user_alerts.apply_title_overrides(&mut alerts);
         Tab { content, alerts }
     }
+
+    pub fn alerts(&self) -> &[AlertSpec] {
+        &self.alerts
+    }
 }
 
 impl<C: Alert + ToCsvRows + ToJsonSummary> ToCsvRows for Tab<C> {
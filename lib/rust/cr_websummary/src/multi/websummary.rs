@@ -1,7 +1,7 @@
 // There are a **lot** of metrics which need to be imported here.
 #![allow(clippy::wildcard_imports)]
 use super::tables::*;
-use crate::alert::AlertLevel;
+use crate::alert::{AlertLevel, TriggeredAlert};
 use crate::multi::svg::SvgGraph;
 use crate::{
     Alert, AlertContext, AlertSpec, CardWithMetric, ChartWithHelp, GenericTable, MakePretty,
@@ -129,6 +129,8 @@ pub struct CountParametersTable {
     pub unspecified_probe_barcodes_detected: Vec<String>,
     pub specified_probe_barcodes_missing: Vec<String>,
     pub mismatched_probe_barcode_pairings: Option<MismatchedProbeBarcodePairings>,
+    /// Library types whose barcodes are incompatible with the other libraries.
+    pub suspected_swapped_libraries: Vec<String>,
 }
 
 impl From<CountParametersTable> for GenericTable {
@@ -157,6 +159,7 @@ impl From<CountParametersTable> for GenericTable {
             unspecified_probe_barcodes_detected: _,
             specified_probe_barcodes_missing: _,
             mismatched_probe_barcode_pairings: _,
+            suspected_swapped_libraries: _,
         } = info;
 
         let chemistry_with_throughput = match throughput {
@@ -401,6 +404,21 @@ impl Alert for CountParametersTable {
             });
         }
 
+        if !self.suspected_swapped_libraries.is_empty() {
+            let libraries = self.suspected_swapped_libraries.join(", ");
+            alerts.push(AlertSpec {
+                level: AlertLevel::Warn,
+                title: "Libraries with incompatible barcodes detected".to_string(),
+                formatted_value: format!("{} library(s)", self.suspected_swapped_libraries.len()),
+                message: format!(
+                    "The barcodes of the library type(s) {libraries} have insufficient overlap \
+                    with the barcodes of the other libraries. These libraries likely originated \
+                    from a different gem well, which may be caused by a mix-up of FASTQ files. \
+                    See the barcode_similarity metrics for the similarity of each pair of libraries."
+                ),
+            });
+        }
+
         alerts
     }
 }
@@ -532,13 +550,16 @@ impl From<VdjParametersTable> for GenericTable {
             chemistry,
             vdj_reference,
             vdj_reference_path,
-            gamma_delta: _, // Only used for alert
+            gamma_delta,
         } = info;
-        let rows = vec![
+        let mut rows = vec![
             TableRow::two_col("Chemistry", chemistry),
             TableRow::two_col("V(D)J Reference", vdj_reference),
             TableRow::two_col("V(D)J Reference Path", vdj_reference_path),
         ];
+        if gamma_delta {
+            rows.push(TableRow::two_col("Chain Type", "TR_GD"));
+        }
         GenericTable { header: None, rows }
     }
 }
@@ -548,10 +569,10 @@ impl Alert for VdjParametersTable {
         let mut alerts = vec![];
         if self.gamma_delta {
             alerts.push(AlertSpec {
-                level: AlertLevel::Warn,
-                title: "Unsupported workflow used".to_string(),
+                level: AlertLevel::Info,
+                title: "Gamma/delta TCR analysis".to_string(),
                 formatted_value: String::default(),
-                message: "Gamma Delta TCR analysis is not a supported workflow. Algorithm performance cannot be guaranteed.".to_string(),
+                message: "This library was analyzed with the TR_GD chain type. Annotation and clonotype metrics are reported for the TRG and TRD chains, and alpha/beta chains are not assembled.".to_string(),
             });
         }
 
@@ -687,6 +708,13 @@ impl MultiWebSummary {
         writer.flush()?;
         Ok(())
     }
+
+    /// Return the alerts raised in the library and sample tabs.
+    pub fn triggered_alerts(&self) -> Vec<TriggeredAlert> {
+        let mut alerts = self.data.library_websummary.triggered_alerts();
+        alerts.append(&mut self.data.sample_websummary.triggered_alerts());
+        alerts
+    }
 }
 
 impl ToCsvRows for MultiWebSummary {
@@ -766,6 +794,22 @@ fn to_json_summary_helper<T: ToJsonSummary>(
     rows
 }
 
+fn triggered_alerts_helper<C: Alert + ToCsvRows + ToJsonSummary>(
+    tab: Option<&Tab<C>>,
+    library_type: &str,
+    sample_or_library: &str,
+) -> Vec<TriggeredAlert> {
+    let Some(tab) = tab else { return vec![] };
+    tab.alerts()
+        .iter()
+        .map(|alert| TriggeredAlert {
+            category: sample_or_library.to_string(),
+            library_type: library_type.to_string(),
+            alert: alert.clone(),
+        })
+        .collect()
+}
+
 mod section {
     pub const GEX: &str = "Gene Expression";
     pub const VDJ_T: &str = "VDJ T";
@@ -835,6 +879,28 @@ impl ToJsonSummary for LibraryWebSummary {
     }
 }
 
+impl LibraryWebSummary {
+    /// Return the alerts raised in the library tabs.
+    pub fn triggered_alerts(&self) -> Vec<TriggeredAlert> {
+        [
+            triggered_alerts_helper(self.gex_tab.as_ref(), section::GEX, TAB_LIBRARY),
+            triggered_alerts_helper(self.vdj_t_tab.as_ref(), section::VDJ_T, TAB_LIBRARY),
+            triggered_alerts_helper(self.vdj_t_gd_tab.as_ref(), section::VDJ_T_GD, TAB_LIBRARY),
+            triggered_alerts_helper(self.vdj_b_tab.as_ref(), section::VDJ_B, TAB_LIBRARY),
+            triggered_alerts_helper(self.antibody_tab.as_ref(), section::AB, TAB_LIBRARY),
+            triggered_alerts_helper(self.antigen_tab.as_ref(), section::AG, TAB_LIBRARY),
+            triggered_alerts_helper(self.crispr_tab.as_ref(), section::CRISPR, TAB_LIBRARY),
+            triggered_alerts_helper(
+                self.custom_feature_tab.as_ref(),
+                section::CUSTOM,
+                TAB_LIBRARY,
+            ),
+            triggered_alerts_helper(self.cmo_tab.as_ref(), section::CMO, TAB_LIBRARY),
+        ]
+        .concat()
+    }
+}
+
 #[derive(Serialize, Clone, Default)]
 pub struct SampleWebSummary {
     pub header_info: SampleHeaderInfo,
@@ -883,6 +949,23 @@ impl ToJsonSummary for SampleWebSummary {
     }
 }
 
+impl SampleWebSummary {
+    /// Return the alerts raised in the sample tabs.
+    pub fn triggered_alerts(&self) -> Vec<TriggeredAlert> {
+        [
+            triggered_alerts_helper(self.gex_tab.as_ref(), section::GEX, TAB_CELLS),
+            triggered_alerts_helper(self.vdj_t_tab.as_ref(), section::VDJ_T, TAB_CELLS),
+            triggered_alerts_helper(self.vdj_t_gd_tab.as_ref(), section::VDJ_T_GD, TAB_CELLS),
+            triggered_alerts_helper(self.vdj_b_tab.as_ref(), section::VDJ_B, TAB_CELLS),
+            triggered_alerts_helper(self.antibody_tab.as_ref(), section::AB, TAB_CELLS),
+            triggered_alerts_helper(self.antigen_tab.as_ref(), section::AG, TAB_CELLS),
+            triggered_alerts_helper(self.crispr_tab.as_ref(), section::CRISPR, TAB_CELLS),
+            triggered_alerts_helper(self.custom_feature_tab.as_ref(), section::CUSTOM, TAB_CELLS),
+        ]
+        .concat()
+    }
+}
+
 impl<T: Alert> Alert for Option<T> {
     fn alerts(&self, ctx: &AlertContext) -> Vec<AlertSpec> {
         match self {
@@ -979,6 +1062,9 @@ pub struct SampleGexWebSummary {
     pub barcode_rank_plot: Option<ChartWithHelp>,
     pub median_genes_per_cell_plot: Option<ChartWithHelp>,
     pub clustering_and_diffexp_plots: Value,
+    pub doublet_histogram: Option<RawChartWithHelp>,
+    pub cell_type_chart: Option<RawChartWithHelp>,
+    pub ambient_rna_chart: Option<RawChartWithHelp>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -1013,6 +1099,7 @@ pub struct SampleAntibodyWebSummary {
     pub clustering_and_diffexp_plots: Option<Value>,
     pub tsne_plot: Option<RawChartWithHelp>,
     pub feature_histogram: Option<RawChartWithHelp>,
+    pub denoised_antibody_chart: Option<RawChartWithHelp>,
 }
 
 #[derive(Serialize, Clone, ToCsvRows, ToJsonSummary, Alert)]
@@ -1178,6 +1265,7 @@ mod tests {
             dropped_tags: Default::default(),
             probe_barcodes_high_gem_overlap: Default::default(),
             mismatched_probe_barcode_pairings: None,
+            suspected_swapped_libraries: Default::default(),
             unspecified_probe_barcodes_detected: Default::default(),
             specified_probe_barcodes_missing: Default::default(),
         }
@@ -1326,6 +1414,9 @@ mod tests {
             )),
             clustering_and_diffexp_plots: Value::String("CLUSTERING_PLOTS_GO_HERE".to_string()),
             barcode_rank_plot: None,
+            doublet_histogram: None,
+            cell_type_chart: None,
+            ambient_rna_chart: None,
         }
     }
 
@@ -1366,6 +1457,7 @@ mod tests {
             tsne_plot: None,
             barcode_rank_plot: None,
             feature_histogram: None,
+            denoised_antibody_chart: None,
         }
     }
 
@@ -2204,4 +2296,64 @@ mod tests {
                 })
         );
     }
+
+    #[test]
+    fn user_alert_config_derived_alerts() {
+        use crate::alert::{Alert, AlertOverride, UserAlert, UserAlertConfig};
+        use cr_types::websummary::AlertIfMetricIs;
+
+        let table = AntibodyLibraryMappingMetricsTable(vec![AntibodyLibraryMappingMetricsRow {
+            fraction_antibody_reads: Some(make_percent(40.0)),
+            fraction_reads_in_aggregate_barcodes: Some(make_percent(20.09)),
+            ..Default::default()
+        }]);
+        let metric = "fraction_reads_in_aggregate_barcodes";
+        let alerts = |overrides: Vec<AlertOverride>, alerts: Vec<UserAlert>| {
+            table
+                .alerts(&AlertContext {
+                    is_rtl: true,
+                    user_alerts: UserAlertConfig { overrides, alerts },
+                    ..Default::default()
+                })
+                .into_iter()
+                .map(|alert| (alert.level, alert.title))
+                .collect::<Vec<_>>()
+        };
+        let metric_override = |warn_threshold, level, disabled| AlertOverride {
+            metric: Some(metric.to_string()),
+            title: None,
+            error_threshold: None,
+            warn_threshold,
+            level,
+            disabled,
+        };
+        let warn_title = "High Fraction of Antibody Reads in Aggregate Barcodes".to_string();
+
+        assert_eq!(
+            alerts(vec![], vec![]),
+            [(AlertLevel::Warn, warn_title.clone())]
+        );
+        assert!(alerts(vec![metric_override(Some(0.3), None, false)], vec![]).is_empty());
+        assert!(alerts(vec![metric_override(None, None, true)], vec![]).is_empty());
+        assert_eq!(
+            alerts(
+                vec![metric_override(None, Some(AlertLevel::Error), false)],
+                vec![]
+            ),
+            [(AlertLevel::Error, warn_title)]
+        );
+
+        let user_alert = UserAlert {
+            metric: "fraction_antibody_reads".to_string(),
+            if_metric_is: AlertIfMetricIs::LessThanOrEqual,
+            error_threshold: None,
+            warn_threshold: Some(0.5),
+            title: "Low Fraction Antibody Reads".to_string(),
+            detail: String::new(),
+        };
+        assert_eq!(
+            alerts(vec![metric_override(None, None, true)], vec![user_alert]),
+            [(AlertLevel::Warn, "Low Fraction Antibody Reads".to_string())]
+        );
+    }
 }
@@ -13,6 +13,7 @@ use cr_wrap::shared_cmd::{self, HiddenCmd, RnaSharedCmd, SharedCmd};
 use cr_wrap::utils::{validate_id, AllArgs, CliPath};
 use cr_wrap::{
     check_deprecated_os, env, execute, make_mro, make_mro_with_comment, mkfastq, set_env_vars,
+    validate_parameter_overrides,
 };
 use serde::{self, Serialize};
 use sha2::{Digest, Sha256};
@@ -90,6 +91,19 @@ enum SubCommand {
     #[clap(name = "aggr")]
     Aggr(Aggr),
 
+    /// Compare the metrics summaries of multiple runs and flag the outliers.
+    #[clap(name = "qc-report")]
+    QcReport(QcReport),
+
+    /// Compare two feature-barcode matrices or molecule_info files by barcode and feature.
+    #[clap(name = "diff")]
+    Diff(Diff),
+
+    /// Split a position-sorted BAM file into one indexed BAM file per group
+    /// of barcodes, or filter it to a list of barcodes.
+    #[clap(name = "split-bam")]
+    SplitBam(SplitBam),
+
     /// Re-run secondary analysis (dimensionality reduction, clustering, etc).
     #[clap(name = "reanalyze")]
     Reanalyze(Reanalyze),
@@ -172,10 +186,37 @@ struct Count {
     #[clap(flatten)]
     create_bam: CreateBam,
 
+    /// Annotate the reads of the BAM files with the cell call of their
+    /// barcode, in the xf tag, and with their assigned sample, in the sa tag.
+    #[clap(long)]
+    tag_bam: bool,
+
     /// Disable secondary analysis, e.g. clustering. Optional.
     #[clap(long = "nosecondary")]
     no_secondary_analysis: bool,
 
+    /// Write the filtered feature-barcode matrix and the secondary
+    /// analysis as an AnnData file, filtered_feature_bc_matrix.h5ad.
+    #[clap(long)]
+    create_h5ad: bool,
+
+    /// Add the spliced, unspliced and ambiguous UMI counts of each gene
+    /// to the AnnData file as layers for RNA velocity analysis.
+    #[clap(long, requires = "create_h5ad")]
+    velocity_layers: bool,
+
+    /// Export the molecule info and the per-barcode read and UMI counts
+    /// as Parquet, molecule_info_parquet and barcode_summary.parquet.
+    #[clap(long)]
+    create_parquet: bool,
+
+    /// A CSV file of the positive and negative marker genes of each
+    /// cell type, with columns cell_type, positive_markers and
+    /// negative_markers. Annotate the cell type of each cell and
+    /// cluster using these markers. Optional.
+    #[clap(long, value_name = "CSV")]
+    marker_gene_sets: Option<CliPath>,
+
     ///  Hard trim the input Read 1 to this length before
     /// analysis.
     #[clap(long, value_name = "NUM")]
@@ -221,6 +262,10 @@ struct Count {
     #[clap(long, value_name = "true|false", default_value = "true", hide = true)]
     filter_aggregates: Option<bool>,
 
+    /// TOML file overriding the pipeline parameters of parameters.toml for this run.
+    #[clap(long, value_name = "TOML")]
+    parameters: Option<CliPath>,
+
     /// Do not execute the pipeline.
     /// Generate a pipeline invocation (.mro) file and stop.
     #[clap(long)]
@@ -241,6 +286,8 @@ impl Count {
             );
         }
 
+        validate_parameter_overrides(c.parameters.as_deref())?;
+
         let mut sample_defs = Vec::new();
         if let Some(ref libraries) = c.libraries {
             // parse the libraries.csv & convert it into a set of SampleDefs.
@@ -267,6 +314,10 @@ impl Count {
             reference_path: c.transcriptome,
             recovered_cells: c.expect_cells,
             no_bam: !c.create_bam.validated()?,
+            tag_bam: c.tag_bam,
+            no_h5ad: !c.create_h5ad,
+            velocity_layers: c.velocity_layers,
+            no_parquet: !c.create_parquet,
             no_secondary_analysis: c.no_secondary_analysis,
             no_target_umi_filter: false,
             force_cells: c.force_cells.map(|fc| fc.0),
@@ -281,6 +332,8 @@ impl Count {
             include_introns: c.include_introns.unwrap(),
             check_library_compatibility: c.check_library_compatibility.unwrap_or(true),
             disable_ab_aggregate_detection: !c.filter_aggregates.unwrap_or(true),
+            marker_gene_sets: c.marker_gene_sets,
+            parameters: c.parameters,
         })
     }
 }
@@ -292,6 +345,10 @@ struct CountCsMro {
     reference_path: CliPath,
     recovered_cells: Option<usize>,
     no_bam: bool,
+    tag_bam: bool,
+    no_h5ad: bool,
+    velocity_layers: bool,
+    no_parquet: bool,
     no_secondary_analysis: bool,
     no_target_umi_filter: bool,
     force_cells: Option<usize>,
@@ -306,6 +363,8 @@ struct CountCsMro {
     include_introns: bool,
     check_library_compatibility: bool,
     disable_ab_aggregate_detection: bool,
+    marker_gene_sets: Option<CliPath>,
+    parameters: Option<CliPath>,
 }
 
 /// A subcommand for controlling testing
@@ -323,6 +382,15 @@ struct Multi {
     #[clap(long, value_name = "CSV")]
     csv: CliPath,
 
+    /// TOML or JSON file overriding the web summary alert thresholds
+    /// and defining additional metric alerts.
+    #[clap(long, value_name = "FILE")]
+    alerts_config: Option<CliPath>,
+
+    /// TOML file overriding the pipeline parameters of parameters.toml for this run.
+    #[clap(long, value_name = "TOML")]
+    parameters: Option<CliPath>,
+
     /// Do not execute the pipeline.
     /// Generate a pipeline invocation (.mro) file and stop.
     #[clap(long)]
@@ -338,10 +406,13 @@ struct MultiCsMro {
     config: FileOrBytes,
     config_hash: String,
     no_preflight: bool,
+    alerts_config: Option<CliPath>,
+    parameters: Option<CliPath>,
 }
 
 impl Multi {
     pub fn to_mro_args(&self) -> Result<MultiCsMro> {
+        validate_parameter_overrides(self.parameters.as_deref())?;
         let config_hash = {
             let data = std::fs::read(&self.csv).with_context(|| self.csv.to_string())?;
             let mut hasher = Sha256::new();
@@ -357,6 +428,8 @@ impl Multi {
             },
             config_hash,
             no_preflight: self.mrp.nopreflight,
+            alerts_config: self.alerts_config.clone(),
+            parameters: self.parameters.clone(),
         })
     }
 }
@@ -406,12 +479,13 @@ struct Aggr {
     #[clap(long = "csv", value_name = "CSV")]
     aggregation_csv: CliPath,
 
-    /// Library depth normalization mode.
+    /// Library depth normalization mode. "rate" reads the per-library
+    /// subsampling rate from the normalization_rate column of the CSV.
     #[clap(
         long = "normalize",
         default_value = "mapped",
         value_name = "MODE",
-        value_parser = ["mapped", "none"],
+        value_parser = ["mapped", "median_umis", "saturation", "rate", "none"],
     )]
     normalization_mode: String,
 
@@ -419,6 +493,79 @@ struct Aggr {
     #[clap(long = "nosecondary")]
     no_secondary_analysis: bool,
 
+    /// Correct the PCA for batch effects with Harmony before clustering,
+    /// t-SNE and UMAP. KEY is gem_group, library_id or another column of
+    /// the CSV, such as batch.
+    #[clap(long, value_name = "KEY")]
+    harmony_batch_key: Option<String>,
+
+    /// Normalize the Antibody Capture counts by their ambient background
+    /// in the empty droplets of the aggregated raw matrix, and use the
+    /// denoised values for the antibody PCA.
+    #[clap(long)]
+    antibody_normalization: bool,
+
+    /// Test each graph-based cluster for differential expression between
+    /// two conditions, using the pseudobulk counts of each library as
+    /// replicates. KEY is the column of the CSV defining the condition of
+    /// each library, such as treatment.
+    #[clap(
+        long,
+        value_name = "KEY",
+        requires_all = ["de_reference_condition", "de_test_condition"]
+    )]
+    de_condition_key: Option<String>,
+
+    /// The condition of the denominator of the log2 fold changes.
+    #[clap(long, value_name = "VALUE", requires = "de_condition_key")]
+    de_reference_condition: Option<String>,
+
+    /// The condition of the numerator of the log2 fold changes.
+    #[clap(long, value_name = "VALUE", requires = "de_condition_key")]
+    de_test_condition: Option<String>,
+
+    /// TOML or JSON file overriding the web summary alert thresholds
+    /// and defining additional metric alerts.
+    #[clap(long, value_name = "FILE")]
+    alerts_config: Option<CliPath>,
+
+    /// Do not execute the pipeline.
+    /// Generate a pipeline invocation (.mro) file and stop.
+    #[serde(skip)]
+    #[clap(long)]
+    dry: bool,
+
+    // not a cmd-line arg -- should be filled in with the working dir
+    #[clap(hide = true, default_value = ".")]
+    pipestance_root: PathBuf,
+
+    #[serde(skip)]
+    #[clap(flatten)]
+    mrp: MrpArgs,
+}
+
+/// Builds an HTML report comparing the metrics of multiple runs
+/// and flagging the runs that deviate from the cohort.
+#[derive(Parser, Debug, Clone, Serialize)]
+struct QcReport {
+    /// A unique run id and output folder name [a-zA-Z0-9_-]+.
+    #[clap(long = "id", value_name = "ID", value_parser = validate_id, required = true)]
+    sample_id: String,
+
+    /// Sample description to embed in output files.
+    #[clap(long = "description", default_value = "", value_name = "TEXT")]
+    sample_desc: String,
+
+    /// Path of a CSV file with the columns run_id and metrics_summary,
+    /// listing the metrics_summary.csv or .json file of each run.
+    #[clap(long = "csv", value_name = "CSV")]
+    runs_csv: CliPath,
+
+    /// Flag a metric of a run when the absolute value of its z-score
+    /// relative to the other runs is at least this large.
+    #[clap(long = "zscore-threshold", value_name = "FLOAT")]
+    z_score_threshold: Option<f64>,
+
     /// Do not execute the pipeline.
     /// Generate a pipeline invocation (.mro) file and stop.
     #[serde(skip)]
@@ -434,6 +581,73 @@ struct Aggr {
     mrp: MrpArgs,
 }
 
+/// Compares two feature-barcode matrix HDF5 files or two molecule_info.h5 files,
+/// aligning their counts by barcode and feature ID.
+#[derive(Parser, Debug, Clone, Serialize)]
+struct Diff {
+    /// A unique run id and output folder name [a-zA-Z0-9_-]+.
+    #[serde(skip)]
+    #[clap(long = "id", value_name = "ID", value_parser = validate_id, required = true)]
+    id: String,
+
+    /// The first feature-barcode matrix or molecule_info HDF5 file.
+    #[clap(value_name = "A")]
+    h5_a: CliPath,
+
+    /// The second feature-barcode matrix or molecule_info HDF5 file.
+    #[clap(value_name = "B")]
+    h5_b: CliPath,
+
+    /// The filtered barcodes CSV of A, whose cells are compared to those of B.
+    /// Defaults to the barcodes of a matrix, or to the cells of a molecule_info file.
+    #[clap(long, value_name = "CSV")]
+    filtered_barcodes_a: Option<CliPath>,
+
+    /// The filtered barcodes CSV of B, whose cells are compared to those of A.
+    /// Defaults to the barcodes of a matrix, or to the cells of a molecule_info file.
+    #[clap(long, value_name = "CSV")]
+    filtered_barcodes_b: Option<CliPath>,
+
+    /// Do not execute the pipeline.
+    /// Generate a pipeline invocation (.mro) file and stop.
+    #[serde(skip)]
+    #[clap(long)]
+    dry: bool,
+
+    #[serde(skip)]
+    #[clap(flatten)]
+    mrp: MrpArgs,
+}
+
+#[derive(Parser, Debug, Clone, Serialize)]
+struct SplitBam {
+    /// A unique run id and output folder name [a-zA-Z0-9_-]+.
+    #[serde(skip)]
+    #[clap(long = "id", value_name = "ID", value_parser = validate_id, required = true)]
+    id: String,
+
+    /// A position-sorted BAM file with its index file .bam.bai or .bam.csi,
+    /// such as possorted_genome_bam.bam.
+    #[clap(long, value_name = "BAM")]
+    bam: CliPath,
+
+    /// A CSV file with the column barcode and an optional column group.
+    /// Write one BAM file per group, or a single BAM file of the listed
+    /// barcodes when the group column is omitted.
+    #[clap(long = "barcodes", value_name = "CSV")]
+    barcode_groups: CliPath,
+
+    /// Do not execute the pipeline.
+    /// Generate a pipeline invocation (.mro) file and stop.
+    #[serde(skip)]
+    #[clap(long)]
+    dry: bool,
+
+    #[serde(skip)]
+    #[clap(flatten)]
+    mrp: MrpArgs,
+}
+
 #[derive(Parser, Debug, Clone, Serialize)]
 struct Reanalyze {
     /// A unique run id and output folder name [a-zA-Z0-9_-]+.
@@ -449,6 +663,12 @@ struct Reanalyze {
     #[clap(long = "matrix", value_name = "MATRIX_H5")]
     filtered_matrices_h5: CliPath,
 
+    /// The raw feature-barcode matrix of the same run, whose empty
+    /// droplets are the background of antibody_normalization in
+    /// --params. Optional.
+    #[clap(long = "raw-matrix", value_name = "MATRIX_H5")]
+    raw_matrices_h5: Option<CliPath>,
+
     /// A CSV file specifying analysis parameters. Optional.
     #[clap(long = "params", value_name = "PARAMS_CSV")]
     params_csv: Option<CliPath>,
@@ -486,6 +706,18 @@ struct Reanalyze {
     #[clap(long = "force-cells", value_name = "NUM")]
     force_cells: Option<ForceCells>,
 
+    /// The analysis folder of a previous run. Project the cells onto its
+    /// principal components, and transfer its graph-based clusters and UMAP
+    /// coordinates to each cell along with a mapping confidence. Optional.
+    #[clap(long = "reference-analysis", value_name = "PATH")]
+    reference_analysis: Option<CliPath>,
+
+    /// A CSV file of the positive and negative marker genes of each
+    /// cell type. Annotate the cell type of each cell and cluster
+    /// using these markers. Optional.
+    #[clap(long = "marker-gene-sets", value_name = "CSV")]
+    marker_gene_sets: Option<CliPath>,
+
     /// Do not execute the pipeline.
     /// Generate a pipeline invocation (.mro) file and stop.
     #[serde(skip)]
@@ -501,6 +733,8 @@ struct Reanalyze {
 #[derive(Serialize, Debug, Clone, Copy)]
 enum VdjChainType {
     TR,
+    #[serde(rename = "TR_GD")]
+    TRGD,
     IG,
     #[serde(rename = "auto")]
     Auto,
@@ -512,9 +746,9 @@ impl FromStr for VdjChainType {
     fn from_str(s: &str) -> Result<VdjChainType> {
         Ok(match s {
             "TR" => VdjChainType::TR,
+            "TR_GD" => VdjChainType::TRGD,
             "IG" => VdjChainType::IG,
             "auto" => VdjChainType::Auto,
-            "TR_GD" => bail!("Please use Cellranger multi to run Gamma/Delta TCR datasets."),
             _ => bail!("unknown chain type \"{s}\""),
         })
     }
@@ -553,8 +787,9 @@ struct Vdj {
     #[clap(long, hide = true)]
     skip_clonotyping: bool,
 
-    /// Chain type to display metrics for: 'TR' for T cell receptors,
-    /// 'IG' for B cell receptors, or 'auto' to autodetect.
+    /// Chain type to display metrics for: 'TR' for alpha/beta T cell receptors,
+    /// 'TR_GD' for gamma/delta T cell receptors, 'IG' for B cell receptors,
+    /// or 'auto' to autodetect.
     #[clap(long = "chain", default_value = "auto", value_name = "CHAIN_SPEC")]
     chain_type: VdjChainType,
 
@@ -621,6 +856,10 @@ impl Testrun {
             reference_path,
             recovered_cells: None,
             no_bam: false,
+            tag_bam: false,
+            no_h5ad: true,
+            velocity_layers: false,
+            no_parquet: true,
             no_secondary_analysis: false,
             no_target_umi_filter: false,
             force_cells: None,
@@ -635,6 +874,8 @@ impl Testrun {
             include_introns: false,
             check_library_compatibility: true,
             disable_ab_aggregate_detection: false,
+            marker_gene_sets: None,
+            parameters: None,
         })
     }
 }
@@ -729,6 +970,28 @@ If you want to proceed with a feature barcode reference, but no feature barcode
             execute(&aggr.sample_id, &mro, &aggr.mrp, aggr.dry)
         }
 
+        SubCommand::QcReport(mut qc) => {
+            // fill in pipestance_root
+            qc.pipestance_root = std::env::current_dir()?;
+            let mro = make_mro_with_comment(
+                "SC_QC_REPORT_CS",
+                &qc,
+                "rna/sc_qc_report_cs.mro",
+                &read_to_string(&qc.runs_csv).with_context(|| qc.runs_csv.to_string())?,
+            )?;
+            execute(&qc.sample_id, &mro, &qc.mrp, qc.dry)
+        }
+
+        SubCommand::Diff(diff) => {
+            let mro = make_mro("SC_H5_DIFF_CS", &diff, "rna/sc_h5_diff_cs.mro")?;
+            execute(&diff.id, &mro, &diff.mrp, diff.dry)
+        }
+
+        SubCommand::SplitBam(split) => {
+            let mro = make_mro("SC_SPLIT_BAM_CS", &split, "rna/sc_split_bam_cs.mro")?;
+            execute(&split.id, &mro, &split.mrp, split.dry)
+        }
+
         SubCommand::Reanalyze(ra) => {
             // Custom validation
 
@@ -749,6 +1012,12 @@ If you want to proceed with a feature barcode reference, but no feature barcode
                 })?;
             }
 
+            if matches!(vdj.chain_type, VdjChainType::TRGD)
+                && vdj.inner_enrichment_primers.is_none()
+            {
+                bail!(vdj_reference::TRGD_INNER_PRIMERS_REQUIRED);
+            }
+
             let mro = make_mro("SC_VDJ_ASSEMBLER_CS", &vdj, "rna/sc_vdj_assembler_cs.mro")?;
             execute(&vdj.sample_id, &mro, &vdj.mrp, vdj.dry)
         }
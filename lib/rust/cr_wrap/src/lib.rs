@@ -233,6 +233,15 @@ fn set_env_columns() {
     }
 }
 
+/// Validate the per-run overrides of parameters.toml of --parameters,
+/// before passing the file to the pipeline.
+pub fn validate_parameter_overrides(path: Option<&Path>) -> Result<()> {
+    if let Some(path) = path {
+        parameters_toml::read_overrides(path)?;
+    }
+    Ok(())
+}
+
 // Set environment variables.
 pub fn set_env_vars() {
     set_env_cmdline();
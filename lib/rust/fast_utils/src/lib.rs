@@ -14,8 +14,8 @@ use crate::diff_exp::{compute_sseq_params_o3, sseq_differential_expression_o3};
 use crate::gdna_analysis::count_umis_per_probe;
 use crate::library_read_counter::count_reads_per_library;
 use crate::molecule_info::{
-    concatenate_molecule_infos, count_reads_and_reads_in_cells, count_usable_reads,
-    downsample_molinfo, get_num_umis_per_barcode,
+    compute_normalization_rates, concatenate_molecule_infos, count_reads_and_reads_in_cells,
+    count_usable_reads, downsample_molinfo, get_num_umis_per_barcode,
 };
 use crate::multi_graph::MultiGraph;
 use barcode::binned::SquareBinIndex;
@@ -159,6 +159,7 @@ fn fast_utils(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(count_umis_per_probe, m)?)?;
     m.add_function(wrap_pyfunction!(count_reads_and_reads_in_cells, m)?)?;
     m.add_function(wrap_pyfunction!(count_usable_reads, m)?)?;
+    m.add_function(wrap_pyfunction!(compute_normalization_rates, m)?)?;
     m.add_function(wrap_pyfunction!(sseq_differential_expression_o3, m)?)?;
     m.add_function(wrap_pyfunction!(compute_sseq_params_o3, m)?)?;
     m.add_function(wrap_pyfunction!(validate_reference, m)?)?;
@@ -3,15 +3,17 @@
 // Code for downsampling and collecting filters, to move a downsampler out of pandas and into Rust
 
 use cr_h5::molecule_info::{
-    BarcodeIdxType, FeatureIdxType, FullUmiCount, GemGroupType, LibraryIdxType,
-    MoleculeInfoIterator, MoleculeInfoReader, MoleculeInfoWriter, PerLibrarySubSampler,
+    BarcodeIdxType, FeatureIdxType, FullUmiCount, GemGroupType, LibraryDepthProfile,
+    LibraryIdxType, MoleculeInfoIterator, MoleculeInfoReader, MoleculeInfoWriter,
+    PerLibrarySubSampler,
 };
 use cr_types::{LibraryInfo, LibraryType};
 use itertools::Itertools;
 use numpy::PyArray1;
-use pyo3::exceptions::PyIOError;
+use pyo3::exceptions::{PyIOError, PyValueError};
 use pyo3::prelude::*;
 use std::collections::{HashMap, HashSet};
+use std::iter::zip;
 use std::path::{Path, PathBuf};
 
 /// Read and downsample a molecule_info.h5 file, and produce a feature-barcode matrix.
@@ -112,6 +114,50 @@ pub(crate) fn count_usable_reads(_py: Python<'_>, mol_info_path: String) -> Hash
         .sum()
 }
 
+/// Compute the read subsampling rate of each library for an aggr normalization mode, from a
+/// single pass over the molecule info:
+/// - "median_umis": match the lowest median UMIs per cell among libraries of the same group
+/// - "saturation": match the lowest sequencing saturation among libraries of the same group
+///
+/// library_groups: the group (e.g. library type) of each library
+#[pyfunction]
+pub(crate) fn compute_normalization_rates(
+    _py: Python<'_>,
+    mol_info_path: String,
+    mode: &str,
+    library_groups: Vec<usize>,
+) -> PyResult<Vec<f64>> {
+    let metric: fn(&LibraryDepthProfile, f64) -> f64 = match mode {
+        "median_umis" => LibraryDepthProfile::expected_median_umis_per_cell,
+        "saturation" => LibraryDepthProfile::expected_saturation,
+        _ => {
+            return Err(PyValueError::new_err(format!(
+                "Unknown normalization mode: {mode}"
+            )))
+        }
+    };
+    let profiles = LibraryDepthProfile::from_molecule_info(Path::new(&mol_info_path))
+        .map_err(|err| PyIOError::new_err(format!("{err:#}")))?;
+    if library_groups.len() != profiles.len() {
+        return Err(PyValueError::new_err(format!(
+            "Expected a group for each of the {} libraries",
+            profiles.len()
+        )));
+    }
+
+    // The lowest value of the metric in each group
+    let targets: HashMap<usize, f64> = zip(&library_groups, &profiles)
+        .map(|(&group, profile)| (group, metric(profile, 1.0)))
+        .into_grouping_map()
+        .fold(f64::INFINITY, |acc, _, x| acc.min(x));
+    Ok(zip(&library_groups, &profiles)
+        .map(|(group, profile)| match mode {
+            "median_umis" => profile.rate_for_median_umis_per_cell(targets[group]),
+            _ => profile.rate_for_saturation(targets[group]),
+        })
+        .collect())
+}
+
 /// Method to concatenate molecule. Takes in a molecule info file as a path where all the top
 /// level data (feature_ref, barcodes, metrics, library_info, etc). has been set (by python code in
 /// MERGE_MOLECULES) and proceeds to append the mol_info_columns to the input file by concatentating
@@ -388,6 +388,10 @@ pub struct GeneExpressionParams {
     pub check_library_compatibility: bool,
     pub aligner: Option<AlignerParam>,
     pub create_bam: bool,
+    pub tag_bam: bool,
+    pub create_h5ad: bool,
+    pub create_parquet: bool,
+    pub velocity_layers: bool,
     pub filter_probes: Option<bool>,
     pub filter_high_occupancy_gems: bool,
     pub cmo_set: Option<PathBuf>,
@@ -395,6 +399,9 @@ pub struct GeneExpressionParams {
     pub barcode_sample_assignment: Option<PathBuf>,
     /// Select which model is used by the cell annotation service. The default string is "default" but will be validated by CAS
     pub cas_model: Option<String>,
+    /// A CSV of the positive and negative marker genes of each cell type, used to annotate
+    /// cell types locally.
+    pub marker_gene_sets: Option<PathBuf>,
 }
 
 impl GeneExpressionParams {
@@ -477,10 +484,15 @@ impl<'a> TryFrom<&Section<'a>> for GeneExpressionParams {
         let mut check_library_compatibility = true;
         let mut aligner: Option<AlignerParam> = None;
         let mut create_bam = None;
+        let mut tag_bam = false;
+        let mut create_h5ad = false;
+        let mut create_parquet = false;
+        let mut velocity_layers = false;
         let mut cmo_set: Option<PathBuf> = None;
         let mut min_assignment_confidence: Option<f64> = None;
         let mut barcode_sample_assignment: Option<PathBuf> = None;
         let mut cas_model: Option<String> = None;
+        let mut marker_gene_sets: Option<PathBuf> = None;
         let mut filter_high_occupancy_gems = true;
         for row in &sec.rows {
             if row.is_empty() {
@@ -581,6 +593,26 @@ impl<'a> TryFrom<&Section<'a>> for GeneExpressionParams {
                         create_bam = Some(val.parse::<Bool>(ctx)?.0);
                     }
                 }
+                "tag-bam" => {
+                    if let Some(val) = row.get(1).and_then(empty_is_none) {
+                        tag_bam = val.parse::<Bool>(ctx)?.into();
+                    }
+                }
+                "create-h5ad" => {
+                    if let Some(val) = row.get(1).and_then(empty_is_none) {
+                        create_h5ad = val.parse::<Bool>(ctx)?.into();
+                    }
+                }
+                "create-parquet" => {
+                    if let Some(val) = row.get(1).and_then(empty_is_none) {
+                        create_parquet = val.parse::<Bool>(ctx)?.into();
+                    }
+                }
+                "velocity-layers" => {
+                    if let Some(val) = row.get(1).and_then(empty_is_none) {
+                        velocity_layers = val.parse::<Bool>(ctx)?.into();
+                    }
+                }
                 "cmoset" | "cmo-set" => {
                     if let Some(val) = row.get(1).and_then(empty_is_none) {
                         cmo_set = Some(val.parse::<PathBuf>(ctx)?);
@@ -601,6 +633,11 @@ impl<'a> TryFrom<&Section<'a>> for GeneExpressionParams {
                         cas_model = Some(val.parse::<String>(ctx)?);
                     }
                 }
+                "marker-gene-sets" => {
+                    if let Some(val) = row.get(1).and_then(empty_is_none) {
+                        marker_gene_sets = Some(val.parse::<PathBuf>(ctx)?);
+                    }
+                }
                 "filter-high-occupancy-gems" => {
                     if let Some(val) = row.get(1).and_then(empty_is_none) {
                         filter_high_occupancy_gems = val.parse::<Bool>(ctx)?.into();
@@ -627,6 +664,11 @@ impl<'a> TryFrom<&Section<'a>> for GeneExpressionParams {
             "{ctx} only one of force-cells or expect-cells is allowed.",
         );
 
+        ensure!(
+            !velocity_layers || create_h5ad,
+            "{ctx} velocity-layers requires create-h5ad.",
+        );
+
         if filter_probes.is_some() {
             ensure!(
                 probe_set.is_some(),
@@ -682,10 +724,15 @@ impl<'a> TryFrom<&Section<'a>> for GeneExpressionParams {
             check_library_compatibility,
             aligner,
             create_bam,
+            tag_bam,
+            create_h5ad,
+            create_parquet,
+            velocity_layers,
             cmo_set,
             min_assignment_confidence,
             barcode_sample_assignment,
             cas_model,
+            marker_gene_sets,
             filter_high_occupancy_gems,
         })
     }
@@ -697,6 +744,7 @@ pub struct FeatureParams {
     pub r1_length: Option<usize>,
     pub r2_length: Option<usize>,
     pub filter_aggregates: bool,
+    pub antibody_normalization: bool,
 }
 
 impl<'a> TryFrom<&Section<'a>> for FeatureParams {
@@ -708,6 +756,7 @@ impl<'a> TryFrom<&Section<'a>> for FeatureParams {
         let mut r1_length: Option<usize> = None;
         let mut r2_length: Option<usize> = None;
         let mut filter_aggregates = true;
+        let mut antibody_normalization = false;
         for row in &sec.rows {
             if row.is_empty() {
                 continue;
@@ -735,6 +784,11 @@ impl<'a> TryFrom<&Section<'a>> for FeatureParams {
                         filter_aggregates = val.parse::<Bool>(ctx)?.into();
                     }
                 }
+                "antibody-normalization" => {
+                    if let Some(val) = row.get(1).and_then(empty_is_none) {
+                        antibody_normalization = val.parse::<Bool>(ctx)?.into();
+                    }
+                }
                 _ => {
                     bail!(
                         "{ctx} unknown parameter '{}' provided at line: {}, col: {}",
@@ -750,6 +804,7 @@ impl<'a> TryFrom<&Section<'a>> for FeatureParams {
             r1_length,
             r2_length,
             filter_aggregates,
+            antibody_normalization,
         })
     }
 }
@@ -1826,9 +1881,8 @@ impl<'a> TryFrom<(&TxHashSet<GemWell>, &Section<'a>)> for SamplesCsv {
 
     fn try_from((valid_gws, sec): (&TxHashSet<GemWell>, &Section<'a>)) -> Result<Self> {
         use samplesconst::{
-            CMO_IDS, DESCRIPTION, EMPTYDROPS_MINIMUM_UMIS, EXPECT_CELLS, FORCE_CELLS,
+            _GEM_WELLS, CMO_IDS, DESCRIPTION, EMPTYDROPS_MINIMUM_UMIS, EXPECT_CELLS, FORCE_CELLS,
             MAX_MITO_FRAC, OH_IDS, PROBE_BARCODE_IDS, SAMPLE_ID, SAMP_OPT_HDRS, SAMP_REQ_HDRS,
-            _GEM_WELLS,
         };
         let hdr = sec.name;
         let parser = CsvParser::new(sec.clone(), SAMP_REQ_HDRS, SAMP_OPT_HDRS)?;
@@ -2228,6 +2282,42 @@ impl<'a> TryFrom<&Section<'a>> for FunctionalMapCsv {
         Ok(FunctionalMapCsv(data))
     }
 }
+/// Per-run overrides of the pipeline parameters of parameters.toml,
+/// one `name,value` row per parameter.
+#[derive(Debug, Serialize, Clone, Default)]
+#[serde(transparent)]
+pub struct ParametersCsv(pub toml::Table);
+
+impl<'a> TryFrom<&Section<'a>> for ParametersCsv {
+    type Error = anyhow::Error;
+
+    fn try_from(sec: &Section<'a>) -> Result<Self> {
+        let ctx = ParseCtx::Hdr(sec.name);
+        let rows: Vec<(&str, String)> = sec
+            .rows
+            .iter()
+            .filter(|row| !row.is_empty())
+            .map(|row| {
+                ensure!(
+                    row.len() > 1,
+                    "{ctx} no value provided for '{}'",
+                    row[0].fragment()
+                );
+                // A value such as the STAR command line may include commas.
+                Ok((
+                    *row[0].fragment(),
+                    row[1..].iter().map(|v| *v.fragment()).join(","),
+                ))
+            })
+            .collect::<Result<_>>()?;
+        let overrides = parameters_toml::overrides_from_key_values(
+            rows.iter().map(|(name, value)| (*name, value.as_str())),
+        )
+        .with_context(|| format!("{ctx} invalid parameter"))?;
+        Ok(ParametersCsv(overrides))
+    }
+}
+
 pub fn create_feature_config(
     antigen_specificity_csv: Option<&AntigenSpecificityCsv>,
     functional_map_csv: Option<&FunctionalMapCsv>,
@@ -2311,6 +2401,7 @@ pub mod multiconst {
     pub const GWS: &str = "gws";
     pub const ANTIGEN_SPECIFICITY: &str = "antigen-specificity";
     pub const FUNCTIONAL_MAP: &str = "feature-functional-map";
+    pub const PARAMETERS: &str = "parameters";
 
     lazy_static! {
         pub static ref VALID_SECTIONS: TxHashSet<&'static str> = {
@@ -2322,6 +2413,7 @@ pub mod multiconst {
             s.insert(LIBRARIES);
             s.insert(LIBS);
             s.insert(ANTIGEN_SPECIFICITY);
+            s.insert(PARAMETERS);
             // TODO: bring this back one day
             #[cfg(test)]
             {
@@ -2357,10 +2449,21 @@ pub struct MultiConfigCsv {
     pub gem_wells: Option<GemWellsCsv>,
     pub antigen_specificity: Option<AntigenSpecificityCsv>,
     pub functional_map: Option<FunctionalMapCsv>,
+    pub parameters: Option<ParametersCsv>,
 }
 
 /// A representation of a `cellranger multi` configuration
 impl MultiConfigCsv {
+    /// Return the per-run overrides of the pipeline parameters: the [parameters]
+    /// section, overridden in turn by the TOML file `path` of --parameters.
+    pub fn parameter_overrides(&self, path: Option<&Path>) -> Result<toml::Table> {
+        let mut overrides = self.parameters.clone().unwrap_or_default().0;
+        if let Some(path) = path {
+            overrides.extend(parameters_toml::read_overrides(path)?);
+        }
+        Ok(overrides)
+    }
+
     /// Load a MultiConfigCsv from a path
     fn from_csv<P: AsRef<Path>>(path: P) -> Result<Self> {
         let f = File::open(path.as_ref())?;
@@ -2578,6 +2681,7 @@ struct MultiConfigCsvBuilder {
     pub gem_wells: Option<GemWellsCsv>,
     pub antigen_specificity: Option<AntigenSpecificityCsv>,
     pub functional_map: Option<FunctionalMapCsv>,
+    pub parameters: Option<ParametersCsv>,
 }
 
 macro_rules! setter {
@@ -2632,7 +2736,7 @@ impl MultiConfigCsvBuilder {
     fn push(&mut self, section: &Section<'_>) -> Result<()> {
         use multiconst::{
             ANTIGEN_SPECIFICITY, FEATURE, GEM_WELLS, GENE_EXPRESSION, GEX, GWS, LIBRARIES, LIBS,
-            SAMPLES, VDJ,
+            PARAMETERS, SAMPLES, VDJ,
         };
         let name = section.name.fragment().to_ascii_lowercase();
         match name.as_str() {
@@ -2644,6 +2748,7 @@ impl MultiConfigCsvBuilder {
             GEM_WELLS | GWS => self.gem_wells(section),
             ANTIGEN_SPECIFICITY => self.antigen_specificity(section),
             FUNCTIONAL_MAP => self.functional_map(section),
+            PARAMETERS => self.parameters(section),
             _ => bail!(
                 "failed to parse CSV, unknown section [{}] at line: {}, col: {}",
                 section.name.fragment(),
@@ -2659,6 +2764,7 @@ impl MultiConfigCsvBuilder {
     setter!(libraries, LibrariesCsv);
     setter!(antigen_specificity, AntigenSpecificityCsv);
     setter!(functional_map, FunctionalMapCsv);
+    setter!(parameters, ParametersCsv);
 
     setter_validate_gws!(samples, SamplesCsv, multiconst::SAMPLES);
     setter_validate_gws!(gem_wells, GemWellsCsv, multiconst::GEM_WELLS);
@@ -2673,6 +2779,7 @@ impl MultiConfigCsvBuilder {
             gem_wells,
             antigen_specificity,
             functional_map,
+            parameters,
         } = self;
 
         let Some(libraries) = libraries else {
@@ -2885,6 +2992,7 @@ impl MultiConfigCsvBuilder {
             gem_wells,
             antigen_specificity,
             functional_map,
+            parameters,
         })
     }
 }
@@ -3567,6 +3675,40 @@ vdj_ig,fastqs/cellranger/multi/vdj_ig_tiny,any,,vdj,
         Ok(())
     }
 
+    #[test]
+    fn test_parameters_section() -> Result<()> {
+        let csv = r#"
+    [gene-expression]
+    ref,mm10-2020-A-chr19
+    create-bam,true
+
+    [parameters]
+    detect_chemistry_total_reads,5000000
+    star_parameters,--outSJtype None,--outFilterMismatchNmax 5
+
+    [libraries]
+    fastq_id,fastqs,lanes,physical_library_id,feature_types,subsample_rate
+    tiny_gex,fastqs/cellranger/multi/1245140_gex_vdj_beam_ab/gex,any,gex,gene expression,0.5
+    "#;
+
+        let xtra = XtraData::new("test::test_parameters_section");
+        let cfg = MultiConfigCsv::from_reader(csv.as_bytes(), xtra)?;
+        let parameters = cfg.parameters.unwrap().0;
+        assert_eq!(
+            parameters["detect_chemistry_total_reads"].as_integer(),
+            Some(5_000_000)
+        );
+        assert_eq!(
+            parameters["star_parameters"].as_str(),
+            Some("--outSJtype None,--outFilterMismatchNmax 5")
+        );
+
+        let csv = csv.replace("detect_chemistry_total_reads", "no_such_parameter");
+        let xtra = XtraData::new("test::test_parameters_section");
+        assert!(MultiConfigCsv::from_reader(csv.as_bytes(), xtra).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_blank_lines() -> Result<()> {
         let csv = r#"
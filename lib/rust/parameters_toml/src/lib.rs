@@ -46,12 +46,13 @@
     while_true
 )]
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use log::warn;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
 use std::sync::OnceLock;
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 struct Parameters {
     /// DETECT_CHEMISTRY samples detect_chemistry_sample_reads from detect_chemistry_total_reads.
     detect_chemistry_sample_reads: usize,
@@ -93,28 +94,136 @@ const DEFAULT_PARAMETERS: Parameters = Parameters {
     min_major_probe_bc_frac: 0.7,
 };
 static PARAMETERS: OnceLock<Result<Parameters>> = OnceLock::new();
+static OVERRIDES: OnceLock<toml::Table> = OnceLock::new();
+
+fn default_parameters() -> Result<toml::Table> {
+    match toml::Value::try_from(DEFAULT_PARAMETERS)? {
+        toml::Value::Table(table) => Ok(table),
+        _ => unreachable!(),
+    }
+}
+
+/// Return the parameters of the install tree, from the parameters.toml
+/// located next to the running executable.
+fn install_parameters() -> Result<toml::Table> {
+    let path = bazel_utils::current_exe()
+        .context("Unable to locate the running executable")?
+        .with_file_name("parameters.toml");
+    if !path.exists() {
+        warn!(
+            "could not find parameters.toml at {}, falling back to defaults",
+            path.display()
+        );
+        default_parameters()
+    } else {
+        let s = std::fs::read_to_string(&path).with_context(|| path.display().to_string())?;
+        Ok(toml::from_str(&s).with_context(|| path.display().to_string())?)
+    }
+}
+
+/// Layer the overrides on top of the base parameters.
+fn merge_parameters(mut base: toml::Table, overrides: toml::Table) -> Result<Parameters> {
+    base.extend(overrides);
+    Ok(toml::Value::Table(base).try_into()?)
+}
 
 /// Return a reference to the global parameters.
 /// The parameters may need to be loaded; if loading fails, return Err.
 fn parameters() -> &'static Result<Parameters> {
     // TODO: use get_or_try_init once [#109737](https://github.com/rust-lang/rust/issues/109737) is stabilized
     PARAMETERS.get_or_init(|| {
-        let path = bazel_utils::current_exe()
-            .context("Unable to locate the running executable")?
-            .with_file_name("parameters.toml");
-        if !path.exists() {
-            warn!(
-                "could not find parameters.toml at {}, falling back to defaults",
-                path.display()
-            );
-            Ok(DEFAULT_PARAMETERS)
-        } else {
-            let s = std::fs::read_to_string(&path).with_context(|| path.display().to_string())?;
-            Ok(toml::from_str(&s).with_context(|| path.display().to_string())?)
-        }
+        merge_parameters(
+            install_parameters()?,
+            OVERRIDES.get().cloned().unwrap_or_default(),
+        )
     })
 }
 
+/// Layer the per-run overrides on top of the install parameters.
+/// Must be called before the first parameter is read.
+pub fn set_overrides(overrides: toml::Table) -> Result<()> {
+    if *OVERRIDES.get().unwrap_or(&toml::Table::new()) == overrides {
+        return Ok(());
+    }
+    if OVERRIDES.get().is_some() {
+        bail!("the parameter overrides are already set");
+    }
+    if PARAMETERS.get().is_some() {
+        bail!("the parameters were read before the overrides were set");
+    }
+    validate_overrides(&overrides)?;
+    OVERRIDES
+        .set(overrides)
+        .map_err(|_| anyhow!("the parameter overrides are already set"))
+}
+
+/// Read the parameter overrides of the TOML file `path`, such as the --parameters
+/// file of the command line or the parameters.toml of WRITE_EFFECTIVE_PARAMETERS.
+pub fn read_overrides(path: &Path) -> Result<toml::Table> {
+    let s = std::fs::read_to_string(path).with_context(|| path.display().to_string())?;
+    parse_overrides(&s).with_context(|| path.display().to_string())
+}
+
+/// Layer the parameter overrides of the TOML file `path`, if any, on top of
+/// the install parameters. Stages call this with their parameters input
+/// before reading a parameter.
+pub fn load_overrides(path: Option<&Path>) -> Result<()> {
+    match path {
+        Some(path) => set_overrides(read_overrides(path)?),
+        None => Ok(()),
+    }
+}
+
+/// Parse per-run parameter overrides written as TOML.
+/// Return an error if a parameter is unknown or has the wrong type.
+pub fn parse_overrides(s: &str) -> Result<toml::Table> {
+    let overrides: toml::Table = toml::from_str(s)?;
+    validate_overrides(&overrides)?;
+    Ok(overrides)
+}
+
+/// Convert name,value pairs, such as the rows of the [parameters] section of a
+/// multi config CSV, to parameter overrides.
+/// A value that is not a TOML literal, such as the STAR command line, is a string.
+pub fn overrides_from_key_values<'a>(
+    pairs: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> Result<toml::Table> {
+    let mut overrides = toml::Table::new();
+    for (name, value) in pairs {
+        let value = value.trim();
+        let value = toml::from_str::<toml::Table>(&format!("value = {value}"))
+            .ok()
+            .and_then(|mut t| t.remove("value"))
+            .unwrap_or_else(|| toml::Value::String(value.to_string()));
+        if overrides.insert(name.trim().to_string(), value).is_some() {
+            bail!("parameter {name} is specified more than once");
+        }
+    }
+    validate_overrides(&overrides)?;
+    Ok(overrides)
+}
+
+/// Ensure that each override is a known parameter of the right type.
+/// Unknown keys of the install parameters.toml are ignored.
+fn validate_overrides(overrides: &toml::Table) -> Result<()> {
+    let defaults = default_parameters()?;
+    for name in overrides.keys() {
+        if !defaults.contains_key(name) {
+            bail!("unknown parameter {name}");
+        }
+    }
+    merge_parameters(defaults, overrides.clone())?;
+    Ok(())
+}
+
+/// Return the effective parameters of this run, including the overrides, as TOML.
+pub fn effective_parameters_toml() -> Result<String> {
+    match parameters() {
+        Err(e) => Err(anyhow::anyhow!(e)),
+        Ok(p) => Ok(toml::to_string(p)?),
+    }
+}
+
 macro_rules! parameter_getter {
     ($a:ident, $t:ty) => {
         pub fn $a() -> Result<&'static $t> {
@@ -141,3 +250,48 @@ parameter_getter!(fiveprime_multiplexing, bool);
 parameter_getter!(threeprime_lt_multiplexing, bool);
 parameter_getter!(min_major_probe_bc_frac, f64);
 parameter_getter!(star_parameters, str);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overrides() -> Result<()> {
+        let overrides = parse_overrides("max_multiplexing_tags = 16")?;
+        let params = merge_parameters(default_parameters()?, overrides)?;
+        assert_eq!(params.max_multiplexing_tags, 16);
+        assert_eq!(
+            params.detect_chemistry_total_reads,
+            DEFAULT_PARAMETERS.detect_chemistry_total_reads
+        );
+
+        assert!(parse_overrides("no_such_parameter = 1").is_err());
+        assert!(parse_overrides("max_multiplexing_tags = \"many\"").is_err());
+
+        // The install parameters may have keys unknown to this version.
+        let mut base = default_parameters()?;
+        base.insert("retired_parameter".to_string(), toml::Value::Integer(1));
+        assert!(merge_parameters(base, toml::Table::new()).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_overrides_from_key_values() -> Result<()> {
+        let overrides = overrides_from_key_values([
+            ("detect_chemistry_total_reads", "5000000"),
+            ("star_parameters", "--outFilterMismatchNmax 5"),
+            ("fiveprime_multiplexing", "false"),
+        ])?;
+        assert_eq!(
+            overrides["star_parameters"].as_str(),
+            Some("--outFilterMismatchNmax 5")
+        );
+        assert_eq!(overrides["fiveprime_multiplexing"].as_bool(), Some(false));
+        assert_eq!(
+            overrides["detect_chemistry_total_reads"].as_integer(),
+            Some(5_000_000)
+        );
+        assert!(overrides_from_key_values([("max_multiplexing_tags", "1.5")]).is_err());
+        Ok(())
+    }
+}
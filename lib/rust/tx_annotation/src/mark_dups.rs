@@ -4,7 +4,7 @@ use crate::read::{AnnotationInfo, ReadAnnotations, RecordAnnotation};
 use cr_types::probe_set::ProbeSetReference;
 use cr_types::reference::feature_reference::FeatureReference;
 use cr_types::rna_read::RnaChunk;
-use cr_types::types::UmiCount;
+use cr_types::types::{SpliceType, UmiCount};
 use itertools::Itertools;
 use rand::Rng;
 use rand_chacha::ChaCha20Rng;
@@ -64,6 +64,8 @@ pub struct DupInfo {
     pub is_corrected: bool,
     umi_count: Option<UmiCount>,
     is_umi_count: bool,
+    /// The splicing state of the molecule, if this read is representative of its UMI.
+    splice_type: Option<SpliceType>,
     pub is_low_support_umi: bool,
     /// Was this read filtered because the number of reads
     /// mapping to the (UMI, gene) within this barcode is
@@ -81,6 +83,11 @@ impl DupInfo {
     pub fn umi_count(&self) -> Option<UmiCount> {
         self.umi_count
     }
+
+    /// Return the splicing state of this molecule.
+    pub fn splice_type(&self) -> Option<SpliceType> {
+        self.splice_type
+    }
 }
 
 /// Mark as low support umigenes with frequency below the maximum for that UMI.
@@ -116,6 +123,8 @@ pub struct UmiSelectKey {
 pub struct DupBuilder {
     umigene_counts: HashMap<(UmiSeq, Gene), u64>,
     umigene_min_key: HashMap<(UmiSeq, Gene), UmiSelectKey>,
+    umigene_splice_types: HashMap<(UmiSeq, Gene), SpliceType>,
+    track_splice_types: bool,
 }
 
 impl DupBuilder {
@@ -123,8 +132,19 @@ impl DupBuilder {
         DupBuilder {
             umigene_counts: HashMap::new(),
             umigene_min_key: HashMap::new(),
+            umigene_splice_types: HashMap::new(),
+            track_splice_types: false,
+        }
+    }
+
+    /// Also track the splice type of each (UMI, feature) pair, used for RNA velocity.
+    pub fn with_splice_types(self, track_splice_types: bool) -> Self {
+        DupBuilder {
+            track_splice_types,
+            ..self
         }
     }
+
     pub fn observe(&mut self, annotation: &ReadAnnotations, feature_reference: &FeatureReference) {
         if annotation.umi_info.is_valid {
             // Count (raw UMI, feature) pairs to prepare for UMI correction.
@@ -133,6 +153,13 @@ impl DupBuilder {
             if let Some(gene) = annotation.conf_mapped_feature(feature_reference) {
                 let key = (annotation.umi_info.seq, gene);
                 *self.umigene_counts.entry(key).or_insert(0) += 1;
+                if self.track_splice_types {
+                    let splice_type = annotation.splice_type();
+                    self.umigene_splice_types
+                        .entry(key)
+                        .and_modify(|x| *x = x.combine(splice_type))
+                        .or_insert(splice_type);
+                }
                 let header = annotation.read.header().to_vec(); // TODO: Use a buffer to avoid repeated allocations
                 let ann_key = match annotation.is_conf_mapped_unique_txomic() {
                     true => UmiSelectKey {
@@ -162,6 +189,7 @@ impl DupBuilder {
         BarcodeDupMarker::new(
             self.umigene_counts,
             self.umigene_min_key,
+            self.umigene_splice_types,
             filter_umis,
             umi_correction,
             targeted_umi_min_read_count,
@@ -185,6 +213,8 @@ pub struct BarcodeDupMarker {
     low_support_umigenes: HashSet<(UmiSeq, Gene)>,
     umi_corrections: HashMap<(UmiSeq, Gene), UmiSeq>,
     umigene_min_key: HashMap<(UmiSeq, Gene), UmiSelectKey>,
+    /// The splicing state of each corrected (UMI, gene) pair.
+    umigene_splice_types: HashMap<(UmiSeq, Gene), SpliceType>,
     /// If this is Some(r), we filter out (UMI, genes) pairs
     /// with **less than** r reads and not include them in UMI counts.
     targeted_umi_min_read_count: Option<u64>,
@@ -202,6 +232,7 @@ impl BarcodeDupMarker {
     pub fn new(
         mut umigene_counts: HashMap<(UmiSeq, Gene), u64>,
         mut umigene_min_key: HashMap<(UmiSeq, Gene), UmiSelectKey>,
+        raw_umigene_splice_types: HashMap<(UmiSeq, Gene), SpliceType>,
         filter_umis: bool,
         umi_correction: UmiCorrection,
         targeted_umi_min_read_count: Option<u64>,
@@ -267,11 +298,25 @@ impl BarcodeDupMarker {
             umigene_min_key.insert(key, umi_key.clone());
         }
 
+        // A molecule is spliced or unspliced only when all reads of its corrected UMI agree.
+        let mut umigene_splice_types: HashMap<(UmiSeq, Gene), SpliceType> = HashMap::new();
+        for (raw_key, splice_type) in raw_umigene_splice_types {
+            let corrected_key = match umi_corrections.get(&raw_key) {
+                Some(corrected_umi) => (*corrected_umi, raw_key.1),
+                None => raw_key,
+            };
+            umigene_splice_types
+                .entry(corrected_key)
+                .and_modify(|x| *x = x.combine(splice_type))
+                .or_insert(splice_type);
+        }
+
         BarcodeDupMarker {
             umigene_counts,
             umi_corrections,
             low_support_umigenes,
             umigene_min_key,
+            umigene_splice_types,
             targeted_umi_min_read_count,
         }
     }
@@ -352,11 +397,18 @@ impl BarcodeDupMarker {
             None
         };
 
+        let splice_type = if is_umi_count {
+            self.umigene_splice_types.get(&corrected_key).copied()
+        } else {
+            None
+        };
+
         Some(DupInfo {
             processed_umi: corrected_umi,
             is_corrected,
             umi_count,
             is_umi_count,
+            splice_type,
             is_low_support_umi,
             is_filtered_target_umi,
         })
@@ -391,6 +443,51 @@ mod test {
         assert!(corr[&(UmiSeq::from_bytes(b"CCCC"), g0)] == UmiSeq::from_bytes(b"CGCC"));
     }
 
+    #[test]
+    fn test_corrected_splice_types() {
+        let g0 = 0usize;
+        let (aaaa, aaat, cccc) = (
+            UmiSeq::from_bytes(b"AAAA"),
+            UmiSeq::from_bytes(b"AAAT"),
+            UmiSeq::from_bytes(b"CCCC"),
+        );
+        let umigene_counts = HashMap::from([((aaaa, g0), 3u64), ((aaat, g0), 2), ((cccc, g0), 2)]);
+        let umigene_min_key = umigene_counts
+            .keys()
+            .enumerate()
+            .map(|(i, &key)| {
+                let min_key = UmiSelectKey {
+                    utype: UmiType::Txomic,
+                    qname: vec![i as u8],
+                };
+                (key, min_key)
+            })
+            .collect();
+        let splice_types = HashMap::from([
+            ((aaaa, g0), SpliceType::Spliced),
+            ((aaat, g0), SpliceType::Unspliced),
+            ((cccc, g0), SpliceType::Unspliced),
+        ]);
+        let marker = BarcodeDupMarker::new(
+            umigene_counts,
+            umigene_min_key,
+            splice_types,
+            false,
+            UmiCorrection::Enable,
+            None,
+        );
+        // AAAT is corrected to AAAA, whose reads then disagree.
+        assert_eq!(marker.umigene_splice_types.len(), 2);
+        assert_eq!(
+            marker.umigene_splice_types[&(aaaa, g0)],
+            SpliceType::Ambiguous
+        );
+        assert_eq!(
+            marker.umigene_splice_types[&(cccc, g0)],
+            SpliceType::Unspliced
+        );
+    }
+
     #[test]
     fn test_umi_type() {
         let key1 = UmiSelectKey {
@@ -12,12 +12,12 @@ use cr_bam::bam_tags::{
     UNPAIRED_GENE_ID_TAG, UNPAIRED_GENE_NAME_TAG,
 };
 use cr_types::chemistry::ChemistryDef;
-use cr_types::probe_set::MappedProbe;
+use cr_types::probe_set::{MappedProbe, ProbeRegion};
 use cr_types::reference::feature_extraction::FeatureData;
 use cr_types::reference::feature_reference::FeatureReference;
 use cr_types::rna_read::{RnaChunk, RnaRead, UmiPart, HIGH_CONF_MAPQ};
 use cr_types::utils::calculate_median_of_sorted;
-use cr_types::{GenomeName, ReqStrand, UmiCount};
+use cr_types::{GenomeName, ReqStrand, SpliceType, UmiCount};
 use fastq_set::WhichEnd;
 use itertools::Itertools;
 use martian_derive::{martian_filetype, MartianStruct};
@@ -650,6 +650,16 @@ impl ReadAnnotations {
     pub fn umi_count(&self) -> Option<UmiCount> {
         self.dup_info.and_then(|info| info.umi_count())
     }
+
+    /// Return the splicing state of the molecule of which this read is representative.
+    pub fn umi_splice_type(&self) -> Option<SpliceType> {
+        self.dup_info.and_then(|info| info.splice_type())
+    }
+
+    /// Return the splicing state supported by this read alone.
+    pub fn splice_type(&self) -> SpliceType {
+        self.primary.splice_type()
+    }
 }
 
 impl AnnotationInfo for ReadAnnotations {
@@ -1130,6 +1140,26 @@ impl AnnotationInfo for RecordAnnotation {
 }
 
 impl RecordAnnotation {
+    /// Return the splicing state supported by this alignment.
+    /// An exonic read or a spliced probe is spliced, and an intronic read or an unspliced
+    /// probe is unspliced.
+    pub fn splice_type(&self) -> SpliceType {
+        match self {
+            RecordAnnotation::Probe(_, data) => {
+                match data.lhs_probe().and_then(|probe| probe.region.as_ref()) {
+                    Some(ProbeRegion::Spliced) if data.is_conf_mapped() => SpliceType::Spliced,
+                    Some(ProbeRegion::Unspliced) if data.is_conf_mapped() => SpliceType::Unspliced,
+                    _ => SpliceType::Ambiguous,
+                }
+            }
+            _ => match self.conf_mapped_region() {
+                Some((_, AnnotationRegion::Exonic)) => SpliceType::Spliced,
+                Some((_, AnnotationRegion::Intronic)) => SpliceType::Unspliced,
+                Some((_, AnnotationRegion::Intergenic)) | None => SpliceType::Ambiguous,
+            },
+        }
+    }
+
     pub fn new_se(annotator: &TranscriptAnnotator, rec: Record) -> Self {
         if rec.is_unmapped() {
             RecordAnnotation::Unmapped(rec, None)
@@ -1449,3 +1479,84 @@ fn attach_primary_tags(
             .unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cr_types::probe_set::{MappedProbeHalf, Probe};
+
+    fn se_mapped(region: AnnotationRegion, mapq: u8) -> RecordAnnotation {
+        let mut rec = Record::new();
+        rec.set_mapq(mapq);
+        let anno = AnnotationData {
+            transcripts: Vec::new(),
+            antisense: Vec::new(),
+            genes: Vec::new(),
+            region,
+            rescued: false,
+            genome: GenomeName::from("GRCh38"),
+        };
+        RecordAnnotation::SeMapped(rec, anno)
+    }
+
+    fn probe(probe_id: &str, region: Option<ProbeRegion>) -> MappedProbeHalf {
+        let probe = Probe {
+            probe_id: probe_id.to_string(),
+            gene: Gene {
+                id: "ENSG00000000001".to_string(),
+                name: "GENE1".to_string(),
+            },
+            included: true,
+            region,
+        };
+        MappedProbeHalf::new(probe, 50)
+    }
+
+    fn probe_mapped(lhs: MappedProbeHalf, rhs: Option<MappedProbeHalf>) -> RecordAnnotation {
+        let data = MappedProbe::new(Some(lhs), rhs, &GenomeName::from("GRCh38"));
+        RecordAnnotation::Probe(Record::new(), data)
+    }
+
+    #[test]
+    fn test_splice_type() {
+        use AnnotationRegion::{Exonic, Intergenic, Intronic};
+        assert_eq!(
+            se_mapped(Exonic, HIGH_CONF_MAPQ).splice_type(),
+            SpliceType::Spliced
+        );
+        assert_eq!(
+            se_mapped(Intronic, HIGH_CONF_MAPQ).splice_type(),
+            SpliceType::Unspliced
+        );
+        assert_eq!(
+            se_mapped(Intergenic, HIGH_CONF_MAPQ).splice_type(),
+            SpliceType::Ambiguous
+        );
+        // A multimapped read is not confidently mapped.
+        assert_eq!(se_mapped(Exonic, 3).splice_type(), SpliceType::Ambiguous);
+        assert_eq!(
+            RecordAnnotation::Unmapped(Record::new(), None).splice_type(),
+            SpliceType::Ambiguous
+        );
+
+        let spliced = || probe("GENE1|a", Some(ProbeRegion::Spliced));
+        let unspliced = || probe("GENE1|b", Some(ProbeRegion::Unspliced));
+        assert_eq!(
+            probe_mapped(spliced(), Some(spliced())).splice_type(),
+            SpliceType::Spliced
+        );
+        assert_eq!(
+            probe_mapped(unspliced(), Some(unspliced())).splice_type(),
+            SpliceType::Unspliced
+        );
+        // The two halves map to different probes.
+        assert_eq!(
+            probe_mapped(spliced(), Some(unspliced())).splice_type(),
+            SpliceType::Ambiguous
+        );
+        assert_eq!(
+            probe_mapped(probe("GENE1|c", None), Some(probe("GENE1|c", None))).splice_type(),
+            SpliceType::Ambiguous
+        );
+    }
+}
@@ -411,6 +411,8 @@ impl MartianStage for Assembly {
         split_args: Self::ChunkInputs,
         rover: MartianRover,
     ) -> Result<Self::ChunkOutputs> {
+        parameters_toml::load_overrides(args.parameters.as_deref())?;
+
         // Print some environment info.
 
         println!("\nstarting vdj_asm_asm, mem = {:.2}", mem_usage_gb());
@@ -578,6 +580,8 @@ impl MartianStage for Assembly {
         chunk_outs: Vec<Self::ChunkOutputs>,
         rover: MartianRover,
     ) -> Result<Self::StageOutputs> {
+        parameters_toml::load_overrides(args.parameters.as_deref())?;
+
         // Set up logging.
 
         let t = Instant::now();
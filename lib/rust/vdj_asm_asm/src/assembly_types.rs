@@ -2,6 +2,7 @@ use anyhow::Result;
 use barcode::Barcode;
 use cr_types::chemistry::ChemistryDefs;
 use cr_types::rna_read::RnaRead;
+use cr_types::types::TomlFile;
 use itertools::Itertools;
 use martian_derive::{martian_filetype, MartianStruct};
 use martian_filetypes::bin_file::{BinaryFormat, BincodeFile};
@@ -82,4 +83,6 @@ pub struct AssemblyStageInputs {
     pub total_read_pairs: i64,
     pub corrected_bc_counts: JsonFile<SimpleHistogram<Barcode>>,
     pub min_contig_length: Option<usize>,
+    /// The effective parameters.toml of WRITE_EFFECTIVE_PARAMETERS.
+    pub parameters: Option<TomlFile>,
 }
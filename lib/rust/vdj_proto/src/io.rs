@@ -27,22 +27,58 @@
 //!
 //! This follows the recommendation here: https://developers.google.com/protocol-buffers/docs/techniques#streaming
 //!
-//! The number of messages and the length are written in Big endian.
+//! Optionally, the messages are followed by an index which maps each barcode to the byte offsets
+//! of its contig annotation messages, so that the contigs of a barcode can be read without
+//! scanning the whole file. The index is written after the last message and is ignored by
+//! readers that only iterate over the `k` messages, so indexed files remain readable as before.
+//! ```text
+//!
+//! +-------------------------+
+//! | Number of entries(n)    |
+//! |       [4 bytes]         |
+//! +-------------------------+
+//!
+//! +------------+----------------------------+------------+
+//! | Length     |      Barcode 0             | Offset     |
+//! | [4 bytes]  |      [Length Bytes]        | [8 bytes]  |
+//! +------------+----------------------------+------------+
+//! ...
+//! +------------+----------------------------+------------+
+//! | Length     |      Barcode n-1           | Offset     |
+//! | [4 bytes]  |      [Length Bytes]        | [8 bytes]  |
+//! +------------+----------------------------+------------+
+//!
+//! +-------------------------+-------------------------+
+//! | Index start offset      | Magic (`VDJPBIDX`)      |
+//! |       [8 bytes]         |       [8 bytes]         |
+//! +-------------------------+-------------------------+
+//! ```
+//! The index entries are sorted by barcode. A barcode appears once per contig.
+//!
+//! The number of messages, the lengths and the offsets are written in Big endian.
 //!
 
 use crate::types::vdj_proto_message::MessageContent;
 use crate::types::{BarcodeData, MetricsSummary, VdjMetadata, VdjProtoMessage, VdjReferenceRaw};
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use enclone_proto::proto_io::{ProtoReader, ProtoWriter};
+use prost::Message;
 use serde_json::{Map, Value};
+use std::collections::BTreeMap;
 use std::convert::TryInto;
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Seek};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::RangeBounds;
 use std::path::Path;
 
 pub const PROTOBUF_VERSION: &str = "1.0.0";
 
+/// Magic bytes at the very end of a proto file with a barcode index
+const INDEX_MAGIC: &[u8; 8] = b"VDJPBIDX";
+/// Size of the index footer: index start offset followed by the magic bytes
+const INDEX_FOOTER_SIZE: u64 = 16;
+
 /// Helper struct for writing vdj contig proto file.
 ///
 /// Note: We use the `File` interface instead of `Write` because we need to seek to the
@@ -52,6 +88,10 @@ pub struct VdjProtoWriter {
     // value on finish() with a mutable reference
     writer: Option<ProtoWriter<BufWriter<File>>>,
     num_messages: u32,
+    // Byte offset at which the next message will be written
+    offset: u64,
+    // (barcode, offset) for each annotation if we are building an index
+    index: Option<Vec<(String, u64)>>,
 }
 
 impl VdjProtoWriter {
@@ -60,6 +100,27 @@ impl VdjProtoWriter {
         metadata: VdjMetadata,
         reference: VdjReferenceRaw,
         metrics: MetricsSummary,
+    ) -> Result<Self> {
+        Self::create(file_name, metadata, reference, metrics, false)
+    }
+
+    /// Same as `new()`, but also write a barcode index at the end of the file which
+    /// allows random access to the contigs of a barcode using `VdjProtoBarcodeReader`.
+    pub fn new_with_index(
+        file_name: &Path,
+        metadata: VdjMetadata,
+        reference: VdjReferenceRaw,
+        metrics: MetricsSummary,
+    ) -> Result<Self> {
+        Self::create(file_name, metadata, reference, metrics, true)
+    }
+
+    fn create(
+        file_name: &Path,
+        metadata: VdjMetadata,
+        reference: VdjReferenceRaw,
+        metrics: MetricsSummary,
+        with_index: bool,
     ) -> Result<Self> {
         let mut buf_writer = BufWriter::new(
             File::create(file_name)
@@ -69,24 +130,49 @@ impl VdjProtoWriter {
         // We will update this at the end
         buf_writer.write_u32::<BigEndian>(0u32)?;
 
-        let mut writer = ProtoWriter::with_writer(buf_writer);
+        let mut writer = VdjProtoWriter {
+            writer: Some(ProtoWriter::with_writer(buf_writer)),
+            num_messages: 0,
+            offset: 4,
+            index: with_index.then(Vec::new),
+        };
 
         // IMPORTANT: Any changes made here need to be evaluated in sync with VdjProtoReader.
         // Some APIs in the reader explicitly assumes that these two messages exist always
-        writer.encode_and_write(VdjProtoMessage::from(metadata))?;
-        writer.encode_and_write(VdjProtoMessage::from(reference))?;
-        writer.encode_and_write(VdjProtoMessage::from(metrics))?;
+        writer.write_message(VdjProtoMessage::from(metadata))?;
+        writer.write_message(VdjProtoMessage::from(reference))?;
+        writer.write_message(VdjProtoMessage::from(metrics))?;
 
-        Ok(VdjProtoWriter {
-            writer: Some(writer),
-            num_messages: 3, // Metadata, Reference and Metrics
-        })
+        Ok(writer)
+    }
+
+    /// Write a message and return the byte offset at which it was written
+    fn write_message(&mut self, message: VdjProtoMessage) -> Result<u64> {
+        let offset = self.offset;
+        self.offset += 4 + message.encoded_len() as u64;
+        self.writer
+            .as_mut()
+            .unwrap() // Guaranteed to exist by construction
+            .encode_and_write(message)?;
+        self.num_messages += 1;
+        Ok(offset)
     }
 
     // DO NOT CALL THIS DIRECTLY. USE finish()
     fn _finish(&mut self) -> Result<()> {
         if let Some(w) = self.writer.take() {
             let mut writer = w.finish();
+            if let Some(mut index) = self.index.take() {
+                index.sort();
+                writer.write_u32::<BigEndian>(index.len() as u32)?;
+                for (barcode, offset) in &index {
+                    writer.write_u32::<BigEndian>(barcode.len() as u32)?;
+                    writer.write_all(barcode.as_bytes())?;
+                    writer.write_u64::<BigEndian>(*offset)?;
+                }
+                writer.write_u64::<BigEndian>(self.offset)?;
+                writer.write_all(INDEX_MAGIC)?;
+            }
             writer.rewind()?;
             writer.write_u32::<BigEndian>(self.num_messages)?;
         }
@@ -95,7 +181,8 @@ impl VdjProtoWriter {
 
     /// Finish writing the proto file.
     ///
-    /// We need to seek to the beginning of the file and write the number of messages.
+    /// We need to seek to the beginning of the file and write the number of messages, after
+    /// writing the barcode index if requested.
     /// Call this function to explicitly catch any errors as a result. On dropping we attempt
     /// to do it ignoring any errors
     pub fn finish(mut self) -> Result<()> {
@@ -107,11 +194,11 @@ impl VdjProtoWriter {
         &mut self,
         annotation: vdj_ann::annotate::ContigAnnotation,
     ) -> Result<()> {
-        self.writer
-            .as_mut()
-            .unwrap() // Guaranteed to exist by construction
-            .encode_and_write(VdjProtoMessage::from(annotation))?;
-        self.num_messages += 1;
+        let barcode = self.index.is_some().then(|| annotation.barcode.clone());
+        let offset = self.write_message(VdjProtoMessage::from(annotation))?;
+        if let (Some(index), Some(barcode)) = (self.index.as_mut(), barcode) {
+            index.push((barcode, offset));
+        }
         Ok(())
     }
 
@@ -120,11 +207,7 @@ impl VdjProtoWriter {
         &mut self,
         barcode_brief: vdj_asm_utils::barcode_data::BarcodeDataBrief,
     ) -> Result<()> {
-        self.writer
-            .as_mut()
-            .unwrap() // Guaranteed to exist by construction
-            .encode_and_write(VdjProtoMessage::from(barcode_brief))?;
-        self.num_messages += 1;
+        self.write_message(VdjProtoMessage::from(barcode_brief))?;
         Ok(())
     }
 }
@@ -260,6 +343,140 @@ impl Iterator for VdjProtoReader {
     }
 }
 
+/// Random access to the contig annotations of a barcode in a vdj contig proto file.
+///
+/// If the file was written with a barcode index (see `VdjProtoWriter::new_with_index()`),
+/// the index is loaded from the end of the file. Otherwise the index is built by scanning
+/// all the messages once.
+pub struct VdjProtoBarcodeReader {
+    reader: BufReader<File>,
+    index: BTreeMap<String, Vec<u64>>,
+    is_indexed: bool,
+}
+
+impl VdjProtoBarcodeReader {
+    pub fn new(file_name: &Path) -> Result<Self> {
+        let mut reader =
+            BufReader::new(File::open(file_name).with_context(|| {
+                format!("While opening file for reading: {}", file_name.display())
+            })?);
+        let (index, is_indexed) = match Self::read_index(&mut reader)? {
+            Some(index) => (index, true),
+            None => (Self::scan_index(&mut reader)?, false),
+        };
+        Ok(VdjProtoBarcodeReader {
+            reader,
+            index,
+            is_indexed,
+        })
+    }
+
+    /// Load the trailing barcode index if the file has one
+    fn read_index(reader: &mut BufReader<File>) -> Result<Option<BTreeMap<String, Vec<u64>>>> {
+        let file_len = reader.seek(SeekFrom::End(0))?;
+        if file_len < 4 + INDEX_FOOTER_SIZE {
+            return Ok(None);
+        }
+        reader.seek(SeekFrom::End(-(INDEX_FOOTER_SIZE as i64)))?;
+        let index_start = reader.read_u64::<BigEndian>()?;
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != INDEX_MAGIC || index_start >= file_len - INDEX_FOOTER_SIZE {
+            return Ok(None);
+        }
+
+        reader.seek(SeekFrom::Start(index_start))?;
+        let mut index: BTreeMap<String, Vec<u64>> = BTreeMap::new();
+        for _ in 0..reader.read_u32::<BigEndian>()? {
+            let mut barcode = vec![0u8; reader.read_u32::<BigEndian>()? as usize];
+            reader.read_exact(&mut barcode)?;
+            let offset = reader.read_u64::<BigEndian>()?;
+            index
+                .entry(String::from_utf8(barcode)?)
+                .or_default()
+                .push(offset);
+        }
+        Ok(Some(index))
+    }
+
+    /// Build the barcode index by reading all the messages in a file without an index
+    fn scan_index(reader: &mut BufReader<File>) -> Result<BTreeMap<String, Vec<u64>>> {
+        reader.rewind()?;
+        let num_messages = reader.read_u32::<BigEndian>()?;
+        let mut index: BTreeMap<String, Vec<u64>> = BTreeMap::new();
+        let mut offset = 4;
+        let mut buf = Vec::new();
+        for _ in 0..num_messages {
+            let len = reader.read_u32::<BigEndian>()?;
+            buf.resize(len as usize, 0);
+            reader.read_exact(&mut buf)?;
+            if let MessageContent::Annotation(ann) =
+                VdjProtoMessage::decode(buf.as_slice())?.content()
+            {
+                index.entry(ann.barcode).or_default().push(offset);
+            }
+            offset += 4 + u64::from(len);
+        }
+        Ok(index)
+    }
+
+    /// Read the contig annotation message at the given byte offset
+    fn read_annotation_at(
+        reader: &mut BufReader<File>,
+        offset: u64,
+    ) -> Result<vdj_ann::annotate::ContigAnnotation> {
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut buf = vec![0u8; reader.read_u32::<BigEndian>()? as usize];
+        reader.read_exact(&mut buf)?;
+        match VdjProtoMessage::decode(buf.as_slice())?.content() {
+            MessageContent::Annotation(ann) => Ok(ann.into()),
+            _ => bail!("Expected a contig annotation at byte offset {offset}"),
+        }
+    }
+
+    /// Whether the file contained a barcode index
+    pub fn is_indexed(&self) -> bool {
+        self.is_indexed
+    }
+
+    /// Sorted iterator over all the barcodes with at least one contig
+    pub fn barcodes(&self) -> impl Iterator<Item = &str> {
+        self.index.keys().map(String::as_str)
+    }
+
+    /// Read all the contig annotations of a barcode, in the order they were written.
+    /// Returns an empty vector if the barcode has no contigs.
+    pub fn read_barcode(
+        &mut self,
+        barcode: &str,
+    ) -> Result<Vec<vdj_ann::annotate::ContigAnnotation>> {
+        let Some(offsets) = self.index.get(barcode) else {
+            return Ok(Vec::new());
+        };
+        offsets
+            .iter()
+            .map(|&offset| Self::read_annotation_at(&mut self.reader, offset))
+            .collect()
+    }
+
+    /// Returns an iterator over the contig annotations of all the barcodes within the range,
+    /// sorted by barcode.
+    pub fn read_barcode_range<R: RangeBounds<String>>(
+        &mut self,
+        range: R,
+    ) -> impl Iterator<Item = Result<vdj_ann::annotate::ContigAnnotation>> + '_ {
+        let offsets: Vec<u64> = self
+            .index
+            .range(range)
+            .flat_map(|(_, offsets)| offsets.iter().copied())
+            .collect();
+        let reader = &mut self.reader;
+        offsets
+            .into_iter()
+            .map(move |offset| Self::read_annotation_at(reader, offset))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -398,6 +615,68 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_barcode_reader() -> Result<()> {
+        let anns: Vec<vdj_ann::annotate::ContigAnnotation> =
+            serde_json::from_reader(File::open("../vdj_asm_asm/test.json")?)?;
+        let barcodes: Vec<_> = anns
+            .iter()
+            .map(|ann| ann.barcode.clone())
+            .sorted()
+            .dedup()
+            .collect();
+
+        for with_index in [true, false] {
+            let file = tempfile::NamedTempFile::new()?.into_temp_path();
+            let metadata = VdjMetadata::default();
+            let reference = VdjReferenceRaw::default();
+            let metrics = MetricsSummary {
+                raw_json: "{}".into(),
+            };
+            let mut writer = if with_index {
+                VdjProtoWriter::new_with_index(&file, metadata, reference, metrics)?
+            } else {
+                VdjProtoWriter::new(&file, metadata, reference, metrics)?
+            };
+            for ann in &anns {
+                writer.write_annotation(ann.clone())?;
+            }
+            writer.finish()?;
+
+            // The sequential reader is not affected by the index
+            let anns_read: Vec<_> = VdjProtoReader::read_annotations(&file)?.try_collect()?;
+            assert_eq!(anns_read, anns);
+
+            let mut reader = VdjProtoBarcodeReader::new(&file)?;
+            assert_eq!(reader.is_indexed(), with_index);
+            assert_eq!(reader.barcodes().collect::<Vec<_>>(), barcodes);
+            for barcode in &barcodes {
+                let expected: Vec<_> = anns
+                    .iter()
+                    .filter(|a| &a.barcode == barcode)
+                    .cloned()
+                    .collect();
+                assert_eq!(reader.read_barcode(barcode)?, expected);
+            }
+            assert!(reader.read_barcode("NOT_A_BARCODE")?.is_empty());
+
+            let all: Vec<_> = reader.read_barcode_range(..).try_collect()?;
+            let expected: Vec<_> = anns
+                .iter()
+                .sorted_by(|a, b| a.barcode.cmp(&b.barcode))
+                .cloned()
+                .collect();
+            assert_eq!(all, expected);
+
+            let first = barcodes[0].clone();
+            let first_only: Vec<_> = reader
+                .read_barcode_range(first.clone()..=first.clone())
+                .try_collect()?;
+            assert_eq!(first_only, reader.read_barcode(&first)?);
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_cellranger7_compatibility() -> Result<()> {
         let file = Path::new("test/cellranger7-1_contig_info.pb");
@@ -58,7 +58,7 @@ use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fmt::Debug;
 use std::fs::File;
-use std::io::BufRead;
+use std::io::{BufRead, Write};
 use std::ops::Deref;
 use std::path::{Path, PathBuf};
 use std::str::{self, FromStr};
@@ -109,11 +109,18 @@ impl FromStr for VdjReceptor {
             "TR" | "TCR" => VdjReceptor::TR,
             "IG" => VdjReceptor::IG,
             "TR_GD" => VdjReceptor::TRGD,
-            _ => bail!("Unknown variant '{s}' for chain type. Supported variants are: [TR, IG]"),
+            _ => bail!(
+                "Unknown variant '{s}' for chain type. Supported variants are: [TR, TR_GD, IG]"
+            ),
         })
     }
 }
 
+/// The error for gamma/delta chains without inner enrichment primers, which are not in the kit.
+pub const TRGD_INNER_PRIMERS_REQUIRED: &str = "Gamma/delta (TR_GD) libraries require inner \
+    enrichment primers. Please specify them via --inner-enrichment-primers, or via \
+    inner-enrichment-primers in the [vdj] section of the multi config CSV.";
+
 /// Create a `VdjReceptor` from a `VdjChain`
 ///
 /// # Example
@@ -207,7 +214,70 @@ pub(crate) const GENE_NAME_DISALLOWED: [&str; 9] = [
     "segment", "before", "after", "UTR", "V-REGION", "D-REGION", "J-REGION", "C-REGION", "L+V",
 ];
 
+/// The region string used in the reference fasta header for each region.
+fn region_header_str(region: VdjRegion) -> &'static str {
+    match region {
+        VdjRegion::UTR => "5'UTR",
+        VdjRegion::V => "L-REGION+V-REGION",
+        VdjRegion::D => "D-REGION",
+        VdjRegion::J => "J-REGION",
+        VdjRegion::C => "C-REGION",
+    }
+}
+
 impl VdjReferenceHeader {
+    pub fn feature_id(&self) -> u32 {
+        self.feature_id.0
+    }
+    pub fn gene_name(&self) -> &str {
+        &self.gene_name
+    }
+    pub fn display_name(&self) -> &str {
+        &self.display_name
+    }
+    pub fn region(&self) -> VdjRegion {
+        self.region
+    }
+    pub fn chain(&self) -> VdjChain {
+        self.chain
+    }
+    pub fn allele_name(&self) -> Option<&str> {
+        self.allele_name.as_deref()
+    }
+
+    /// Header of a new allele of the same gene, with the given feature id and allele name.
+    pub fn with_allele(&self, feature_id: u32, allele_name: &str) -> Self {
+        VdjReferenceHeader {
+            feature_id: FeatureId(feature_id),
+            display_name: format!("{}*{allele_name}", self.gene_name),
+            allele_name: Some(allele_name.to_string()),
+            ..self.clone()
+        }
+    }
+
+    /// The id of the fasta record, e.g. `382|IGLV5-45`
+    pub fn fasta_id(&self) -> String {
+        format!("{}|{}", self.feature_id.0, self.gene_name)
+    }
+
+    /// The description of the fasta record, e.g.
+    /// `ENST00000390296|IGLV5-45|L-REGION+V-REGION|IG|IGL|None|00`
+    pub fn fasta_desc(&self) -> String {
+        let receptor = match self.receptor {
+            VdjReceptor::IG => "IG",
+            VdjReceptor::TR | VdjReceptor::TRGD => "TR",
+        };
+        format!(
+            "{}|{}|{}|{receptor}|{}|{}|{}",
+            self.record_id,
+            self.gene_name,
+            region_header_str(self.region),
+            self.chain,
+            self.subclass.as_deref().unwrap_or("None"),
+            self.allele_name.as_deref().unwrap_or("00"),
+        )
+    }
+
     pub fn from_record(rec: &Record) -> Result<Self, HeaderErrors> {
         let word0 = rec.id();
         let Some(word1) = rec.desc() else {
@@ -367,6 +437,12 @@ impl TryFrom<Record> for VdjReferenceEntry {
 }
 
 impl VdjReferenceEntry {
+    pub fn new(header: VdjReferenceHeader, sequence: Vec<u8>) -> Self {
+        VdjReferenceEntry { header, sequence }
+    }
+    pub fn header(&self) -> &VdjReferenceHeader {
+        &self.header
+    }
     pub fn seq(&self) -> &[u8] {
         &self.sequence
     }
@@ -459,6 +535,30 @@ impl VdjReference {
         }
         Ok(VdjReference { data })
     }
+    pub fn from_entries(data: Vec<VdjReferenceEntry>) -> Self {
+        VdjReference { data }
+    }
+
+    /// Write the reference as a fasta file which can be read back using
+    /// `VdjReference::from_fasta_reader`.
+    pub fn write_fasta<W: Write>(&self, writer: W) -> Result<()> {
+        let mut writer = fasta::Writer::new(writer);
+        for entry in &self.data {
+            writer.write(&entry.fasta_id(), Some(&entry.fasta_desc()), entry.seq())?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// The largest feature id in this reference.
+    pub fn max_feature_id(&self) -> u32 {
+        self.data
+            .iter()
+            .map(|entry| entry.feature_id.0)
+            .max()
+            .unwrap_or(0)
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = &VdjReferenceEntry> {
         self.data.iter()
     }
@@ -818,6 +918,35 @@ mod tests {
         assert_eq!(entry, expected_entry);
     }
 
+    #[test]
+    fn test_write_fasta_roundtrip() {
+        let fasta = b">382|IGLV5-45 ENST00000390296|IGLV5-45|L-REGION+V-REGION|IG|IGL|None|00\nCAGGCTGTGCTG\n>629|TRGJ1 ENSMUST00000200495|TRGJ1|J-REGION|TR|TRG|None|01\nATAGCTCAGGTT\n>40|IGHG1 ENST00000390542|IGHG1|C-REGION|IG|IGH|G1|00\nGCCTCCACCAAG\n";
+        let reference = VdjReference::from_fasta_reader(fasta::Reader::new(&fasta[..])).unwrap();
+        let mut written = Vec::new();
+        reference.write_fasta(&mut written).unwrap();
+        assert_eq!(
+            str::from_utf8(&written).unwrap(),
+            str::from_utf8(fasta).unwrap()
+        );
+        let reread = VdjReference::from_fasta_reader(fasta::Reader::new(&written[..])).unwrap();
+        assert_eq!(reread, reference);
+    }
+
+    #[test]
+    fn test_with_allele() {
+        let header: VdjReferenceHeader =
+            ">382|IGLV5-45 ENST00000390296|IGLV5-45|L-REGION+V-REGION|IG|IGL|None|00"
+                .parse()
+                .unwrap();
+        let novel = header.with_allele(800, "00_A12G");
+        assert_eq!(novel.feature_id(), 800);
+        assert_eq!(novel.display_name(), "IGLV5-45*00_A12G");
+        let reparsed: VdjReferenceHeader = format!("{} {}", novel.fasta_id(), novel.fasta_desc())
+            .parse()
+            .unwrap();
+        assert_eq!(reparsed, novel);
+    }
+
     #[test]
     fn test_vdj_reference_folder() {
         let reference = VdjReference::from_reference_folder(Path::new(
@@ -865,6 +994,10 @@ mod tests {
     #[test]
     fn test_receptor_name() {
         assert_eq!(VdjReceptor::from_str("TR_GD").unwrap(), VdjReceptor::TRGD);
+        assert_eq!(
+            VdjReceptor::from_str("TRB").unwrap_err().to_string(),
+            "Unknown variant 'TRB' for chain type. Supported variants are: [TR, TR_GD, IG]"
+        );
     }
 
     #[test]
@@ -36,6 +36,13 @@ impl CardWithTableToml {
     }
 }
 
+fn quote_threshold(threshold: Option<f64>) -> proc_macro2::TokenStream {
+    match threshold {
+        Some(t) => quote![Some(#t)],
+        None => quote![None],
+    }
+}
+
 fn check_exclusive_conditions(conditions: &[&AlertConditions]) -> bool {
     if conditions.len() < 2 {
         return true;
@@ -217,36 +224,35 @@ pub fn make_tables(item: TokenStream) -> TokenStream {
                 let warn_title = alert.warn_title(name);
                 let detail = &alert.detail;
                 let symbol = alert.symbol(name);
-                let mut alert_specs = quote![
+                let error_threshold = quote_threshold(alert.error_threshold);
+                let warn_threshold = quote_threshold(alert.warn_threshold);
+                // The thresholds and the level may be overridden by the user alert config
+                let alert_specs = quote![
+                    let (error_threshold, warn_threshold) =
+                        ctx.user_alerts.thresholds(#name, #error_threshold, #warn_threshold);
                     let mut has_error = false;
-                ];
-                if let Some(error_threshold) = alert.error_threshold {
-                    alert_specs = quote![
-                        #alert_specs
-                        if val #symbol #error_threshold {
+                    if let Some(threshold) = error_threshold {
+                        if val #symbol threshold {
                             alert_specs.push(::cr_websummary::alert::AlertSpec {
-                                level: ::cr_websummary::alert::AlertLevel::Error,
+                                level: ctx.user_alerts.level(#name, ::cr_websummary::alert::AlertLevel::Error),
                                 title: #error_title.to_string(),
                                 formatted_value: formatted_value.clone(),
                                 message: #detail.to_string()
                             });
                             has_error = true;
                         }
-                    ];
-                }
-                if let Some(warn_threshold) = alert.warn_threshold {
-                    alert_specs = quote![
-                        #alert_specs
-                        if !has_error && (val #symbol #warn_threshold) {
+                    }
+                    if let Some(threshold) = warn_threshold {
+                        if !has_error && (val #symbol threshold) {
                             alert_specs.push(::cr_websummary::alert::AlertSpec {
-                                level: ::cr_websummary::alert::AlertLevel::Warn,
+                                level: ctx.user_alerts.level(#name, ::cr_websummary::alert::AlertLevel::Warn),
                                 title: #warn_title.to_string(),
                                 formatted_value,
                                 message: #detail.to_string()
                             });
                         }
-                    ];
-                }
+                    }
+                ];
                 let AlertConditions {
                     is_hybrid_capture,
                     is_lt_chemistry,
@@ -292,7 +298,7 @@ pub fn make_tables(item: TokenStream) -> TokenStream {
                     #alert_val
                     if let Some((val, formatted_value)) = #name_ident {
                         #conditions_quote
-                        if conditions_are_met {
+                        if conditions_are_met && !ctx.user_alerts.is_disabled(#name) {
                             #alert_specs
                         }
                     }
@@ -300,6 +306,17 @@ pub fn make_tables(item: TokenStream) -> TokenStream {
             }
         }
 
+        // Alerts defined in the user alert config may use any numeric metric
+        for e in v.entries.iter().filter(|e| v.entry_info[*e].ty != "String") {
+            let name_ident = format_ident!("{}", e);
+            alert_quote = quote![
+                #alert_quote
+                if let Some(m) = self.#name_ident {
+                    alert_specs.extend(ctx.user_alerts.metric_alerts(#e, m.as_f64(), &m.make_pretty()));
+                }
+            ];
+        }
+
         q = quote![
             #q
             #[automatically_derived]
@@ -5,6 +5,8 @@
 
 """Martian stage AGGREGATOR_PREFLIGHT."""
 
+from __future__ import annotations
+
 from typing import NamedTuple
 
 import martian
@@ -22,6 +24,7 @@ from cellranger.rna.library import (
 )
 from cellranger.targeted.targeted_constants import TARGETING_METHOD_HC, TARGETING_METHOD_TL
 from cellranger.utils import string_is_ascii
+from cellranger.websummary.alerts_config import AlertsConfig
 
 SC3P_CHEMISTRY_NAMES = {chemistry["name"] for chemistry in SC3P_CHEMISTRIES}
 SC5P_CHEMISTRY_NAMES = {chemistry["name"] for chemistry in SC5P_CHEMISTRIES}
@@ -31,6 +34,7 @@ stage AGGREGATOR_PREFLIGHT(
     in  map[]  sample_defs,
     in  string normalization_mode,
     in  bool   is_pd,
+    in  path   alerts_config,
     src py     "stages/aggregator/aggregator_preflight",
 )
 """
@@ -40,6 +44,7 @@ class AggregatorPreflightStageInputs:
     sample_defs: list[dict[str, str]]
     normalization_mode: str
     is_pd: bool
+    alerts_config: str | None
 
 
 def incompat_msg(reason):
@@ -51,7 +56,36 @@ def incompat_msg(reason):
     ).format(reason=reason)
 
 
-NORM_MODES = ["mapped", cr_constants.NORM_MODE_NONE]
+NORM_MODES = [
+    cr_constants.NORM_MODE_MAPPED,
+    cr_constants.NORM_MODE_MEDIAN_UMIS,
+    cr_constants.NORM_MODE_SATURATION,
+    cr_constants.NORM_MODE_RATE,
+    cr_constants.NORM_MODE_NONE,
+]
+
+
+def _check_normalization_rates(sample_defs: list[dict[str, str]]):
+    """Check that each library has a valid normalization rate in the aggr CSV."""
+    for sample_def in sample_defs:
+        library_id = sample_def[cr_constants.AGG_ID_FIELD]
+        rate = sample_def.get(cr_constants.AGG_NORMALIZATION_RATE_FIELD)
+        if rate is None:
+            martian.exit(
+                f"Normalization mode '{cr_constants.NORM_MODE_RATE}' requires a "
+                f"'{cr_constants.AGG_NORMALIZATION_RATE_FIELD}' column in the aggr CSV, "
+                f"but none was given for library '{library_id}'."
+            )
+        try:
+            rate = float(rate)
+        except ValueError:
+            rate = None
+        if rate is None or not 0.0 < rate <= 1.0:
+            martian.exit(
+                f"Invalid {cr_constants.AGG_NORMALIZATION_RATE_FIELD} for library '{library_id}': "
+                f"'{sample_def[cr_constants.AGG_NORMALIZATION_RATE_FIELD]}'. "
+                "It must be a number greater than 0 and at most 1."
+            )
 
 
 def _get_include_introns(analysis_parameters):
@@ -105,6 +139,15 @@ def main(args: AggregatorPreflightStageInputs, _outs: None):
     if args.normalization_mode is not None and args.normalization_mode not in NORM_MODES:
         martian.exit("Normalization mode must be one of: %s" % ", ".join(NORM_MODES))
 
+    if args.normalization_mode == cr_constants.NORM_MODE_RATE:
+        _check_normalization_rates(args.sample_defs)
+
+    if args.alerts_config is not None:
+        try:
+            AlertsConfig.load(args.alerts_config)
+        except (OSError, ValueError) as err:
+            martian.exit(f"Invalid alerts config {args.alerts_config}: {err}")
+
     global_fasta_hash = None
     global_gtf_hash = None
     global_feature_ref = None
@@ -22,7 +22,7 @@ import cellranger.rna.library as rna_library
 import cellranger.utils as cr_utils
 import tenkit.safe_json as tk_safe_json
 import tenkit.stats as tk_stats
-from cellranger.fast_utils import count_usable_reads
+from cellranger.fast_utils import compute_normalization_rates, count_usable_reads
 from cellranger.logperf import LogPerf
 from cellranger.matrix import CountMatrix
 from cellranger.molecule_counter import BarcodeInfo, MoleculeCounter
@@ -34,6 +34,7 @@ stage NORMALIZE_DEPTH(
     in  string     normalization_mode,
     in  map<int[]> gem_group_barcode_ranges,
     in  float      targeted_depth_factor,
+    in  map[]      sample_defs,
     out h5[]       raw_matrices_h5,
     out int        raw_nnz,
     out h5[]       filtered_matrices_h5,
@@ -87,6 +88,17 @@ def _adjust_frac_kept(
     return frac_reads_kept
 
 
+def _get_user_rates(sample_defs: list[dict[str, str]], library_info: list[dict[str, Any]]):
+    """Return the normalization rate from the aggr CSV for each library."""
+    rate_by_aggr_id = {
+        sample_def[cr_constants.AGG_ID_FIELD]: float(
+            sample_def[cr_constants.AGG_NORMALIZATION_RATE_FIELD]
+        )
+        for sample_def in sample_defs
+    }
+    return [rate_by_aggr_id[lib["aggr_id"]] for lib in library_info]
+
+
 def any_incorrect_usable_read_pairs(mc: MoleculeCounter) -> bool:
     """Return whether the metric usable_read_pairs is incorrect for any library.
 
@@ -103,7 +115,7 @@ def any_incorrect_usable_read_pairs(mc: MoleculeCounter) -> bool:
 
 def split(args):
     # default to downsampling by mapped reads
-    downsample = args.normalization_mode != cr_constants.NORM_MODE_NONE
+    mode = args.normalization_mode or cr_constants.NORM_MODE_MAPPED
     reads_per_library = None
 
     # compute downsample rates for each library
@@ -159,8 +171,18 @@ def split(args):
         print(f"{lib_type} Usable read pairs per cell: {usable_rpc[lib_idx]}")
         print("%s Minimum read pairs usable per cell: %d" % (lib_type, min_rpc_by_lt[lib_type]))
 
-    if not downsample:
+    # Libraries of the same type are normalized together
+    lib_types = rna_library.sorted_library_types(library_info)
+    library_groups = [lib_types.index(lib[rna_library.LIBRARY_TYPE]) for lib in library_info]
+
+    if mode == cr_constants.NORM_MODE_NONE:
         frac_reads_kept = np.ones(len(library_info), dtype=float)
+    elif mode in (cr_constants.NORM_MODE_MEDIAN_UMIS, cr_constants.NORM_MODE_SATURATION):
+        frac_reads_kept = np.array(
+            compute_normalization_rates(args.molecules, mode, library_groups), dtype=float
+        )
+    elif mode == cr_constants.NORM_MODE_RATE:
+        frac_reads_kept = np.array(_get_user_rates(args.sample_defs, library_info), dtype=float)
     else:
         frac_reads_kept = np.zeros(len(library_info), dtype=float)
         for i, lib in enumerate(library_info):
@@ -368,6 +390,40 @@ def _get_matrix(
     return read_matrix, umi_matrix
 
 
+def _get_cell_depth(
+    chunk: slice,
+    mc: MoleculeCounter,
+    new_read_pairs: np.ndarray,
+    keep_mol: np.ndarray,
+    barcode_info: BarcodeInfo,
+    num_libraries: int,
+):
+    """Count the UMIs of each cell and the reads in cells of each library after downsampling."""
+    library_idx = mc.get_column_lazy("library_idx")[chunk][keep_mol]
+    barcode_idx = mc.get_column_lazy("barcode_idx")[chunk][keep_mol]
+
+    # A cell is a (barcode, library) pair in pass_filter, of any genome
+    num_libraries = np.uint64(num_libraries)
+    mol_keys = barcode_idx.astype(np.uint64) * num_libraries + library_idx.astype(np.uint64)
+    pass_filter = barcode_info.pass_filter.astype(np.uint64)
+    cell_keys = np.unique(pass_filter[:, 0] * num_libraries + pass_filter[:, 1])
+    in_cell = np.isin(mol_keys, cell_keys)
+
+    keys, umis_per_cell = np.unique(mol_keys[in_cell], return_counts=True)
+    cell_library_idx = keys % num_libraries
+    reads_in_cells = np.bincount(
+        library_idx[in_cell].astype(np.int64),
+        weights=new_read_pairs[in_cell],
+        minlength=int(num_libraries),
+    )
+    return {
+        "umis_per_cell": [
+            umis_per_cell[cell_library_idx == i].tolist() for i in range(int(num_libraries))
+        ],
+        "reads_in_cells": reads_in_cells.tolist(),
+    }
+
+
 def _get_barcode_idxs(gem_groups, gem_group_barcode_ranges):
     # Get the range of possible barcode indices for each gem group.
     idx_ranges = np.array(
@@ -396,6 +452,9 @@ def _update_metrics(
     new_read_pairs = _get_new_read_pairs(chunk, mc, frac_reads_kept)
     keep_mol = np.flatnonzero(new_read_pairs)
     new_read_pairs = new_read_pairs[keep_mol]
+    cell_depth = _get_cell_depth(
+        chunk, mc, new_read_pairs, keep_mol, barcode_info, len(library_info)
+    )
 
     # Assert that gem groups start at 1 and are contiguous.  If they are,
     # then the sorted set of unique groups will be identically range [1, N].
@@ -475,7 +534,7 @@ def _update_metrics(
     LogPerf.mem()
     # Construct the raw UMI matrix
     raw_umi_matrix = CountMatrix(feature_ref, barcodes, umi_matrix)
-    return raw_umi_matrix, barcode_seqs, read_summary
+    return raw_umi_matrix, barcode_seqs, read_summary, cell_depth
 
 
 def main(args, outs):
@@ -489,7 +548,7 @@ def main(args, outs):
 
         metrics_out, frac_reads_kept = _make_metrics_out(args, library_info, mc)
 
-        raw_umi_matrix, barcode_seqs, read_summary = _update_metrics(
+        raw_umi_matrix, barcode_seqs, read_summary, cell_depth = _update_metrics(
             args, frac_reads_kept, mc, library_info, barcode_info
         )
         raw_umi_matrix.save_h5_file(outs.raw_matrix_h5, sw_version=martian.get_pipelines_version())
@@ -522,6 +581,7 @@ def main(args, outs):
         summary = {
             "read_summary": read_summary,
             "mol_metrics": metrics_out,
+            "cell_depth": cell_depth,
         }
 
         with open(outs.chunk_summary, "w") as f:
@@ -585,6 +645,17 @@ def join(args, outs, chunk_defs, chunk_outs):
                 read_summary[k] += v
     summary.update(read_summary)
 
+    # Merge the UMIs per cell and reads in cells after downsampling. The chunks preserve
+    # barcode boundaries, so each cell is counted in a single chunk.
+    umis_per_cell = [[] for _ in library_info]
+    reads_in_cells = np.zeros(len(library_info), dtype=float)
+    for filename in [co.chunk_summary for co in chunk_outs]:
+        with open(filename) as f:
+            cell_depth = json.load(f)["cell_depth"]
+            for lib_idx, umis in enumerate(cell_depth["umis_per_cell"]):
+                umis_per_cell[lib_idx].extend(umis)
+            reads_in_cells += cell_depth["reads_in_cells"]
+
     # Get summary metrics
     with open(chunk_outs[0].chunk_summary) as f:
         mol_metrics = json.load(f)["mol_metrics"]
@@ -636,6 +707,15 @@ def join(args, outs, chunk_defs, chunk_outs):
             pre_norm_raw_rppc = tk_stats.robust_divide(raw_read_pairs, n_cells)
             pre_norm_mapped_rppc = tk_stats.robust_divide(mapped_read_pairs, n_cells)
             pre_norm_feature_rppc = tk_stats.robust_divide(feature_reads, n_cells)
+            post_norm_raw_rppc = tk_stats.robust_divide(ds_read_pairs, n_cells)
+
+            # Cells without any molecule left after downsampling have zero UMIs
+            lib_umis_per_cell = np.zeros(max(n_cells, len(umis_per_cell[lib_idx])), dtype=int)
+            lib_umis_per_cell[: len(umis_per_cell[lib_idx])] = umis_per_cell[lib_idx]
+            post_norm_median_umis = np.median(lib_umis_per_cell) if n_cells > 0 else 0.0
+            post_norm_saturation = 1.0 - tk_stats.robust_divide(
+                lib_umis_per_cell.sum(), reads_in_cells[lib_idx]
+            )
 
             # analysis parameter
             gg = library_info[lib_idx]["gem_group"]
@@ -663,6 +743,15 @@ def join(args, outs, chunk_defs, chunk_outs):
                         *p
                     ): pre_norm_feature_rppc,
                     "{}_{}introns_included".format(*p): intron_mode_param,
+                    "{}_{}post_normalization_raw_reads_per_filtered_bc".format(
+                        *p
+                    ): post_norm_raw_rppc,
+                    "{}_{}post_normalization_median_umis_per_filtered_bc".format(
+                        *p
+                    ): post_norm_median_umis,
+                    "{}_{}post_normalization_sequencing_saturation".format(
+                        *p
+                    ): post_norm_saturation,
                 }
             )
 
@@ -76,6 +76,7 @@ META_PARSE_CSV_FIELDS = {
         AGG_SAMPLE_ID_FIELD: agg_fields(type="string", required=True),
         cr_constants.AGG_H5_FIELD: agg_fields(type="file_path", required=True),
         cr_constants.AGG_BATCH_FIELD: agg_fields(type="string", required=False),
+        cr_constants.AGG_NORMALIZATION_RATE_FIELD: agg_fields(type="string", required=False),
     },
 }
 
@@ -13,6 +13,7 @@ from cellranger.websummary.aggr_websummary_builder import (
     build_web_summary_data_aggr,
     build_web_summary_html_aggr,
 )
+from cellranger.websummary.alerts_config import AlertsConfig, write_alerts_json
 from cellranger.websummary.react_components import ReactComponentEncoder
 
 __MRO__ = """
@@ -29,9 +30,11 @@ stage SUMMARIZE_AGGREGATED_REPORTS(
     in  json   antibody_treemap,
     in  json   crispr_analysis_metrics,
     in  string product_type,
+    in  path   alerts_config,
     out json   summary,
     out html   web_summary,
     out json   web_summary_data,
+    out json   web_summary_alerts,
     src py     "stages/aggregator/summarize_aggregated_reports",
 ) split (
 )
@@ -91,6 +94,7 @@ def join(args, outs, _chunk_defs, _chunk_outs):
     )
     # Call the websummary builder.
     gg_id_to_name = {int(id): name[0] for id, name in args.gem_group_index.items()}
+    alerts_config = AlertsConfig.load(args.alerts_config) if args.alerts_config else None
 
     build_web_summary_html_aggr(
         filename=outs.web_summary,
@@ -98,6 +102,7 @@ def join(args, outs, _chunk_defs, _chunk_outs):
         gg_id_to_name_map=gg_id_to_name,
         sample_data_paths=sample_data_paths,
         sample_defs=args.sample_defs,
+        alerts_config=alerts_config,
     )
 
     # Do it again because ReactComponentEncoder transforms and deletes data while encoding
@@ -106,6 +111,8 @@ def join(args, outs, _chunk_defs, _chunk_outs):
         gg_id_to_name_map=gg_id_to_name,
         sample_data_paths=sample_data_paths,
         sample_defs=args.sample_defs,
+        alerts_config=alerts_config,
     )
+    write_alerts_json(outs.web_summary_alerts, ws_data.alarms)
     with open(outs.web_summary_data, "w") as f:
         json.dump(ws_data, f, indent=4, cls=ReactComponentEncoder)
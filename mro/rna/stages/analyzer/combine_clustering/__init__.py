@@ -18,6 +18,8 @@ stage COMBINE_CLUSTERING(
     in  path graphclust_csv,
     in  h5   hclust_h5,
     in  path hclust_csv,
+    in  h5   wnn_h5,
+    in  path wnn_csv,
     out h5   clustering_h5,
     out path clustering_csv,
     src py   "stages/analyzer/combine_clustering",
@@ -39,6 +41,8 @@ def main(args, outs):
     list_of_hdfs = [args.kmeans_h5, args.graphclust_h5]
     if args.hclust_h5:
         list_of_hdfs.append(args.hclust_h5)
+    if args.wnn_h5:
+        list_of_hdfs.append(args.wnn_h5)
     analysis_io.combine_h5_files(
         list_of_hdfs,
         outs.clustering_h5,
@@ -54,3 +58,5 @@ def main(args, outs):
     copy_subdirs(args.graphclust_csv, csv_path)
     if args.hclust_csv:
         copy_subdirs(args.hclust_csv, csv_path)
+    if args.wnn_csv:
+        copy_subdirs(args.wnn_csv, csv_path)
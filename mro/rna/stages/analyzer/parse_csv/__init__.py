@@ -10,9 +10,15 @@ import shutil
 
 import martian
 
+import cellranger.csv_io as cr_csv_io
+import cellranger.matrix as cr_matrix
+
 __MRO__ = """
 stage PARSE_PARAM_CSV(
     in  csv   params_csv,
+    in  h5    filtered_matrices_h5,
+    in  csv   barcodes_csv,
+    in  int   force_cells,
     out csv   params_csv,
     out int   num_analysis_bcs,
     out int   random_seed,
@@ -39,6 +45,9 @@ stage PARSE_PARAM_CSV(
     out int   umap_max_dims,
     out float umap_min_dist,
     out string umap_metric,
+    out int   pseudotime_root_cluster,
+    out string pseudotime_root_barcode,
+    out bool  antibody_normalization,
     src py    "stages/analyzer/parse_csv",
 )
 """
@@ -69,11 +78,15 @@ ANALYSIS_PARAMS = {
     "umap_max_dims": int,
     "umap_min_dist": float,
     "umap_metric": str,
+    "pseudotime_root_cluster": int,
+    "pseudotime_root_barcode": str,
+    "antibody_normalization": bool,
 }
 
 
 def main(args, outs):
     parsed = parse_parameters(args.params_csv)
+    check_pseudotime_root(parsed, args)
     for param in ANALYSIS_PARAMS:
         if param in parsed:
             setattr(outs, param, parsed[param])
@@ -84,6 +97,47 @@ def main(args, outs):
         shutil.copy(args.params_csv, outs.params_csv)
 
 
+def check_pseudotime_root(params, args):
+    """Check that at most one pseudotime root is given, and that the root barcode is analyzed.
+
+    The barcodes analyzed are selected from the filtered matrix as PREPROCESS_MATRIX does, by the
+    force_cells barcodes with the most UMIs, the barcodes CSV, or a random subsample of
+    num_analysis_bcs barcodes. The first depends on the counts, and RUN_DIFFUSION_PSEUDOTIME
+    reports a root barcode that is not analyzed.
+    """
+    root_cluster = params.get("pseudotime_root_cluster")
+    root_barcode = params.get("pseudotime_root_barcode")
+    if root_cluster is not None and root_barcode is not None:
+        martian.exit("Specify either pseudotime_root_cluster or pseudotime_root_barcode, not both.")
+    if root_cluster is not None and root_cluster < 1:
+        martian.exit(
+            "Parameter pseudotime_root_cluster must be a graph-based cluster number of at least 1, "
+            "not %d." % root_cluster
+        )
+    if root_barcode is None or args.filtered_matrices_h5 is None or args.force_cells is not None:
+        return
+    barcodes = cr_matrix.CountMatrix.load_bcs_from_h5(args.filtered_matrices_h5)
+    if root_barcode.encode() not in barcodes:
+        martian.exit(
+            "Parameter pseudotime_root_barcode %s is not a barcode of the filtered matrix."
+            % root_barcode
+        )
+    if args.barcodes_csv is not None:
+        if root_barcode.encode() not in cr_csv_io.load_csv_rownames(args.barcodes_csv):
+            martian.exit(
+                "Parameter pseudotime_root_barcode %s is not one of the barcodes to analyze of "
+                "the barcodes CSV." % root_barcode
+            )
+        return
+    num_analysis_bcs = params.get("num_analysis_bcs")
+    if num_analysis_bcs is not None and num_analysis_bcs < len(barcodes):
+        martian.exit(
+            "Parameter pseudotime_root_barcode cannot be used with num_analysis_bcs %d, which "
+            "analyzes a random subsample of the %d barcodes that may exclude the root barcode. "
+            "Specify pseudotime_root_cluster instead." % (num_analysis_bcs, len(barcodes))
+        )
+
+
 def parse_parameters(filename):
     if filename is None:
         return {}
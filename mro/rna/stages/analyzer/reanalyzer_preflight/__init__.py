@@ -3,6 +3,7 @@
 # Copyright (c) 2017 10x Genomics, Inc. All rights reserved.
 #
 
+import glob
 import os
 
 import martian
@@ -12,14 +13,47 @@ import cellranger.matrix as cr_matrix
 
 __MRO__ = """
 stage REANALYZER_PREFLIGHT(
-    in  h5 filtered_matrices_h5,
-    src py "stages/analyzer/reanalyzer_preflight",
+    in  h5   filtered_matrices_h5,
+    in  path reference_analysis,
+    src py   "stages/analyzer/reanalyzer_preflight",
 ) using (
     volatile = strict,
 )
 """
 
 
+# The files of the gene expression analysis of --reference-analysis used by its projection.
+# The feature scaling of the PCA is recomputed from the filtered matrix next to the analysis
+# folder when its feature_scaling.csv is absent.
+REFERENCE_ANALYSIS_FILES = [
+    "pca/gene_expression_*_components/components.csv",
+    "pca/gene_expression_*_components/features_selected.csv",
+    "pca/gene_expression_*_components/projection.csv",
+    "clustering/gene_expression_graphclust/clusters.csv",
+    "umap/gene_expression_*_components/projection.csv",
+]
+
+
+def check_reference_analysis(reference_analysis):
+    """Check that the analysis folder of a previous run has the PCA, clustering and UMAP."""
+    if not os.path.isdir(reference_analysis):
+        martian.exit("Reference analysis folder does not exist: %s" % reference_analysis)
+    for subdir in ["pca", "clustering", "umap"]:
+        if not os.path.isdir(os.path.join(reference_analysis, subdir)):
+            martian.exit(
+                "Reference analysis folder %s has no %s folder. "
+                "Please provide the analysis folder of a previous run."
+                % (reference_analysis, subdir)
+            )
+    for pattern in REFERENCE_ANALYSIS_FILES:
+        if not glob.glob(os.path.join(reference_analysis, pattern)):
+            martian.exit(
+                "Reference analysis folder %s has no gene expression %s. "
+                "Please provide the analysis folder of a run of this version of the software."
+                % (reference_analysis, pattern)
+            )
+
+
 def main(args, outs):
     if not (args.filtered_matrices_h5 and os.path.exists(args.filtered_matrices_h5)):
         martian.exit("Filtered matrices do not exist: %s" % args.filtered_matrices_h5)
@@ -56,3 +90,6 @@ def main(args, outs):
         )
     if len(flt_genomes) == 0:
         martian.log_info("Only Antibody Capture library detected")
+
+    if args.reference_analysis:
+        check_reference_analysis(args.reference_analysis)
@@ -35,6 +35,11 @@ stage SUMMARIZE_ANALYSIS(
     in  bool chemistry_batch_correction,
     in  float batch_score_before_correction,
     in  float batch_score_after_correction,
+    in  csv  cell_types_csv,
+    in  csv  cluster_cell_types_csv,
+    in  h5   antibody_normalization_h5,
+    in  h5   pseudotime_h5,
+    in  path pseudotime_csv,
     out path analysis,
     out path analysis_csv,
     out json summary,
@@ -68,6 +73,10 @@ def main(args, outs):
     h5s_to_combine = [args.pca_h5, args.clustering_h5, args.diffexp_h5, args.umap_h5]
     if args.tsne_h5:
         h5s_to_combine.append(args.tsne_h5)
+    if args.antibody_normalization_h5:
+        h5s_to_combine.append(args.antibody_normalization_h5)
+    if args.pseudotime_h5:
+        h5s_to_combine.append(args.pseudotime_h5)
     cr_h5.combine_h5s_into_one(analysis_h5, h5s_to_combine)
 
     pca_dir = os.path.join(outs.analysis_csv, "pca")
@@ -86,6 +95,20 @@ def main(args, outs):
     umap_dir = os.path.join(outs.analysis_csv, "umap")
     cr_io.hardlink_with_fallback(args.umap_csv, umap_dir)
 
+    if args.cell_types_csv:
+        cell_types_dir = os.path.join(outs.analysis_csv, "cell_types")
+        os.makedirs(cell_types_dir, exist_ok=True)
+        cr_io.hardlink_with_fallback(
+            args.cell_types_csv, os.path.join(cell_types_dir, "cell_types.csv")
+        )
+        cr_io.hardlink_with_fallback(
+            args.cluster_cell_types_csv, os.path.join(cell_types_dir, "cluster_cell_types.csv")
+        )
+
+    if args.pseudotime_csv:
+        pseudotime_dir = os.path.join(outs.analysis_csv, "diffusion_pseudotime")
+        cr_io.hardlink_with_fallback(args.pseudotime_csv, pseudotime_dir)
+
 
 def join(args, outs, chunk_defs, chunk_outs):
     chunk_out = chunk_outs[0]
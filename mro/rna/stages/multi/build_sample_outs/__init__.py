@@ -11,8 +11,15 @@ stage BUILD_SAMPLE_OUTS(
     in  path            rna_analysis,
     in  path            crispr_analysis,
     in  cloupe          cloupe,
+    in  h5ad            h5ad,
+    in  h5              ambient_corrected_matrix_h5,
+    in  csv             ambient_rna_csv,
+    in  csv             doublets_csv,
+    in  path            molecule_info_parquet,
+    in  parquet         barcode_summary_parquet,
     in  html            web_summary,
     in  csv             metrics_summary_csv,
+    in  json            web_summary_alerts,
     in  VdjOutputsCS    vdj_b_outs,
     in  VdjOutputsCS    vdj_t_outs,
     in  VdjOutputsCS    vdj_t_gd_outs,
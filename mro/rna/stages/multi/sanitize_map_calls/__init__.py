@@ -13,6 +13,10 @@ stage SANITIZE_MAP_CALLS(
     in  map<path>         in_crispr_analysis,
     in  map<path>         in_rna_analysis,
     in  map<cloupe>       in_cloupe_file,
+    in  map<h5ad>         in_h5ad,
+    in  map<h5>           in_ambient_corrected_matrix_h5,
+    in  map<csv>          in_ambient_rna_csv,
+    in  map<csv>          in_doublets_csv,
     in  map<json>         in_metrics_summary,
     in  map<json>         in_sample_tsne_plots,
     in  map<json>         in_sample_barcode_rank_plots,
@@ -23,6 +27,10 @@ stage SANITIZE_MAP_CALLS(
     out map<path>         crispr_analysis,
     out map<path>         rna_analysis,
     out map<cloupe>       cloupe_file,
+    out map<h5ad>         h5ad,
+    out map<h5>           ambient_corrected_matrix_h5,
+    out map<csv>          ambient_rna_csv,
+    out map<csv>          doublets_csv,
     out map<json>         metrics_summary,
     out map<json>         sample_tsne_plots,
     out map<json>         sample_barcode_rank_plots,
@@ -122,6 +130,12 @@ def main(args, outs):
     outs.rna_analysis = cr_io.recursive_hard_link_dict(args.in_rna_analysis)
     outs.crispr_analysis = cr_io.recursive_hard_link_dict(args.in_crispr_analysis)
     outs.cloupe_file = cr_io.recursive_hard_link_dict(args.in_cloupe_file)
+    outs.h5ad = cr_io.recursive_hard_link_dict(args.in_h5ad)
+    outs.ambient_corrected_matrix_h5 = cr_io.recursive_hard_link_dict(
+        args.in_ambient_corrected_matrix_h5
+    )
+    outs.ambient_rna_csv = cr_io.recursive_hard_link_dict(args.in_ambient_rna_csv)
+    outs.doublets_csv = cr_io.recursive_hard_link_dict(args.in_doublets_csv)
     outs.metrics_summary = cr_io.recursive_hard_link_dict(args.in_metrics_summary)
     outs.sample_tsne_plots = cr_io.recursive_hard_link_dict(args.in_sample_tsne_plots)
     outs.sample_barcode_rank_plots = cr_io.recursive_hard_link_dict(